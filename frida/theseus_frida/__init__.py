@@ -10,6 +10,7 @@ import tempfile
 import shutil
 import lzma
 import re
+import uuid
 from pathlib import Path
 from typing import TextIO, Any
 from collections.abc import Callable
@@ -31,6 +32,13 @@ STACK_CONSUMER_B64 = Path(__file__).parent / "StackConsumer.dex.b64"
 # The type is 'int', so it sould be a 32bit signed value?
 HASH_NB_BYTES = 4
 
+# The package of kotlin-reflect, whose frames are skipped to find the call sites of the
+# application
+KOTLIN_REFLECT_PREFIX = "Lkotlin/reflect/"
+# The classes of java.util.ServiceLoader, whose frames are skipped to find the call to
+# Iterator.next() that instantiated a service provider
+SERVICE_LOADER_PREFIX = "Ljava/util/ServiceLoader"
+
 CLASSLOADER_DONE = False
 
 
@@ -45,8 +53,20 @@ def on_message(message, data, data_storage: dict, file_storage: Path):
         handle_class_new_inst_data(message["payload"]["data"], data_storage)
     elif message["type"] == "send" and message["payload"]["type"] == "cnstr-new-isnt":
         handle_cnstr_new_inst_data(message["payload"]["data"], data_storage)
+    elif message["type"] == "send" and message["payload"]["type"] == "kotlin-reflect":
+        handle_kotlin_reflect(message["payload"]["data"], data_storage)
+    elif message["type"] == "send" and message["payload"]["type"] == "array-new-inst":
+        handle_array_new_inst_data(message["payload"]["data"], data_storage)
     elif message["type"] == "send" and message["payload"]["type"] == "load-dex":
         handle_load_dex(message["payload"]["data"], data_storage, file_storage)
+    elif message["type"] == "send" and message["payload"]["type"] == "load-class":
+        handle_load_class(message["payload"]["data"], data_storage)
+    elif message["type"] == "send" and message["payload"]["type"] == "load-native":
+        handle_load_native(message["payload"]["data"], data_storage, file_storage)
+    elif message["type"] == "send" and message["payload"]["type"] == "jni-upcall":
+        handle_jni_upcall(message["payload"]["data"], data_storage)
+    elif message["type"] == "send" and message["payload"]["type"] == "register-natives":
+        handle_register_natives(message["payload"]["data"], data_storage)
     elif message["type"] == "send" and message["payload"]["type"] == "classloader":
         handle_classloader_data(message["payload"]["data"], data_storage)
     elif message["type"] == "send" and message["payload"]["type"] == "classloader-done":
@@ -77,6 +97,12 @@ def cl_id_to_string(classloader: int) -> str | None:
 def handle_classloader_data(data: dict, data_storage: dict):
     data["id"] = cl_id_to_string(data["id"])
     data["parent_id"] = cl_id_to_string(data["parent_id"])
+    data["shared_libraries"] = list(
+        map(cl_id_to_string, data.get("shared_libraries", []))
+    )
+    data["shared_libraries_after"] = list(
+        map(cl_id_to_string, data.get("shared_libraries_after", []))
+    )
     print(f"[+] Got classloader {data['id']}({data['str']})")
     data_storage["classloaders"][data["id"]] = data
 
@@ -93,6 +119,9 @@ def handle_invoke_data(data, data_storage: dict):
     if len(data["stack"]) == 0:
         return
     caller_method = data["stack"][0]["method"]
+    # Recorded at the call site of the application by handle_kotlin_reflect()
+    if caller_method.startswith(KOTLIN_REFLECT_PREFIX):
+        return
     caller_cl_id = cl_id_to_string(data["stack"][0]["cl_id"])
     addr = data["stack"][0]["bytecode_index"]
     is_static = data["is_static"]
@@ -136,6 +165,11 @@ def handle_class_new_inst_data(data, data_storage: dict):
         frame = data["stack"][1]
     else:
         return
+    if frame["method"].startswith(SERVICE_LOADER_PREFIX):
+        handle_service_new_inst_data(
+            constructor, constructor_cl_id, data["stack"], data_storage
+        )
+        return
     caller_method = frame["method"]
     caller_cl_id = cl_id_to_string(frame["cl_id"])
     addr = frame["bytecode_index"]
@@ -163,11 +197,21 @@ def handle_class_new_inst_data(data, data_storage: dict):
 def handle_cnstr_new_inst_data(data, data_storage: dict):
     constructor = data["constructor"]
     constructor_cl_id = cl_id_to_string(data["constructor_cl_id"])
+    if len(data["stack"]) > 0 and data["stack"][0]["method"].startswith(
+        SERVICE_LOADER_PREFIX
+    ):
+        handle_service_new_inst_data(
+            constructor, constructor_cl_id, data["stack"], data_storage
+        )
+        return
     if not constructor.startswith("Lcom/example/theseus"):
         return
     if len(data["stack"]) == 0:
         return
     caller_method = data["stack"][0]["method"]
+    # Recorded at the call site of the application by handle_kotlin_reflect()
+    if caller_method.startswith(KOTLIN_REFLECT_PREFIX):
+        return
     caller_cl_id = cl_id_to_string(data["stack"][0]["cl_id"])
     addr = data["stack"][0]["bytecode_index"]
     print("[+] Constructor.newInstance:")
@@ -191,15 +235,138 @@ def handle_cnstr_new_inst_data(data, data_storage: dict):
     )
 
 
+def handle_service_new_inst_data(
+    constructor: str, constructor_cl_id: str | None, stack: list, data_storage: dict
+):
+    # Skip the frames of the reflective call and of the ServiceLoader to find the call to
+    # Iterator.next() of the application
+    reflective_frames = (
+        "Ljava/lang/Class;->newInstance(",
+        "Ljava/lang/reflect/Constructor;->newInstance(",
+    )
+    frames = [
+        frame for frame in stack if not frame["method"].startswith(reflective_frames)
+    ]
+    while len(frames) > 0 and frames[0]["method"].startswith(SERVICE_LOADER_PREFIX):
+        frames.pop(0)
+    if len(frames) == 0:
+        return
+    caller_method = frames[0]["method"]
+    caller_cl_id = cl_id_to_string(frames[0]["cl_id"])
+    addr = frames[0]["bytecode_index"]
+    print("[+] ServiceLoader provider instantiated:")
+    print(f"    called: [{constructor_cl_id}]{constructor}")
+    print(f"    by:     [{caller_cl_id}]{caller_method}")
+    print(f"    at:     0x{addr:08x}")
+    if addr < 0:
+        return
+    data_storage["service_new_inst_data"].append(
+        {
+            "constructor": constructor,
+            "constructor_cl_id": constructor_cl_id,
+            "renamed_constructor": None,
+            "caller_method": caller_method,
+            "caller_cl_id": caller_cl_id,
+            "renamed_caller_method": None,
+            "addr": addr,
+        }
+    )
+
+
+def handle_array_new_inst_data(data, data_storage: dict):
+    component_type = data["component_type"]
+    component_cl_id = cl_id_to_string(data["component_cl_id"])
+    if len(data["stack"]) == 0:
+        return
+    if not data["stack"][0]["method"].startswith("Ljava/lang/reflect/Array;->"):
+        frame = data["stack"][0]
+    elif len(data["stack"]) > 1:
+        frame = data["stack"][1]
+    else:
+        return
+    caller_method = frame["method"]
+    caller_cl_id = cl_id_to_string(frame["cl_id"])
+    # The platform creates arrays with Array.newInstance() all the time (eg
+    # ArrayList.toArray()), only keep the calls from the application
+    if caller_cl_id is None:
+        return
+    addr = frame["bytecode_index"]
+    print("[+] Array.newInstance:")
+    print(f"    type:   [{component_cl_id}]{component_type}")
+    print(f"    by:     [{caller_cl_id}]{caller_method}")
+    print(f"    at:     0x{addr:08x}")
+    if addr < 0:
+        return
+    data_storage["array_new_inst_data"].append(
+        {
+            "component_type": component_type,
+            "component_cl_id": component_cl_id,
+            "renamed_component_type": None,
+            "caller_method": caller_method,
+            "caller_cl_id": caller_cl_id,
+            "renamed_caller_method": None,
+            "addr": addr,
+        }
+    )
+
+
+def handle_kotlin_reflect(data, data_storage: dict):
+    method = data["method"]
+    method_cl_id = cl_id_to_string(data["method_cl_id"])
+    entry = data["entry"]
+    # Skip the frames of kotlin-reflect to find the call site of the application
+    frames = list(data["stack"])
+    outermost = None
+    while len(frames) > 0 and frames[0]["method"].startswith(KOTLIN_REFLECT_PREFIX):
+        outermost = frames.pop(0)
+    if len(frames) == 0:
+        return
+    # createInstance() calls the constructor with callBy()
+    if outermost is not None and "->createInstance(" in outermost["method"]:
+        entry = "create_instance"
+    caller_method = frames[0]["method"]
+    caller_cl_id = cl_id_to_string(frames[0]["cl_id"])
+    addr = frames[0]["bytecode_index"]
+    is_static = data["is_static"]
+    bound_receiver = data["bound_receiver"]
+    print(f"[+] Kotlin reflection ({entry}):")
+    print(f"    called: [{method_cl_id}]{method}{' (static)' if is_static else ''}")
+    print(f"    by:     [{caller_cl_id}]{caller_method}")
+    print(f"    at:     0x{addr:08x}")
+    if addr < 0:
+        return
+    data_storage["kotlin_reflection"].append(
+        {
+            "entry": entry,
+            "method": method,
+            "method_cl_id": method_cl_id,
+            "renamed_method": None,
+            "caller_method": caller_method,
+            "caller_cl_id": caller_cl_id,
+            "renamed_caller_method": None,
+            "addr": addr,
+            "is_static": is_static,
+            "bound_receiver": bound_receiver,
+        }
+    )
+
+
 def handle_load_dex(data, data_storage: dict, file_storage: Path):
     dex = data["dex"]
     classloader_class = data["classloader_class"]
     classloader = cl_id_to_string(data["classloader"])
     classloader_parent = cl_id_to_string(data["classloader_parent"])
+    classloader_shared_libraries = list(
+        map(cl_id_to_string, data.get("classloader_shared_libraries", []))
+    )
     short_class = classloader_class.split("/")[-1].removesuffix(";")
+    # The path given to the class loader, None for in-memory dex files
+    source = data.get("source")
     files = []
     print("[+] DEX file loaded:")
     print(f"    by:     {classloader_class} ({classloader})")
+    if source is not None:
+        print(f"    from:   {source}")
     for file in dex:
         file_bin = base64.b64decode(file)
         hasher = hashlib.sha1()
@@ -219,16 +386,201 @@ def handle_load_dex(data, data_storage: dict, file_storage: Path):
             fp.write(file_bin)
         print(f"    stored: {str(fname)}")
         files.append(str(fname))
+    construction_site = find_construction_site(
+        data.get("stack", []), classloader_class
+    )
+    if construction_site is not None:
+        print(f"    site:   {construction_site['caller_method']}")
+        print(f"    at:     0x{construction_site['addr']:08x}")
     data_storage["dyn_code_load"].append(
         {
             "classloader_class": classloader_class,
             "classloader": classloader,
             "files": files,
+            "sources": [source] if source is not None else [],
             "classloader_parent": classloader_parent,
+            "classloader_shared_libraries": classloader_shared_libraries,
+            "construction_site": construction_site,
         }
     )
 
 
+def find_construction_site(stack, classloader_class: str) -> dict | None:
+    """Find the frame that called the constructor of the class loader loading the code."""
+    constructor = None
+    for frame in stack:
+        if frame["method"].startswith(classloader_class + "-><init>("):
+            # The outermost constructor if they call each other
+            constructor = frame["method"]
+        elif constructor is not None:
+            if frame["bytecode_index"] < 0:
+                return None
+            return {
+                "constructor": constructor,
+                "caller_method": frame["method"],
+                "caller_cl_id": cl_id_to_string(frame["cl_id"]),
+                "renamed_caller_method": None,
+                "addr": frame["bytecode_index"],
+            }
+    return None
+
+
+def handle_load_class(data, data_storage: dict):
+    cls = data["class"]
+    classloader = cl_id_to_string(data["classloader"])
+    class_cl_id = cl_id_to_string(data["class_cl_id"])
+    # Skip ClassLoader.loadClass() and its overrides
+    frames = [
+        frame
+        for frame in data["stack"]
+        if "->loadClass(Ljava/lang/String;" not in frame["method"]
+    ]
+    if len(frames) == 0:
+        return
+    frame = frames[0]
+    caller_method = frame["method"]
+    caller_cl_id = cl_id_to_string(frame["cl_id"])
+    addr = frame["bytecode_index"]
+    print("[+] ClassLoader.loadClass:")
+    print(f"    class:  [{class_cl_id}]{cls}")
+    print(f"    from:   {classloader}")
+    print(f"    by:     [{caller_cl_id}]{caller_method}")
+    print(f"    at:     0x{addr:08x}")
+    if addr < 0:
+        return
+    data_storage["class_loading"].append(
+        {
+            "class": cls,
+            "classloader": classloader,
+            "class_cl_id": class_cl_id,
+            "renamed_class": None,
+            "caller_method": caller_method,
+            "caller_cl_id": caller_cl_id,
+            "renamed_caller_method": None,
+            "addr": addr,
+        }
+    )
+
+
+# ELF e_machine values of the ABIs supported by android
+ELF_MACHINE_TO_ABI = {
+    3: "x86",
+    40: "armeabi-v7a",
+    62: "x86_64",
+    183: "arm64-v8a",
+}
+
+
+def get_elf_abi(lib: bytes) -> str | None:
+    if len(lib) < 20 or lib[:4] != b"\x7fELF":
+        return None
+    byteorder = "little" if lib[5] == 1 else "big"
+    return ELF_MACHINE_TO_ABI.get(int.from_bytes(lib[18:20], byteorder))
+
+
+def handle_load_native(data, data_storage: dict, file_storage: Path):
+    path = data["path"]
+    if len(data["stack"]) == 0:
+        return
+    if data["stack"][0]["method"] != "Ljava/lang/System;->load(Ljava/lang/String;)V":
+        frame = data["stack"][0]
+    elif len(data["stack"]) > 1:
+        frame = data["stack"][1]
+    else:
+        return
+    caller_method = frame["method"]
+    caller_cl_id = cl_id_to_string(frame["cl_id"])
+    addr = frame["bytecode_index"]
+    print("[+] Native library loaded:")
+    print(f"    path:   {path}")
+    print(f"    by:     [{caller_cl_id}]{caller_method}")
+    print(f"    at:     0x{addr:08x}")
+    fname = None
+    abi = None
+    if data["lib"] is not None:
+        lib_bin = base64.b64decode(data["lib"])
+        hasher = hashlib.sha1()
+        hasher.update(lib_bin)
+        h = hasher.digest().hex()
+        abi = get_elf_abi(lib_bin)
+        print(f"    hash:   {h}")
+        print(f"    abi:    {abi}")
+        fname = (file_storage / f"native_{h[:16]}.so").absolute().resolve()
+        if not fname.exists():
+            with fname.open("wb") as fp:
+                fp.write(lib_bin)
+        print(f"    stored: {str(fname)}")
+    if addr < 0:
+        return
+    data_storage["native_loads"].append(
+        {
+            "path": path,
+            "file": None if fname is None else str(fname),
+            "abi": abi,
+            "caller_method": caller_method,
+            "caller_cl_id": caller_cl_id,
+            "renamed_caller_method": None,
+            "addr": addr,
+        }
+    )
+
+
+def handle_jni_upcall(data, data_storage: dict):
+    method = data["method"]
+    method_cl_id = cl_id_to_string(data["method_cl_id"])
+    # The native method whose implementation called the JNI function is the innermost
+    # native frame
+    frames = [frame for frame in data["stack"] if frame["is_native"]]
+    if len(frames) == 0:
+        return
+    native_method = frames[0]["method"]
+    native_method_cl_id = cl_id_to_string(frames[0]["cl_id"])
+    if native_method_cl_id is None:
+        return
+    print(f"[+] JNI upcall ({data['function']}):")
+    print(f"    called: [{method_cl_id}]{method}{' (static)' if data['is_static'] else ''}")
+    print(f"    by:     [{native_method_cl_id}]{native_method}")
+    print(f"    from:   {data['library']}+0x{data['offset']:x}")
+    data_storage["jni_upcalls"].append(
+        {
+            "function": data["function"],
+            "method": method,
+            "method_cl_id": method_cl_id,
+            "renamed_method": None,
+            "is_static": data["is_static"],
+            "is_interface": data["is_interface"],
+            "native_method": native_method,
+            "native_method_cl_id": native_method_cl_id,
+            "renamed_native_method": None,
+            "library": data["library"],
+            "offset": data["offset"],
+        }
+    )
+
+
+def handle_register_natives(data, data_storage: dict):
+    method_cl_id = cl_id_to_string(data["cl_id"])
+    # The platform registers the native methods of its own classes
+    if method_cl_id is None:
+        return
+    for binding in data["methods"]:
+        print("[+] Native method registered:")
+        print(f"    method: [{method_cl_id}]{binding['method']}")
+        print(f"    symbol: {binding['symbol']}")
+        if binding["offset"] is not None:
+            print(f"    at:     {binding['library']}+0x{binding['offset']:x}")
+        data_storage["native_bindings"].append(
+            {
+                "method": binding["method"],
+                "method_cl_id": method_cl_id,
+                "renamed_method": None,
+                "library": binding["library"],
+                "symbol": binding["symbol"],
+                "offset": binding["offset"],
+            }
+        )
+
+
 caml_pattern = re.compile(r"([a-z])([A-Z])")
 
 
@@ -334,8 +686,16 @@ def collect_runtime(
         "class_new_inst_data": [],
         "cnstr_new_inst_data": [],
         "dyn_code_load": [],
+        "class_loading": [],
+        "native_loads": [],
+        "kotlin_reflection": [],
+        "service_new_inst_data": [],
+        "array_new_inst_data": [],
+        "jni_upcalls": [],
+        "native_bindings": [],
         "classloaders": {},
         "app_info": None,
+        "run_id": str(uuid.uuid4()),
     }
     try:
         env = dict(os.environ)
@@ -486,6 +846,9 @@ def collect_runtime(
             for data in data_storage["cnstr_new_inst_data"]:
                 if data["caller_cl_id"] in nb_occ:
                     nb_occ[data["caller_cl_id"]] += 1
+            for data in data_storage["kotlin_reflection"]:
+                if data["caller_cl_id"] in nb_occ:
+                    nb_occ[data["caller_cl_id"]] += 1
             main_class_loader = max(cls.keys(), key=lambda x: nb_occ[x])
         else:
             main_class_loader = list(cls.keys())[0]
@@ -1,19 +1,50 @@
 const sended_class_loaders = new Set();
 
+// Get the shared library class loaders of a BaseDexClassLoader (`field` is
+// 'sharedLibraryLoaders' or 'sharedLibraryLoadersAfter')
+function get_shared_libraries(cl, field) {
+  let loaders = [];
+  try {
+    const BaseDexClassLoader = Java.use('dalvik.system.BaseDexClassLoader');
+    let array = Java.cast(cl, BaseDexClassLoader)[field].value;
+    if (array !== null) {
+      for (let i = 0; i < array.length; i++) {
+        loaders.push(array[i]);
+      }
+    }
+  } catch (e) {
+    // Not a BaseDexClassLoader, or the field does not exist on this version of android
+  }
+  return loaders;
+}
+
+function get_shared_library_ids(cl, field) {
+  const System = Java.use('java.lang.System');
+  return get_shared_libraries(cl, field).map((l) => System.identityHashCode(l));
+}
+
 function send_class_loader(cl) {
   get_app_info();
   const System = Java.use('java.lang.System');
   let cl_id = System.identityHashCode(cl);
   while (cl != null && !sended_class_loaders.has(cl_id)) {
     let parent_ = cl.getParent();
+    let shared_libraries = get_shared_libraries(cl, 'sharedLibraryLoaders');
+    let shared_libraries_after = get_shared_libraries(cl, 'sharedLibraryLoadersAfter');
     send({"type": "classloader", "data": {
       "id": cl_id,
       "parent_id": System.identityHashCode(parent_),
       "str": cl.toString(),
-      "cname": cl.getClass().descriptorString()
+      "cname": cl.getClass().descriptorString(),
+      "shared_libraries": shared_libraries.map((l) => System.identityHashCode(l)),
+      "shared_libraries_after": shared_libraries_after.map((l) => System.identityHashCode(l)),
     }});
     sended_class_loaders.add(cl_id);
+    for (let l of shared_libraries.concat(shared_libraries_after)) {
+      send_class_loader(l);
+    }
     cl = parent_;
+    cl_id = System.identityHashCode(cl);
   }
 }
 
@@ -162,6 +193,7 @@ Java.perform(() => {
   const Method = Java.use("java.lang.reflect.Method");
   const Class = Java.use("java.lang.Class");
   const Constructor = Java.use("java.lang.reflect.Constructor");
+  const ReflectArray = Java.use("java.lang.reflect.Array");
   const Modifier = Java.use("java.lang.reflect.Modifier");
   const DexFile = Java.use("dalvik.system.DexFile");
   const File = Java.use('java.io.File');
@@ -170,7 +202,11 @@ Java.perform(() => {
   const System = Java.use('java.lang.System');
   const Arrays = Java.use('java.util.Arrays');
 
+  const ClassLoader = Java.use('java.lang.ClassLoader');
+
   const StackConsumer = registerStackConsumer();
+  // The ids of the class loaders that loaded code dynamically
+  const dynamic_loaders = new Set();
 
   const get_stack = function () {
     // console.log(Java.use("android.util.Log").getStackTraceString(Java.use("java.lang.Exception").$new()));
@@ -312,6 +348,72 @@ Java.perform(() => {
     return this.newInstance(args);
   };
 
+  // Array.newInstance(componentType, length)
+  ReflectArray.newInstance.overload(
+    "java.lang.Class", "int"
+  ).implementation = function (componentType, length) {
+    let cl = componentType.getClassLoader();
+    send_class_loader(cl);
+    send({
+      "type": "array-new-inst",
+      "data": {
+        "component_type": componentType.descriptorString(),
+        "component_cl_id": System.identityHashCode(cl),
+        "stack": get_stack()
+      }
+    });
+    return this.newInstance(componentType, length);
+  };
+
+  // The service providers of java.util.ServiceLoader are instantiated with Class.newInstance()
+  // or Constructor.newInstance(), and recognized from the stack.
+
+  // ****** Kotlin Reflection ******
+
+  // KCallable.call(..args) and KCallable.callBy(args): kotlin-reflect calls the method with
+  // Method.invoke() (or Constructor.newInstance()) from its internals, so the call made by the
+  // application is recorded here. KClasses.createInstance() is recognized from the stack.
+  try {
+    const KCallableImpl = Java.use("kotlin.reflect.jvm.internal.KCallableImpl");
+    const KFunction = Java.use("kotlin.reflect.KFunction");
+    const ReflectJvmMapping = Java.use("kotlin.reflect.jvm.ReflectJvmMapping");
+    const send_kotlin_reflect = function (callable, entry) {
+      // Only functions are called, properties are read
+      if (!KFunction.class.isInstance(callable)) {
+        return;
+      }
+      let fun = Java.cast(callable, KFunction);
+      let mth = ReflectJvmMapping.getJavaMethod(fun);
+      let cnstr = mth === null ? ReflectJvmMapping.getJavaConstructor(fun) : null;
+      if (mth === null && cnstr === null) {
+        return;
+      }
+      let cl = (mth !== null ? mth : cnstr).getDeclaringClass().getClassLoader();
+      send_class_loader(cl);
+      send({
+        "type": "kotlin-reflect",
+        "data": {
+          "entry": entry,
+          "method": mth !== null ? get_method_dsc(mth) : get_constr_dsc(cnstr),
+          "method_cl_id": System.identityHashCode(cl),
+          "is_static": mth !== null && Modifier.isStatic(mth.getModifiers()),
+          "bound_receiver": callable.isBound(),
+          "stack": get_stack(),
+        }
+      });
+    };
+    KCallableImpl.call.implementation = function (args) {
+      send_kotlin_reflect(this, "call");
+      return this.call(args);
+    };
+    KCallableImpl.callBy.implementation = function (args) {
+      send_kotlin_reflect(this, "call_by");
+      return this.callBy(args);
+    };
+  } catch (e) {
+    // The application does not use kotlin-reflect
+  }
+
   // ****** Dynamic Class Loading ******
 
   // DexFile.openDexFileNative(sourceName, outputName, flags, loader, elements): load .dex from file
@@ -346,11 +448,16 @@ Java.perform(() => {
       "type": "load-dex",
       "data": {
         "dex": [b64],
+        "source": sourceName,
         "classloader_class": classloader_class,
         "classloader": classloader_id,
         "classloader_parent": System.identityHashCode(loader.getParent()),
+        // sharedLibraryLoadersAfter is not set yet when the dex files are opened
+        "classloader_shared_libraries": get_shared_library_ids(loader, 'sharedLibraryLoaders'),
+        "stack": get_stack(),
       }
     });
+    dynamic_loaders.add(classloader_id);
 
     let is_wr = file.canWrite();
     if (is_wr) {
@@ -421,8 +528,12 @@ Java.perform(() => {
         "classloader_class": classloader_class,
         "classloader": classloader_id,
         "classloader_parent": System.identityHashCode(loader.getParent()),
+        // sharedLibraryLoadersAfter is not set yet when the dex files are opened
+        "classloader_shared_libraries": get_shared_library_ids(loader, 'sharedLibraryLoaders'),
+        "stack": get_stack(),
       }
     });
+    dynamic_loaders.add(classloader_id);
     return this.openInMemoryDexFilesNative(
       bufs,
       arrays,
@@ -432,6 +543,163 @@ Java.perform(() => {
       elements,
     );
   };
+  // ClassLoader.loadClass(name): only the calls to the class loaders that loaded code dynamically
+  ClassLoader.loadClass.overload(
+    'java.lang.String',
+  ).implementation = function (name) {
+    let cls = this.loadClass(name);
+    let loader_id = System.identityHashCode(this);
+    if (dynamic_loaders.has(loader_id) && cls !== null) {
+      let cl = cls.getClassLoader();
+      send_class_loader(cl);
+      send({
+        "type": "load-class",
+        "data": {
+          "class": cls.descriptorString(),
+          "classloader": loader_id,
+          "class_cl_id": System.identityHashCode(cl),
+          "stack": get_stack(),
+        }
+      });
+    }
+    return cls;
+  };
+
+  // ****** Native Code Loading ******
+
+  // System.load(filename): load a native library from an absolute path
+  // See https://cs.android.com/android/platform/superproject/main/+/main:libcore/ojluni/src/main/java/java/lang/System.java;l=1627
+  System.load.overload(
+    'java.lang.String',
+  ).implementation = function (filename) {
+    let lib = null;
+    try {
+      let path = Path.of(filename, []);
+      lib = Base64.encodeToString(Files.readAllBytes(path), Base64.DEFAULT.value);
+    } catch (e) {
+      // The call will fail anyway, but we still record the call site
+    }
+    send({
+      "type": "load-native",
+      "data": {
+        "path": filename,
+        "lib": lib,
+        "stack": get_stack(),
+      }
+    });
+    return this.load(filename);
+  };
+
+  // ****** JNI Upcalls ******
+
+  // The JNI functions calling Java methods, hooked in the function table of the JNIEnv.
+  // See https://docs.oracle.com/javase/8/docs/technotes/guides/jni/spec/functions.html
+  // The indices are the positions in the function table, the variants `V` and `A` follow the
+  // variadic function.
+  const JNI_RETURN_TYPES = [
+    "Object", "Boolean", "Byte", "Char", "Short", "Int", "Long", "Float", "Double", "Void"
+  ];
+  const jni_upcall_functions = [];
+  ["", "V", "A"].forEach((variant, offset) => {
+    // NewObject(env, clazz, methodID, ...)
+    jni_upcall_functions.push({"name": "NewObject" + variant, "index": 28 + offset, "cls_arg": 1, "mid_arg": 2, "is_static": false});
+    JNI_RETURN_TYPES.forEach((ty, i) => {
+      // Call<Type>Method(env, obj, methodID, ...): the class is the class of obj (cls_arg: null)
+      jni_upcall_functions.push({"name": "Call" + ty + "Method" + variant, "index": 34 + 3 * i + offset, "cls_arg": null, "mid_arg": 2, "is_static": false});
+      // CallNonvirtual<Type>Method(env, obj, clazz, methodID, ...)
+      jni_upcall_functions.push({"name": "CallNonvirtual" + ty + "Method" + variant, "index": 64 + 3 * i + offset, "cls_arg": 2, "mid_arg": 3, "is_static": false});
+      // CallStatic<Type>Method(env, clazz, methodID, ...)
+      jni_upcall_functions.push({"name": "CallStatic" + ty + "Method" + variant, "index": 114 + 3 * i + offset, "cls_arg": 1, "mid_arg": 2, "is_static": true});
+    });
+  });
+  const Executable = Java.use("java.lang.reflect.Executable");
+  // Only record the calls made by the native libraries of the application: the platform and
+  // Frida itself use the JNI all the time.
+  const is_app_module = function (module) {
+    return module !== null &&
+      !/^\/(system|apex|vendor|product)\//.test(module.path) &&
+      !module.name.includes("frida");
+  };
+  const jni_functions = Java.vm.getEnv().handle.readPointer();
+  jni_upcall_functions.forEach((func) => {
+    const address = jni_functions.add(func.index * Process.pointerSize).readPointer();
+    Interceptor.attach(address, {
+      onEnter(args) {
+        let module = Process.findModuleByAddress(this.returnAddress);
+        if (!is_app_module(module)) {
+          return;
+        }
+        let env = Java.vm.getEnv();
+        // CheckJNI aborts if ToReflectedMethod() is not given a class
+        let obj_cls = func.cls_arg === null ? env.getObjectClass(args[1]) : null;
+        let reflected = env.toReflectedMethod(
+          obj_cls === null ? args[func.cls_arg] : obj_cls, args[func.mid_arg], func.is_static ? 1 : 0
+        );
+        if (obj_cls !== null) {
+          env.deleteLocalRef(obj_cls);
+        }
+        if (reflected.isNull()) {
+          env.exceptionClear();
+          return;
+        }
+        let exec = Java.cast(reflected, Executable);
+        let is_cnstr = Constructor.class.isInstance(exec);
+        let cls = exec.getDeclaringClass();
+        let cl = cls.getClassLoader();
+        send_class_loader(cl);
+        send({
+          "type": "jni-upcall",
+          "data": {
+            "function": func.name,
+            "method": is_cnstr ? get_constr_dsc(Java.cast(exec, Constructor)) : get_method_dsc(Java.cast(exec, Method)),
+            "method_cl_id": System.identityHashCode(cl),
+            "is_static": func.is_static,
+            "is_interface": cls.isInterface(),
+            "library": module.name,
+            "offset": this.returnAddress.sub(module.base).toUInt32(),
+            "stack": get_stack(),
+          }
+        });
+        env.deleteLocalRef(reflected);
+      }
+    });
+  });
+
+  // ****** Native Method Registration ******
+
+  // RegisterNatives(env, clazz, methods, nMethods), methods is an array of
+  // JNINativeMethod { const char* name; const char* signature; void* fnPtr; }
+  const JNI_REGISTER_NATIVES = 215;
+  Interceptor.attach(jni_functions.add(JNI_REGISTER_NATIVES * Process.pointerSize).readPointer(), {
+    onEnter(args) {
+      let cls = Java.cast(args[1], Class);
+      let cl = cls.getClassLoader();
+      send_class_loader(cl);
+      let methods = [];
+      for (let i = 0; i < args[3].toInt32(); i++) {
+        let entry = args[2].add(3 * i * Process.pointerSize);
+        let name = entry.readPointer().readCString();
+        let signature = entry.add(Process.pointerSize).readPointer().readCString();
+        let fn_ptr = entry.add(2 * Process.pointerSize).readPointer();
+        let module = Process.findModuleByAddress(fn_ptr);
+        let symbol = DebugSymbol.fromAddress(fn_ptr);
+        methods.push({
+          "method": cls.descriptorString() + "->" + name + signature,
+          "library": module === null ? null : module.name,
+          // Only the symbols starting exactly at the implementation
+          "symbol": symbol.address.equals(fn_ptr) ? symbol.name : null,
+          "offset": module === null ? null : fn_ptr.sub(module.base).toUInt32(),
+        });
+      }
+      send({
+        "type": "register-natives",
+        "data": {
+          "cl_id": System.identityHashCode(cl),
+          "methods": methods,
+        }
+      });
+    }
+  });
   dump_classloaders();
 });
 
@@ -0,0 +1,105 @@
+//! Compare the lookup of the reflection data of each patched method using the linear
+//! `RuntimeData::get_*_for()` scans and using a `RuntimeDataIndex`, on a synthetic runtime
+//! data set of the size of a large application.
+
+use std::collections::HashMap;
+use std::hint::black_box;
+
+use androscalpel::IdMethod;
+use criterion::{criterion_group, criterion_main, Criterion};
+use patcher::runtime_data::{
+    ReflectionClassNewInstData, ReflectionCnstrNewInstData, ReflectionInvokeData, RuntimeData,
+};
+
+const NB_CALLER_METHODS: usize = 2_000;
+const NB_CALLS_PER_METHOD: usize = 8;
+
+fn synthetic_runtime_data() -> RuntimeData {
+    let mut data = RuntimeData {
+        invoke_data: vec![],
+        class_new_inst_data: vec![],
+        cnstr_new_inst_data: vec![],
+        dyn_code_load: vec![],
+        class_loading: vec![],
+        native_loads: vec![],
+        kotlin_reflection: vec![],
+        service_new_inst_data: vec![],
+        array_new_inst_data: vec![],
+        jni_upcalls: vec![],
+        native_bindings: vec![],
+        apk_cl_id: Some("00000001".into()),
+        classloaders: HashMap::new(),
+        app_info: None,
+        run_id: None,
+    };
+    for i in 0..NB_CALLER_METHODS {
+        let caller =
+            IdMethod::from_smali(&format!("Lcom/example/C{}/Caller{i};->run(I)V", i % 64)).unwrap();
+        for j in 0..NB_CALLS_PER_METHOD {
+            let callee = IdMethod::from_smali(&format!(
+                "Lcom/example/target/T{j};->m{i}(Ljava/lang/String;)I"
+            ))
+            .unwrap();
+            let constructor =
+                IdMethod::from_smali(&format!("Lcom/example/target/T{j};-><init>()V")).unwrap();
+            data.invoke_data.push(ReflectionInvokeData {
+                method: callee,
+                method_cl_id: "00000001".into(),
+                renamed_method: None,
+                caller_method: caller.clone(),
+                caller_cl_id: "00000001".into(),
+                renamed_caller_method: None,
+                addr: j * 0x10,
+                is_static: j % 2 == 0,
+            });
+            data.class_new_inst_data.push(ReflectionClassNewInstData {
+                constructor: constructor.clone(),
+                constructor_cl_id: "00000001".into(),
+                renamed_constructor: None,
+                caller_method: caller.clone(),
+                caller_cl_id: "00000001".into(),
+                renamed_caller_method: None,
+                addr: j * 0x10 + 4,
+            });
+            data.cnstr_new_inst_data.push(ReflectionCnstrNewInstData {
+                constructor,
+                constructor_cl_id: "00000001".into(),
+                renamed_constructor: None,
+                caller_method: caller.clone(),
+                caller_cl_id: "00000001".into(),
+                renamed_caller_method: None,
+                addr: j * 0x10 + 8,
+            });
+        }
+    }
+    data
+}
+
+fn bench_lookups(c: &mut Criterion) {
+    let data = synthetic_runtime_data();
+    let methods = data.get_method_referenced();
+
+    let mut group = c.benchmark_group("runtime_data_lookup");
+    group.sample_size(10);
+    group.bench_function("linear_scan", |b| {
+        b.iter(|| {
+            for method in &methods {
+                black_box(data.get_invoke_data_for(method));
+                black_box(data.get_class_new_instance_data_for(method));
+                black_box(data.get_cnstr_new_instance_data_for(method));
+            }
+        })
+    });
+    group.bench_function("index", |b| {
+        b.iter(|| {
+            let index = data.index();
+            for method in &methods {
+                black_box(index.get(method));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_lookups);
+criterion_main!(benches);
@@ -0,0 +1,1000 @@
+//! In-process replacement for `zipalign` and `apksigner`.
+//!
+//! This module rebuilds the output APK itself: the entries of the original APK are copied (minus
+//! the old dex files and signatures), the new dex files are added, uncompressed entries are
+//! aligned the way `zipalign -p 4` does, and the result is signed with the APK Signature Scheme
+//! v1 (JAR signing), v2 and v3. Like `apksigner`, the v1 signature uses SHA-1 when the
+//! `minSdkVersion` of the application is below 18 (the first version supporting SHA-256 in JAR
+//! signatures), and SHA-256 otherwise.
+//!
+//! Only RSA keys are supported, stored either in a PKCS12 or a JKS keystore.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::*;
+use clap::ValueEnum;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use log::{debug, warn};
+use rsa::pkcs1v15::SigningKey as RsaSigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::RsaPrivateKey;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::axml::min_sdk_version;
+use crate::components::MANIFEST_PATH;
+
+/// Id of the APK Signature Scheme v2 block.
+const APK_SIGNATURE_SCHEME_V2_BLOCK_ID: u32 = 0x7109871a;
+/// Id of the APK Signature Scheme v3 block.
+const APK_SIGNATURE_SCHEME_V3_BLOCK_ID: u32 = 0xf05368c0;
+/// Id of the v2 additional attribute telling the verifier that a v3 signature must be present.
+const STRIPPING_PROTECTION_ATTR_ID: u32 = 0xbeeff00d;
+/// RSASSA-PKCS1-v1_5 with SHA2-256 digest.
+const SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256: u32 = 0x0103;
+const APK_SIG_BLOCK_MAGIC: &[u8; 16] = b"APK Sig Block 42";
+/// Android P, the first version that verifies v3 signatures.
+const V3_MIN_SDK: u32 = 28;
+const V3_MAX_SDK: u32 = i32::MAX as u32;
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Alignment of uncompressed entries (what `zipalign 4` does).
+const DEFAULT_ALIGNMENT: u16 = 4;
+/// Alignment of uncompressed native libraries, so that they can be mmaped (`zipalign -p`).
+const PAGE_ALIGNMENT: u16 = 4096;
+/// The header id of the extra field used by `apksigner` to pad entries.
+const ALIGNMENT_EXTRA_FIELD_ID: u16 = 0xd935;
+/// 1981-01-01 00:00:00, the timestamp used for all entries so that the output only depends on the
+/// content of the APK.
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 9) | (1 << 5) | 1;
+
+const MANIFEST_NAME: &str = "META-INF/MANIFEST.MF";
+const SIGNATURE_FILE_NAME: &str = "META-INF/CERT.SF";
+const SIGNATURE_BLOCK_NAME: &str = "META-INF/CERT.RSA";
+const CREATED_BY: &str = "1.0 (Android of Theseus)";
+
+/// The tools used to align and sign the output APK.
+#[derive(ValueEnum, Debug, PartialEq, Clone, Copy, Default)]
+pub enum SigningTool {
+    /// Use `zipalign` and `apksigner` if both their paths are provided, the builtin signer if
+    /// none is provided.
+    #[default]
+    Auto,
+    /// Use the builtin signer, no external tool is needed.
+    Builtin,
+    /// Use `zipalign` and `apksigner` from the android SDK build tools.
+    External,
+}
+
+impl SigningTool {
+    /// Whether the external tools must be used, knowing if the paths of `zipalign` and
+    /// `apksigner` were provided.
+    pub fn use_external_tools(self, zipalign: bool, apksigner: bool) -> Result<bool> {
+        match self {
+            Self::Auto if zipalign != apksigner => bail!(
+                "Only the path of {} was provided: provide both zipalign and apksigner to use \
+                 them, or none to use the builtin signer",
+                if zipalign { "zipalign" } else { "apksigner" }
+            ),
+            Self::Auto => Ok(zipalign),
+            Self::Builtin => Ok(false),
+            Self::External => Ok(true),
+        }
+    }
+}
+
+/// The digest algorithm of the v1 signature.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum V1Digest {
+    Sha1,
+    Sha256,
+}
+
+impl V1Digest {
+    /// The first API level supporting SHA-256 digests in JAR signatures.
+    const SHA256_MIN_SDK: u32 = 18;
+
+    /// The digest `apksigner` uses for an application supporting `min_sdk`.
+    fn for_min_sdk(min_sdk: u32) -> Self {
+        if min_sdk < Self::SHA256_MIN_SDK {
+            Self::Sha1
+        } else {
+            Self::Sha256
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha1 => Sha1::digest(data).to_vec(),
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    /// The name of the digest in the JAR manifest attributes.
+    fn attribute_name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "SHA1",
+            Self::Sha256 => "SHA-256",
+        }
+    }
+
+    fn oid(self) -> &'static [u8] {
+        match self {
+            Self::Sha1 => OID_SHA1,
+            Self::Sha256 => OID_SHA256,
+        }
+    }
+}
+
+/// A key and its certificate chain, used to sign APKs.
+pub struct ApkSigningKey {
+    key: RsaPrivateKey,
+    /// The DER encoded certificates, the first one is the certificate of `key`.
+    certificates: Vec<Vec<u8>>,
+}
+
+impl ApkSigningKey {
+    /// Load the first private key of a keystore. The keystore can be a PKCS12 or a JKS keystore,
+    /// and the same password is used for the store and the key (like `keytool` does by default).
+    pub fn from_keystore(path: impl AsRef<Path>, password: Option<&str>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut data = vec![];
+        File::open(path)
+            .with_context(|| format!("Failed to open keystore {}", path.display()))?
+            .read_to_end(&mut data)?;
+        let password = password.unwrap_or("");
+        let (key, certificates) = if data.starts_with(&JKS_MAGIC.to_be_bytes()) {
+            debug!("Loading JKS keystore {}", path.display());
+            read_jks(&data, password)?
+        } else {
+            debug!("Loading PKCS12 keystore {}", path.display());
+            let keystore = p12_keystore::KeyStore::from_pkcs12(&data, password)
+                .with_context(|| format!("Failed to read keystore {}", path.display()))?;
+            let (alias, chain) = keystore
+                .private_key_chain()
+                .with_context(|| format!("No private key found in {}", path.display()))?;
+            debug!("Using key {alias}");
+            (
+                chain.key().to_vec(),
+                chain
+                    .chain()
+                    .iter()
+                    .map(|cert| cert.as_der().to_vec())
+                    .collect(),
+            )
+        };
+        if certificates.is_empty() {
+            bail!("No certificate found for the key in {}", path.display());
+        }
+        let key = RsaPrivateKey::from_pkcs8_der(&key)
+            .context("Failed to decode the private key, only RSA keys are supported")?;
+        Ok(Self { key, certificates })
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        RsaSigningKey::<Sha256>::new(self.key.clone())
+            .sign(data)
+            .to_vec()
+    }
+
+    fn sign_with(&self, data: &[u8], digest: V1Digest) -> Vec<u8> {
+        match digest {
+            V1Digest::Sha1 => RsaSigningKey::<Sha1>::new(self.key.clone())
+                .sign(data)
+                .to_vec(),
+            V1Digest::Sha256 => self.sign(data),
+        }
+    }
+
+    fn certificate(&self) -> &[u8] {
+        &self.certificates[0]
+    }
+}
+
+/// Drop-in replacement for `apk_frauder::replace_dex` that does not need `zipalign` and
+/// `apksigner`.
+///
+/// `dex_files` replace the `classes*.dex` of `apk`, in order. `additional_files` are files to add
+/// to the APK (or to remove, if the value is `None`). The native libraries (`lib/*.so`) are
+/// stored uncompressed.
+pub fn replace_dex(
+    apk: impl AsRef<Path>,
+    out: impl AsRef<Path>,
+    dex_files: &mut [Cursor<Vec<u8>>],
+    keystore: impl AsRef<Path>,
+    keypassword: Option<&str>,
+    additional_files: Option<HashMap<String, Option<Cursor<&[u8]>>>>,
+) -> Result<()> {
+    let key = ApkSigningKey::from_keystore(keystore, keypassword)?;
+    let mut additional_files = additional_files.unwrap_or_default();
+
+    let mut entries = vec![];
+    for (i, dex) in dex_files.iter_mut().enumerate() {
+        let name = if i == 0 {
+            "classes.dex".into()
+        } else {
+            format!("classes{}.dex", i + 1)
+        };
+        let mut data = vec![];
+        dex.read_to_end(&mut data)?;
+        entries.push(ZipEntry::deflated(name, &data)?);
+    }
+
+    let mut archive = ZipArchive::new(
+        File::open(apk.as_ref())
+            .with_context(|| format!("Failed to open {}", apk.as_ref().display()))?,
+    )?;
+    for i in 0..archive.len() {
+        let name = archive.by_index_raw(i)?.name().to_string();
+        if is_dex_file(&name) || is_signature_file(&name) || additional_files.contains_key(&name) {
+            debug!("Dropping {name} from the original apk");
+            continue;
+        }
+        entries.push(ZipEntry::copy_from(&mut archive, i)?);
+    }
+    let mut additional_names: Vec<_> = additional_files.keys().cloned().collect();
+    additional_names.sort();
+    for name in additional_names {
+        if let Some(Some(mut file)) = additional_files.remove(&name) {
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            // Native libraries are stored uncompressed (and page-aligned) so that they can be
+            // loaded from the apk when the application does not extract them
+            if name.starts_with("lib/") && name.ends_with(".so") {
+                entries.push(ZipEntry::stored(name, &data)?);
+            } else {
+                entries.push(ZipEntry::deflated(name, &data)?);
+            }
+        }
+    }
+
+    let min_sdk = match entries.iter().find(|entry| entry.name == MANIFEST_PATH) {
+        Some(manifest) => min_sdk_version(&manifest.content).unwrap_or_else(|err| {
+            warn!("Failed to read the minSdkVersion of the application: {err}");
+            None
+        }),
+        None => {
+            warn!("No {MANIFEST_PATH} in the apk");
+            None
+        }
+    };
+    // Without minSdkVersion, the application supports all the versions
+    let mut v1_entries = sign_v1(&entries, &key, V1Digest::for_min_sdk(min_sdk.unwrap_or(1)))?;
+    entries.append(&mut v1_entries);
+    let apk = sign_v2_v3(&entries, &key)?;
+    File::create(out.as_ref())
+        .with_context(|| format!("Failed to create {}", out.as_ref().display()))?
+        .write_all(&apk)?;
+    Ok(())
+}
+
+fn is_dex_file(name: &str) -> bool {
+    name.strip_prefix("classes")
+        .and_then(|name| name.strip_suffix(".dex"))
+        .is_some_and(|n| n.is_empty() || n.parse::<u32>().is_ok())
+}
+
+fn is_signature_file(name: &str) -> bool {
+    if let Some(name) = name.strip_prefix("META-INF/") {
+        !name.contains('/')
+            && (name == "MANIFEST.MF"
+                || name.ends_with(".SF")
+                || name.ends_with(".RSA")
+                || name.ends_with(".DSA")
+                || name.ends_with(".EC"))
+    } else {
+        false
+    }
+}
+
+/// An entry of the output zip file.
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc32: u32,
+    uncompressed_size: u32,
+    /// The data as stored in the zip (ie compressed if `method` is deflate).
+    data: Vec<u8>,
+    /// The uncompressed data.
+    content: Vec<u8>,
+}
+
+impl ZipEntry {
+    fn deflated(name: String, content: &[u8]) -> Result<Self> {
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        encoder.write_all(content)?;
+        Ok(Self {
+            name,
+            method: 8,
+            crc32: crc32fast::hash(content),
+            uncompressed_size: content.len().try_into()?,
+            data: encoder.finish()?,
+            content: content.to_vec(),
+        })
+    }
+
+    fn stored(name: String, content: &[u8]) -> Result<Self> {
+        Ok(Self {
+            name,
+            method: 0,
+            crc32: crc32fast::hash(content),
+            uncompressed_size: content.len().try_into()?,
+            data: content.to_vec(),
+            content: content.to_vec(),
+        })
+    }
+
+    fn copy_from<R: Read + std::io::Seek>(archive: &mut ZipArchive<R>, i: usize) -> Result<Self> {
+        let mut content = vec![];
+        archive.by_index(i)?.read_to_end(&mut content)?;
+        let mut file = archive.by_index_raw(i)?;
+        let method = match file.compression() {
+            CompressionMethod::Stored => 0,
+            CompressionMethod::Deflated => 8,
+            method => bail!(
+                "Unsupported compression method for {}: {method}",
+                file.name()
+            ),
+        };
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        Ok(Self {
+            name: file.name().to_string(),
+            method,
+            crc32: file.crc32(),
+            uncompressed_size: file.size().try_into()?,
+            data,
+            content,
+        })
+    }
+
+    fn alignment(&self) -> Option<u16> {
+        if self.method != 0 {
+            None
+        } else if self.name.starts_with("lib/") && self.name.ends_with(".so") {
+            Some(PAGE_ALIGNMENT)
+        } else {
+            Some(DEFAULT_ALIGNMENT)
+        }
+    }
+}
+
+/// The raw output zip, split in the sections used by the v2 and v3 signature schemes.
+struct ZipSections {
+    entries: Vec<u8>,
+    central_directory: Vec<u8>,
+    nb_entries: u16,
+}
+
+impl ZipSections {
+    fn eocd(&self, central_directory_offset: u32) -> Vec<u8> {
+        let mut eocd = vec![];
+        eocd.extend(0x06054b50u32.to_le_bytes());
+        eocd.extend(0u16.to_le_bytes()); // number of this disk
+        eocd.extend(0u16.to_le_bytes()); // disk where central directory starts
+        eocd.extend(self.nb_entries.to_le_bytes());
+        eocd.extend(self.nb_entries.to_le_bytes());
+        eocd.extend((self.central_directory.len() as u32).to_le_bytes());
+        eocd.extend(central_directory_offset.to_le_bytes());
+        eocd.extend(0u16.to_le_bytes()); // comment length
+        eocd
+    }
+}
+
+/// Write the entries, aligning the uncompressed ones.
+fn write_zip(entries: &[ZipEntry]) -> Result<ZipSections> {
+    let mut out = vec![];
+    let mut central_directory = vec![];
+    for entry in entries {
+        let offset: u32 = out.len().try_into().context("APK too large")?;
+        let name = entry.name.as_bytes();
+        let extra = match entry.alignment() {
+            None => vec![],
+            Some(alignment) => {
+                let unpadded = out.len() + 30 + name.len() + 6;
+                let padding =
+                    (alignment as usize - unpadded % alignment as usize) % alignment as usize;
+                let mut extra = vec![];
+                extra.extend(ALIGNMENT_EXTRA_FIELD_ID.to_le_bytes());
+                extra.extend(((2 + padding) as u16).to_le_bytes());
+                extra.extend(alignment.to_le_bytes());
+                extra.extend(vec![0; padding]);
+                extra
+            }
+        };
+        let compressed_size: u32 = entry.data.len().try_into()?;
+        let flags: u16 = if entry.name.is_ascii() { 0 } else { 1 << 11 };
+
+        out.extend(0x04034b50u32.to_le_bytes());
+        out.extend(20u16.to_le_bytes()); // version needed to extract
+        out.extend(flags.to_le_bytes());
+        out.extend(entry.method.to_le_bytes());
+        out.extend(DOS_TIME.to_le_bytes());
+        out.extend(DOS_DATE.to_le_bytes());
+        out.extend(entry.crc32.to_le_bytes());
+        out.extend(compressed_size.to_le_bytes());
+        out.extend(entry.uncompressed_size.to_le_bytes());
+        out.extend((name.len() as u16).to_le_bytes());
+        out.extend((extra.len() as u16).to_le_bytes());
+        out.extend(name);
+        out.extend(&extra);
+        out.extend(&entry.data);
+
+        central_directory.extend(0x02014b50u32.to_le_bytes());
+        central_directory.extend(20u16.to_le_bytes()); // version made by
+        central_directory.extend(20u16.to_le_bytes()); // version needed to extract
+        central_directory.extend(flags.to_le_bytes());
+        central_directory.extend(entry.method.to_le_bytes());
+        central_directory.extend(DOS_TIME.to_le_bytes());
+        central_directory.extend(DOS_DATE.to_le_bytes());
+        central_directory.extend(entry.crc32.to_le_bytes());
+        central_directory.extend(compressed_size.to_le_bytes());
+        central_directory.extend(entry.uncompressed_size.to_le_bytes());
+        central_directory.extend((name.len() as u16).to_le_bytes());
+        central_directory.extend(0u16.to_le_bytes()); // extra field length
+        central_directory.extend(0u16.to_le_bytes()); // comment length
+        central_directory.extend(0u16.to_le_bytes()); // disk number start
+        central_directory.extend(0u16.to_le_bytes()); // internal attributes
+        central_directory.extend(0u32.to_le_bytes()); // external attributes
+        central_directory.extend(offset.to_le_bytes());
+        central_directory.extend(name);
+    }
+    Ok(ZipSections {
+        entries: out,
+        central_directory,
+        nb_entries: entries
+            .len()
+            .try_into()
+            .context("Too many entries in APK")?,
+    })
+}
+
+// ----- v1 signature -----
+
+/// Format an attribute of a JAR manifest, wrapping lines at 72 bytes.
+fn manifest_attribute(out: &mut Vec<u8>, name: &str, value: &str) {
+    let line = format!("{name}: {value}");
+    let mut line = line.as_bytes();
+    let mut first = true;
+    while !line.is_empty() {
+        let max_len = if first { 72 } else { 71 };
+        let (head, tail) = line.split_at(max_len.min(line.len()));
+        if !first {
+            out.push(b' ');
+        }
+        out.extend(head);
+        out.extend(b"\r\n");
+        line = tail;
+        first = false;
+    }
+}
+
+/// Generate `META-INF/MANIFEST.MF`, `META-INF/CERT.SF` and `META-INF/CERT.RSA`.
+fn sign_v1(entries: &[ZipEntry], key: &ApkSigningKey, digest: V1Digest) -> Result<Vec<ZipEntry>> {
+    let digest_attribute = format!("{}-Digest", digest.attribute_name());
+    let mut manifest = vec![];
+    manifest_attribute(&mut manifest, "Manifest-Version", "1.0");
+    manifest_attribute(&mut manifest, "Created-By", CREATED_BY);
+    manifest.extend(b"\r\n");
+
+    let mut signature_file = vec![];
+    manifest_attribute(&mut signature_file, "Signature-Version", "1.0");
+    manifest_attribute(&mut signature_file, "Created-By", CREATED_BY);
+    let mut entries_signatures = vec![];
+
+    let mut names: Vec<_> = entries.iter().filter(|e| !e.name.ends_with('/')).collect();
+    names.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in names {
+        let mut section = vec![];
+        manifest_attribute(&mut section, "Name", &entry.name);
+        manifest_attribute(
+            &mut section,
+            &digest_attribute,
+            &BASE64_STANDARD.encode(digest.digest(&entry.content)),
+        );
+        section.extend(b"\r\n");
+
+        manifest_attribute(&mut entries_signatures, "Name", &entry.name);
+        manifest_attribute(
+            &mut entries_signatures,
+            &digest_attribute,
+            &BASE64_STANDARD.encode(digest.digest(&section)),
+        );
+        entries_signatures.extend(b"\r\n");
+        manifest.append(&mut section);
+    }
+
+    manifest_attribute(
+        &mut signature_file,
+        &format!("{digest_attribute}-Manifest"),
+        &BASE64_STANDARD.encode(digest.digest(&manifest)),
+    );
+    manifest_attribute(&mut signature_file, "X-Android-APK-Signed", "2, 3");
+    signature_file.extend(b"\r\n");
+    signature_file.append(&mut entries_signatures);
+
+    let signature_block = pkcs7_signed_data(key, digest, &key.sign_with(&signature_file, digest))?;
+
+    Ok(vec![
+        ZipEntry::deflated(MANIFEST_NAME.into(), &manifest)?,
+        ZipEntry::deflated(SIGNATURE_FILE_NAME.into(), &signature_file)?,
+        ZipEntry::deflated(SIGNATURE_BLOCK_NAME.into(), &signature_block)?,
+    ])
+}
+
+const OID_SIGNED_DATA: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02,
+];
+const OID_DATA: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01,
+];
+const OID_SHA1: &[u8] = &[0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const OID_SHA256: &[u8] = &[
+    0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01,
+];
+const OID_RSA_ENCRYPTION: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01,
+];
+const DER_NULL: &[u8] = &[0x05, 0x00];
+const DER_SEQUENCE: u8 = 0x30;
+const DER_SET: u8 = 0x31;
+const DER_INTEGER: u8 = 0x02;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_CONTEXT_0: u8 = 0xa0;
+
+/// Encode a DER TLV.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | len_bytes.len() as u8);
+        out.extend(len_bytes);
+    }
+    out.extend(content);
+    out
+}
+
+/// A DER element: its tag, content and full encoding.
+struct DerElement<'a> {
+    tag: u8,
+    content: &'a [u8],
+    raw: &'a [u8],
+}
+
+/// Read the first DER element of `data`, return it and the remaining data.
+fn der_next(data: &[u8]) -> Result<(DerElement<'_>, &[u8])> {
+    if data.len() < 2 {
+        bail!("Truncated DER element");
+    }
+    let tag = data[0];
+    let (len, header_len) = if data[1] < 0x80 {
+        (data[1] as usize, 2)
+    } else {
+        let nb_bytes = (data[1] & 0x7f) as usize;
+        if nb_bytes == 0 || nb_bytes > 4 || data.len() < 2 + nb_bytes {
+            bail!("Invalid DER length");
+        }
+        let len = data[2..2 + nb_bytes]
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        (len, 2 + nb_bytes)
+    };
+    if data.len() < header_len + len {
+        bail!("Truncated DER element");
+    }
+    Ok((
+        DerElement {
+            tag,
+            content: &data[header_len..header_len + len],
+            raw: &data[..header_len + len],
+        },
+        &data[header_len + len..],
+    ))
+}
+
+/// The fields of a X.509 certificate needed for signing.
+struct CertificateInfo<'a> {
+    serial_number: &'a [u8],
+    issuer: &'a [u8],
+    subject_public_key_info: &'a [u8],
+}
+
+fn parse_certificate(cert: &[u8]) -> Result<CertificateInfo<'_>> {
+    let (certificate, _) = der_next(cert)?;
+    let (tbs, _) = der_next(certificate.content)?;
+    let mut fields = vec![];
+    let mut data = tbs.content;
+    while !data.is_empty() {
+        let (field, rest) = der_next(data)?;
+        fields.push(field);
+        data = rest;
+    }
+    // The version is optional
+    if fields.first().is_some_and(|f| f.tag == DER_CONTEXT_0) {
+        fields.remove(0);
+    }
+    // serialNumber, signature, issuer, validity, subject, subjectPublicKeyInfo
+    if fields.len() < 6 || fields[0].tag != DER_INTEGER {
+        bail!("Failed to parse the signing certificate");
+    }
+    Ok(CertificateInfo {
+        serial_number: fields[0].raw,
+        issuer: fields[2].raw,
+        subject_public_key_info: fields[5].raw,
+    })
+}
+
+/// Generate the PKCS#7 SignedData (the content of `CERT.RSA`) for a detached `signature`.
+fn pkcs7_signed_data(key: &ApkSigningKey, digest: V1Digest, signature: &[u8]) -> Result<Vec<u8>> {
+    let cert = parse_certificate(key.certificate())?;
+    let digest_algorithm = der(DER_SEQUENCE, &[digest.oid(), DER_NULL].concat());
+    let rsa = der(DER_SEQUENCE, &[OID_RSA_ENCRYPTION, DER_NULL].concat());
+    let signer_info = der(
+        DER_SEQUENCE,
+        &[
+            der(DER_INTEGER, &[1]),
+            der(DER_SEQUENCE, &[cert.issuer, cert.serial_number].concat()),
+            digest_algorithm.clone(),
+            rsa,
+            der(DER_OCTET_STRING, signature),
+        ]
+        .concat(),
+    );
+    let signed_data = der(
+        DER_SEQUENCE,
+        &[
+            der(DER_INTEGER, &[1]),
+            der(DER_SET, &digest_algorithm),
+            der(DER_SEQUENCE, OID_DATA),
+            der(DER_CONTEXT_0, &key.certificates.concat()),
+            der(DER_SET, &signer_info),
+        ]
+        .concat(),
+    );
+    Ok(der(
+        DER_SEQUENCE,
+        &[OID_SIGNED_DATA, &der(DER_CONTEXT_0, &signed_data)].concat(),
+    ))
+}
+
+// ----- v2 and v3 signature -----
+
+/// Prefix `data` with its length as a little endian u32.
+fn length_prefixed(data: &[u8]) -> Vec<u8> {
+    let mut out = (data.len() as u32).to_le_bytes().to_vec();
+    out.extend(data);
+    out
+}
+
+fn length_prefixed_seq<'a>(items: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    length_prefixed(
+        &items
+            .into_iter()
+            .flat_map(length_prefixed)
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Compute the chunked SHA-256 digest of the APK content, as defined by the v2 scheme.
+fn content_digest(sections: &[&[u8]]) -> Vec<u8> {
+    let mut chunk_digests = vec![];
+    let mut nb_chunks: u32 = 0;
+    for section in sections {
+        for chunk in section.chunks(CHUNK_SIZE) {
+            let mut hasher = Sha256::new();
+            hasher.update([0xa5]);
+            hasher.update((chunk.len() as u32).to_le_bytes());
+            hasher.update(chunk);
+            chunk_digests.extend(hasher.finalize());
+            nb_chunks += 1;
+        }
+    }
+    let mut hasher = Sha256::new();
+    hasher.update([0x5a]);
+    hasher.update(nb_chunks.to_le_bytes());
+    hasher.update(&chunk_digests);
+    hasher.finalize().to_vec()
+}
+
+/// Generate the value of a v2 or v3 signature scheme block.
+fn signature_scheme_block(
+    key: &ApkSigningKey,
+    digest: &[u8],
+    sdk_range: Option<(u32, u32)>,
+) -> Result<Vec<u8>> {
+    let cert = parse_certificate(key.certificate())?;
+    let mut digest_item = SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256.to_le_bytes().to_vec();
+    digest_item.extend(length_prefixed(digest));
+
+    let mut signed_data = length_prefixed_seq([digest_item.as_slice()]);
+    signed_data.extend(length_prefixed_seq(
+        key.certificates.iter().map(|c| c.as_slice()),
+    ));
+    if let Some((min_sdk, max_sdk)) = sdk_range {
+        signed_data.extend(min_sdk.to_le_bytes());
+        signed_data.extend(max_sdk.to_le_bytes());
+        signed_data.extend(length_prefixed_seq([]));
+    } else {
+        // v2 signed along a v3 signature: tell the verifier not to accept the v2 signature alone
+        let mut attribute = STRIPPING_PROTECTION_ATTR_ID.to_le_bytes().to_vec();
+        attribute.extend(3u32.to_le_bytes());
+        signed_data.extend(length_prefixed_seq([attribute.as_slice()]));
+    }
+
+    let mut signature_item = SIGNATURE_RSA_PKCS1_V1_5_WITH_SHA256.to_le_bytes().to_vec();
+    signature_item.extend(length_prefixed(&key.sign(&signed_data)));
+
+    let mut signer = length_prefixed(&signed_data);
+    if let Some((min_sdk, max_sdk)) = sdk_range {
+        signer.extend(min_sdk.to_le_bytes());
+        signer.extend(max_sdk.to_le_bytes());
+    }
+    signer.extend(length_prefixed_seq([signature_item.as_slice()]));
+    signer.extend(length_prefixed(cert.subject_public_key_info));
+    Ok(length_prefixed_seq([signer.as_slice()]))
+}
+
+/// Write the zip file and insert the APK Signing Block containing the v2 and v3 signatures.
+fn sign_v2_v3(entries: &[ZipEntry], key: &ApkSigningKey) -> Result<Vec<u8>> {
+    let sections = write_zip(entries)?;
+    let central_directory_offset: u32 = sections.entries.len().try_into()?;
+    let digest = content_digest(&[
+        &sections.entries,
+        &sections.central_directory,
+        &sections.eocd(central_directory_offset),
+    ]);
+
+    let mut pairs = vec![];
+    for (id, value) in [
+        (
+            APK_SIGNATURE_SCHEME_V2_BLOCK_ID,
+            signature_scheme_block(key, &digest, None)?,
+        ),
+        (
+            APK_SIGNATURE_SCHEME_V3_BLOCK_ID,
+            signature_scheme_block(key, &digest, Some((V3_MIN_SDK, V3_MAX_SDK)))?,
+        ),
+    ] {
+        pairs.extend((4 + value.len() as u64).to_le_bytes());
+        pairs.extend(id.to_le_bytes());
+        pairs.extend(value);
+    }
+    let block_size = (pairs.len() + 8 + APK_SIG_BLOCK_MAGIC.len()) as u64;
+    let mut block = block_size.to_le_bytes().to_vec();
+    block.extend(pairs);
+    block.extend(block_size.to_le_bytes());
+    block.extend(APK_SIG_BLOCK_MAGIC);
+
+    let new_central_directory_offset: u32 = (sections.entries.len() + block.len()).try_into()?;
+    let mut apk = sections.entries.clone();
+    apk.extend(block);
+    apk.extend(&sections.central_directory);
+    apk.extend(sections.eocd(new_central_directory_offset));
+    Ok(apk)
+}
+
+// ----- JKS -----
+
+const JKS_MAGIC: u32 = 0xfeedfeed;
+const JKS_PRIVATE_KEY_TAG: u32 = 1;
+const JKS_TRUSTED_CERT_TAG: u32 = 2;
+const JKS_KEY_PROTECTOR_OID: &[u8] = &[
+    0x06, 0x0a, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x2a, 0x02, 0x11, 0x01, 0x01,
+];
+
+struct JksReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> JksReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            bail!("Truncated JKS keystore");
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+    /// Read a java 'modified UTF-8' string, we only need to skip them.
+    fn utf(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+}
+
+/// Read the first private key of a JKS keystore, returns the PKCS#8 encoded key and the DER
+/// encoded certificate chain.
+fn read_jks(data: &[u8], password: &str) -> Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    let mut reader = JksReader { data };
+    if reader.u32()? != JKS_MAGIC {
+        bail!("Not a JKS keystore");
+    }
+    let version = reader.u32()?;
+    if version != 1 && version != 2 {
+        bail!("Unsupported JKS version {version}");
+    }
+    let nb_entries = reader.u32()?;
+    for _ in 0..nb_entries {
+        let tag = reader.u32()?;
+        let alias = String::from_utf8_lossy(reader.utf()?).to_string();
+        reader.bytes(8)?; // timestamp
+        match tag {
+            JKS_PRIVATE_KEY_TAG => {
+                let len = reader.u32()? as usize;
+                let encrypted_key = reader.bytes(len)?;
+                let nb_certs = reader.u32()?;
+                let mut certificates = vec![];
+                for _ in 0..nb_certs {
+                    if version == 2 {
+                        reader.utf()?; // certificate type
+                    }
+                    let len = reader.u32()? as usize;
+                    certificates.push(reader.bytes(len)?.to_vec());
+                }
+                debug!("Using key {alias}");
+                let key = jks_decrypt_key(encrypted_key, password)?;
+                return Ok((key, certificates));
+            }
+            JKS_TRUSTED_CERT_TAG => {
+                if version == 2 {
+                    reader.utf()?; // certificate type
+                }
+                let len = reader.u32()? as usize;
+                reader.bytes(len)?;
+            }
+            _ => bail!("Unknown JKS entry type {tag}"),
+        }
+    }
+    bail!("No private key found in JKS keystore")
+}
+
+/// Decrypt a key protected by the proprietary `sun.security.provider.KeyProtector` algorithm.
+fn jks_decrypt_key(encrypted_key_info: &[u8], password: &str) -> Result<Vec<u8>> {
+    const SALT_LEN: usize = 20;
+    const DIGEST_LEN: usize = 20;
+    let (info, _) = der_next(encrypted_key_info)?;
+    let (algorithm, rest) = der_next(info.content)?;
+    let (encrypted, _) = der_next(rest)?;
+    if !algorithm.content.starts_with(JKS_KEY_PROTECTOR_OID) {
+        bail!("Unsupported JKS key protection algorithm");
+    }
+    let encrypted = encrypted.content;
+    if encrypted.len() < SALT_LEN + DIGEST_LEN {
+        bail!("Truncated JKS protected key");
+    }
+    let password: Vec<u8> = password
+        .encode_utf16()
+        .flat_map(|c| c.to_be_bytes())
+        .collect();
+    let salt = &encrypted[..SALT_LEN];
+    let ciphertext = &encrypted[SALT_LEN..encrypted.len() - DIGEST_LEN];
+    let check = &encrypted[encrypted.len() - DIGEST_LEN..];
+
+    let mut keystream: Vec<u8> = vec![];
+    let mut digest = salt.to_vec();
+    while keystream.len() < ciphertext.len() {
+        digest = Sha1::new()
+            .chain_update(&password)
+            .chain_update(&digest)
+            .finalize()
+            .to_vec();
+        keystream.extend(&digest);
+    }
+    let key: Vec<u8> = ciphertext
+        .iter()
+        .zip(keystream)
+        .map(|(c, k)| c ^ k)
+        .collect();
+    let expected_check = Sha1::new()
+        .chain_update(&password)
+        .chain_update(&key)
+        .finalize();
+    if expected_check.as_slice() != check {
+        warn!("JKS key integrity check failed, the password is probably wrong");
+        bail!("Failed to decrypt the JKS key: wrong password");
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn stored_entries_are_aligned() {
+        let entries = vec![
+            ZipEntry::deflated("AndroidManifest.xml".into(), b"manifest").unwrap(),
+            ZipEntry::stored("resources.arsc".into(), &[1; 13]).unwrap(),
+            ZipEntry::stored("assets/odd_name.bin".into(), &[2; 7]).unwrap(),
+            ZipEntry::stored("lib/arm64-v8a/libnative.so".into(), &[3; 5000]).unwrap(),
+            ZipEntry::stored("lib/x86_64/libnative.so".into(), &[4; 3]).unwrap(),
+        ];
+        let sections = write_zip(&entries).unwrap();
+        let mut apk = sections.entries.clone();
+        apk.extend(&sections.central_directory);
+        apk.extend(sections.eocd(sections.entries.len() as u32));
+
+        let mut archive = ZipArchive::new(Cursor::new(apk)).unwrap();
+        assert_eq!(archive.len(), entries.len());
+        for entry in &entries {
+            let mut file = archive.by_name(&entry.name).unwrap();
+            if let Some(alignment) = entry.alignment() {
+                assert_eq!(
+                    file.data_start() % alignment as u64,
+                    0,
+                    "{} is not aligned",
+                    entry.name
+                );
+            }
+            let mut content = vec![];
+            file.read_to_end(&mut content).unwrap();
+            assert_eq!(content, entry.content);
+        }
+        assert_eq!(entries[3].alignment(), Some(PAGE_ALIGNMENT));
+        assert_eq!(entries[1].alignment(), Some(DEFAULT_ALIGNMENT));
+        assert_eq!(entries[0].alignment(), None);
+    }
+
+    #[test]
+    fn v2_content_digest() {
+        // Computed with the algorithm of the APK Signature Scheme v2 specification, the last
+        // section is split in two chunks of 1MiB and 256 bytes
+        let large: Vec<u8> = (0..=255u8).cycle().take(256 * 4097).collect();
+        assert_eq!(
+            hex(&content_digest(&[b"abc", b"", &large])),
+            "e979068c9c49936a60e89b11a65d708c41c9268d7ce2f9e5755e5845d1444b64"
+        );
+        assert_eq!(
+            hex(&content_digest(&[b"", b"", b""])),
+            "1043190b67a6bc391c83a3770c7c1fc51f694c6326bfc07b2b5cdc2f2732c4e0"
+        );
+    }
+
+    #[test]
+    fn v1_digest_depends_on_min_sdk() {
+        assert_eq!(V1Digest::for_min_sdk(1), V1Digest::Sha1);
+        assert_eq!(V1Digest::for_min_sdk(17), V1Digest::Sha1);
+        assert_eq!(V1Digest::for_min_sdk(18), V1Digest::Sha256);
+        assert_eq!(
+            BASE64_STANDARD.encode(V1Digest::Sha1.digest(b"abc")),
+            "qZk+NkcGgWq6PiVxeFDCbJzQ2J0="
+        );
+    }
+
+    #[test]
+    fn auto_needs_both_tools() {
+        assert!(!SigningTool::Auto.use_external_tools(false, false).unwrap());
+        assert!(SigningTool::Auto.use_external_tools(true, true).unwrap());
+        assert!(SigningTool::Auto.use_external_tools(true, false).is_err());
+        assert!(SigningTool::Auto.use_external_tools(false, true).is_err());
+        assert!(SigningTool::External
+            .use_external_tools(false, false)
+            .unwrap());
+        assert!(!SigningTool::Builtin.use_external_tools(true, true).unwrap());
+    }
+}
@@ -0,0 +1,983 @@
+//! Minimal editor of the Android binary XML format, used to modify the `AndroidManifest.xml` of
+//! an apk.
+//!
+//! Only the chunks needed to add elements are decoded: the string pool, the resource map and
+//! the headers of the XML nodes. The other nodes are kept as is. New strings are appended to the
+//! string pool, except the names of the attributes from the `android` namespace that must be in
+//! the part of the pool mapped to resource ids; in that case the string references of all the
+//! nodes are shifted.
+
+use anyhow::{bail, Context, Result};
+
+const RES_STRING_POOL_TYPE: u16 = 0x0001;
+const RES_XML_TYPE: u16 = 0x0003;
+const RES_XML_START_NAMESPACE_TYPE: u16 = 0x0100;
+const RES_XML_END_NAMESPACE_TYPE: u16 = 0x0101;
+const RES_XML_START_ELEMENT_TYPE: u16 = 0x0102;
+const RES_XML_END_ELEMENT_TYPE: u16 = 0x0103;
+const RES_XML_CDATA_TYPE: u16 = 0x0104;
+const RES_XML_RESOURCE_MAP_TYPE: u16 = 0x0180;
+
+const UTF8_FLAG: u32 = 0x100;
+/// The value of a missing string reference.
+const NO_ENTRY: u32 = 0xFFFF_FFFF;
+
+const TYPE_STRING: u8 = 0x03;
+const TYPE_INT_DEC: u8 = 0x10;
+const TYPE_INT_HEX: u8 = 0x11;
+const TYPE_INT_BOOLEAN: u8 = 0x12;
+
+/// The size of the header of the XML nodes.
+const NODE_HEADER_SIZE: usize = 16;
+/// The size of an attribute of a start element.
+const ATTRIBUTE_SIZE: usize = 20;
+
+/// `android.R.attr.minSdkVersion`
+const MIN_SDK_VERSION_ATTR: u32 = 0x0101_020c;
+/// `android.R.attr.targetSdkVersion`
+const TARGET_SDK_VERSION_ATTR: u32 = 0x0101_0270;
+
+/// The uri of the `android` namespace.
+pub const ANDROID_NS: &str = "http://schemas.android.com/apk/res/android";
+
+fn u16_at(data: &[u8], off: usize) -> Result<u16> {
+    data.get(off..off + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .context("Truncated binary XML")
+}
+
+fn u32_at(data: &[u8], off: usize) -> Result<u32> {
+    data.get(off..off + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .context("Truncated binary XML")
+}
+
+fn set_u32(data: &mut [u8], off: usize, val: u32) {
+    data[off..off + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+/// A string of the string pool, with its encoded value.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct PoolString {
+    value: String,
+    raw: Vec<u8>,
+}
+
+impl PoolString {
+    fn decode_utf8(chunk: &[u8], off: usize) -> Result<Self> {
+        let len = |off: usize| -> Result<(usize, usize)> {
+            let b0 = *chunk.get(off).context("Truncated string pool")? as usize;
+            if b0 & 0x80 != 0 {
+                let b1 = *chunk.get(off + 1).context("Truncated string pool")? as usize;
+                Ok((((b0 & 0x7f) << 8) | b1, 2))
+            } else {
+                Ok((b0, 1))
+            }
+        };
+        let (_, n16) = len(off)?;
+        let (byte_len, n8) = len(off + n16)?;
+        let start = off + n16 + n8;
+        let bytes = chunk
+            .get(start..start + byte_len)
+            .context("Truncated string pool")?;
+        Ok(Self {
+            value: String::from_utf8_lossy(bytes).into_owned(),
+            raw: chunk
+                .get(off..start + byte_len + 1)
+                .context("Truncated string pool")?
+                .to_vec(),
+        })
+    }
+
+    fn decode_utf16(chunk: &[u8], off: usize) -> Result<Self> {
+        let u0 = u16_at(chunk, off)? as usize;
+        let (len, header) = if u0 & 0x8000 != 0 {
+            (((u0 & 0x7fff) << 16) | u16_at(chunk, off + 2)? as usize, 4)
+        } else {
+            (u0, 2)
+        };
+        let units = (0..len)
+            .map(|i| u16_at(chunk, off + header + 2 * i))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            value: String::from_utf16_lossy(&units),
+            raw: chunk
+                .get(off..off + header + 2 * len + 2)
+                .context("Truncated string pool")?
+                .to_vec(),
+        })
+    }
+
+    fn encode(value: &str, utf8: bool) -> Self {
+        let mut raw = vec![];
+        if utf8 {
+            let len = |n: usize, raw: &mut Vec<u8>| {
+                if n > 0x7f {
+                    raw.extend([0x80 | (n >> 8) as u8, n as u8]);
+                } else {
+                    raw.push(n as u8);
+                }
+            };
+            len(value.encode_utf16().count(), &mut raw);
+            len(value.len(), &mut raw);
+            raw.extend(value.as_bytes());
+            raw.push(0);
+        } else {
+            let units: Vec<u16> = value.encode_utf16().collect();
+            if units.len() > 0x7fff {
+                raw.extend((0x8000 | (units.len() >> 16) as u16).to_le_bytes());
+            }
+            raw.extend((units.len() as u16).to_le_bytes());
+            for unit in units {
+                raw.extend(unit.to_le_bytes());
+            }
+            raw.extend([0, 0]);
+        }
+        Self {
+            value: value.into(),
+            raw,
+        }
+    }
+}
+
+/// A node of the XML tree, stored as its binary chunk.
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Node {
+    ty: u16,
+    data: Vec<u8>,
+}
+
+impl Node {
+    /// The offsets of the attributes of a start element.
+    fn attribute_offsets(&self) -> Vec<usize> {
+        if self.ty != RES_XML_START_ELEMENT_TYPE {
+            return vec![];
+        }
+        let (Ok(start), Ok(size), Ok(count)) = (
+            u16_at(&self.data, NODE_HEADER_SIZE + 8),
+            u16_at(&self.data, NODE_HEADER_SIZE + 10),
+            u16_at(&self.data, NODE_HEADER_SIZE + 12),
+        ) else {
+            return vec![];
+        };
+        (0..count as usize)
+            .map(|i| NODE_HEADER_SIZE + start as usize + i * size as usize)
+            .filter(|off| off + ATTRIBUTE_SIZE <= self.data.len())
+            .collect()
+    }
+
+    /// The offsets of the references to the string pool.
+    fn string_ref_offsets(&self) -> Vec<usize> {
+        let mut offsets = vec![12];
+        match self.ty {
+            RES_XML_START_NAMESPACE_TYPE
+            | RES_XML_END_NAMESPACE_TYPE
+            | RES_XML_END_ELEMENT_TYPE => {
+                offsets.extend([NODE_HEADER_SIZE, NODE_HEADER_SIZE + 4]);
+            }
+            RES_XML_CDATA_TYPE => {
+                offsets.push(NODE_HEADER_SIZE);
+                if self.data.get(NODE_HEADER_SIZE + 7) == Some(&TYPE_STRING) {
+                    offsets.push(NODE_HEADER_SIZE + 8);
+                }
+            }
+            RES_XML_START_ELEMENT_TYPE => {
+                offsets.extend([NODE_HEADER_SIZE, NODE_HEADER_SIZE + 4]);
+                for off in self.attribute_offsets() {
+                    offsets.extend([off, off + 4, off + 8]);
+                    if self.data[off + 15] == TYPE_STRING {
+                        offsets.push(off + 16);
+                    }
+                }
+            }
+            _ => (),
+        }
+        offsets.retain(|off| off + 4 <= self.data.len());
+        offsets
+    }
+
+    /// The index of the name of a start or end element.
+    fn name(&self) -> Option<u32> {
+        match self.ty {
+            RES_XML_START_ELEMENT_TYPE | RES_XML_END_ELEMENT_TYPE => {
+                u32_at(&self.data, NODE_HEADER_SIZE + 4).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The value of an attribute of a new element.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum AttributeValue {
+    String(String),
+    Boolean(bool),
+}
+
+/// A binary XML document.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BinaryXml {
+    utf8: bool,
+    strings: Vec<PoolString>,
+    resource_map: Vec<u32>,
+    nodes: Vec<Node>,
+}
+
+impl BinaryXml {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if u16_at(data, 0)? != RES_XML_TYPE {
+            bail!("Not a binary XML file");
+        }
+        let header_size = u16_at(data, 2)? as usize;
+        let size = (u32_at(data, 4)? as usize).min(data.len());
+        let mut xml = Self {
+            utf8: false,
+            strings: vec![],
+            resource_map: vec![],
+            nodes: vec![],
+        };
+        let mut off = header_size;
+        while off + 8 <= size {
+            let ty = u16_at(data, off)?;
+            let chunk_size = u32_at(data, off + 4)? as usize;
+            if chunk_size < 8 {
+                bail!("Invalid chunk size {chunk_size} at 0x{off:x}");
+            }
+            let chunk = data
+                .get(off..off + chunk_size)
+                .context("Truncated binary XML")?;
+            match ty {
+                RES_STRING_POOL_TYPE => xml.parse_string_pool(chunk)?,
+                RES_XML_RESOURCE_MAP_TYPE => {
+                    let header_size = u16_at(chunk, 2)? as usize;
+                    xml.resource_map = chunk[header_size..]
+                        .chunks_exact(4)
+                        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect();
+                }
+                _ => xml.nodes.push(Node {
+                    ty,
+                    data: chunk.to_vec(),
+                }),
+            }
+            off += chunk_size;
+        }
+        Ok(xml)
+    }
+
+    fn parse_string_pool(&mut self, chunk: &[u8]) -> Result<()> {
+        let header_size = u16_at(chunk, 2)? as usize;
+        let count = u32_at(chunk, 8)? as usize;
+        let style_count = u32_at(chunk, 12)?;
+        let flags = u32_at(chunk, 16)?;
+        let strings_start = u32_at(chunk, 20)? as usize;
+        if style_count != 0 {
+            bail!("String pools with styles are not supported");
+        }
+        self.utf8 = flags & UTF8_FLAG != 0;
+        self.strings = (0..count)
+            .map(|i| {
+                let off = strings_start + u32_at(chunk, header_size + 4 * i)? as usize;
+                if self.utf8 {
+                    PoolString::decode_utf8(chunk, off)
+                } else {
+                    PoolString::decode_utf16(chunk, off)
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    /// Serialize the document.
+    pub fn write(&self) -> Vec<u8> {
+        let mut pool = vec![];
+        let strings_start = 28 + 4 * self.strings.len();
+        let mut offset = 0;
+        for string in &self.strings {
+            pool.extend((offset as u32).to_le_bytes());
+            offset += string.raw.len();
+        }
+        for string in &self.strings {
+            pool.extend(&string.raw);
+        }
+        while pool.len() % 4 != 0 {
+            pool.push(0);
+        }
+        // The pool is not sorted anymore if strings were added
+        let flags = if self.utf8 { UTF8_FLAG } else { 0 };
+
+        let mut chunks = vec![];
+        chunks.extend(RES_STRING_POOL_TYPE.to_le_bytes());
+        chunks.extend(28u16.to_le_bytes());
+        chunks.extend(((28 + pool.len()) as u32).to_le_bytes());
+        chunks.extend((self.strings.len() as u32).to_le_bytes());
+        chunks.extend(0u32.to_le_bytes());
+        chunks.extend(flags.to_le_bytes());
+        chunks.extend((strings_start as u32).to_le_bytes());
+        chunks.extend(0u32.to_le_bytes());
+        chunks.extend(pool);
+        if !self.resource_map.is_empty() {
+            chunks.extend(RES_XML_RESOURCE_MAP_TYPE.to_le_bytes());
+            chunks.extend(8u16.to_le_bytes());
+            chunks.extend(((8 + 4 * self.resource_map.len()) as u32).to_le_bytes());
+            for id in &self.resource_map {
+                chunks.extend(id.to_le_bytes());
+            }
+        }
+        for node in &self.nodes {
+            chunks.extend(&node.data);
+        }
+
+        let mut data = vec![];
+        data.extend(RES_XML_TYPE.to_le_bytes());
+        data.extend(8u16.to_le_bytes());
+        data.extend(((8 + chunks.len()) as u32).to_le_bytes());
+        data.extend(chunks);
+        data
+    }
+
+    fn string(&self, idx: u32) -> Option<&str> {
+        self.strings.get(idx as usize).map(|s| s.value.as_str())
+    }
+
+    /// Get the index of `value` in the string pool, adding it at the end of the pool if needed.
+    fn add_string(&mut self, value: &str) -> u32 {
+        if let Some(idx) = self
+            .strings
+            .iter()
+            .skip(self.resource_map.len())
+            .position(|s| s.value == value)
+        {
+            return (idx + self.resource_map.len()) as u32;
+        }
+        self.strings.push(PoolString::encode(value, self.utf8));
+        (self.strings.len() - 1) as u32
+    }
+
+    /// Get the index of the name of the attribute with the resource id `res_id`, adding it to
+    /// the part of the pool mapped to resource ids if needed. Adding a name shifts the indices
+    /// of the strings: call this method for all the attributes used before taking any other
+    /// index.
+    pub fn attribute_name_index(&mut self, name: &str, res_id: u32) -> u32 {
+        if let Some(idx) = self.resource_map.iter().position(|id| *id == res_id) {
+            return idx as u32;
+        }
+        let idx = self.resource_map.len() as u32;
+        for node in &mut self.nodes {
+            for off in node.string_ref_offsets() {
+                let val = u32_at(&node.data, off).unwrap();
+                if val != NO_ENTRY && val >= idx {
+                    set_u32(&mut node.data, off, val + 1);
+                }
+            }
+        }
+        self.strings
+            .insert(idx as usize, PoolString::encode(name, self.utf8));
+        self.resource_map.push(res_id);
+        idx
+    }
+
+    /// The name of the element starting at `node`.
+    pub fn element_name(&self, node: usize) -> Option<&str> {
+        let node = self.nodes.get(node)?;
+        if node.ty != RES_XML_START_ELEMENT_TYPE {
+            return None;
+        }
+        self.string(node.name()?)
+    }
+
+    /// Get the offset of an attribute of the element starting at `node`. The attribute is
+    /// matched by resource id if `res_id` is set, else by name.
+    fn attribute_offset(&self, node: usize, name: &str, res_id: Option<u32>) -> Option<usize> {
+        let node = self.nodes.get(node)?;
+        node.attribute_offsets().into_iter().find(|off| {
+            let name_idx = u32_at(&node.data, off + 4).unwrap();
+            match res_id {
+                Some(res_id) => self.resource_map.get(name_idx as usize) == Some(&res_id),
+                None => self.string(name_idx) == Some(name),
+            }
+        })
+    }
+
+    /// Get the value of a string attribute of the element starting at `node`.
+    pub fn attribute_string(&self, node: usize, name: &str, res_id: Option<u32>) -> Option<&str> {
+        let off = self.attribute_offset(node, name, res_id)?;
+        let data = &self.nodes[node].data;
+        let raw = u32_at(data, off + 8).ok()?;
+        if raw != NO_ENTRY {
+            self.string(raw)
+        } else if data[off + 15] == TYPE_STRING {
+            self.string(u32_at(data, off + 16).ok()?)
+        } else {
+            None
+        }
+    }
+
+    /// Get the value of an integer attribute of the element starting at `node`.
+    pub fn attribute_int(&self, node: usize, name: &str, res_id: Option<u32>) -> Option<u32> {
+        let off = self.attribute_offset(node, name, res_id)?;
+        let data = &self.nodes[node].data;
+        match data[off + 15] {
+            TYPE_INT_DEC | TYPE_INT_HEX => u32_at(data, off + 16).ok(),
+            _ => None,
+        }
+    }
+
+    /// Get the value of a boolean attribute of the element starting at `node`.
+    pub fn attribute_bool(&self, node: usize, name: &str, res_id: Option<u32>) -> Option<bool> {
+        let off = self.attribute_offset(node, name, res_id)?;
+        let data = &self.nodes[node].data;
+        match data[off + 15] {
+            TYPE_INT_BOOLEAN | TYPE_INT_DEC | TYPE_INT_HEX => {
+                u32_at(data, off + 16).ok().map(|value| value != 0)
+            }
+            _ => None,
+        }
+    }
+
+    /// Set the value of a string attribute of the element starting at `node`. Return `false`
+    /// if the element does not have the attribute.
+    pub fn set_attribute_string(&mut self, node: usize, res_id: u32, value: &str) -> bool {
+        let Some(off) = self.attribute_offset(node, "", Some(res_id)) else {
+            return false;
+        };
+        let idx = self.add_string(value);
+        let data = &mut self.nodes[node].data;
+        set_u32(data, off + 8, idx);
+        data[off + 15] = TYPE_STRING;
+        set_u32(data, off + 16, idx);
+        true
+    }
+
+    /// Set the value of a boolean attribute in the `android` namespace of the element starting
+    /// at `node`, adding the attribute if the element does not have it.
+    pub fn set_attribute_bool(
+        &mut self,
+        node: usize,
+        name: &str,
+        res_id: u32,
+        value: bool,
+    ) -> Result<()> {
+        let data = if value { NO_ENTRY } else { 0 };
+        if let Some(off) = self.attribute_offset(node, name, Some(res_id)) {
+            let data_ref = &mut self.nodes[node].data;
+            set_u32(data_ref, off + 8, NO_ENTRY);
+            data_ref[off + 15] = TYPE_INT_BOOLEAN;
+            set_u32(data_ref, off + 16, data);
+            return Ok(());
+        }
+        let name_idx = self.attribute_name_index(name, res_id);
+        let ns = self.add_string(ANDROID_NS);
+        let resource_map = &self.resource_map;
+        let element = self
+            .nodes
+            .get_mut(node)
+            .filter(|element| element.ty == RES_XML_START_ELEMENT_TYPE)
+            .context("Not a start element")?;
+        if u16_at(&element.data, NODE_HEADER_SIZE + 10)? as usize != ATTRIBUTE_SIZE {
+            bail!("Unsupported attribute size");
+        }
+        // The attributes are sorted by resource id
+        let offsets = element.attribute_offsets();
+        let pos = offsets
+            .iter()
+            .position(|off| {
+                let idx = u32_at(&element.data, off + 4).unwrap();
+                resource_map
+                    .get(idx as usize)
+                    .is_none_or(|other_id| *other_id > res_id)
+            })
+            .unwrap_or(offsets.len());
+        let insert_at = offsets.get(pos).copied().unwrap_or(
+            NODE_HEADER_SIZE
+                + u16_at(&element.data, NODE_HEADER_SIZE + 8)? as usize
+                + ATTRIBUTE_SIZE * offsets.len(),
+        );
+        let mut attribute = vec![];
+        attribute.extend(ns.to_le_bytes());
+        attribute.extend(name_idx.to_le_bytes());
+        attribute.extend(NO_ENTRY.to_le_bytes());
+        attribute.extend(8u16.to_le_bytes());
+        attribute.push(0);
+        attribute.push(TYPE_INT_BOOLEAN);
+        attribute.extend(data.to_le_bytes());
+        element.data.splice(insert_at..insert_at, attribute);
+        let count = u16_at(&element.data, NODE_HEADER_SIZE + 12)?;
+        element.data[NODE_HEADER_SIZE + 12..NODE_HEADER_SIZE + 14]
+            .copy_from_slice(&(count + 1).to_le_bytes());
+        // The id, class and style attribute indices are 1-based, 0 if absent
+        for off in [
+            NODE_HEADER_SIZE + 14,
+            NODE_HEADER_SIZE + 16,
+            NODE_HEADER_SIZE + 18,
+        ] {
+            let idx = u16_at(&element.data, off)? as usize;
+            if idx > pos {
+                element.data[off..off + 2].copy_from_slice(&(idx as u16 + 1).to_le_bytes());
+            }
+        }
+        let size = element.data.len() as u32;
+        set_u32(&mut element.data, 4, size);
+        Ok(())
+    }
+
+    /// Remove the element starting at `node` and its children.
+    pub fn remove_element(&mut self, node: usize) -> Result<()> {
+        let end = self.element_end(node).context("Element without end node")?;
+        self.nodes.drain(node..=end);
+        Ok(())
+    }
+
+    /// The first element of the document.
+    pub fn root(&self) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.ty == RES_XML_START_ELEMENT_TYPE)
+    }
+
+    /// The index of the node ending the element starting at `start`.
+    pub fn element_end(&self, start: usize) -> Option<usize> {
+        let mut depth = 0;
+        for (i, node) in self.nodes.iter().enumerate().skip(start) {
+            match node.ty {
+                RES_XML_START_ELEMENT_TYPE => depth += 1,
+                RES_XML_END_ELEMENT_TYPE => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(i);
+                    }
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+
+    /// The direct children of the element starting at `parent`.
+    pub fn children(&self, parent: usize) -> Vec<usize> {
+        let Some(end) = self.element_end(parent) else {
+            return vec![];
+        };
+        let mut children = vec![];
+        let mut i = parent + 1;
+        while i < end {
+            if self.nodes[i].ty == RES_XML_START_ELEMENT_TYPE {
+                children.push(i);
+                i = self.element_end(i).unwrap_or(end);
+            }
+            i += 1;
+        }
+        children
+    }
+
+    /// Copy the element starting at `element`, and its children, before the end of the element
+    /// starting at `parent`. Return the index of the copy.
+    pub fn append_copy(&mut self, parent: usize, element: usize) -> Result<usize> {
+        let end = self
+            .element_end(element)
+            .context("Element without end node")?;
+        let copy: Vec<_> = self.nodes[element..=end].to_vec();
+        self.append_nodes(parent, copy)
+    }
+
+    /// Add a new element without children before the end of the element starting at `parent`.
+    /// The attributes are `(name, resource id, value)` in the `android` namespace. Return the
+    /// index of the new element.
+    pub fn append_element(
+        &mut self,
+        parent: usize,
+        name: &str,
+        attributes: &[(&str, u32, AttributeValue)],
+    ) -> Result<usize> {
+        let mut attributes: Vec<_> = attributes
+            .iter()
+            .map(|(attr, res_id, value)| (*res_id, self.attribute_name_index(attr, *res_id), value))
+            .collect();
+        attributes.sort_by_key(|(res_id, _, _)| *res_id);
+        let ns = self.add_string(ANDROID_NS);
+        let name = self.add_string(name);
+
+        let mut start = vec![];
+        start.extend(RES_XML_START_ELEMENT_TYPE.to_le_bytes());
+        start.extend((NODE_HEADER_SIZE as u16).to_le_bytes());
+        start.extend(
+            ((NODE_HEADER_SIZE + 20 + ATTRIBUTE_SIZE * attributes.len()) as u32).to_le_bytes(),
+        );
+        start.extend(0u32.to_le_bytes()); // line number
+        start.extend(NO_ENTRY.to_le_bytes()); // comment
+        start.extend(NO_ENTRY.to_le_bytes()); // namespace
+        start.extend(name.to_le_bytes());
+        start.extend(20u16.to_le_bytes()); // attribute start
+        start.extend((ATTRIBUTE_SIZE as u16).to_le_bytes());
+        start.extend((attributes.len() as u16).to_le_bytes());
+        start.extend([0; 6]); // id, class and style indices
+        for (_, attr_name, value) in attributes {
+            let (raw, ty, data) = match value {
+                AttributeValue::String(value) => {
+                    let idx = self.add_string(value);
+                    (idx, TYPE_STRING, idx)
+                }
+                AttributeValue::Boolean(value) => (
+                    NO_ENTRY,
+                    TYPE_INT_BOOLEAN,
+                    if *value { NO_ENTRY } else { 0 },
+                ),
+            };
+            start.extend(ns.to_le_bytes());
+            start.extend(attr_name.to_le_bytes());
+            start.extend(raw.to_le_bytes());
+            start.extend(8u16.to_le_bytes());
+            start.push(0);
+            start.push(ty);
+            start.extend(data.to_le_bytes());
+        }
+
+        let mut end = vec![];
+        end.extend(RES_XML_END_ELEMENT_TYPE.to_le_bytes());
+        end.extend((NODE_HEADER_SIZE as u16).to_le_bytes());
+        end.extend(((NODE_HEADER_SIZE + 8) as u32).to_le_bytes());
+        end.extend(0u32.to_le_bytes());
+        end.extend(NO_ENTRY.to_le_bytes());
+        end.extend(NO_ENTRY.to_le_bytes());
+        end.extend(name.to_le_bytes());
+
+        self.append_nodes(
+            parent,
+            vec![
+                Node {
+                    ty: RES_XML_START_ELEMENT_TYPE,
+                    data: start,
+                },
+                Node {
+                    ty: RES_XML_END_ELEMENT_TYPE,
+                    data: end,
+                },
+            ],
+        )
+    }
+
+    fn append_nodes(&mut self, parent: usize, nodes: Vec<Node>) -> Result<usize> {
+        let end = self
+            .element_end(parent)
+            .context("Element without end node")?;
+        self.nodes.splice(end..end, nodes);
+        Ok(end)
+    }
+}
+
+/// Get the `minSdkVersion` of the binary `manifest`.
+pub fn min_sdk_version(manifest: &[u8]) -> Result<Option<u32>> {
+    let xml = BinaryXml::parse(manifest)?;
+    let Some(root) = xml.root() else {
+        return Ok(None);
+    };
+    Ok(xml
+        .children(root)
+        .into_iter()
+        .find(|node| xml.element_name(*node) == Some("uses-sdk"))
+        .and_then(|node| xml.attribute_int(node, "minSdkVersion", Some(MIN_SDK_VERSION_ATTR))))
+}
+
+/// Get the `targetSdkVersion` (or the `minSdkVersion` if not set) of the binary `manifest`.
+pub fn target_sdk_version(manifest: &[u8]) -> Result<Option<u32>> {
+    let xml = BinaryXml::parse(manifest)?;
+    let Some(root) = xml.root() else {
+        return Ok(None);
+    };
+    Ok(xml
+        .children(root)
+        .into_iter()
+        .find(|node| xml.element_name(*node) == Some("uses-sdk"))
+        .and_then(|node| {
+            xml.attribute_int(node, "targetSdkVersion", Some(TARGET_SDK_VERSION_ATTR))
+                .or_else(|| xml.attribute_int(node, "minSdkVersion", Some(MIN_SDK_VERSION_ATTR)))
+        }))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Encode a start element: `attributes` are `(namespace, name, raw value, type, data)`.
+    fn start_element(name: u32, attributes: &[(u32, u32, u32, u8, u32)]) -> Node {
+        let mut data = vec![];
+        data.extend(RES_XML_START_ELEMENT_TYPE.to_le_bytes());
+        data.extend((NODE_HEADER_SIZE as u16).to_le_bytes());
+        data.extend(
+            ((NODE_HEADER_SIZE + 20 + ATTRIBUTE_SIZE * attributes.len()) as u32).to_le_bytes(),
+        );
+        data.extend(1u32.to_le_bytes());
+        data.extend(NO_ENTRY.to_le_bytes());
+        data.extend(NO_ENTRY.to_le_bytes());
+        data.extend(name.to_le_bytes());
+        data.extend(20u16.to_le_bytes());
+        data.extend((ATTRIBUTE_SIZE as u16).to_le_bytes());
+        data.extend((attributes.len() as u16).to_le_bytes());
+        data.extend([0; 6]);
+        for (ns, name, raw, ty, value) in attributes {
+            data.extend(ns.to_le_bytes());
+            data.extend(name.to_le_bytes());
+            data.extend(raw.to_le_bytes());
+            data.extend(8u16.to_le_bytes());
+            data.push(0);
+            data.push(*ty);
+            data.extend(value.to_le_bytes());
+        }
+        Node {
+            ty: RES_XML_START_ELEMENT_TYPE,
+            data,
+        }
+    }
+
+    fn end_node(ty: u16, first: u32, second: u32) -> Node {
+        let mut data = vec![];
+        data.extend(ty.to_le_bytes());
+        data.extend((NODE_HEADER_SIZE as u16).to_le_bytes());
+        data.extend(((NODE_HEADER_SIZE + 8) as u32).to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        data.extend(NO_ENTRY.to_le_bytes());
+        data.extend(first.to_le_bytes());
+        data.extend(second.to_le_bytes());
+        Node { ty, data }
+    }
+
+    /// The binary version of the manifest of `test_apks/simple_demo`.
+    pub(crate) fn demo_manifest(utf8: bool) -> Vec<u8> {
+        let strings = [
+            "name",
+            "exported",
+            "label",
+            "minSdkVersion",
+            "targetSdkVersion",
+            "android",
+            ANDROID_NS,
+            "manifest",
+            "package",
+            "com.example.theseus",
+            "uses-sdk",
+            "application",
+            "Theseus Demo",
+            "activity",
+            ".MainActivity",
+            "intent-filter",
+            "action",
+            "android.intent.action.MAIN",
+            "category",
+            "android.intent.category.LAUNCHER",
+        ];
+        let idx = |value: &str| strings.iter().position(|s| *s == value).unwrap() as u32;
+        let ns = idx(ANDROID_NS);
+        let string_attr =
+            |name: &str, value: &str| (ns, idx(name), idx(value), TYPE_STRING, idx(value));
+        let element = |name: &str, attributes: &[(u32, u32, u32, u8, u32)]| {
+            start_element(idx(name), attributes)
+        };
+        let end = |name: &str| end_node(RES_XML_END_ELEMENT_TYPE, NO_ENTRY, idx(name));
+        let nodes = vec![
+            end_node(RES_XML_START_NAMESPACE_TYPE, idx("android"), ns),
+            element(
+                "manifest",
+                &[(
+                    NO_ENTRY,
+                    idx("package"),
+                    idx("com.example.theseus"),
+                    TYPE_STRING,
+                    idx("com.example.theseus"),
+                )],
+            ),
+            element(
+                "uses-sdk",
+                &[
+                    (ns, idx("minSdkVersion"), NO_ENTRY, TYPE_INT_DEC, 28),
+                    (ns, idx("targetSdkVersion"), NO_ENTRY, TYPE_INT_DEC, 34),
+                ],
+            ),
+            end("uses-sdk"),
+            element("application", &[string_attr("label", "Theseus Demo")]),
+            element(
+                "activity",
+                &[
+                    string_attr("name", ".MainActivity"),
+                    (ns, idx("exported"), NO_ENTRY, TYPE_INT_BOOLEAN, NO_ENTRY),
+                ],
+            ),
+            element("intent-filter", &[]),
+            element(
+                "action",
+                &[string_attr("name", "android.intent.action.MAIN")],
+            ),
+            end("action"),
+            element(
+                "category",
+                &[string_attr("name", "android.intent.category.LAUNCHER")],
+            ),
+            end("category"),
+            end("intent-filter"),
+            end("activity"),
+            end("application"),
+            end("manifest"),
+            end_node(RES_XML_END_NAMESPACE_TYPE, idx("android"), ns),
+        ];
+        BinaryXml {
+            utf8,
+            strings: strings
+                .iter()
+                .map(|value| PoolString::encode(value, utf8))
+                .collect(),
+            resource_map: vec![
+                0x0101_0003,
+                0x0101_0010,
+                0x0101_0001,
+                0x0101_020c,
+                0x0101_0270,
+            ],
+            nodes,
+        }
+        .write()
+    }
+
+    #[test]
+    fn parse_write_round_trip() {
+        for utf8 in [true, false] {
+            let manifest = demo_manifest(utf8);
+            let xml = BinaryXml::parse(&manifest).unwrap();
+            assert_eq!(xml.write(), manifest);
+            assert_eq!(BinaryXml::parse(&xml.write()).unwrap(), xml);
+        }
+    }
+
+    #[test]
+    fn sdk_versions() {
+        let manifest = demo_manifest(true);
+        assert_eq!(min_sdk_version(&manifest).unwrap(), Some(28));
+        assert_eq!(target_sdk_version(&manifest).unwrap(), Some(34));
+    }
+
+    #[test]
+    fn read_manifest_attributes() {
+        let xml = BinaryXml::parse(&demo_manifest(true)).unwrap();
+        let root = xml.root().unwrap();
+        assert_eq!(xml.element_name(root), Some("manifest"));
+        assert_eq!(
+            xml.attribute_string(root, "package", None),
+            Some("com.example.theseus")
+        );
+        let children = xml.children(root);
+        assert_eq!(children.len(), 2);
+        assert_eq!(
+            xml.attribute_int(children[0], "targetSdkVersion", Some(0x0101_0270)),
+            Some(34)
+        );
+        let activity = xml.children(children[1])[0];
+        assert_eq!(
+            xml.attribute_string(activity, "name", Some(0x0101_0003)),
+            Some(".MainActivity")
+        );
+        assert_eq!(
+            xml.attribute_bool(activity, "exported", Some(0x0101_0010)),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn add_element() {
+        for utf8 in [true, false] {
+            let mut xml = BinaryXml::parse(&demo_manifest(utf8)).unwrap();
+            // Shifts the string indices of the existing nodes
+            xml.attribute_name_index("authorities", 0x0101_0018);
+            let application = xml.children(xml.root().unwrap())[1];
+            xml.append_element(
+                application,
+                "provider",
+                &[
+                    (
+                        "name",
+                        0x0101_0003,
+                        AttributeValue::String("com.example.P".into()),
+                    ),
+                    ("exported", 0x0101_0010, AttributeValue::Boolean(false)),
+                    (
+                        "authorities",
+                        0x0101_0018,
+                        AttributeValue::String("com.example.P".into()),
+                    ),
+                ],
+            )
+            .unwrap();
+
+            let xml = BinaryXml::parse(&xml.write()).unwrap();
+            let application = xml.children(xml.root().unwrap())[1];
+            let children = xml.children(application);
+            assert_eq!(children.len(), 2);
+            assert_eq!(
+                xml.attribute_string(children[0], "name", Some(0x0101_0003)),
+                Some(".MainActivity")
+            );
+            let provider = children[1];
+            assert_eq!(xml.element_name(provider), Some("provider"));
+            assert_eq!(
+                xml.attribute_string(provider, "authorities", Some(0x0101_0018)),
+                Some("com.example.P")
+            );
+            assert_eq!(
+                xml.attribute_bool(provider, "exported", Some(0x0101_0010)),
+                Some(false)
+            );
+        }
+    }
+
+    #[test]
+    fn copy_and_edit_element() {
+        let mut xml = BinaryXml::parse(&demo_manifest(true)).unwrap();
+        let application = xml.children(xml.root().unwrap())[1];
+        let activity = xml.children(application)[0];
+        let copy = xml.append_copy(application, activity).unwrap();
+        assert!(xml.set_attribute_string(copy, 0x0101_0003, "com.example.Copy"));
+        let filter = xml.children(copy)[0];
+        xml.remove_element(filter).unwrap();
+        xml.set_attribute_bool(copy, "exported", 0x0101_0010, false)
+            .unwrap();
+
+        let xml = BinaryXml::parse(&xml.write()).unwrap();
+        let application = xml.children(xml.root().unwrap())[1];
+        let [activity, copy] = xml.children(application)[..] else {
+            panic!("Expected two activities");
+        };
+        assert_eq!(xml.children(activity).len(), 1);
+        assert!(xml.children(copy).is_empty());
+        assert_eq!(
+            xml.attribute_string(copy, "name", Some(0x0101_0003)),
+            Some("com.example.Copy")
+        );
+        assert_eq!(
+            xml.attribute_bool(copy, "exported", Some(0x0101_0010)),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn add_missing_attribute() {
+        let mut xml = BinaryXml::parse(&demo_manifest(false)).unwrap();
+        let application = xml.children(xml.root().unwrap())[1];
+        xml.set_attribute_bool(application, "debuggable", 0x0101_000f, true)
+            .unwrap();
+
+        let xml = BinaryXml::parse(&xml.write()).unwrap();
+        let application = xml.children(xml.root().unwrap())[1];
+        assert_eq!(
+            xml.attribute_bool(application, "debuggable", Some(0x0101_000f)),
+            Some(true)
+        );
+        assert_eq!(
+            xml.attribute_string(application, "label", Some(0x0101_0001)),
+            Some("Theseus Demo")
+        );
+    }
+
+    #[test]
+    fn truncated_string() {
+        // The NUL terminator is after the end of the chunk
+        assert!(PoolString::decode_utf8(&[3, 3, b'a', b'b', b'c'], 0).is_err());
+        assert!(PoolString::decode_utf16(&[1, 0, b'a', 0], 0).is_err());
+    }
+}
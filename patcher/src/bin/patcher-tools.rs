@@ -0,0 +1,150 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use androscalpel::Apk;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+
+use patcher::{
+    callgraph::{CallGraph, CallGraphDiff},
+    class_mapping::ClassMapping,
+    labeling,
+    report::PatchReport,
+    runtime_data::RuntimeData,
+    trace::{parse_logcat, site_statistics},
+};
+
+/// Tools to inspect the applications generated by the patcher.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, arg_required_else_help = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compute the hit rate of each patched call site from a logcat capture of an application
+    /// patched with `--trace`.
+    TraceStats {
+        /// The logcat capture.
+        #[arg(short, long)]
+        logcat: PathBuf,
+        /// The report written by the patcher with `--report`.
+        #[arg(short, long)]
+        report: PathBuf,
+        /// Write the statistics in this file instead of stdout (JSON).
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+    /// Compare the call graph of an application with the call graph of its patched version,
+    /// and attribute the new edges to the runtime data.
+    CallgraphDiff {
+        /// The original application.
+        #[arg(long)]
+        original: PathBuf,
+        /// The patched application.
+        #[arg(long)]
+        patched: PathBuf,
+        /// The runtime data used to patch the application.
+        #[arg(short, long)]
+        runtime_data: PathBuf,
+        /// Bytecode loaded dynamically (dex or apk), added to the call graph of the original
+        /// application. Defaults to the files listed in the runtime data.
+        #[arg(long)]
+        dyn_code: Vec<PathBuf>,
+        /// The mapping of the classes renamed by the patcher (written with `--mapping`), to
+        /// compare the methods of the renamed classes by their original names.
+        #[arg(long)]
+        mapping: Option<PathBuf>,
+        #[arg(short, long, default_value_t, value_enum)]
+        format: DiffFormat,
+        /// Write the result in this file instead of stdout.
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Debug, PartialEq, Clone, Copy, Default)]
+enum DiffFormat {
+    #[default]
+    Json,
+    Dot,
+}
+
+fn open(path: &PathBuf) -> Result<File> {
+    File::open(path).with_context(|| format!("Failed to open {}", path.display()))
+}
+
+fn output(path: Option<&PathBuf>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?,
+        ),
+        None => Box::new(std::io::stdout()),
+    })
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let cli = Cli::parse();
+    match cli.command {
+        Command::TraceStats {
+            logcat,
+            report,
+            out,
+        } => {
+            let report = PatchReport::load(open(&report)?)?;
+            let events = parse_logcat(open(&logcat)?)?;
+            let stats = site_statistics(&report, &events);
+            let mut out = output(out.as_ref())?;
+            serde_json::to_writer_pretty(&mut out, &stats)?;
+            writeln!(out)?;
+        }
+        Command::CallgraphDiff {
+            original,
+            patched,
+            runtime_data,
+            dyn_code,
+            mapping,
+            format,
+            out,
+        } => {
+            let mut json = String::new();
+            open(&runtime_data)?.read_to_string(&mut json)?;
+            let runtime_data: RuntimeData = serde_json::from_str(&json)?;
+
+            let mut original_cg =
+                CallGraph::new(&Apk::load_apk(open(&original)?, labeling, false)?);
+            let dyn_code = if dyn_code.is_empty() {
+                runtime_data
+                    .dyn_code_load
+                    .iter()
+                    .flat_map(|data| data.files.iter().cloned())
+                    .collect()
+            } else {
+                dyn_code
+            };
+            for file in &dyn_code {
+                let mut apk = Apk::new();
+                apk.add_code(open(file)?, labeling, false)
+                    .with_context(|| format!("Could not add code from {}", file.display()))?;
+                original_cg.add_apk(&apk);
+            }
+            let patched_cg = CallGraph::new(&Apk::load_apk(open(&patched)?, labeling, false)?);
+
+            let mapping = mapping
+                .map(|path| ClassMapping::load(open(&path)?))
+                .transpose()?;
+            let diff =
+                CallGraphDiff::new(&original_cg, &patched_cg, &runtime_data, mapping.as_ref());
+            let mut out = output(out.as_ref())?;
+            match format {
+                DiffFormat::Json => writeln!(out, "{}", diff.to_json()?)?,
+                DiffFormat::Dot => write!(out, "{}", diff.to_dot())?,
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,6 +1,9 @@
 use anyhow::Context;
 use log::warn;
 use rand::distr::{Alphanumeric, SampleString};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Cursor, Read};
@@ -10,13 +13,30 @@ use androscalpel::{Apk, Class, IdType};
 
 use androscalpel::SmaliName;
 use patcher::{
-    code_loading_patcher::{insert_code, CodePatchingStrategy},
+    apk_signer::{self, SigningTool},
+    axml::{min_sdk_version, target_sdk_version},
+    class_loader_patcher::neutralize_class_loaders,
+    class_loader_policy::ClassLoaderPolicies,
+    code_loading_patcher::{insert_code, CodeLoadingOptions, CodePatchingStrategy},
+    components::{
+        find_components, read_manifest, register_components, ComponentStubs, MANIFEST_PATH,
+    },
+    dex_layout::{
+        dex_file_report, layout_dex_files, original_dex_files, DexLayoutStrategy, OriginalDexLayout,
+    },
+    jni_upcalls::add_jni_upcall_companions,
     labeling,
-    reflection_patcher::transform_method,
+    native_bindings::{annotate_native_bindings, write_native_bindings},
+    native_libs::{extracts_native_libs, repackage_native_libraries, RepackagedNativeCode},
+    platform_api::{PlatformApiAction, PlatformApiPolicy},
+    reflection_patcher::{transform_method, PatchingOptions},
+    report::PatchReport,
     runtime_data::RuntimeData, // ReflectionInvokeData, ReflectionClassNewInstData, ReflectionCnstrNewInstData,
+    smali_dump::{dump_generated_class, dump_method_diff},
 };
 
 use clap::Parser;
+use rayon::prelude::*;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None, arg_required_else_help = true)]
@@ -37,11 +57,116 @@ struct Cli {
     runtime_data: PathBuf,
     #[arg(short, long, default_value_t, value_enum)]
     code_loading_patch_strategy: CodePatchingStrategy,
+    /// The delegation policies of the custom class loaders (JSON), used by the
+    /// `model-class-loaders` strategy.
+    #[arg(long)]
+    class_loader_policies: Option<PathBuf>,
+    /// Rewrite the string constants naming a class renamed to avoid a collision (eg the
+    /// argument of `Class.forName()`), in the code of the class loader that renamed it.
+    #[arg(long)]
+    rewrite_class_strings: bool,
+    /// Replace the class loaders built to load the code inserted in the apk by the class loader
+    /// of the application, and their `loadClass()` calls by `const-class` instructions.
+    #[arg(long)]
+    neutralize_class_loaders: bool,
+    /// Declare in the manifest the activities, services, receivers and providers defined by the
+    /// code loaded at runtime.
+    #[arg(long)]
+    register_components: bool,
+    /// The stub components used to start the components loaded at runtime (JSON object mapping
+    /// the component classes to the stub classes), implies `--register-components`. The
+    /// components are declared as a copy of their stub.
+    #[arg(long)]
+    component_stubs: Option<PathBuf>,
+    /// Keep the `exported` attribute and the `MAIN`/`LAUNCHER` intent filters of the stubs when
+    /// copying them. By default the copies are not exported and are not entry points.
+    #[arg(long)]
+    keep_stub_exported: bool,
+    /// Add the native libraries loaded with `System.load()` to the apk (in `lib/<abi>/`), and
+    /// load them with `System.loadLibrary()` at the call sites that loaded them.
+    #[arg(long)]
+    native_libs: bool,
+    /// Add a method next to the native methods that called Java methods through the JNI,
+    /// calling the same methods, so that the calls from native code appear in the bytecode.
+    #[arg(long)]
+    jni_upcalls: bool,
+    /// Write the native methods registered with `RegisterNatives()` and their implementations
+    /// in this file (JSON). The bindings are also attached to the native methods with the
+    /// `Ltheseus/NativeBinding;` annotation.
+    #[arg(long)]
+    native_bindings: Option<PathBuf>,
+    /// The hidden API flags of a platform (`hiddenapi-flags.csv`), overriding the platform list
+    /// of androscalpel to classify the platform methods called by reflection.
+    #[arg(long)]
+    hidden_api_flags: Option<PathBuf>,
+    /// The `minSdkVersion` used to classify the platform methods (API level of the SDK
+    /// methods), read from the manifest by default.
+    #[arg(long)]
+    min_sdk: Option<u32>,
+    /// The `targetSdkVersion` used to classify the platform methods (hidden API restrictions),
+    /// read from the manifest by default.
+    #[arg(long)]
+    target_sdk: Option<u32>,
+    /// What to do with the reflective calls to the non-SDK platform methods accessible to the
+    /// application.
+    #[arg(long, default_value = "helper", value_enum)]
+    restricted_api_action: PlatformApiAction,
+    /// What to do with the reflective calls to the non-SDK platform methods not accessible to
+    /// the application.
+    #[arg(long, default_value = "skip", value_enum)]
+    blocked_api_action: PlatformApiAction,
+    /// How to place the classes in the dex files of the patched application.
+    #[arg(long, default_value_t, value_enum)]
+    dex_layout: DexLayoutStrategy,
+    #[arg(long, default_value_t, value_enum)]
+    signer: SigningTool,
+    /// Derive the generated names from the input files so that patching the same apk with the
+    /// same runtime data always gives the same output.
+    #[arg(long)]
+    deterministic: bool,
+    /// Seed mixed with the hash of the inputs to derive the generated names, implies
+    /// `--deterministic`.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Number of threads used to patch the methods, 0 to use one thread per CPU.
+    #[arg(short, long, default_value_t = 0)]
+    jobs: usize,
+    /// Use a dispatch table instead of a chain of tests for the `Method.invoke()` call sites
+    /// with at least this number of targets.
+    #[arg(long)]
+    dispatch_threshold: Option<usize>,
+    /// Write the list of the classes renamed to avoid collisions in this file
+    /// (ProGuard mapping format).
+    #[arg(long)]
+    mapping: Option<PathBuf>,
+    /// Remove the original reflective calls when the call site has runtime data. The output
+    /// is easier to analyse statically but is NOT meant to be run.
+    #[arg(long)]
+    analysis_only: bool,
+    /// Log the patched call sites reached at runtime (see `patcher-tools trace-stats`).
+    #[arg(long)]
+    trace: bool,
+    /// Write the list of the call sites patched in this file (JSON).
+    #[arg(long)]
+    report: Option<PathBuf>,
+    /// Write the diff between the original and patched listing of the modified methods, and
+    /// the listing of the generated class, in this directory.
+    #[arg(long)]
+    dump_smali: Option<PathBuf>,
 }
 
+/// File added to the apks generated with `--analysis-only`.
+const ANALYSIS_ONLY_MARKER: &str = "assets/THESEUS_ANALYSIS_ONLY";
+const ANALYSIS_ONLY_MARKER_CONTENT: &str = "This application was patched by Theseus for static \
+analysis only: the reflective calls not observed at runtime throw a java.lang.Error.\n";
+
 fn main() {
     env_logger::init();
     let cli = Cli::parse();
+    let use_external_tools = cli
+        .signer
+        .use_external_tools(cli.zipalign.is_some(), cli.apksigner.is_some())
+        .unwrap();
     let mut apk = Apk::load_apk(File::open(&cli.path).unwrap(), labeling, false).unwrap();
 
     //println!("{:#?}", apk.list_classes());
@@ -54,31 +179,162 @@ fn main() {
     rt_data.dedup();
 
     // Dynamic Loading
-    insert_code(cli.code_loading_patch_strategy, &mut apk, &mut rt_data).unwrap();
+    let code_loading_options = CodeLoadingOptions {
+        policies: match &cli.class_loader_policies {
+            Some(path) => ClassLoaderPolicies::load(File::open(path).unwrap()).unwrap(),
+            None => ClassLoaderPolicies::default(),
+        },
+        rewrite_class_strings: cli.rewrite_class_strings,
+    };
+    let inserted_code = insert_code(
+        cli.code_loading_patch_strategy,
+        &mut apk,
+        &mut rt_data,
+        &code_loading_options,
+    )
+    .unwrap();
+    if let Some(mapping) = &cli.mapping {
+        inserted_code
+            .mapping
+            .write(File::create(mapping).unwrap())
+            .with_context(|| format!("Failed to write the class mapping to {}", mapping.display()))
+            .unwrap();
+    }
+    let mut registered_components = vec![];
+    let manifest = if cli.register_components || cli.component_stubs.is_some() {
+        let mut stubs = match &cli.component_stubs {
+            Some(path) => ComponentStubs::load(File::open(path).unwrap()).unwrap(),
+            None => ComponentStubs::default(),
+        };
+        stubs.keep_exported = cli.keep_stub_exported;
+        let components = find_components(&apk, &inserted_code.injected_classes);
+        let (manifest, mut registered) =
+            register_components(&read_manifest(&cli.path).unwrap(), &components, &stubs).unwrap();
+        registered_components.append(&mut registered);
+        Some(manifest)
+    } else {
+        None
+    };
     let rt_data = rt_data; // not mut anymore
+    let rt_data_index = rt_data.index();
+    let mut platform_api = PlatformApiPolicy {
+        restricted: cli.restricted_api_action,
+        blocked: cli.blocked_api_action,
+        ..Default::default()
+    };
+    if let Some(path) = &cli.hidden_api_flags {
+        platform_api.load_flags(File::open(path).unwrap()).unwrap();
+    }
+    platform_api.min_sdk = cli.min_sdk.or_else(|| {
+        read_manifest(&cli.path)
+            .and_then(|manifest| min_sdk_version(&manifest))
+            .unwrap_or_else(|err| {
+                warn!("Failed to read the minSdkVersion of the application: {err}");
+                None
+            })
+    });
+    platform_api.target_sdk = cli.target_sdk.or_else(|| {
+        read_manifest(&cli.path)
+            .and_then(|manifest| target_sdk_version(&manifest))
+            .unwrap_or_else(|err| {
+                warn!("Failed to read the targetSdkVersion of the application: {err}");
+                None
+            })
+    });
+    let patching_options = PatchingOptions {
+        dispatch_threshold: cli.dispatch_threshold,
+        analysis_only: cli.analysis_only,
+        trace: cli.trace,
+        platform_api,
+    };
+    if cli.analysis_only {
+        warn!(
+            "Analysis-only mode: the reflective calls are removed, the generated application \
+            is not meant to be run"
+        );
+    }
 
     // Reflection
     let mut test_methods = HashMap::new();
+    let mut rng = if cli.deterministic || cli.seed.is_some() {
+        let mut hasher = Sha256::new();
+        hasher.update(cli.seed.unwrap_or(0).to_le_bytes());
+        hasher.update(std::fs::read(&cli.path).unwrap());
+        hasher.update(json.as_bytes());
+        StdRng::from_seed(hasher.finalize().into())
+    } else {
+        StdRng::from_os_rng()
+    };
     // Generate a new, unique name
     let test_class = loop {
         let ty = IdType::class(&format!(
             "theseus/{}/T",
-            Alphanumeric.sample_string(&mut rand::rng(), 16),
+            Alphanumeric.sample_string(&mut rng, 16),
         ));
         if apk.get_class(&ty).is_none() {
             break ty;
         }
     };
-    for method in rt_data.get_method_referenced().iter() {
+    // Class loaders
+    let class_loader_rewrites = if cli.neutralize_class_loaders {
+        neutralize_class_loaders(&mut apk, &rt_data, test_class.clone(), &mut test_methods).unwrap()
+    } else {
+        vec![]
+    };
+    // Native libraries
+    let native_code = if cli.native_libs {
+        repackage_native_libraries(
+            &mut apk,
+            &cli.path,
+            &rt_data,
+            test_class.clone(),
+            &mut test_methods,
+        )
+        .unwrap()
+    } else {
+        RepackagedNativeCode::default()
+    };
+    // The external tools may compress the libraries, they are only loaded if extracted
+    if use_external_tools
+        && !native_code.files.is_empty()
+        && !read_manifest(&cli.path)
+            .and_then(|manifest| extracts_native_libs(&manifest))
+            .unwrap()
+    {
+        panic!(
+            "The application does not extract its native libraries \
+            (android:extractNativeLibs=\"false\"), the libraries added by --native-libs must be \
+            stored uncompressed: use --signer builtin"
+        );
+    }
+    // JNI upcalls
+    let jni_upcall_companions = if cli.jni_upcalls {
+        add_jni_upcall_companions(&mut apk, &rt_data).unwrap()
+    } else {
+        vec![]
+    };
+    // RegisterNatives() bindings
+    let native_bindings = annotate_native_bindings(&mut apk, &rt_data).unwrap();
+    if let Some(path) = &cli.native_bindings {
+        write_native_bindings(&native_bindings, File::create(path).unwrap())
+            .with_context(|| format!("Failed to write the native bindings to {}", path.display()))
+            .unwrap();
+    }
+    // Sorted so that the patching order does not depend on the hash of the methods
+    let mut methods: Vec<_> = rt_data_index.methods().collect();
+    methods.sort();
+    // Take the methods out of their classes so that they can be patched in parallel
+    let mut work_units = vec![];
+    for method in methods {
         if let Some(class) = apk.get_class_mut(&method.class_) {
             //println!("{:#?}", class.direct_methods.keys());
             //println!("{:#?}", class.virtual_methods.keys());
-            let method = if let Some(method) = class.virtual_methods.get_mut(method) {
-                method
+            if let Some(method) = class.virtual_methods.remove(method) {
+                work_units.push((method, true));
             } else {
-                class
+                let method = class
                     .direct_methods
-                    .get_mut(method)
+                    .remove(method)
                     .with_context(|| {
                         format!(
                             "method {} not found in {}",
@@ -86,30 +342,110 @@ fn main() {
                             class.descriptor.try_to_smali().unwrap()
                         )
                     })
-                    .unwrap()
-            };
-            // May be native method or other kind of android shenanigan.
-            if method.code.is_some() {
-                if let Err(err) =
-                    transform_method(method, &rt_data, test_class.clone(), &mut test_methods)
-                {
-                    warn!(
-                        "Failed to patch method {}: {}",
-                        method.descriptor.__str__(),
-                        err
-                    );
-                };
+                    .unwrap();
+                work_units.push((method, false));
             }
         }
     }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.jobs)
+        .build()
+        .unwrap();
+    let results: Vec<_> = pool.install(|| {
+        work_units
+            .into_par_iter()
+            .map(|(mut method, is_virtual)| {
+                // Each work unit generates its own tester methods, they are merged afterward
+                let mut test_methods = HashMap::new();
+                let mut sites = vec![];
+                // May be native method or other kind of android shenanigan.
+                if method.code.is_some() {
+                    let original = cli.dump_smali.as_ref().map(|_| method.clone());
+                    match transform_method(
+                        &mut method,
+                        &rt_data,
+                        &rt_data_index,
+                        &patching_options,
+                        test_class.clone(),
+                        &mut test_methods,
+                    ) {
+                        Ok(patched_sites) => sites = patched_sites,
+                        Err(err) => warn!(
+                            "Failed to patch method {}: {}",
+                            method.descriptor.__str__(),
+                            err
+                        ),
+                    };
+                    if let (Some(dir), Some(original)) = (&cli.dump_smali, original) {
+                        if let Err(err) = dump_method_diff(dir, &original, &method) {
+                            warn!(
+                                "Failed to dump the smali of {}: {}",
+                                method.descriptor.__str__(),
+                                err
+                            );
+                        }
+                    }
+                }
+                (method, is_virtual, test_methods, sites)
+            })
+            .collect()
+    });
+    // The results are in the order of the work units, and a tester method only depends on its
+    // key, so the merge does not depend on the scheduling of the work units.
+    let mut report = PatchReport {
+        run_id: rt_data.run_id.clone(),
+        sites: vec![],
+        class_collisions: inserted_code.collisions,
+        class_string_rewrites: inserted_code.string_rewrites,
+        registered_components,
+        class_loader_rewrites,
+        native_libraries: native_code.libraries,
+        native_load_rewrites: native_code.rewrites,
+        jni_upcall_companions,
+        dex_files: vec![],
+    };
+    for (method, is_virtual, methods, mut sites) in results {
+        report.sites.append(&mut sites);
+        let class = apk.get_class_mut(&method.descriptor.class_).unwrap();
+        if is_virtual {
+            class
+                .virtual_methods
+                .insert(method.descriptor.clone(), method);
+        } else {
+            class
+                .direct_methods
+                .insert(method.descriptor.clone(), method);
+        }
+        for (key, test_method) in methods {
+            test_methods.entry(key).or_insert(test_method);
+        }
+    }
     let mut class = Class::new(test_class.get_name()).unwrap();
     class.is_final = true;
     class.direct_methods = test_methods
         .into_values()
         .map(|v| (v.descriptor.clone(), v))
         .collect();
+    if let Some(dir) = &cli.dump_smali {
+        dump_generated_class(dir, &class)
+            .with_context(|| format!("Failed to dump the smali of {}", test_class.__str__()))
+            .unwrap();
+    }
     apk.add_class("classes.dex", class).unwrap();
-    apk.redistribute_classes();
+    let original_dex_files = match cli.dex_layout {
+        DexLayoutStrategy::Redistribute => {
+            apk.redistribute_classes();
+            // Only used to report the new dex files
+            original_dex_files(&cli.path)
+                .inspect_err(|err| warn!("Failed to list the dex files of the application: {err}"))
+                .ok()
+        }
+        DexLayoutStrategy::KeepOriginal => {
+            let original_layout = OriginalDexLayout::read(&cli.path).unwrap();
+            layout_dex_files(&mut apk, &original_layout).unwrap();
+            Some(original_layout.dex_files)
+        }
+    };
 
     let mut dex_files = vec![];
     let mut files = apk.gen_raw_dex().unwrap();
@@ -121,22 +457,64 @@ fn main() {
             format!("classes{}.dex", i + 1)
         };
         if let Some(file) = files.remove(&name) {
+            report.dex_files.push(
+                dex_file_report(
+                    &name,
+                    &file,
+                    original_dex_files
+                        .as_ref()
+                        .map(|dex_files| !dex_files.contains(&name)),
+                )
+                .unwrap(),
+            );
             dex_files.push(Cursor::new(file))
         } else {
             break;
         }
         i += 1;
     }
-    // TODO: aapt would be a lot more stable?
-    apk_frauder::replace_dex(
-        cli.path,
-        cli.out,
-        &mut dex_files,
-        cli.keystore,
-        cli.zipalign,
-        cli.apksigner,
-        cli.keypassword.as_deref(),
-        None::<HashMap<_, Option<Cursor<&[u8]>>>>,
-    )
-    .unwrap();
+    if let Some(path) = &cli.report {
+        report
+            .write(File::create(path).unwrap())
+            .with_context(|| format!("Failed to write the report to {}", path.display()))
+            .unwrap();
+    }
+    let mut additional_files: HashMap<String, Option<Cursor<&[u8]>>> = HashMap::new();
+    if cli.analysis_only {
+        additional_files.insert(
+            ANALYSIS_ONLY_MARKER.into(),
+            Some(Cursor::new(ANALYSIS_ONLY_MARKER_CONTENT.as_bytes())),
+        );
+    }
+    if let Some(manifest) = &manifest {
+        additional_files.insert(MANIFEST_PATH.into(), Some(Cursor::new(manifest.as_slice())));
+    }
+    for (name, lib) in &native_code.files {
+        additional_files.insert(name.clone(), Some(Cursor::new(lib.as_slice())));
+    }
+    let additional_files = (!additional_files.is_empty()).then_some(additional_files);
+    if use_external_tools {
+        // TODO: aapt would be a lot more stable?
+        apk_frauder::replace_dex(
+            cli.path,
+            cli.out,
+            &mut dex_files,
+            cli.keystore,
+            cli.zipalign,
+            cli.apksigner,
+            cli.keypassword.as_deref(),
+            additional_files,
+        )
+        .unwrap();
+    } else {
+        apk_signer::replace_dex(
+            cli.path,
+            cli.out,
+            &mut dex_files,
+            cli.keystore,
+            cli.keypassword.as_deref(),
+            additional_files,
+        )
+        .unwrap();
+    }
 }
@@ -0,0 +1,353 @@
+//! Comparison of the call graphs of an application and of its patched version.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use androscalpel::{Apk, IdMethod, Instruction, Method, SmaliName};
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::class_mapping::ClassMapping;
+use crate::dex_types::GLUE_METHODS;
+use crate::jni_upcalls::{companion_method, companion_of, is_companion_method};
+use crate::runtime_data::RuntimeData;
+
+/// The call graph of an application: the calls found in the code reachable in the control flow
+/// graph of the methods (computed by androscalpel), without resolving the virtual calls.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct CallGraph {
+    pub edges: BTreeSet<(IdMethod, IdMethod)>,
+}
+
+impl CallGraph {
+    /// Build the call graph of `apk`.
+    pub fn new(apk: &Apk) -> Self {
+        let mut graph = Self::default();
+        graph.add_apk(apk);
+        graph
+    }
+
+    /// Add the calls made by the methods of `apk` to the graph.
+    pub fn add_apk(&mut self, apk: &Apk) {
+        for cls in apk.list_classes() {
+            let Some(class) = apk.get_class(&cls) else {
+                continue;
+            };
+            for method in class
+                .direct_methods
+                .values()
+                .chain(class.virtual_methods.values())
+            {
+                // The calls made by native code are declared by the companion method
+                if let Some(companion) = companion_of(class, method) {
+                    self.edges.insert((method.descriptor.clone(), companion));
+                }
+                for callee in called_methods(method) {
+                    self.edges.insert((method.descriptor.clone(), callee));
+                }
+            }
+        }
+    }
+
+    /// Rename the methods of the classes renamed by the patcher (`theseus/dedup/...`) to their
+    /// original names.
+    pub fn apply_mapping(&self, mapping: &ClassMapping) -> Self {
+        Self {
+            edges: self
+                .edges
+                .iter()
+                .map(|(caller, callee)| {
+                    (
+                        original_method(mapping, caller),
+                        original_method(mapping, callee),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Count the number of edges, and the number of those edges that may have been added by
+    /// the patcher (calls from a tester class or to a [`GLUE_METHODS`]).
+    pub fn count_edges(&self) -> (usize, usize) {
+        let glue = self
+            .edges
+            .iter()
+            .filter(|(caller, callee)| is_glue_edge(caller, callee))
+            .count();
+        (self.edges.len(), glue)
+    }
+}
+
+/// The methods called in the reachable blocks of the control flow graph of `method`.
+fn called_methods(method: &Method) -> BTreeSet<IdMethod> {
+    let cfg = match method.get_cfg() {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            warn!(
+                "Failed to compute the control flow graph of {}: {err}",
+                method.descriptor.__str__()
+            );
+            return BTreeSet::new();
+        }
+    };
+    let mut called = BTreeSet::new();
+    let mut visited = vec![false; cfg.nodes.len()];
+    let mut to_visit = vec![0];
+    while let Some(i) = to_visit.pop() {
+        if i >= cfg.nodes.len() || visited[i] {
+            continue;
+        }
+        visited[i] = true;
+        let node = &cfg.nodes[i];
+        to_visit.extend(node.next_nodes.iter().copied());
+        for ins in node.code_block {
+            match ins {
+                Instruction::InvokeVirtual { method: callee, .. }
+                | Instruction::InvokeSuper { method: callee, .. }
+                | Instruction::InvokeDirect { method: callee, .. }
+                | Instruction::InvokeStatic { method: callee, .. }
+                | Instruction::InvokeInterface { method: callee, .. } => {
+                    called.insert(callee.clone());
+                }
+                _ => (),
+            }
+        }
+    }
+    called
+}
+
+/// Get the name of `method` before its class was renamed by the patcher.
+fn original_method(mapping: &ClassMapping, method: &IdMethod) -> IdMethod {
+    method
+        .try_to_smali()
+        .and_then(|smali| mapping.apply(&smali))
+        .and_then(|smali| IdMethod::from_smali(&smali))
+        .unwrap_or_else(|err| {
+            warn!("Failed to rename {}: {err}", method.__str__());
+            method.clone()
+        })
+}
+
+/// Check if the method is defined in a class generated by the patcher (`Ltheseus/<id>/T;`).
+pub fn is_generated_method(method: &IdMethod) -> bool {
+    let class = method.class_.__str__();
+    class.starts_with("Ltheseus/") && class.ends_with("/T;")
+}
+
+/// Check if the call from `caller` to `callee` may have been added by the patcher.
+pub fn is_glue_edge(caller: &IdMethod, callee: &IdMethod) -> bool {
+    is_generated_method(caller)
+        || is_generated_method(callee)
+        || is_companion_method(callee)
+        || GLUE_METHODS.contains(callee)
+}
+
+/// A runtime data record that explains a new edge of the call graph.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+pub struct RecordRef {
+    /// `invoke`, `class_new_instance`, `constructor_new_instance`, `kotlin_reflection`,
+    /// `service_new_instance` or `jni_upcall`.
+    pub kind: String,
+    /// The method calling the reflective method (the companion method for `jni_upcall`).
+    pub caller: IdMethod,
+    /// The method called by reflection (or by native code for `jni_upcall`).
+    pub target: IdMethod,
+    /// The address of the reflective call in `caller`, 0 for the JNI upcalls (declared by the
+    /// companion method of the native method).
+    pub addr: usize,
+}
+
+/// An edge found in the patched application but not in the original application.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct NewEdge {
+    pub caller: IdMethod,
+    pub callee: IdMethod,
+    /// The runtime data records explaining the edge, empty if the edge was not expected.
+    pub records: Vec<RecordRef>,
+}
+
+/// The result of the comparison of two call graphs.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct CallGraphDiff {
+    pub nb_edges_original: usize,
+    pub nb_glue_edges_original: usize,
+    pub nb_edges_patched: usize,
+    pub nb_glue_edges_patched: usize,
+    /// The new edges, excluding the glue edges.
+    pub new_edges: Vec<NewEdge>,
+    /// The number of new edges explained by each record of the runtime data. Records that
+    /// explain no new edge are listed with a count of 0.
+    pub edges_by_record: Vec<(RecordRef, usize)>,
+}
+
+/// List the records of the runtime data, with the name of the caller and target in the
+/// patched application, or their original name if `mapping` is set.
+fn list_records(runtime_data: &RuntimeData, mapping: Option<&ClassMapping>) -> Vec<RecordRef> {
+    let mut records = vec![];
+    for data in &runtime_data.invoke_data {
+        records.push(RecordRef {
+            kind: "invoke".into(),
+            caller: data
+                .renamed_caller_method
+                .clone()
+                .unwrap_or_else(|| data.caller_method.clone()),
+            target: data.get_static_callee(),
+            addr: data.addr,
+        });
+    }
+    for data in &runtime_data.class_new_inst_data {
+        records.push(RecordRef {
+            kind: "class_new_instance".into(),
+            caller: data
+                .renamed_caller_method
+                .clone()
+                .unwrap_or_else(|| data.caller_method.clone()),
+            target: data.get_static_constructor(),
+            addr: data.addr,
+        });
+    }
+    for data in &runtime_data.cnstr_new_inst_data {
+        records.push(RecordRef {
+            kind: "constructor_new_instance".into(),
+            caller: data
+                .renamed_caller_method
+                .clone()
+                .unwrap_or_else(|| data.caller_method.clone()),
+            target: data.get_static_constructor(),
+            addr: data.addr,
+        });
+    }
+    for data in &runtime_data.kotlin_reflection {
+        records.push(RecordRef {
+            kind: "kotlin_reflection".into(),
+            caller: data.get_static_caller(),
+            target: data.get_static_callee(),
+            addr: data.addr,
+        });
+    }
+    for data in &runtime_data.service_new_inst_data {
+        records.push(RecordRef {
+            kind: "service_new_instance".into(),
+            caller: data.get_static_caller(),
+            target: data.get_static_constructor(),
+            addr: data.addr,
+        });
+    }
+    for data in &runtime_data.jni_upcalls {
+        records.push(RecordRef {
+            kind: "jni_upcall".into(),
+            caller: companion_method(&data.get_static_native_method()),
+            target: data.get_static_method(),
+            addr: 0,
+        });
+    }
+    if let Some(mapping) = mapping {
+        for record in &mut records {
+            record.caller = original_method(mapping, &record.caller);
+            record.target = original_method(mapping, &record.target);
+        }
+    }
+    records.sort();
+    records.dedup();
+    records
+}
+
+impl CallGraphDiff {
+    /// Compare the call graph of the original application (including the code loaded
+    /// dynamically) to the call graph of the patched application. If the patcher renamed
+    /// classes, `mapping` is used to compare the methods and the records by their original
+    /// names.
+    pub fn new(
+        original: &CallGraph,
+        patched: &CallGraph,
+        runtime_data: &RuntimeData,
+        mapping: Option<&ClassMapping>,
+    ) -> Self {
+        let renamed;
+        let patched = match mapping {
+            Some(mapping) => {
+                renamed = patched.apply_mapping(mapping);
+                &renamed
+            }
+            None => patched,
+        };
+        let (nb_edges_original, nb_glue_edges_original) = original.count_edges();
+        let (nb_edges_patched, nb_glue_edges_patched) = patched.count_edges();
+        let records = list_records(runtime_data, mapping);
+        let mut records_by_edge: BTreeMap<(&IdMethod, &IdMethod), Vec<&RecordRef>> =
+            BTreeMap::new();
+        for record in &records {
+            records_by_edge
+                .entry((&record.caller, &record.target))
+                .or_default()
+                .push(record);
+        }
+        let mut edges_by_record: BTreeMap<&RecordRef, usize> =
+            records.iter().map(|record| (record, 0)).collect();
+
+        let mut new_edges = vec![];
+        for (caller, callee) in patched.edges.difference(&original.edges) {
+            if is_glue_edge(caller, callee) {
+                continue;
+            }
+            let records: Vec<RecordRef> = records_by_edge
+                .get(&(caller, callee))
+                .into_iter()
+                .flatten()
+                .map(|record| (*record).clone())
+                .collect();
+            for record in &records {
+                *edges_by_record.get_mut(record).unwrap() += 1;
+            }
+            new_edges.push(NewEdge {
+                caller: caller.clone(),
+                callee: callee.clone(),
+                records,
+            });
+        }
+        Self {
+            nb_edges_original,
+            nb_glue_edges_original,
+            nb_edges_patched,
+            nb_glue_edges_patched,
+            new_edges,
+            edges_by_record: edges_by_record
+                .into_iter()
+                .map(|(record, n)| (record.clone(), n))
+                .collect(),
+        }
+    }
+
+    /// Generate a graph of the new edges in the DOT format. The edges explained by the runtime
+    /// data are labelled with the address of the reflective call, the others are in red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph new_edges {\n");
+        for edge in &self.new_edges {
+            let label = edge
+                .records
+                .iter()
+                .map(|record| format!("{} at 0x{:x}", record.kind, record.addr))
+                .collect::<Vec<_>>()
+                .join("\\n");
+            let attrs = if edge.records.is_empty() {
+                "color=red".to_string()
+            } else {
+                format!("label=\"{label}\"")
+            };
+            writeln!(
+                dot,
+                "    \"{}\" -> \"{}\" [{attrs}];",
+                edge.caller.__str__().replace('"', "\\\""),
+                edge.callee.__str__().replace('"', "\\\""),
+            )
+            .unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
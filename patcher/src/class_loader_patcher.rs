@@ -0,0 +1,573 @@
+//! Neutralisation of the class loaders built by the application, once the code they load was
+//! inserted in the apk by [`crate::code_loading_patcher::insert_code`].
+//!
+//! After the code is inserted, the application still builds a `DexClassLoader` (or another class
+//! loader of the SDK) and calls `loadClass()` on it: static analysers see the loaded classes, but
+//! not the flow from the class loader to them. This pass:
+//!
+//! - replaces the class loader built at a recorded construction site by the class loader of the
+//!   application, right after the call to its constructor, when it loads the recorded code: the
+//!   dex path given to the constructor is one of the recorded paths (or the size of the buffer of
+//!   an `InMemoryDexClassLoader` is the size of one of the recorded files), otherwise the class
+//!   loader built is kept,
+//! - redirects the recorded `loadClass(name)` call sites to a generated method that returns the
+//!   (possibly renamed) class with a `const-class` when `name` is one of the classes loaded at
+//!   this site, and calls `loadClass(name)` otherwise.
+//!
+//! Only the sites of the class loaders recorded in [`RuntimeData::dyn_code_load`] (whose code was
+//! inserted) are rewritten. The constructor of the class loader is still called, so the code is
+//! still loaded at runtime, but not used anymore. Custom class loaders call the constructor of
+//! their SDK parent class in their own constructor, where `this` cannot be replaced, so only their
+//! `loadClass()` call sites are rewritten.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use androscalpel::{Apk, Code, IdMethod, IdMethodType, IdType, Instruction, Method, VisitorMut};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+
+use crate::dex_types::*;
+use crate::reflection_patcher::patched_annotation;
+use crate::report::{ClassLoaderRewrite, ClassLoaderRewriteKind, PatchedSite};
+use crate::runtime_data::RuntimeData;
+
+/// Rewrite the construction and `loadClass()` call sites of the class loaders that loaded the
+/// code inserted in the apk.
+///
+/// The generated methods are added to `tester_methods`, to be defined in
+/// `tester_methods_class`.
+pub fn neutralize_class_loaders(
+    apk: &mut Apk,
+    runtime_data: &RuntimeData,
+    tester_methods_class: IdType,
+    tester_methods: &mut HashMap<(IdMethod, String), Method>,
+) -> Result<Vec<ClassLoaderRewrite>> {
+    let dynamic_loaders: HashSet<&String> = runtime_data
+        .dyn_code_load
+        .iter()
+        .map(|data| &data.classloader)
+        .collect();
+    let mut rewrites = vec![];
+
+    // -- loadClass() sites --
+    // Redirected first, so that they do not use the type of the class loaders replaced
+    // (caller, label) -> (class loader ids, runtime name -> static class)
+    type LoadClassSite = (BTreeSet<String>, BTreeMap<IdType, IdType>);
+    let mut load_class_sites: BTreeMap<(IdMethod, String), LoadClassSite> = BTreeMap::new();
+    for data in &runtime_data.class_loading {
+        if !dynamic_loaders.contains(&data.classloader) {
+            debug!(
+                "{} was not loaded by a class loader that loaded code, skip it",
+                data.class.__str__()
+            );
+            continue;
+        }
+        let class = data.get_static_class();
+        if !class.is_platform_class() && apk.get_class(&class).is_none() {
+            warn!(
+                "{} (loaded at 0x{:08X} in {}) is not in the application, skip it",
+                class.__str__(),
+                data.addr,
+                data.caller_method.__str__()
+            );
+            continue;
+        }
+        let site = load_class_sites
+            .entry((
+                data.get_static_caller(),
+                format!("THESEUS_ADDR_{:08X}", data.addr),
+            ))
+            .or_default();
+        site.0.insert(data.classloader.clone());
+        site.1.insert(data.class.clone(), class);
+    }
+    for ((caller, label), (loader_ids, classes)) in load_class_sites {
+        let result = redirect_load_class(
+            apk,
+            &caller,
+            &label,
+            &classes,
+            &tester_methods_class,
+            runtime_data,
+        );
+        let helper = match result {
+            Ok(helper) => helper,
+            Err(err) => {
+                warn!(
+                    "Failed to redirect the loadClass() call at {label} in {}: {err}",
+                    caller.__str__()
+                );
+                continue;
+            }
+        };
+        let helper_descriptor = helper.descriptor.clone();
+        tester_methods.insert((CL_LOAD_CLASS.clone(), helper_descriptor.__str__()), helper);
+        rewrites.push(ClassLoaderRewrite {
+            caller,
+            label,
+            kind: ClassLoaderRewriteKind::LoadClass,
+            loader_ids: loader_ids.into_iter().collect(),
+            helper: Some(helper_descriptor),
+            classes: classes.into_values().collect(),
+        });
+    }
+
+    // -- Construction sites --
+    // (caller, label) -> (ids of the class loaders built at this site, dex paths, buffer sizes)
+    type ConstructionSite = (BTreeSet<String>, BTreeSet<String>, BTreeSet<i32>);
+    let mut constructions: BTreeMap<(IdMethod, String), ConstructionSite> = BTreeMap::new();
+    // class loader id -> paths of the files it loaded, in loading order
+    let mut loader_sources: HashMap<&String, Vec<&String>> = HashMap::new();
+    for data in &runtime_data.dyn_code_load {
+        loader_sources
+            .entry(&data.classloader)
+            .or_default()
+            .extend(&data.sources);
+    }
+    for data in &runtime_data.dyn_code_load {
+        let Some(site) = &data.construction_site else {
+            continue;
+        };
+        if !DEX_CLASS_LOADERS.contains(&site.constructor.class_) {
+            debug!(
+                "{} is not a class loader of the SDK, keep its construction in {}",
+                site.constructor.class_.__str__(),
+                site.caller_method.__str__()
+            );
+            continue;
+        }
+        let site = constructions
+            .entry((
+                site.get_static_caller(),
+                format!("THESEUS_ADDR_{:08X}", site.addr),
+            ))
+            .or_default();
+        site.0.insert(data.classloader.clone());
+        // The dex path of the class loader is the list of the files it loads
+        if let Some(sources) = loader_sources
+            .get(&data.classloader)
+            .filter(|sources| !sources.is_empty())
+        {
+            site.1.insert(
+                sources
+                    .iter()
+                    .map(|source| source.as_str())
+                    .collect::<Vec<_>>()
+                    .join(":"),
+            );
+        }
+        for file in &data.files {
+            match std::fs::metadata(file).map(|meta| i32::try_from(meta.len())) {
+                Ok(Ok(size)) => {
+                    site.2.insert(size);
+                }
+                _ => warn!("Failed to get the size of {}", file.display()),
+            }
+        }
+    }
+    for ((caller, label), (loader_ids, paths, sizes)) in constructions {
+        let result = replace_constructed_loader(
+            apk,
+            &caller,
+            &label,
+            &paths,
+            &sizes,
+            &tester_methods_class,
+            runtime_data,
+        );
+        let helper = match result {
+            Ok(helper) => helper,
+            Err(err) => {
+                warn!(
+                    "Failed to neutralize the class loader built at {label} in {}: {err}",
+                    caller.__str__()
+                );
+                continue;
+            }
+        };
+        info!(
+            "Replaced the class loader built at {label} in {} by the application class loader",
+            caller.__str__()
+        );
+        let helper_descriptor = helper.descriptor.clone();
+        tester_methods.insert(
+            (_GET_CLASS_LOADER.clone(), helper_descriptor.__str__()),
+            helper,
+        );
+        rewrites.push(ClassLoaderRewrite {
+            caller,
+            label,
+            kind: ClassLoaderRewriteKind::Construction,
+            loader_ids: loader_ids.into_iter().collect(),
+            helper: Some(helper_descriptor),
+            classes: vec![],
+        });
+    }
+    Ok(rewrites)
+}
+
+/// Get the method `caller` in `apk`.
+fn get_method_mut<'a>(apk: &'a mut Apk, caller: &IdMethod) -> Result<&'a mut Method> {
+    let class = apk
+        .get_class_mut(&caller.class_)
+        .with_context(|| format!("Class {} not found", caller.class_.__str__()))?;
+    class
+        .direct_methods
+        .get_mut(caller)
+        .or_else(|| class.virtual_methods.get_mut(caller))
+        .context("Method not found")
+}
+
+/// Visitor checking if one of some types is used.
+struct TypeUseVisitor {
+    types: HashSet<IdType>,
+    found: Option<IdType>,
+}
+
+impl VisitorMut for TypeUseVisitor {
+    fn visit_type(&mut self, id: IdType) -> Result<IdType> {
+        if self.found.is_none() && self.types.contains(&id) {
+            self.found = Some(id.clone());
+        }
+        Ok(id)
+    }
+}
+
+/// Find the index of the first instruction after the label `label` in `insns`.
+fn find_labeled_ins(insns: &[Instruction], label: &str) -> Option<usize> {
+    let mut found = false;
+    for (i, ins) in insns.iter().enumerate() {
+        match ins {
+            Instruction::Label { name } if name == label => found = true,
+            ins if found && !ins.is_pseudo_ins() => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Replace the class loader built by the constructor call labeled `label` in `caller` by the
+/// class loader of the application when it loads the recorded code, and return the generated
+/// method making this choice (see [`gen_select_loader_method`]).
+fn replace_constructed_loader(
+    apk: &mut Apk,
+    caller: &IdMethod,
+    label: &str,
+    paths: &BTreeSet<String>,
+    sizes: &BTreeSet<i32>,
+    tester_methods_class: &IdType,
+    runtime_data: &RuntimeData,
+) -> Result<Method> {
+    let method = get_method_mut(apk, caller)?;
+    let descriptor = method.descriptor.clone();
+    let code = method.code.as_mut().context("Code not found")?;
+    let Some(i) = find_labeled_ins(&code.insns, label) else {
+        bail!("No instruction labeled {label}");
+    };
+    let (loader_class, reg, key_reg, guard) = match &code.insns[i] {
+        Instruction::InvokeDirect { method, args }
+            if DEX_CLASS_LOADERS.contains(&method.class_) && args.len() >= 2 =>
+        {
+            let reg = u8::try_from(args[0])
+                .with_context(|| format!("Register v{} of the class loader is too big", args[0]))?;
+            // The first parameter of the constructors is the dex path, or the buffer(s) of the
+            // code loaded from memory
+            let guard = match method.proto.get_parameters().first() {
+                Some(ty) if ty == &*STRING_TY && !paths.is_empty() => LoaderGuard::DexPaths(paths),
+                Some(ty) if ty == &*BYTE_BUFFER_TY && !sizes.is_empty() => {
+                    LoaderGuard::BufferSizes(sizes)
+                }
+                _ => bail!(
+                    "Cannot check at runtime which code is loaded by {}",
+                    method.__str__()
+                ),
+            };
+            (method.class_.clone(), reg, args[1], guard)
+        }
+        ins => bail!("Expected a class loader constructor call, found {ins:?}"),
+    };
+    // The register now holds a `ClassLoader`, so the method must not use the class loader as an
+    // instance of its class or of one of its SDK superclasses (`BaseDexClassLoader`,
+    // `PathClassLoader` for `DelegateLastClassLoader`)
+    let mut uses = TypeUseVisitor {
+        types: DEX_CLASS_LOADERS
+            .iter()
+            .chain([&*BASE_DEX_CLASS_LOADER])
+            .cloned()
+            .collect(),
+        found: None,
+    };
+    uses.visit_method_id(descriptor)?;
+    for (j, ins) in code.insns.iter().enumerate() {
+        if j == i || matches!(ins, Instruction::NewInstance { lit, .. } if *lit == loader_class) {
+            continue;
+        }
+        uses.visit_instruction(ins.clone())?;
+    }
+    if let Some(ty) = uses.found {
+        bail!(
+            "the method uses {} for something else than building the class loader",
+            ty.__str__()
+        );
+    }
+    let helper = gen_select_loader_method(
+        tester_methods_class.clone(),
+        caller,
+        label,
+        guard,
+        runtime_data,
+    )?;
+    // The registers of the arguments of the constructor are not modified by the call
+    code.insns.splice(
+        i + 1..i + 1,
+        [
+            Instruction::InvokeStatic {
+                method: helper.descriptor.clone(),
+                args: vec![reg as u16, key_reg],
+            },
+            Instruction::MoveResultObject { to: reg },
+        ],
+    );
+    Ok(helper)
+}
+
+/// The argument of the constructor of a class loader compared to the recorded values to know if
+/// it loads the code inserted in the apk.
+enum LoaderGuard<'a> {
+    /// The dex path (`String`), compared to the `:` separated lists of the recorded paths.
+    DexPaths(&'a BTreeSet<String>),
+    /// The `ByteBuffer` of an `InMemoryDexClassLoader`, whose remaining bytes are compared to the
+    /// size of the recorded files.
+    BufferSizes(&'a BTreeSet<i32>),
+}
+
+/// Generate the method called after the constructor of a class loader: it returns the class
+/// loader of the application if the argument checked by `guard` matches the recorded code, and
+/// the class loader built otherwise. The class loader of the application is the class loader of
+/// `tester_methods_class`.
+fn gen_select_loader_method(
+    tester_methods_class: IdType,
+    caller: &IdMethod,
+    label: &str,
+    guard: LoaderGuard,
+    runtime_data: &RuntimeData,
+) -> Result<Method> {
+    let mut hasher = DefaultHasher::new();
+    caller.hash(&mut hasher);
+    label.hash(&mut hasher);
+    let hash = hasher.finish();
+    let key_ty = match guard {
+        LoaderGuard::DexPaths(_) => STRING_TY.clone(),
+        LoaderGuard::BufferSizes(_) => BYTE_BUFFER_TY.clone(),
+    };
+    let loader_ty = IdType::class("java/lang/ClassLoader");
+    let descriptor = IdMethod::new(
+        format!("select_loader_{hash:016x}").as_str().into(),
+        IdMethodType::new(loader_ty.clone(), vec![loader_ty, key_ty]),
+        tester_methods_class.clone(),
+    );
+    let mut method = Method::new(descriptor);
+    const REG_TMP: u8 = 0;
+    const REG_KEY_VAL: u8 = 1;
+    const REG_LOADER: u8 = 2;
+    const REG_KEY: u8 = 3;
+    let app_loader_label = "label_app_loader".to_string();
+    let mut insns = vec![];
+    let targets: Vec<String> = match guard {
+        LoaderGuard::DexPaths(paths) => {
+            for path in paths {
+                insns.append(&mut vec![
+                    Instruction::ConstString {
+                        reg: REG_TMP,
+                        lit: path.as_str().into(),
+                    },
+                    Instruction::InvokeVirtual {
+                        method: STR_EQ.clone(),
+                        args: vec![REG_KEY as u16, REG_TMP as u16],
+                    },
+                    Instruction::MoveResult { to: REG_KEY_VAL },
+                    Instruction::IfNeZ {
+                        a: REG_KEY_VAL,
+                        label: app_loader_label.clone(),
+                    },
+                ]);
+            }
+            paths.iter().cloned().collect()
+        }
+        LoaderGuard::BufferSizes(sizes) => {
+            insns.append(&mut vec![
+                Instruction::InvokeVirtual {
+                    method: BUFFER_REMAINING.clone(),
+                    args: vec![REG_KEY as u16],
+                },
+                Instruction::MoveResult { to: REG_KEY_VAL },
+            ]);
+            for size in sizes {
+                insns.append(&mut vec![
+                    Instruction::Const {
+                        reg: REG_TMP,
+                        lit: *size,
+                    },
+                    Instruction::IfEq {
+                        a: REG_KEY_VAL,
+                        b: REG_TMP,
+                        label: app_loader_label.clone(),
+                    },
+                ]);
+            }
+            sizes.iter().map(|size| format!("{size} bytes")).collect()
+        }
+    };
+    insns.append(&mut vec![
+        // Not the recorded code: keep the class loader built
+        Instruction::ReturnObject { reg: REG_LOADER },
+        Instruction::Label {
+            name: app_loader_label,
+        },
+        Instruction::ConstClass {
+            reg: REG_TMP,
+            lit: tester_methods_class,
+        },
+        Instruction::InvokeVirtual {
+            method: _GET_CLASS_LOADER.clone(),
+            args: vec![REG_TMP as u16],
+        },
+        Instruction::MoveResultObject { to: REG_TMP },
+        Instruction::ReturnObject { reg: REG_TMP },
+    ]);
+    method.is_static = true;
+    method.is_final = true;
+    method.code = Some(Code::new(
+        4, //registers_size, 2 reg + 2 parameter reg
+        insns,
+        Some(vec![Some("loader".into()), Some("key".into())]), // parameter_names
+    ));
+    method.annotations.push(patched_annotation(
+        vec![PatchedSite::site_id(caller, label)],
+        targets,
+        runtime_data.run_id.as_deref(),
+    ));
+    Ok(method)
+}
+
+/// Replace the `loadClass()` call labeled `label` in `caller` by a call to a generated method
+/// returning the classes of `classes` (runtime name -> static name), and return this method.
+fn redirect_load_class(
+    apk: &mut Apk,
+    caller: &IdMethod,
+    label: &str,
+    classes: &BTreeMap<IdType, IdType>,
+    tester_methods_class: &IdType,
+    runtime_data: &RuntimeData,
+) -> Result<Method> {
+    let code = get_method_mut(apk, caller)?
+        .code
+        .as_mut()
+        .context("Code not found")?;
+    let Some(i) = find_labeled_ins(&code.insns, label) else {
+        bail!("No instruction labeled {label}");
+    };
+    let args = match &code.insns[i] {
+        Instruction::InvokeVirtual { method, args }
+            if method.name == CL_LOAD_CLASS.name && method.proto == CL_LOAD_CLASS.proto =>
+        {
+            args.clone()
+        }
+        ins => bail!("Expected a call to loadClass(), found {ins:?}"),
+    };
+    let helper = gen_load_class_method(
+        tester_methods_class.clone(),
+        caller,
+        label,
+        classes,
+        runtime_data,
+    )?;
+    code.insns[i] = Instruction::InvokeStatic {
+        method: helper.descriptor.clone(),
+        args,
+    };
+    Ok(helper)
+}
+
+/// Generate the method called instead of `loader.loadClass(name)`: the class named `name` is
+/// returned with a `const-class` if it is one of the keys of `classes`. The class loader is a
+/// `java.lang.ClassLoader` so that the method also accepts the class loader of the application.
+fn gen_load_class_method(
+    tester_methods_class: IdType,
+    caller: &IdMethod,
+    label: &str,
+    classes: &BTreeMap<IdType, IdType>,
+    runtime_data: &RuntimeData,
+) -> Result<Method> {
+    let mut hasher = DefaultHasher::new();
+    caller.hash(&mut hasher);
+    label.hash(&mut hasher);
+    let hash = hasher.finish();
+    let descriptor = IdMethod::new(
+        format!("load_class_{hash:016x}").as_str().into(),
+        IdMethodType::new(
+            CL_LOAD_CLASS.proto.get_return_type(),
+            vec![
+                IdType::class("java/lang/ClassLoader"),
+                IdType::class("java/lang/String"),
+            ],
+        ),
+        tester_methods_class,
+    );
+    let mut method = Method::new(descriptor);
+    const REG_TMP: u8 = 0;
+    const REG_IF_RES: u8 = 1;
+    const REG_LOADER: u8 = 2;
+    const REG_NAME: u8 = 3;
+    let mut insns = vec![];
+    for (i, (class, static_class)) in classes.iter().enumerate() {
+        let name: String = (&class
+            .get_class_name()
+            .with_context(|| format!("{} is not a class", class.__str__()))?)
+            .try_into()?;
+        let next_label = format!("label_not_{i}");
+        insns.append(&mut vec![
+            Instruction::ConstString {
+                reg: REG_TMP,
+                lit: name.replace('/', ".").into(),
+            },
+            Instruction::InvokeVirtual {
+                method: STR_EQ.clone(),
+                args: vec![REG_NAME as u16, REG_TMP as u16],
+            },
+            Instruction::MoveResult { to: REG_IF_RES },
+            Instruction::IfEqZ {
+                a: REG_IF_RES,
+                label: next_label.clone(),
+            },
+            Instruction::ConstClass {
+                reg: REG_TMP,
+                lit: static_class.clone(),
+            },
+            Instruction::ReturnObject { reg: REG_TMP },
+            Instruction::Label { name: next_label },
+        ]);
+    }
+    insns.append(&mut vec![
+        Instruction::InvokeVirtual {
+            method: CL_LOAD_CLASS.clone(),
+            args: vec![REG_LOADER as u16, REG_NAME as u16],
+        },
+        Instruction::MoveResultObject { to: REG_TMP },
+        Instruction::ReturnObject { reg: REG_TMP },
+    ]);
+    method.is_static = true;
+    method.is_final = true;
+    method.code = Some(Code::new(
+        4, //registers_size, 2 reg + 2 parameter reg
+        insns,
+        Some(vec![Some("loader".into()), Some("name".into())]), // parameter_names
+    ));
+    method.annotations.push(patched_annotation(
+        vec![PatchedSite::site_id(caller, label)],
+        classes.values().map(|class| class.__str__()).collect(),
+        runtime_data.run_id.as_deref(),
+    ));
+    Ok(method)
+}
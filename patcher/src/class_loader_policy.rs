@@ -0,0 +1,134 @@
+//! Delegation policies of the class loaders that are not part of the android SDK.
+//!
+//! [`crate::code_loading_patcher`] models the class resolution of the SDK class loaders. Custom
+//! class loaders (plugin frameworks, packers, ...) can resolve classes in any order, so their
+//! behavior must be declared in a policy file, a JSON object mapping the class of the loader to
+//! its policy:
+//!
+//! ```json
+//! {
+//!     "Lcom/example/ChildFirstClassLoader;": { "delegation": "child_first" },
+//!     "Lcom/example/PluginClassLoader;": {
+//!         "delegation": { "custom": [
+//!             "own",
+//!             { "loaders_of_class": "Lcom/example/PluginClassLoader;" },
+//!             "parent"
+//!         ]},
+//!         "platform_first": false
+//!     }
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use androscalpel::IdType;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// A step of the resolution of a class by a class loader.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DelegationStep {
+    /// The classes of the android platform.
+    Platform,
+    /// Delegate to the parent class loader.
+    Parent,
+    /// The classes defined by the class loader itself.
+    Own,
+    /// The shared library class loaders searched before the classes of the class loader
+    /// (`BaseDexClassLoader.sharedLibraryLoaders`).
+    SharedLibraries,
+    /// The shared library class loaders searched after the classes of the class loader
+    /// (`BaseDexClassLoader.sharedLibraryLoadersAfter`).
+    SharedLibrariesAfter,
+    /// The classes defined by the class loader with this id. The id is only valid for the run
+    /// that collected the runtime data.
+    Loader(String),
+    /// The classes defined by the other class loaders of this class, in the order they loaded
+    /// their code.
+    LoadersOfClass(String),
+}
+
+/// The order in which a class loader searches a class.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Delegation {
+    /// Delegate to the parent first, like the SDK class loaders.
+    ParentFirst,
+    /// Search the classes defined by the class loader before delegating to the parent.
+    ChildFirst,
+    /// An ordered list of steps.
+    Custom(Vec<DelegationStep>),
+}
+
+/// The policy of a class of class loaders.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct ClassLoaderPolicy {
+    pub delegation: Delegation,
+    /// Search the platform classes before any other step.
+    #[serde(default = "default_platform_first")]
+    pub platform_first: bool,
+}
+
+fn default_platform_first() -> bool {
+    true
+}
+
+impl ClassLoaderPolicy {
+    /// The ordered steps of the policy.
+    pub fn steps(&self) -> Vec<DelegationStep> {
+        let mut steps = vec![];
+        if self.platform_first {
+            steps.push(DelegationStep::Platform);
+        }
+        match &self.delegation {
+            Delegation::ParentFirst => steps.extend([
+                DelegationStep::Parent,
+                DelegationStep::SharedLibraries,
+                DelegationStep::Own,
+                DelegationStep::SharedLibrariesAfter,
+            ]),
+            Delegation::ChildFirst => steps.extend([
+                DelegationStep::SharedLibraries,
+                DelegationStep::Own,
+                DelegationStep::SharedLibrariesAfter,
+                DelegationStep::Parent,
+            ]),
+            Delegation::Custom(custom) => steps.extend(
+                custom
+                    .iter()
+                    .filter(|step| !self.platform_first || **step != DelegationStep::Platform)
+                    .cloned(),
+            ),
+        }
+        steps
+    }
+}
+
+/// The policies declared by the user, by class of class loader.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ClassLoaderPolicies {
+    pub policies: HashMap<IdType, ClassLoaderPolicy>,
+}
+
+impl ClassLoaderPolicies {
+    /// Load a policy file.
+    pub fn load(input: impl Read) -> Result<Self> {
+        let policies: HashMap<String, ClassLoaderPolicy> =
+            serde_json::from_reader(input).context("Invalid class loader policy file")?;
+        let policies = policies
+            .into_iter()
+            .map(|(class, policy)| {
+                let class = IdType::from_smali(&class)
+                    .with_context(|| format!("Invalid class loader class {class}"))?;
+                Ok((class, policy))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { policies })
+    }
+
+    pub fn get(&self, class: &IdType) -> Option<&ClassLoaderPolicy> {
+        self.policies.get(class)
+    }
+}
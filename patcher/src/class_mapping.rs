@@ -0,0 +1,248 @@
+//! Mapping between the classes renamed by the patcher and their original name.
+//!
+//! When two class loaders define the same class, [`crate::code_loading_patcher`] moves one of
+//! the definitions to `theseus/dedup/<loader-id>/[i/]<original name>`. The mapping is stored in
+//! a ProGuard/R8 style file so that the names found in the output of analysis tools (FlowDroid,
+//! Jadx, ...) can be translated back:
+//!
+//! ```text
+//! com.example.Foo -> theseus.dedup.5e2b1c3f.com.example.Foo:
+//! # {"id":"theseus.classLoader","loaderId":"5e2b1c3f","loaderClass":"dalvik.system.DexClassLoader","sourceFiles":["/tmp/a.dex"]}
+//! ```
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+
+use androscalpel::IdType;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// The id of the metadata comment storing the class loader information.
+const CLASS_LOADER_INFO_ID: &str = "theseus.classLoader";
+
+/// A class definition renamed to avoid a collision with a class defined by another class loader.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct RenamedClass {
+    /// The name of the class in the patched application.
+    pub new_name: IdType,
+    /// The name of the class in the application at runtime.
+    pub original_name: IdType,
+    /// The id of the class loader that defined the class.
+    pub loader_id: String,
+    /// The class of the class loader that defined the class.
+    pub loader_class: IdType,
+    /// The files loaded by the class loader.
+    pub source_files: Vec<PathBuf>,
+}
+
+/// The metadata of a class, stored in a comment after the class line.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClassLoaderInfo {
+    id: String,
+    loader_id: String,
+    loader_class: String,
+    source_files: Vec<PathBuf>,
+}
+
+/// The list of the classes renamed by the patcher.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Deserialize, Serialize)]
+pub struct ClassMapping {
+    pub classes: Vec<RenamedClass>,
+}
+
+/// Get the java name of a class (eg `com.example.Foo` for `Lcom/example/Foo;`).
+fn dotted_name(ty: &IdType) -> Result<String> {
+    let name: String = (&ty
+        .get_class_name()
+        .with_context(|| format!("{} is not a class", ty.__str__()))?)
+        .try_into()?;
+    Ok(name.replace('/', "."))
+}
+
+impl ClassMapping {
+    /// Write the mapping in the ProGuard/R8 format.
+    pub fn write(&self, mut out: impl Write) -> Result<()> {
+        writeln!(out, "# Classes renamed by the Theseus patcher")?;
+        for class in &self.classes {
+            writeln!(
+                out,
+                "{} -> {}:",
+                dotted_name(&class.original_name)?,
+                dotted_name(&class.new_name)?
+            )?;
+            let info = ClassLoaderInfo {
+                id: CLASS_LOADER_INFO_ID.into(),
+                loader_id: class.loader_id.clone(),
+                loader_class: dotted_name(&class.loader_class)?,
+                source_files: class.source_files.clone(),
+            };
+            writeln!(out, "# {}", serde_json::to_string(&info)?)?;
+        }
+        Ok(())
+    }
+
+    /// Load a mapping written by [`ClassMapping::write`]. Members mappings and unknown
+    /// metadata are ignored.
+    pub fn load(input: impl Read) -> Result<Self> {
+        let mut classes: Vec<RenamedClass> = vec![];
+        for (i, line) in BufReader::new(input).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with(char::is_whitespace) {
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix('#') {
+                let Ok(info) = serde_json::from_str::<ClassLoaderInfo>(comment.trim()) else {
+                    continue;
+                };
+                if info.id != CLASS_LOADER_INFO_ID {
+                    continue;
+                }
+                let Some(class) = classes.last_mut() else {
+                    bail!("line {}: class loader info found before any class", i + 1);
+                };
+                class.loader_id = info.loader_id;
+                class.loader_class = IdType::class(&info.loader_class.replace('.', "/"));
+                class.source_files = info.source_files;
+                continue;
+            }
+            let Some((original, new)) = line
+                .strip_suffix(':')
+                .and_then(|line| line.split_once(" -> "))
+            else {
+                bail!("line {}: invalid class mapping: {line}", i + 1);
+            };
+            classes.push(RenamedClass {
+                new_name: IdType::class(&new.trim().replace('.', "/")),
+                original_name: IdType::class(&original.trim().replace('.', "/")),
+                loader_id: String::new(),
+                loader_class: IdType::class("java/lang/ClassLoader"),
+                source_files: vec![],
+            });
+        }
+        Ok(Self { classes })
+    }
+
+    /// Replace the new names of the renamed classes by their original names in `report`.
+    /// Names are replaced in their dotted (`a.b.C`), binary (`a/b/C`) and descriptor
+    /// (`La/b/C;`) forms, only where the whole name is found (see [`replace_name`]).
+    pub fn apply(&self, report: &str) -> Result<String> {
+        let mut names = vec![];
+        for class in &self.classes {
+            let new = dotted_name(&class.new_name)?;
+            let original = dotted_name(&class.original_name)?;
+            names.push((new.replace('.', "/"), original.replace('.', "/")));
+            names.push((new, original));
+        }
+        // Longest names first, so that a name followed by a member (`a.b.C.m`) is not replaced
+        // inside a longer name
+        names.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then(a.cmp(b)));
+        let mut report = report.to_string();
+        for (new, original) in names {
+            report = replace_name(&report, &new, &original);
+        }
+        Ok(report)
+    }
+}
+
+/// If `b` can be part of a class name, in its dotted or binary form.
+fn is_name_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'_' | b'$' | b'.' | b'/') || !b.is_ascii()
+}
+
+/// Replace the occurrences of the class name `name` in `text` by `replacement`. Only the
+/// occurrences of the whole name are replaced, not a name that contains it (like `a.b.CD`,
+/// `a.b.C$D` or `x.a.b.C` for `a.b.C`). The name can be followed by a member (`a.b.C.m`) and
+/// be in a descriptor (`La/b/C;`).
+fn replace_name(text: &str, name: &str, replacement: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, _) in text.match_indices(name) {
+        if start < last {
+            continue;
+        }
+        let end = start + name.len();
+        let starts_name = match start.checked_sub(1).map(|i| bytes[i]) {
+            None => true,
+            // Descriptor
+            Some(b'L') => start < 2 || !is_name_byte(bytes[start - 2]),
+            Some(b) => !is_name_byte(b),
+        };
+        let ends_name = match bytes.get(end) {
+            None => true,
+            // Member of the class, or end of a sentence
+            Some(b'.') => true,
+            Some(b) => !is_name_byte(*b),
+        };
+        if starts_name && ends_name {
+            out.push_str(&text[last..start]);
+            out.push_str(replacement);
+            last = end;
+        }
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping() -> ClassMapping {
+        ClassMapping {
+            classes: vec![
+                RenamedClass {
+                    new_name: IdType::class("theseus/dedup/5e2b1c3f/com/example/Foo"),
+                    original_name: IdType::class("com/example/Foo"),
+                    loader_id: "5e2b1c3f".into(),
+                    loader_class: IdType::class("dalvik/system/DexClassLoader"),
+                    source_files: vec!["/tmp/a.dex".into()],
+                },
+                RenamedClass {
+                    new_name: IdType::class("theseus/dedup/5e2b1c3f/1/com/example/Foo"),
+                    original_name: IdType::class("com/example/Foo"),
+                    loader_id: "5e2b1c3f".into(),
+                    loader_class: IdType::class("dalvik/system/PathClassLoader"),
+                    source_files: vec![],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn write_load_apply_round_trip() {
+        let mut out = vec![];
+        mapping().write(&mut out).unwrap();
+        let loaded = ClassMapping::load(out.as_slice()).unwrap();
+        assert_eq!(loaded, mapping());
+
+        let report = "theseus.dedup.5e2b1c3f.com.example.Foo.run() calls \
+                      Ltheseus/dedup/5e2b1c3f/1/com/example/Foo;->run()V";
+        assert_eq!(
+            loaded.apply(report).unwrap(),
+            "com.example.Foo.run() calls Lcom/example/Foo;->run()V"
+        );
+    }
+
+    #[test]
+    fn apply_matches_whole_names() {
+        let mapping = mapping();
+        for text in [
+            "theseus.dedup.5e2b1c3f.com.example.FooBar",
+            "theseus.dedup.5e2b1c3f.com.example.Foo$Inner",
+            "Ltheseus/dedup/5e2b1c3f/com/example/Foo/Bar;",
+            "xtheseus.dedup.5e2b1c3f.com.example.Foo",
+            "my.theseus.dedup.5e2b1c3f.com.example.Foo",
+            "ALtheseus/dedup/5e2b1c3f/com/example/Foo;",
+        ] {
+            assert_eq!(mapping.apply(text).unwrap(), text);
+        }
+        assert_eq!(
+            mapping
+                .apply("[Ltheseus/dedup/5e2b1c3f/com/example/Foo; theseus/dedup/5e2b1c3f/com/example/Foo.")
+                .unwrap(),
+            "[Lcom/example/Foo; com/example/Foo."
+        );
+    }
+}
@@ -0,0 +1,359 @@
+//! Detection of the format of the files loaded by the class loaders, and extraction of their dex
+//! files.
+//!
+//! Besides plain `.dex` files, the runtime data can point to the files ART leaves behind after
+//! compiling the loaded code (`.vdex`, `.odex`/`.oat`), to zip containers (`.jar`, `.apk`) with
+//! several dex files, and to the dumps of the buffers given to `InMemoryDexClassLoader` (raw, with
+//! trailing bytes, or encoded in base64).
+
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use base64::prelude::*;
+use log::{debug, warn};
+use zip::ZipArchive;
+
+const DEX_MAGIC: &[u8; 4] = b"dex\n";
+const CDEX_MAGIC: &[u8; 4] = b"cdex";
+const VDEX_MAGIC: &[u8; 4] = b"vdex";
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ZIP_MAGIC: &[u8; 4] = b"PK\x03\x04";
+const EMPTY_ZIP_MAGIC: &[u8; 4] = b"PK\x05\x06";
+/// Size of the header of a dex file.
+const DEX_HEADER_SIZE: usize = 0x70;
+/// Offset of `file_size` in the header of a dex file.
+const DEX_FILE_SIZE_OFF: usize = 0x20;
+/// Offset of `header_size` in the header of a dex file.
+const DEX_HEADER_SIZE_OFF: usize = 0x24;
+/// The first version of vdex files using a table of sections (Android 11).
+const VDEX_SECTIONS_VERSION: u32 = 21;
+/// Kind of the section of a vdex file containing the dex files.
+const VDEX_DEX_SECTION: u32 = 1;
+const EMBEDDED_CDEX: &str = "the embedded dex files are compact dex files, which cannot be \
+converted back to dex files, use the original file";
+
+/// The formats of the files loaded by the class loaders.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CodeFormat {
+    Dex,
+    /// Compact dex, the dex format ART uses internally (in vdex files).
+    CompactDex,
+    Vdex,
+    /// An ELF file generated by `dex2oat` (`.odex`, `.oat`).
+    Oat,
+    /// A zip file (`.jar`, `.apk`, `.zip`).
+    Zip,
+    /// Any other format, encoded in base64.
+    Base64,
+}
+
+impl fmt::Display for CodeFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Dex => "dex",
+            Self::CompactDex => "compact dex",
+            Self::Vdex => "vdex",
+            Self::Oat => "oat",
+            Self::Zip => "zip",
+            Self::Base64 => "base64",
+        })
+    }
+}
+
+impl CodeFormat {
+    /// Detect the format of a file from its content.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        let magic: &[u8; 4] = data.get(..4)?.try_into().unwrap();
+        match magic {
+            DEX_MAGIC => Some(Self::Dex),
+            CDEX_MAGIC => Some(Self::CompactDex),
+            VDEX_MAGIC => Some(Self::Vdex),
+            ELF_MAGIC => Some(Self::Oat),
+            ZIP_MAGIC | EMPTY_ZIP_MAGIC => Some(Self::Zip),
+            _ if is_base64(data) => Some(Self::Base64),
+            _ => None,
+        }
+    }
+}
+
+/// The error returned when the dex files of a file cannot be extracted.
+#[derive(Debug)]
+pub enum CodeFormatError {
+    Io(PathBuf, std::io::Error),
+    /// The format is recognized, but the dex files cannot be extracted from it.
+    Unsupported {
+        file: PathBuf,
+        format: CodeFormat,
+        reason: String,
+    },
+    /// The format is recognized, but the file is malformed.
+    Malformed {
+        file: PathBuf,
+        format: CodeFormat,
+        reason: String,
+    },
+    /// The format is not recognized, `magic` is the start of the file.
+    Unknown {
+        file: PathBuf,
+        magic: Vec<u8>,
+    },
+}
+
+impl fmt::Display for CodeFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(file, err) => write!(f, "Failed to read {}: {err}", file.display()),
+            Self::Unsupported {
+                file,
+                format,
+                reason,
+            } => write!(f, "Unsupported {format} file {}: {reason}", file.display()),
+            Self::Malformed {
+                file,
+                format,
+                reason,
+            } => write!(f, "Malformed {format} file {}: {reason}", file.display()),
+            Self::Unknown { file, magic } => write!(
+                f,
+                "Unknown format for {} (starts with {})",
+                file.display(),
+                magic
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CodeFormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Read a file loaded by a class loader and extract its dex files, in loading order.
+pub fn read_dex_files(file: &Path) -> Result<Vec<Vec<u8>>, CodeFormatError> {
+    let data = std::fs::read(file).map_err(|err| CodeFormatError::Io(file.into(), err))?;
+    extract_dex_files(file, &data)
+}
+
+/// Extract the dex files of `data`, the content of `file`.
+pub fn extract_dex_files(file: &Path, data: &[u8]) -> Result<Vec<Vec<u8>>, CodeFormatError> {
+    let format = CodeFormat::detect(data).ok_or_else(|| CodeFormatError::Unknown {
+        file: file.into(),
+        magic: data.iter().take(8).cloned().collect(),
+    })?;
+    debug!("{} is a {format} file", file.display());
+    let unsupported = |reason: &str| CodeFormatError::Unsupported {
+        file: file.into(),
+        format,
+        reason: reason.into(),
+    };
+    let malformed = |reason: String| CodeFormatError::Malformed {
+        file: file.into(),
+        format,
+        reason,
+    };
+    match format {
+        CodeFormat::Dex => {
+            let size = dex_size(data).map_err(malformed)?;
+            if size < data.len() {
+                // The buffers given to InMemoryDexClassLoader can be larger than the dex file
+                debug!(
+                    "Ignore the {} bytes after the dex file in {}",
+                    data.len() - size,
+                    file.display()
+                );
+            }
+            Ok(vec![data[..size].to_vec()])
+        }
+        CodeFormat::CompactDex => Err(unsupported(
+            "compact dex files cannot be converted back to dex files, use the original file",
+        )),
+        CodeFormat::Vdex => {
+            if data.len() < 12 {
+                return Err(malformed(format!(
+                    "truncated header ({} bytes)",
+                    data.len()
+                )));
+            }
+            let version = std::str::from_utf8(&data[4..data.len().min(7)])
+                .ok()
+                .and_then(|version| version.parse::<u32>().ok())
+                .ok_or_else(|| malformed("invalid version".into()))?;
+            let dex_files = if version >= VDEX_SECTIONS_VERSION {
+                let section = vdex_dex_section(data).map_err(malformed)?;
+                scan_dex_files(section, false)
+            } else {
+                // The layout of the older versions changed a lot, look for the dex files
+                scan_dex_files(&data[8..], true)
+            };
+            match dex_files {
+                Ok(dex_files) if dex_files.is_empty() => Err(unsupported(
+                    "no dex file embedded, the dex files are still in the original file",
+                )),
+                Ok(dex_files) => Ok(dex_files),
+                Err(ScanError::CompactDex) => Err(unsupported(EMBEDDED_CDEX)),
+                Err(ScanError::Malformed(reason)) => Err(malformed(reason)),
+            }
+        }
+        CodeFormat::Oat => match scan_dex_files(data, true) {
+            Ok(dex_files) if dex_files.is_empty() => Err(unsupported(
+                "no dex file embedded, the dex files are in the vdex file",
+            )),
+            Ok(dex_files) => Ok(dex_files),
+            Err(ScanError::CompactDex) => Err(unsupported(EMBEDDED_CDEX)),
+            Err(ScanError::Malformed(reason)) => Err(malformed(reason)),
+        },
+        CodeFormat::Zip => {
+            let mut archive =
+                ZipArchive::new(Cursor::new(data)).map_err(|err| malformed(err.to_string()))?;
+            let mut dex_files = vec![];
+            // Like ART, load classes.dex, classes2.dex, ... until an entry is missing
+            for i in 1.. {
+                let name = if i == 1 {
+                    "classes.dex".into()
+                } else {
+                    format!("classes{i}.dex")
+                };
+                let Ok(mut entry) = archive.by_name(&name) else {
+                    break;
+                };
+                let mut dex = vec![];
+                entry
+                    .read_to_end(&mut dex)
+                    .map_err(|err| malformed(format!("failed to read {name}: {err}")))?;
+                dex_files.append(&mut extract_dex_files(&file.join(&name), &dex)?);
+            }
+            if dex_files.is_empty() {
+                warn!("No classes.dex in {}", file.display());
+            }
+            Ok(dex_files)
+        }
+        CodeFormat::Base64 => {
+            let decoded = decode_base64(data).map_err(malformed)?;
+            if CodeFormat::detect(&decoded) == Some(CodeFormat::Base64) {
+                return Err(malformed("encoded in base64 twice".into()));
+            }
+            extract_dex_files(file, &decoded)
+        }
+    }
+}
+
+/// Check the header of the dex file at the start of `data` and return its size.
+fn dex_size(data: &[u8]) -> Result<usize, String> {
+    if data.len() < DEX_HEADER_SIZE {
+        return Err(format!("truncated header ({} bytes)", data.len()));
+    }
+    let version = &data[4..8];
+    if !version[..3].iter().all(u8::is_ascii_digit) || version[3] != 0 {
+        return Err("invalid version".into());
+    }
+    let header_size = read_u32(data, DEX_HEADER_SIZE_OFF).unwrap() as usize;
+    if header_size != DEX_HEADER_SIZE {
+        return Err(format!("invalid header size 0x{header_size:x}"));
+    }
+    let size = read_u32(data, DEX_FILE_SIZE_OFF).unwrap() as usize;
+    if size < DEX_HEADER_SIZE || size > data.len() {
+        return Err(format!(
+            "invalid file size 0x{size:x} ({} bytes available)",
+            data.len()
+        ));
+    }
+    Ok(size)
+}
+
+/// Get the section of a vdex file (version 021 and later) containing the dex files.
+fn vdex_dex_section(data: &[u8]) -> Result<&[u8], String> {
+    let nb_sections = read_u32(data, 8).ok_or("truncated header")?;
+    for i in 0..nb_sections as usize {
+        let header = 12 + i * 12;
+        let (Some(kind), Some(offset), Some(size)) = (
+            read_u32(data, header),
+            read_u32(data, header + 4),
+            read_u32(data, header + 8),
+        ) else {
+            return Err("truncated section headers".into());
+        };
+        if kind == VDEX_DEX_SECTION {
+            return data
+                .get(offset as usize..offset as usize + size as usize)
+                .ok_or_else(|| format!("dex section out of the file (0x{offset:x}+0x{size:x})"));
+        }
+    }
+    Ok(&[])
+}
+
+enum ScanError {
+    CompactDex,
+    Malformed(String),
+}
+
+/// Find the dex files in `data`. The dex files are aligned on 4 bytes. If `search`, the data
+/// between the dex files is skipped, else `data` must only contain dex files.
+fn scan_dex_files(data: &[u8], search: bool) -> Result<Vec<Vec<u8>>, ScanError> {
+    let mut dex_files = vec![];
+    let mut offset = 0;
+    while offset + DEX_HEADER_SIZE <= data.len() {
+        let magic = &data[offset..offset + 4];
+        let version = &data[offset + 4..offset + 8];
+        if magic == CDEX_MAGIC && version[..3].iter().all(u8::is_ascii_digit) && version[3] == 0 {
+            return Err(ScanError::CompactDex);
+        }
+        if magic == DEX_MAGIC {
+            match dex_size(&data[offset..]) {
+                Ok(size) => {
+                    dex_files.push(data[offset..offset + size].to_vec());
+                    offset += size.next_multiple_of(4);
+                    continue;
+                }
+                Err(err) if !search => return Err(ScanError::Malformed(err)),
+                Err(_) => (),
+            }
+        } else if !search {
+            return Err(ScanError::Malformed(format!(
+                "no dex file at 0x{offset:x} of the dex section"
+            )));
+        }
+        offset += 4;
+    }
+    Ok(dex_files)
+}
+
+/// Check if `data` only contains base64 characters, ignoring whitespaces.
+fn is_base64(data: &[u8]) -> bool {
+    let mut len = 0;
+    for c in data {
+        if c.is_ascii_whitespace() {
+            continue;
+        }
+        if !(c.is_ascii_alphanumeric() || matches!(c, b'+' | b'/' | b'=')) {
+            return false;
+        }
+        len += 1;
+    }
+    len >= 8 && len % 4 == 0
+}
+
+fn decode_base64(data: &[u8]) -> Result<Vec<u8>, String> {
+    let data: Vec<u8> = data
+        .iter()
+        .filter(|c| !c.is_ascii_whitespace())
+        .cloned()
+        .collect();
+    BASE64_STANDARD
+        .decode(data)
+        .map_err(|err| format!("invalid base64: {err}"))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
@@ -1,43 +1,117 @@
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Cursor;
+use std::path::PathBuf;
 
-use androscalpel::{Apk, DexString, IdType, VisitorMut};
-use anyhow::{Context, Result};
+use androscalpel::{Apk, DexString, IdType, Instruction, VisitorMut};
+use anyhow::{bail, Context, Result};
 use clap::ValueEnum;
 use log::{debug, info};
 
+use crate::class_loader_policy::{ClassLoaderPolicies, DelegationStep};
+use crate::class_mapping::{ClassMapping, RenamedClass};
+use crate::code_format::read_dex_files;
 use crate::dex_types::DELEGATE_LAST_CLASS_LOADER;
+use crate::report::{ClassCollision, ClassStringRewrite, CollisionAction};
 use crate::runtime_data::RuntimeData;
 
 #[derive(ValueEnum, Debug, PartialEq, Clone, Copy, Default)]
 pub enum CodePatchingStrategy {
+    /// Add the code loaded at runtime to the application, ignoring the collisions.
     #[default]
     Naive,
+    /// Rename the definitions colliding with a definition from another class loader, and
+    /// resolve the references by modeling the class loaders.
     ModelClassLoaders,
+    /// Keep the first definition of a class (the application, then the code in the order it
+    /// was loaded), drop the others.
+    KeepFirst,
+    /// Keep the last definition of a class loaded, drop the others.
+    KeepLast,
+    /// Like [`Self::ModelClassLoaders`], but rename every class loaded at runtime, not only
+    /// the colliding ones.
+    RenameAllDynamic,
+    /// Fail if a class is defined more than once.
+    FailOnCollision,
 }
 
+/// The result of [`insert_code`].
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct InsertedCode {
+    /// The classes renamed.
+    pub mapping: ClassMapping,
+    /// The definitions dropped or renamed, and why.
+    pub collisions: Vec<ClassCollision>,
+    /// The string constants rewritten to name the renamed classes.
+    pub string_rewrites: Vec<ClassStringRewrite>,
+    /// The classes added to the application, with their name in the patched application.
+    pub injected_classes: Vec<IdType>,
+}
+
+/// The options of [`insert_code`] for the strategies modeling the class loaders.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct CodeLoadingOptions {
+    /// The delegation policies of the custom class loaders.
+    pub policies: ClassLoaderPolicies,
+    /// Rewrite the `const-string` naming a class renamed in the same class loader.
+    pub rewrite_class_strings: bool,
+}
+
+/// Insert the code loaded at runtime in the apk, and return the list of the classes
+/// renamed or dropped to avoid collisions.
 pub fn insert_code(
     strategy: CodePatchingStrategy,
     apk: &mut Apk,
     data: &mut RuntimeData,
-) -> Result<()> {
-    match strategy {
-        CodePatchingStrategy::Naive => insert_code_naive(apk, data),
-        CodePatchingStrategy::ModelClassLoaders => insert_code_model_class_loaders(apk, data),
-    }
+    options: &CodeLoadingOptions,
+) -> Result<InsertedCode> {
+    let original_classes = apk.list_classes();
+    let mut inserted = match strategy {
+        CodePatchingStrategy::Naive => {
+            insert_code_naive(apk, data).map(|()| InsertedCode::default())
+        }
+        CodePatchingStrategy::ModelClassLoaders => {
+            insert_code_model_class_loaders(apk, data, options, false)
+        }
+        CodePatchingStrategy::RenameAllDynamic => {
+            insert_code_model_class_loaders(apk, data, options, true)
+        }
+        CodePatchingStrategy::KeepFirst
+        | CodePatchingStrategy::KeepLast
+        | CodePatchingStrategy::FailOnCollision => {
+            insert_code_single_definition(strategy, apk, data).map(|collisions| InsertedCode {
+                collisions,
+                ..Default::default()
+            })
+        }
+    }?;
+    inserted.injected_classes = apk
+        .list_classes()
+        .difference(&original_classes)
+        .cloned()
+        .collect();
+    inserted.injected_classes.sort();
+    Ok(inserted)
 }
 
-/// Insert statically bytecode that was loaded from other source at runtime.
-/// For now, we ignore class collision.
-fn insert_code_naive(apk: &mut Apk, data: &RuntimeData) -> Result<()> {
-    for dyn_data in &data.dyn_code_load {
-        for file_name in &dyn_data.files {
-            let file = File::open(file_name)?;
-            apk.add_code(file, crate::labeling, false)
+/// Load the bytecode files loaded by a class loader.
+fn load_code(files: &[PathBuf]) -> Result<Apk> {
+    let mut apk = Apk::new();
+    add_code(&mut apk, files)?;
+    Ok(apk)
+}
+
+/// Add the dex files of the bytecode files loaded by a class loader to `apk`.
+fn add_code(apk: &mut Apk, files: &[PathBuf]) -> Result<()> {
+    for file_name in files {
+        let dex_files = read_dex_files(file_name)?;
+        let nb_dex = dex_files.len();
+        for (i, dex) in dex_files.into_iter().enumerate() {
+            apk.add_code(Cursor::new(dex), crate::labeling, false)
                 .with_context(|| {
                     format!(
-                        "Could not add code from {}",
-                        file_name.to_str().unwrap_or("<failed to parse file name>")
+                        "Could not add code from {} (dex file {}/{nb_dex})",
+                        file_name.to_str().unwrap_or("<failed to parse file name>"),
+                        i + 1
                     )
                 })?;
         }
@@ -45,14 +119,136 @@ fn insert_code_naive(apk: &mut Apk, data: &RuntimeData) -> Result<()> {
     Ok(())
 }
 
-fn insert_code_model_class_loaders(apk: &mut Apk, runtime_data: &mut RuntimeData) -> Result<()> {
-    let mut class_defined = apk.list_classes();
+/// Insert statically bytecode that was loaded from other source at runtime.
+/// For now, we ignore class collision.
+fn insert_code_naive(apk: &mut Apk, data: &RuntimeData) -> Result<()> {
+    for dyn_data in &data.dyn_code_load {
+        add_code(apk, &dyn_data.files)?;
+    }
+    Ok(())
+}
+
+/// A class loader that defined classes, for the collision report.
+struct DefiningLoader {
+    id: String,
+    class: IdType,
+    files: Vec<PathBuf>,
+}
+
+impl DefiningLoader {
+    fn collision(&self, cls: &IdType, action: CollisionAction, reason: String) -> ClassCollision {
+        ClassCollision {
+            class: cls.clone(),
+            loader_id: self.id.clone(),
+            loader_class: self.class.clone(),
+            source_files: self.files.clone(),
+            action,
+            reason,
+        }
+    }
+}
+
+/// Insert the code loaded at runtime, keeping only one definition of each class
+/// ([`CodePatchingStrategy::KeepFirst`] and [`CodePatchingStrategy::KeepLast`]), or failing on
+/// the first class defined twice ([`CodePatchingStrategy::FailOnCollision`]).
+fn insert_code_single_definition(
+    strategy: CodePatchingStrategy,
+    apk: &mut Apk,
+    data: &RuntimeData,
+) -> Result<Vec<ClassCollision>> {
+    let mut loaders = vec![DefiningLoader {
+        id: data.apk_cl_id.clone().unwrap_or_else(|| "MAIN".to_string()),
+        class: IdType::from_smali("Ldalvik/system/PathClassLoader;").unwrap(),
+        files: vec![],
+    }];
+    let mut defined_by: HashMap<IdType, usize> =
+        apk.list_classes().into_iter().map(|cls| (cls, 0)).collect();
+    let mut collisions = vec![];
+    for dyn_data in &data.dyn_code_load {
+        let mut other = load_code(&dyn_data.files)?;
+        let idx = loaders.len();
+        loaders.push(DefiningLoader {
+            id: dyn_data.classloader.clone(),
+            class: dyn_data.classloader_class.clone(),
+            files: dyn_data.files.clone(),
+        });
+        // Sorted to keep the output independent of the hash of the types
+        let mut classes: Vec<_> = other.list_classes().into_iter().collect();
+        classes.sort();
+        for cls in classes {
+            let Some(&prev) = defined_by.get(&cls) else {
+                defined_by.insert(cls, idx);
+                continue;
+            };
+            let (prev_loader, new_loader) = (&loaders[prev], &loaders[idx]);
+            match strategy {
+                CodePatchingStrategy::FailOnCollision => bail!(
+                    "Class {} is defined by {}({}) and {}({})",
+                    cls.__str__(),
+                    prev_loader.id,
+                    prev_loader.class.__str__(),
+                    new_loader.id,
+                    new_loader.class.__str__()
+                ),
+                CodePatchingStrategy::KeepLast => {
+                    apk.remove_class(&cls, None)?;
+                    collisions.push(prev_loader.collision(
+                        &cls,
+                        CollisionAction::Dropped,
+                        format!(
+                            "redefined later by {}({}), keep the last definition",
+                            new_loader.id,
+                            new_loader.class.__str__()
+                        ),
+                    ));
+                    defined_by.insert(cls, idx);
+                }
+                _ => {
+                    other.remove_class(&cls, None)?;
+                    collisions.push(new_loader.collision(
+                        &cls,
+                        CollisionAction::Dropped,
+                        format!(
+                            "already defined by {}({}), keep the first definition",
+                            prev_loader.id,
+                            prev_loader.class.__str__()
+                        ),
+                    ));
+                }
+            }
+        }
+        apk.merge(other);
+    }
+    for collision in &collisions {
+        info!(
+            "Dropped definition of {} from {}: {}",
+            collision.class.__str__(),
+            collision.loader_id,
+            collision.reason
+        );
+    }
+    Ok(collisions)
+}
+
+fn insert_code_model_class_loaders(
+    apk: &mut Apk,
+    runtime_data: &mut RuntimeData,
+    options: &CodeLoadingOptions,
+    rename_all: bool,
+) -> Result<InsertedCode> {
+    // The id of the class loader that defined each class first
+    let mut class_defined: HashMap<IdType, String> = HashMap::new();
     let mut class_redefined = HashSet::new();
     let mut class_loaders = HashMap::new();
     let main_cl_id = runtime_data
         .apk_cl_id
         .clone()
         .unwrap_or_else(|| "MAIN".to_string());
+    for cls in apk.list_classes() {
+        class_defined.insert(cls, main_cl_id.clone());
+    }
+    let mut collisions = vec![];
+    let main_cl_data = runtime_data.classloaders.get(&main_cl_id);
     class_loaders.insert(
         main_cl_id.clone(),
         ClassLoader {
@@ -60,46 +256,118 @@ fn insert_code_model_class_loaders(apk: &mut Apk, runtime_data: &mut RuntimeData
             parent: None,
             class: IdType::from_smali("Ldalvik/system/PathClassLoader;").unwrap(),
             apk: ApkOrRef::Ref(apk),
+            files: vec![],
+            shared_libraries: main_cl_data
+                .map(|data| data.shared_libraries.clone())
+                .unwrap_or_default(),
+            shared_libraries_after: main_cl_data
+                .map(|data| data.shared_libraries_after.clone())
+                .unwrap_or_default(),
+            policy: None,
             renamed_classes: HashMap::new(),
         },
     );
     // -- Rename class def --
     for dyn_data in &runtime_data.dyn_code_load {
-        let mut apk = Apk::new();
+        let apk = load_code(&dyn_data.files)?;
         let class = dyn_data.classloader_class.clone();
-        for file_name in &dyn_data.files {
-            let file = File::open(file_name)?;
-            apk.add_code(file, crate::labeling, false)
-                .with_context(|| {
-                    format!(
-                        "Could not add code from {}",
-                        file_name.to_str().unwrap_or("<failed to parse file name>")
-                    )
-                })?;
-        }
 
         if class_loaders.contains_key(&dyn_data.classloader) {
             panic!("The same class loader should not appear twice in runtime_data.dyn_code_load, found: {}", &dyn_data.classloader)
         }
 
         let classes = apk.list_classes();
+        // The classloader data is sent after the code is loaded, when the shared libraries
+        // searched after the classloader are known.
+        let cl_data = runtime_data.classloaders.get(&dyn_data.classloader);
+        let shared_libraries = match cl_data {
+            Some(data) if !data.shared_libraries.is_empty() => data.shared_libraries.clone(),
+            _ => dyn_data.classloader_shared_libraries.clone(),
+        };
+        let shared_libraries_after = cl_data
+            .map(|data| data.shared_libraries_after.clone())
+            .unwrap_or_default();
         let mut class_loader = ClassLoader {
             id: dyn_data.classloader.clone(),
             parent: dyn_data.classloader_parent.clone(),
             class,
             apk: ApkOrRef::Owned(apk),
+            files: dyn_data.files.clone(),
+            shared_libraries,
+            shared_libraries_after,
+            policy: None,
             renamed_classes: HashMap::new(),
         };
-        let collisions = class_defined.intersection(&classes);
-        for cls in collisions {
-            class_loader.rename_classdef(cls)?;
+        // Sorted to keep the output independent of the hash of the types
+        let mut classes: Vec<_> = classes.into_iter().collect();
+        classes.sort();
+        let defining_loader = DefiningLoader {
+            id: dyn_data.classloader.clone(),
+            class: dyn_data.classloader_class.clone(),
+            files: dyn_data.files.clone(),
+        };
+        for cls in classes {
+            let reason = match class_defined.get(&cls) {
+                Some(first_cl_id) => format!("also defined by {first_cl_id}"),
+                None if rename_all => "loaded at runtime, all dynamically loaded classes \
+                    are renamed"
+                    .to_string(),
+                None => {
+                    class_defined.insert(cls, dyn_data.classloader.clone());
+                    continue;
+                }
+            };
+            class_loader.rename_classdef(&cls)?;
+            collisions.push(defining_loader.collision(
+                &cls,
+                CollisionAction::Renamed(class_loader.renamed_classes[&cls].clone()),
+                reason,
+            ));
             class_redefined.insert(cls.clone());
+            class_defined
+                .entry(cls)
+                .or_insert(dyn_data.classloader.clone());
         }
-        class_defined.extend(classes);
 
         class_loaders.insert(dyn_data.classloader.clone(), class_loader);
     }
 
+    // -- Apply the delegation policies --
+    let loading_order: Vec<(String, IdType)> = runtime_data
+        .dyn_code_load
+        .iter()
+        .map(|data| (data.classloader.clone(), data.classloader_class.clone()))
+        .collect();
+    let mut without_policy = BTreeSet::new();
+    for cl in class_loaders.values_mut() {
+        let Some(policy) = options.policies.get(&cl.class) else {
+            if !cl.class.is_platform_class() {
+                without_policy.insert(cl.class.__str__());
+            }
+            continue;
+        };
+        let mut steps = vec![];
+        for step in policy.steps() {
+            match step {
+                DelegationStep::LoadersOfClass(class) => steps.extend(
+                    loading_order
+                        .iter()
+                        .filter(|(id, cl_class)| *id != cl.id && cl_class.__str__() == class)
+                        .map(|(id, _)| DelegationStep::Loader(id.clone())),
+                ),
+                step => steps.push(step),
+            }
+        }
+        cl.policy = Some(steps);
+    }
+    if !without_policy.is_empty() {
+        log::warn!(
+            "No delegation policy for the custom class loaders {}, \
+            modeled as dalvik.system.DexClassLoader",
+            without_policy.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+
     // -- Rename class ref --
     let mut renamers: HashMap<_, _> = class_loaders
         .values()
@@ -204,6 +472,210 @@ fn insert_code_model_class_loaders(apk: &mut Apk, runtime_data: &mut RuntimeData
                 }
             }
         });
+    runtime_data
+        .dyn_code_load
+        .iter_mut()
+        .filter_map(|data| data.construction_site.as_mut())
+        .for_each(|data| {
+            if let Some(visitor) = renamers.get_mut(&data.caller_cl_id) {
+                match visitor.visit_method_id(data.caller_method.clone()) {
+                    Err(err) => log::warn!(
+                        "Failed to generate new name for {} from {}: {err}",
+                        data.caller_method.__str__(),
+                        data.caller_cl_id
+                    ),
+                    Ok(new_method) => data.renamed_caller_method = Some(new_method),
+                }
+            }
+        });
+    runtime_data.class_loading.iter_mut().for_each(|data| {
+        if let Some(visitor) = renamers.get_mut(&data.class_cl_id) {
+            match visitor.visit_type(data.class.clone()) {
+                Err(err) => log::warn!(
+                    "Failed to generate new name for {} from {}: {err}",
+                    data.class.__str__(),
+                    data.class_cl_id
+                ),
+                Ok(new_class) => data.renamed_class = Some(new_class),
+            }
+        }
+        if let Some(visitor) = renamers.get_mut(&data.caller_cl_id) {
+            match visitor.visit_method_id(data.caller_method.clone()) {
+                Err(err) => log::warn!(
+                    "Failed to generate new name for {} from {}: {err}",
+                    data.caller_method.__str__(),
+                    data.caller_cl_id
+                ),
+                Ok(new_method) => data.renamed_caller_method = Some(new_method),
+            }
+        }
+    });
+    runtime_data.kotlin_reflection.iter_mut().for_each(|data| {
+        if let Some(visitor) = renamers.get_mut(&data.method_cl_id) {
+            match visitor.visit_method_id(data.method.clone()) {
+                Err(err) => log::warn!(
+                    "Failed to generate new name for {} from {}: {err}",
+                    data.method.__str__(),
+                    data.method_cl_id
+                ),
+                Ok(new_method) => data.renamed_method = Some(new_method),
+            }
+        }
+        if let Some(visitor) = renamers.get_mut(&data.caller_cl_id) {
+            match visitor.visit_method_id(data.caller_method.clone()) {
+                Err(err) => log::warn!(
+                    "Failed to generate new name for {} from {}: {err}",
+                    data.caller_method.__str__(),
+                    data.caller_cl_id
+                ),
+                Ok(new_method) => data.renamed_caller_method = Some(new_method),
+            }
+        }
+    });
+    runtime_data
+        .service_new_inst_data
+        .iter_mut()
+        .for_each(|data| {
+            if let Some(visitor) = renamers.get_mut(&data.constructor_cl_id) {
+                match visitor.visit_method_id(data.constructor.clone()) {
+                    Err(err) => log::warn!(
+                        "Failed to generate new name for {} from {}: {err}",
+                        data.constructor.__str__(),
+                        data.constructor_cl_id
+                    ),
+                    Ok(new_method) => data.renamed_constructor = Some(new_method),
+                }
+            }
+            if let Some(visitor) = renamers.get_mut(&data.caller_cl_id) {
+                match visitor.visit_method_id(data.caller_method.clone()) {
+                    Err(err) => log::warn!(
+                        "Failed to generate new name for {} from {}: {err}",
+                        data.caller_method.__str__(),
+                        data.caller_cl_id
+                    ),
+                    Ok(new_method) => data.renamed_caller_method = Some(new_method),
+                }
+            }
+        });
+    runtime_data
+        .array_new_inst_data
+        .iter_mut()
+        .for_each(|data| {
+            if let Some(visitor) = data
+                .component_cl_id
+                .as_ref()
+                .and_then(|cl_id| renamers.get_mut(cl_id))
+            {
+                match visitor.visit_type(data.component_type.clone()) {
+                    Err(err) => log::warn!(
+                        "Failed to generate new name for {} from {:?}: {err}",
+                        data.component_type.__str__(),
+                        data.component_cl_id
+                    ),
+                    Ok(new_type) => data.renamed_component_type = Some(new_type),
+                }
+            }
+            if let Some(visitor) = renamers.get_mut(&data.caller_cl_id) {
+                match visitor.visit_method_id(data.caller_method.clone()) {
+                    Err(err) => log::warn!(
+                        "Failed to generate new name for {} from {}: {err}",
+                        data.caller_method.__str__(),
+                        data.caller_cl_id
+                    ),
+                    Ok(new_method) => data.renamed_caller_method = Some(new_method),
+                }
+            }
+        });
+    runtime_data.jni_upcalls.iter_mut().for_each(|data| {
+        if let Some(visitor) = data
+            .method_cl_id
+            .as_ref()
+            .and_then(|cl_id| renamers.get_mut(cl_id))
+        {
+            match visitor.visit_method_id(data.method.clone()) {
+                Err(err) => log::warn!(
+                    "Failed to generate new name for {} from {:?}: {err}",
+                    data.method.__str__(),
+                    data.method_cl_id
+                ),
+                Ok(new_method) => data.renamed_method = Some(new_method),
+            }
+        }
+        if let Some(visitor) = renamers.get_mut(&data.native_method_cl_id) {
+            match visitor.visit_method_id(data.native_method.clone()) {
+                Err(err) => log::warn!(
+                    "Failed to generate new name for {} from {}: {err}",
+                    data.native_method.__str__(),
+                    data.native_method_cl_id
+                ),
+                Ok(new_method) => data.renamed_native_method = Some(new_method),
+            }
+        }
+    });
+    runtime_data.native_bindings.iter_mut().for_each(|data| {
+        if let Some(visitor) = renamers.get_mut(&data.method_cl_id) {
+            match visitor.visit_method_id(data.method.clone()) {
+                Err(err) => log::warn!(
+                    "Failed to generate new name for {} from {}: {err}",
+                    data.method.__str__(),
+                    data.method_cl_id
+                ),
+                Ok(new_method) => data.renamed_method = Some(new_method),
+            }
+        }
+    });
+    runtime_data.native_loads.iter_mut().for_each(|data| {
+        if let Some(visitor) = renamers.get_mut(&data.caller_cl_id) {
+            match visitor.visit_method_id(data.caller_method.clone()) {
+                Err(err) => log::warn!(
+                    "Failed to generate new name for {} from {}: {err}",
+                    data.caller_method.__str__(),
+                    data.caller_cl_id
+                ),
+                Ok(new_method) => data.renamed_caller_method = Some(new_method),
+            }
+        }
+    });
+
+    // -- rewrite the strings naming renamed classes --
+    let mut string_rewrites = vec![];
+    if options.rewrite_class_strings {
+        for cl in class_loaders.values_mut() {
+            string_rewrites.append(&mut cl.rewrite_class_strings());
+        }
+        string_rewrites.sort();
+        for rewrite in &string_rewrites {
+            info!(
+                "Rewrote \"{}\" to \"{}\" in {}",
+                rewrite.original,
+                rewrite.new,
+                rewrite.method.__str__()
+            );
+        }
+    }
+
+    // -- list renamed classes --
+    let mut mapping = ClassMapping::default();
+    for cl in class_loaders.values() {
+        for (original_name, new_name) in &cl.renamed_classes {
+            mapping.classes.push(RenamedClass {
+                new_name: new_name.clone(),
+                original_name: original_name.clone(),
+                loader_id: cl.id.clone(),
+                loader_class: cl.class.clone(),
+                source_files: cl.files.clone(),
+            });
+        }
+    }
+    mapping.classes.sort_by(|a, b| a.new_name.cmp(&b.new_name));
+    for collision in &collisions {
+        info!(
+            "Renamed definition of {} from {}: {}",
+            collision.class.__str__(),
+            collision.loader_id,
+            collision.reason
+        );
+    }
 
     // -- inject code to apk --
     let apk = match class_loaders.remove(&main_cl_id).unwrap().apk {
@@ -212,6 +684,9 @@ fn insert_code_model_class_loaders(apk: &mut Apk, runtime_data: &mut RuntimeData
             panic!("Main APK is not stored as ref?")
         }
     };
+    // Merge in a stable order so that the output only depends on the inputs
+    let mut class_loaders: Vec<_> = class_loaders.into_iter().collect();
+    class_loaders.sort_by(|(id_a, _), (id_b, _)| id_a.cmp(id_b));
     for (_, ClassLoader { apk: other, .. }) in class_loaders.into_iter() {
         match other {
             ApkOrRef::Owned(other) => {
@@ -222,7 +697,12 @@ fn insert_code_model_class_loaders(apk: &mut Apk, runtime_data: &mut RuntimeData
             }
         }
     }
-    Ok(())
+    Ok(InsertedCode {
+        mapping,
+        collisions,
+        string_rewrites,
+        ..Default::default()
+    })
 }
 
 /// Structure modelizing a class loader.
@@ -232,6 +712,16 @@ struct ClassLoader<'a> {
     pub parent: Option<String>,
     pub class: IdType,
     pub apk: ApkOrRef<'a>,
+    /// The files loaded by the class loader.
+    pub files: Vec<PathBuf>,
+    /// The ids of the shared library class loaders searched before the classes of the
+    /// class loader.
+    pub shared_libraries: Vec<String>,
+    /// The ids of the shared library class loaders searched after the classes of the
+    /// class loader.
+    pub shared_libraries_after: Vec<String>,
+    /// The steps of the delegation policy declared for the class of the class loader, if any.
+    pub policy: Option<Vec<DelegationStep>>,
     pub renamed_classes: HashMap<IdType, IdType>,
 }
 
@@ -290,6 +780,63 @@ impl ClassLoader<'_> {
         Ok(())
     }
 
+    /// Rewrite the `const-string` naming a class renamed by the class loader, in the dotted
+    /// (`com.example.Foo`), internal (`com/example/Foo`) or descriptor (`Lcom/example/Foo;`)
+    /// form. Only the strings equal to a class name are rewritten.
+    pub fn rewrite_class_strings(&mut self) -> Vec<ClassStringRewrite> {
+        let mut new_strings: HashMap<String, String> = HashMap::new();
+        for (original, new) in &self.renamed_classes {
+            let (original, new) = (original.__str__(), new.__str__());
+            let (Some(original_name), Some(new_name)) = (
+                original.strip_prefix('L').and_then(|n| n.strip_suffix(';')),
+                new.strip_prefix('L').and_then(|n| n.strip_suffix(';')),
+            ) else {
+                continue;
+            };
+            new_strings.insert(original_name.replace('/', "."), new_name.replace('/', "."));
+            new_strings.insert(original_name.into(), new_name.into());
+            new_strings.insert(original.clone(), new.clone());
+        }
+        let mut rewrites = vec![];
+        if new_strings.is_empty() {
+            return rewrites;
+        }
+        let id = self.id.clone();
+        let apk = self.apk_mut();
+        for cls in apk.list_classes() {
+            let Some(class) = apk.get_class_mut(&cls) else {
+                continue;
+            };
+            for method in class
+                .direct_methods
+                .values_mut()
+                .chain(class.virtual_methods.values_mut())
+            {
+                let Some(code) = method.code.as_mut() else {
+                    continue;
+                };
+                for ins in code.insns.iter_mut() {
+                    let Instruction::ConstString { lit, .. } = ins else {
+                        continue;
+                    };
+                    let Ok(string) = String::try_from(&*lit) else {
+                        continue;
+                    };
+                    if let Some(new_string) = new_strings.get(&string) {
+                        *lit = new_string.as_str().into();
+                        rewrites.push(ClassStringRewrite {
+                            loader_id: id.clone(),
+                            method: method.descriptor.clone(),
+                            original: string,
+                            new: new_string.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        rewrites
+    }
+
     pub fn get_ref_new_names(
         &self,
         tys: &HashSet<IdType>,
@@ -314,64 +861,35 @@ impl ClassLoader<'_> {
 
     /// Return the new name of a type after class renaming.
     /// This method select the right renamed type by modeling the behavior of
-    /// the android SDK class loaders.
-    /// If the class loader is not a class loader from the android SDK, default
-    /// to the behavior of DexClassLoader: Platform classes have precedence over
-    /// classes defined by a parent classloader that have precedence over classes
-    /// defined by the classloader itself.
+    /// the android SDK class loaders, or by following the delegation policy
+    /// declared for the class of the class loader (see [`crate::class_loader_policy`]).
+    /// If the class loader is not a class loader from the android SDK and has no
+    /// policy, default to the behavior of DexClassLoader: Platform classes have
+    /// precedence over classes defined by a parent classloader that have precedence
+    /// over classes found by [`Self::find_class`] (shared libraries, then classes
+    /// defined by the classloader itself, then shared libraries loaded after).
+    /// DelegateLastClassLoader search platform classes, then [`Self::find_class`],
+    /// then the parent classloader.
     pub fn get_ref_new_name(
         &self,
         ty: &IdType,
         class_loaders: &HashMap<String, Self>,
     ) -> Option<IdType> {
+        if let Some(policy) = &self.policy {
+            return self.get_ref_new_name_with_policy(ty, policy, class_loaders);
+        }
         if ty.is_platform_class() {
             // Platform classes have precedence for all android SDK classloader.
             debug!("Class {} is a platform class, no renaming", ty.__str__());
             return Some(ty.clone());
         }
         if self.class == *DELEGATE_LAST_CLASS_LOADER {
-            if let Some(new_ty) = self.renamed_classes.get(ty) {
-                debug!(
-                    "Class {} found in {} ({}) among renamed class before delagation, use name {}",
-                    ty.__str__(),
-                    self.class.__str__(),
-                    self.id,
-                    new_ty.__str__()
-                );
-                return Some(new_ty.clone());
-            } else if self.apk().get_class(ty).is_some() {
-                debug!(
-                    "Class {} found in {} ({}) among unique classes before delagation, use name {}",
-                    ty.__str__(),
-                    self.class.__str__(),
-                    self.id,
-                    ty.__str__()
-                );
-                return Some(ty.clone());
+            if let Some(new_ty) = self.find_class(ty, class_loaders, "before delagation") {
+                return Some(new_ty);
             }
         }
-        if let Some(ref parent_id) = self.parent {
-            if let Some(parent) = class_loaders.get(parent_id) {
-                if let Some(new_ty) = parent.get_ref_new_name(ty, class_loaders) {
-                    debug!(
-                        "Class {} found by delegating to parent ({}) \
-                        of {}({}), use name {}",
-                        ty.__str__(),
-                        parent_id,
-                        self.class.__str__(),
-                        self.id,
-                        new_ty.__str__()
-                    );
-                    return Some(new_ty);
-                }
-            } else {
-                log::warn!(
-                    "Class Loader {}({}) has parent {}, but parent was not found in class loader list",
-                    self.id,
-                    self.class.__str__(),
-                    parent_id
-                );
-            }
+        if let Some(new_ty) = self.delegate_to_parent(ty, class_loaders) {
+            return Some(new_ty);
         }
         if self.class == *DELEGATE_LAST_CLASS_LOADER {
             debug!(
@@ -382,9 +900,132 @@ impl ClassLoader<'_> {
             );
             return None;
         }
+        let new_ty = self.find_class(ty, class_loaders, "after delagation");
+        if new_ty.is_none() {
+            debug!(
+                "Class {} not found by {}({})",
+                ty.__str__(),
+                self.class.__str__(),
+                self.id
+            );
+        }
+        new_ty
+    }
+
+    /// Return the new name of a type by following the steps of a delegation policy.
+    /// The class loaders delegated to by [`DelegationStep::Loader`] only search the classes
+    /// they define (and their shared libraries), to avoid delegation cycles between siblings.
+    fn get_ref_new_name_with_policy(
+        &self,
+        ty: &IdType,
+        policy: &[DelegationStep],
+        class_loaders: &HashMap<String, Self>,
+    ) -> Option<IdType> {
+        for step in policy {
+            let new_ty = match step {
+                DelegationStep::Platform if ty.is_platform_class() => {
+                    debug!("Class {} is a platform class, no renaming", ty.__str__());
+                    Some(ty.clone())
+                }
+                DelegationStep::Platform => None,
+                DelegationStep::Parent => self.delegate_to_parent(ty, class_loaders),
+                DelegationStep::Own => self.find_own_class(ty, "with the delegation policy"),
+                DelegationStep::SharedLibraries => {
+                    self.find_class_in_shared_libraries(ty, &self.shared_libraries, class_loaders)
+                }
+                DelegationStep::SharedLibrariesAfter => self.find_class_in_shared_libraries(
+                    ty,
+                    &self.shared_libraries_after,
+                    class_loaders,
+                ),
+                DelegationStep::Loader(id) => {
+                    if let Some(delegate) = class_loaders.get(id) {
+                        delegate.find_class(ty, class_loaders, "as a delegate")
+                    } else {
+                        log::warn!(
+                            "Class Loader {}({}) delegates to {} according to its policy, \
+                            but {} was not found in class loader list",
+                            self.id,
+                            self.class.__str__(),
+                            id,
+                            id
+                        );
+                        None
+                    }
+                }
+                // Expanded to the list of loaders when building the model
+                DelegationStep::LoadersOfClass(_) => None,
+            };
+            if new_ty.is_some() {
+                return new_ty;
+            }
+        }
+        debug!(
+            "Class {} not found by {}({}) with the delegation policy",
+            ty.__str__(),
+            self.class.__str__(),
+            self.id
+        );
+        None
+    }
+
+    /// Search `ty` with the parent class loader.
+    fn delegate_to_parent(
+        &self,
+        ty: &IdType,
+        class_loaders: &HashMap<String, Self>,
+    ) -> Option<IdType> {
+        let parent_id = self.parent.as_ref()?;
+        if let Some(parent) = class_loaders.get(parent_id) {
+            if let Some(new_ty) = parent.get_ref_new_name(ty, class_loaders) {
+                debug!(
+                    "Class {} found by delegating to parent ({}) \
+                    of {}({}), use name {}",
+                    ty.__str__(),
+                    parent_id,
+                    self.class.__str__(),
+                    self.id,
+                    new_ty.__str__()
+                );
+                return Some(new_ty);
+            }
+        } else {
+            log::warn!(
+                "Class Loader {}({}) has parent {}, but parent was not found in class loader list",
+                self.id,
+                self.class.__str__(),
+                parent_id
+            );
+        }
+        None
+    }
+
+    /// Model `BaseDexClassLoader.findClass()`: search `ty` in the shared libraries, then
+    /// among the classes defined by the class loader, then in the shared libraries loaded
+    /// after. `step` is only used for the logs.
+    fn find_class(
+        &self,
+        ty: &IdType,
+        class_loaders: &HashMap<String, Self>,
+        step: &str,
+    ) -> Option<IdType> {
+        if let Some(new_ty) =
+            self.find_class_in_shared_libraries(ty, &self.shared_libraries, class_loaders)
+        {
+            return Some(new_ty);
+        }
+        if let Some(new_ty) = self.find_own_class(ty, step) {
+            return Some(new_ty);
+        }
+        self.find_class_in_shared_libraries(ty, &self.shared_libraries_after, class_loaders)
+    }
+
+    /// Search `ty` among the classes defined by the class loader. `step` is only used for the
+    /// logs.
+    fn find_own_class(&self, ty: &IdType, step: &str) -> Option<IdType> {
         if let Some(new_ty) = self.renamed_classes.get(ty) {
             debug!(
-                "Class {} found in {} ({}) among renamed class after delagation, use name {}",
+                "Class {} found in {} ({}) among renamed class {step}, use name {}",
                 ty.__str__(),
                 self.class.__str__(),
                 self.id,
@@ -393,7 +1034,7 @@ impl ClassLoader<'_> {
             Some(new_ty.clone())
         } else if self.apk().get_class(ty).is_some() {
             debug!(
-                "Class {} found in {} ({}) among unique classes after delagation, use name {}",
+                "Class {} found in {} ({}) among unique classes {step}, use name {}",
                 ty.__str__(),
                 self.class.__str__(),
                 self.id,
@@ -401,16 +1042,43 @@ impl ClassLoader<'_> {
             );
             Some(ty.clone())
         } else {
-            debug!(
-                "Class {} not found by {}({})",
-                ty.__str__(),
-                self.class.__str__(),
-                self.id
-            );
             None
         }
     }
 
+    /// Search `ty` in `shared_libraries`, each shared library class loader resolving the class
+    /// with its own delegation model (`loadClass()`).
+    fn find_class_in_shared_libraries(
+        &self,
+        ty: &IdType,
+        shared_libraries: &[String],
+        class_loaders: &HashMap<String, Self>,
+    ) -> Option<IdType> {
+        for lib_id in shared_libraries {
+            let Some(lib) = class_loaders.get(lib_id) else {
+                log::warn!(
+                    "Class Loader {}({}) has shared library {}, but it was not found in class loader list",
+                    self.id,
+                    self.class.__str__(),
+                    lib_id
+                );
+                continue;
+            };
+            if let Some(new_ty) = lib.get_ref_new_name(ty, class_loaders) {
+                debug!(
+                    "Class {} found in shared library ({}) of {}({}), use name {}",
+                    ty.__str__(),
+                    lib_id,
+                    self.class.__str__(),
+                    self.id,
+                    new_ty.__str__()
+                );
+                return Some(new_ty);
+            }
+        }
+        None
+    }
+
     pub fn rename_refs(self, renamer: &mut RenameTypeVisitor) -> Result<Self> {
         Ok(Self {
             apk: match self.apk {
@@ -0,0 +1,381 @@
+//! Registration in the manifest of the components (activities, services, ...) defined by the code
+//! loaded at runtime.
+//!
+//! Plugin frameworks start components that are not declared in the manifest by launching a
+//! declared stub component and swapping the class at runtime. Static analysis tools only use the
+//! components of the manifest as entry points, so the components found in the injected code are
+//! added to the manifest: as a copy of the stub they are mapped to (keeping its intent filters,
+//! permissions, ...), or as a minimal non-exported declaration. The copies are not exported and
+//! lose the `MAIN`/`LAUNCHER` intent filters of the stub, unless
+//! [`ComponentStubs::keep_exported`] is set.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use androscalpel::{Apk, IdType};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use zip::ZipArchive;
+
+use crate::axml::{AttributeValue, BinaryXml};
+
+/// `android.R.attr.name`
+const NAME_ATTR: u32 = 0x0101_0003;
+/// `android.R.attr.exported`
+const EXPORTED_ATTR: u32 = 0x0101_0010;
+/// `android.R.attr.authorities`
+const AUTHORITIES_ATTR: u32 = 0x0101_0018;
+
+/// The action of the intent filters of the entry points of the application.
+const ACTION_MAIN: &str = "android.intent.action.MAIN";
+/// The category of the intent filters of the activities shown in the launcher.
+const CATEGORY_LAUNCHER: &str = "android.intent.category.LAUNCHER";
+
+/// The path of the manifest in an apk.
+pub const MANIFEST_PATH: &str = "AndroidManifest.xml";
+
+/// The kinds of components of an application.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentKind {
+    Activity,
+    Service,
+    Receiver,
+    Provider,
+}
+
+impl ComponentKind {
+    /// The name of the manifest element declaring the component.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Self::Activity => "activity",
+            Self::Service => "service",
+            Self::Receiver => "receiver",
+            Self::Provider => "provider",
+        }
+    }
+
+    /// Get the kind of component of the subclasses of a platform class.
+    fn from_platform_class(class: &str) -> Option<Self> {
+        match class {
+            "Landroid/app/Activity;"
+            | "Landroid/app/ActivityGroup;"
+            | "Landroid/app/AliasActivity;"
+            | "Landroid/app/ExpandableListActivity;"
+            | "Landroid/app/LauncherActivity;"
+            | "Landroid/app/ListActivity;"
+            | "Landroid/app/NativeActivity;"
+            | "Landroid/app/TabActivity;"
+            | "Landroid/preference/PreferenceActivity;" => Some(Self::Activity),
+            "Landroid/app/Service;"
+            | "Landroid/app/IntentService;"
+            | "Landroid/app/job/JobService;"
+            | "Landroid/accessibilityservice/AccessibilityService;"
+            | "Landroid/inputmethodservice/InputMethodService;"
+            | "Landroid/service/notification/NotificationListenerService;"
+            | "Landroid/service/wallpaper/WallpaperService;" => Some(Self::Service),
+            "Landroid/content/BroadcastReceiver;"
+            | "Landroid/app/admin/DeviceAdminReceiver;"
+            | "Landroid/appwidget/AppWidgetProvider;" => Some(Self::Receiver),
+            "Landroid/content/ContentProvider;"
+            | "Landroid/content/SearchRecentSuggestionsProvider;" => Some(Self::Provider),
+            _ => None,
+        }
+    }
+}
+
+/// A component added to the manifest.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct RegisteredComponent {
+    /// The java name of the class of the component.
+    pub class: String,
+    pub kind: ComponentKind,
+    /// The stub component copied to declare the component, if any.
+    pub stub: Option<String>,
+}
+
+/// The stub components used to start the components loaded at runtime: a JSON object mapping
+/// the java name of a component to the name of the stub component declared in the manifest.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ComponentStubs {
+    pub stubs: HashMap<String, String>,
+    /// Keep the `exported` attribute and the `MAIN`/`LAUNCHER` intent filters of the stubs in
+    /// their copies.
+    pub keep_exported: bool,
+}
+
+impl ComponentStubs {
+    pub fn load(input: impl Read) -> Result<Self> {
+        let stubs: HashMap<String, String> =
+            serde_json::from_reader(input).context("Invalid component stub file")?;
+        Ok(Self {
+            stubs: stubs
+                .into_iter()
+                .map(|(component, stub)| (java_name(&component), java_name(&stub)))
+                .collect(),
+            keep_exported: false,
+        })
+    }
+}
+
+/// Remove the intent filters of the element starting at `node` that make it an entry point of
+/// the application (action `MAIN` or category `LAUNCHER`).
+fn remove_launcher_filters(xml: &mut BinaryXml, node: usize) -> Result<()> {
+    let filters: Vec<usize> = xml
+        .children(node)
+        .into_iter()
+        .filter(|filter| xml.element_name(*filter) == Some("intent-filter"))
+        .filter(|filter| {
+            xml.children(*filter).into_iter().any(|child| {
+                let name = xml.attribute_string(child, "name", Some(NAME_ATTR));
+                match xml.element_name(child) {
+                    Some("action") => name == Some(ACTION_MAIN),
+                    Some("category") => name == Some(CATEGORY_LAUNCHER),
+                    _ => false,
+                }
+            })
+        })
+        .collect();
+    // Remove the last ones first, so that the indices of the others stay valid
+    for filter in filters.into_iter().rev() {
+        xml.remove_element(filter)?;
+    }
+    Ok(())
+}
+
+/// Get the java name of a class from its java name or descriptor.
+fn java_name(name: &str) -> String {
+    name.strip_prefix('L')
+        .and_then(|name| name.strip_suffix(';'))
+        .unwrap_or(name)
+        .replace('/', ".")
+}
+
+/// Find the (non abstract) components among `classes`.
+pub fn find_components(apk: &Apk, classes: &[IdType]) -> Vec<(IdType, ComponentKind)> {
+    let mut components = vec![];
+    for cls in classes {
+        let Some(class) = apk.get_class(cls) else {
+            continue;
+        };
+        if class.is_abstract || class.is_interface {
+            continue;
+        }
+        let mut visited = HashSet::new();
+        let mut superclass = class.superclass.clone();
+        while let Some(current) = superclass {
+            if let Some(kind) = ComponentKind::from_platform_class(&current.__str__()) {
+                debug!("{} is a {}", cls.__str__(), kind.tag());
+                components.push((cls.clone(), kind));
+                break;
+            }
+            if !visited.insert(current.clone()) {
+                break;
+            }
+            superclass = apk
+                .get_class(&current)
+                .and_then(|class| class.superclass.clone());
+        }
+    }
+    components.sort();
+    components
+}
+
+/// Read the manifest of an apk.
+pub fn read_manifest(apk: impl AsRef<Path>) -> Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(
+        File::open(apk.as_ref())
+            .with_context(|| format!("Failed to open {}", apk.as_ref().display()))?,
+    )?;
+    let mut manifest = vec![];
+    archive
+        .by_name(MANIFEST_PATH)
+        .with_context(|| format!("No {MANIFEST_PATH} in {}", apk.as_ref().display()))?
+        .read_to_end(&mut manifest)?;
+    Ok(manifest)
+}
+
+/// Add the `components` not already declared in the binary `manifest`, and return the new
+/// manifest with the list of the components added.
+pub fn register_components(
+    manifest: &[u8],
+    components: &[(IdType, ComponentKind)],
+    stubs: &ComponentStubs,
+) -> Result<(Vec<u8>, Vec<RegisteredComponent>)> {
+    let mut xml = BinaryXml::parse(manifest).context("Failed to parse the manifest")?;
+    // Adding an attribute name shifts the string indices, so do it before anything else
+    xml.attribute_name_index("name", NAME_ATTR);
+    xml.attribute_name_index("exported", EXPORTED_ATTR);
+    xml.attribute_name_index("authorities", AUTHORITIES_ATTR);
+
+    let Some(root) = xml
+        .root()
+        .filter(|root| xml.element_name(*root) == Some("manifest"))
+    else {
+        bail!("The manifest does not start with a <manifest> element");
+    };
+    let package = xml
+        .attribute_string(root, "package", None)
+        .unwrap_or_default()
+        .to_string();
+    let Some(application) = xml
+        .children(root)
+        .into_iter()
+        .find(|node| xml.element_name(*node) == Some("application"))
+    else {
+        bail!("No <application> element in the manifest");
+    };
+
+    // The components already declared, by java name
+    let mut declared: HashMap<String, (usize, String)> = HashMap::new();
+    for node in xml.children(application) {
+        let Some(tag) = xml.element_name(node) else {
+            continue;
+        };
+        if !matches!(
+            tag,
+            "activity" | "activity-alias" | "service" | "receiver" | "provider"
+        ) {
+            continue;
+        }
+        let Some(name) = xml.attribute_string(node, "name", Some(NAME_ATTR)) else {
+            continue;
+        };
+        let name = if name.starts_with('.') {
+            format!("{package}{name}")
+        } else if !name.contains('.') {
+            format!("{package}.{name}")
+        } else {
+            name.to_string()
+        };
+        declared.insert(name, (node, tag.to_string()));
+    }
+
+    let mut registered = vec![];
+    for (cls, kind) in components {
+        let class = java_name(&cls.__str__());
+        if declared.contains_key(&class) {
+            debug!("{class} is already declared in the manifest");
+            continue;
+        }
+        let stub = stubs
+            .stubs
+            .get(&class)
+            .and_then(|stub| match declared.get(stub) {
+                Some((node, tag)) if tag == kind.tag() => Some((stub.clone(), *node)),
+                Some((_, tag)) => {
+                    warn!(
+                        "The stub {stub} of {class} is declared as an {tag}, not as an {}",
+                        kind.tag()
+                    );
+                    None
+                }
+                None => {
+                    warn!("The stub {stub} of {class} is not declared in the manifest");
+                    None
+                }
+            });
+        let stub = match stub {
+            Some((stub, stub_node)) => {
+                let node = xml.append_copy(application, stub_node)?;
+                xml.set_attribute_string(node, NAME_ATTR, &class);
+                if *kind == ComponentKind::Provider {
+                    // The authorities of a provider must be unique
+                    xml.set_attribute_string(node, AUTHORITIES_ATTR, &class);
+                }
+                if !stubs.keep_exported {
+                    remove_launcher_filters(&mut xml, node)?;
+                    xml.set_attribute_bool(node, "exported", EXPORTED_ATTR, false)?;
+                }
+                Some(stub)
+            }
+            None => {
+                let mut attributes = vec![
+                    ("name", NAME_ATTR, AttributeValue::String(class.clone())),
+                    ("exported", EXPORTED_ATTR, AttributeValue::Boolean(false)),
+                ];
+                if *kind == ComponentKind::Provider {
+                    attributes.push((
+                        "authorities",
+                        AUTHORITIES_ATTR,
+                        AttributeValue::String(class.clone()),
+                    ));
+                }
+                xml.append_element(application, kind.tag(), &attributes)?;
+                None
+            }
+        };
+        info!(
+            "Register {} {class} in the manifest{}",
+            kind.tag(),
+            stub.as_ref()
+                .map(|stub| format!(" (copy of {stub})"))
+                .unwrap_or_default()
+        );
+        registered.push(RegisteredComponent {
+            class,
+            kind: *kind,
+            stub,
+        });
+    }
+    Ok((xml.write(), registered))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::axml::tests::demo_manifest;
+
+    fn register_plugin(keep_exported: bool) -> (BinaryXml, usize) {
+        let stubs = ComponentStubs {
+            stubs: [(
+                "com.example.Plugin".to_string(),
+                "com.example.theseus.MainActivity".to_string(),
+            )]
+            .into(),
+            keep_exported,
+        };
+        let components = [(
+            IdType::from_smali("Lcom/example/Plugin;").unwrap(),
+            ComponentKind::Activity,
+        )];
+        let (manifest, registered) =
+            register_components(&demo_manifest(true), &components, &stubs).unwrap();
+        assert_eq!(registered.len(), 1);
+        assert_eq!(
+            registered[0].stub.as_deref(),
+            Some("com.example.theseus.MainActivity")
+        );
+        let xml = BinaryXml::parse(&manifest).unwrap();
+        let application = xml.children(xml.root().unwrap())[1];
+        let copy = xml.children(application)[1];
+        assert_eq!(
+            xml.attribute_string(copy, "name", Some(NAME_ATTR)),
+            Some("com.example.Plugin")
+        );
+        (xml, copy)
+    }
+
+    #[test]
+    fn stub_copy_is_not_an_entry_point() {
+        let (xml, copy) = register_plugin(false);
+        assert!(xml.children(copy).is_empty());
+        assert_eq!(
+            xml.attribute_bool(copy, "exported", Some(EXPORTED_ATTR)),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn stub_copy_keeps_exported() {
+        let (xml, copy) = register_plugin(true);
+        assert_eq!(xml.children(copy).len(), 1);
+        assert_eq!(
+            xml.attribute_bool(copy, "exported", Some(EXPORTED_ATTR)),
+            Some(true)
+        );
+    }
+}
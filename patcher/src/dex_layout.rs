@@ -0,0 +1,331 @@
+//! Placement of the classes in the dex files of the patched application.
+//!
+//! `Apk::redistribute_classes()` moves classes between dex files without knowing which classes
+//! must stay in `classes.dex`: multidex applications running on Android versions older than 5.0
+//! only load the main dex file before `MultiDex.install()` runs. [`layout_dex_files`] keeps
+//! the classes of the original application in their original dex file, and places the classes
+//! added by the patcher (the code loaded at runtime and the generated class) in new dex files,
+//! each under the limit of 65536 method, field and type references of the dex format.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use androscalpel::{Apk, Class, IdField, IdMethod, IdType, VisitorMut};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use zip::ZipArchive;
+
+use crate::report::DexFileReport;
+
+/// The maximum number of method, field or type references of a dex file.
+pub const MAX_REFS: usize = 0x10000;
+
+/// Size of the header of a dex file.
+const DEX_HEADER_SIZE: usize = 0x70;
+/// Offsets of the `(size, offset)` pairs of the header of a dex file.
+const STRING_IDS_OFF: usize = 0x38;
+const TYPE_IDS_OFF: usize = 0x40;
+const PROTO_IDS_OFF: usize = 0x48;
+const FIELD_IDS_OFF: usize = 0x50;
+const METHOD_IDS_OFF: usize = 0x58;
+const CLASS_DEFS_OFF: usize = 0x60;
+/// Size of a `class_def_item`.
+const CLASS_DEF_SIZE: usize = 0x20;
+
+#[derive(ValueEnum, Debug, PartialEq, Clone, Copy, Default)]
+pub enum DexLayoutStrategy {
+    /// Let androscalpel redistribute the classes between the dex files.
+    #[default]
+    Redistribute,
+    /// Keep the classes of the application in their original dex file (`classes.dex` being the
+    /// main dex), and put the classes added by the patcher in new dex files.
+    KeepOriginal,
+}
+
+/// The name of the `i`-th dex file of an apk.
+fn dex_name(i: usize) -> String {
+    if i == 0 {
+        "classes.dex".into()
+    } else {
+        format!("classes{}.dex", i + 1)
+    }
+}
+
+/// The dex files of the original application, and the classes they define.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct OriginalDexLayout {
+    /// The names of the dex files, `classes.dex`, `classes2.dex`, ...
+    pub dex_files: Vec<String>,
+    /// The index in `dex_files` of the dex file defining each class.
+    pub classes: HashMap<IdType, usize>,
+}
+
+impl OriginalDexLayout {
+    /// Read the layout of the dex files of the apk at `path`.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut archive = ZipArchive::new(
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+        )?;
+        let mut layout = Self::default();
+        loop {
+            let name = dex_name(layout.dex_files.len());
+            let Ok(mut file) = archive.by_name(&name) else {
+                break;
+            };
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            for descriptor in read_class_descriptors(&data)
+                .with_context(|| format!("Failed to read the classes of {name}"))?
+            {
+                match IdType::from_smali(&descriptor) {
+                    Ok(ty) => {
+                        layout.classes.entry(ty).or_insert(layout.dex_files.len());
+                    }
+                    Err(err) => warn!("Invalid class descriptor {descriptor:?} in {name}: {err}"),
+                }
+            }
+            layout.dex_files.push(name);
+        }
+        Ok(layout)
+    }
+}
+
+/// List the dex files of the apk at `path`, without reading them.
+pub fn original_dex_files(path: impl AsRef<Path>) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let archive = ZipArchive::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    )?;
+    let mut dex_files = vec![];
+    while archive.index_for_name(&dex_name(dex_files.len())).is_some() {
+        dex_files.push(dex_name(dex_files.len()));
+    }
+    Ok(dex_files)
+}
+
+fn read_u32(data: &[u8], off: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(
+        data.get(off..off + 4)
+            .with_context(|| format!("Offset 0x{off:x} out of the dex file"))?
+            .try_into()
+            .unwrap(),
+    ))
+}
+
+fn read_uleb128(data: &[u8], off: &mut usize) -> Result<u32> {
+    let mut result = 0;
+    for i in 0..5 {
+        let byte = *data.get(*off).context("Truncated uleb128")?;
+        *off += 1;
+        result |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+/// Decode the MUTF-8 string at `off`.
+fn read_mutf8(data: &[u8], mut off: usize) -> Result<String> {
+    let mut utf16 = vec![];
+    loop {
+        let b0 = *data.get(off).context("Truncated string")? as u16;
+        if b0 == 0 {
+            break;
+        }
+        let (unit, len) = if b0 & 0x80 == 0 {
+            (b0, 1)
+        } else if b0 & 0xe0 == 0xc0 {
+            let b1 = *data.get(off + 1).context("Truncated string")? as u16;
+            (((b0 & 0x1f) << 6) | (b1 & 0x3f), 2)
+        } else {
+            let b1 = *data.get(off + 1).context("Truncated string")? as u16;
+            let b2 = *data.get(off + 2).context("Truncated string")? as u16;
+            (((b0 & 0x0f) << 12) | ((b1 & 0x3f) << 6) | (b2 & 0x3f), 3)
+        };
+        utf16.push(unit);
+        off += len;
+    }
+    Ok(String::from_utf16_lossy(&utf16))
+}
+
+/// Read the descriptors of the classes defined in a dex file.
+fn read_class_descriptors(data: &[u8]) -> Result<Vec<String>> {
+    if data.len() < DEX_HEADER_SIZE || !data.starts_with(b"dex\n") {
+        bail!("Not a dex file");
+    }
+    let string_ids_off = read_u32(data, STRING_IDS_OFF + 4)? as usize;
+    let type_ids_off = read_u32(data, TYPE_IDS_OFF + 4)? as usize;
+    let class_defs_size = read_u32(data, CLASS_DEFS_OFF)? as usize;
+    let class_defs_off = read_u32(data, CLASS_DEFS_OFF + 4)? as usize;
+    let mut descriptors = vec![];
+    for i in 0..class_defs_size {
+        let type_idx = read_u32(data, class_defs_off + i * CLASS_DEF_SIZE)? as usize;
+        let string_idx = read_u32(data, type_ids_off + type_idx * 4)? as usize;
+        let mut off = read_u32(data, string_ids_off + string_idx * 4)? as usize;
+        read_uleb128(data, &mut off)?; // utf16_size
+        descriptors.push(read_mutf8(data, off)?);
+    }
+    Ok(descriptors)
+}
+
+/// Get the number of references and classes of a generated dex file from its header.
+pub fn dex_file_report(name: &str, data: &[u8], generated: Option<bool>) -> Result<DexFileReport> {
+    if data.len() < DEX_HEADER_SIZE {
+        bail!("{name} is too short to be a dex file");
+    }
+    Ok(DexFileReport {
+        name: name.into(),
+        generated,
+        classes: read_u32(data, CLASS_DEFS_OFF)?,
+        string_ids: read_u32(data, STRING_IDS_OFF)?,
+        type_ids: read_u32(data, TYPE_IDS_OFF)?,
+        proto_ids: read_u32(data, PROTO_IDS_OFF)?,
+        field_ids: read_u32(data, FIELD_IDS_OFF)?,
+        method_ids: read_u32(data, METHOD_IDS_OFF)?,
+    })
+}
+
+/// The method, field and type references of a set of classes.
+#[derive(Default)]
+struct References {
+    methods: HashSet<IdMethod>,
+    fields: HashSet<IdField>,
+    types: HashSet<IdType>,
+}
+
+impl VisitorMut for References {
+    fn visit_type(&mut self, id: IdType) -> Result<IdType> {
+        self.types.insert(id.clone());
+        Ok(id)
+    }
+
+    fn visit_method_id(&mut self, id: IdMethod) -> Result<IdMethod> {
+        self.types.insert(id.class_.clone());
+        self.types.insert(id.proto.get_return_type());
+        self.types.extend(id.proto.get_parameters());
+        self.methods.insert(id.clone());
+        Ok(id)
+    }
+
+    fn visit_field_id(&mut self, id: IdField) -> Result<IdField> {
+        self.types.insert(id.class_.clone());
+        self.types.insert(id.type_.clone());
+        self.fields.insert(id.clone());
+        Ok(id)
+    }
+}
+
+impl References {
+    fn of(class: &Class) -> Result<Self> {
+        let mut refs = Self::default();
+        refs.visit_class(class.clone())?;
+        Ok(refs)
+    }
+
+    /// If the references of `other` can be added without exceeding [`MAX_REFS`].
+    fn fits(&self, other: &Self) -> bool {
+        fn union_len<T: Eq + std::hash::Hash>(a: &HashSet<T>, b: &HashSet<T>) -> usize {
+            a.len() + b.iter().filter(|v| !a.contains(v)).count()
+        }
+        union_len(&self.methods, &other.methods) <= MAX_REFS
+            && union_len(&self.fields, &other.fields) <= MAX_REFS
+            && union_len(&self.types, &other.types) <= MAX_REFS
+    }
+
+    fn add(&mut self, other: Self) {
+        self.methods.extend(other.methods);
+        self.fields.extend(other.fields);
+        self.types.extend(other.types);
+    }
+}
+
+/// Place the classes of `apk` in its dex files: the classes of the original application stay
+/// in the dex file that defined them in `original`, the other classes go in new dex files.
+///
+/// The classes of an original dex file other than `classes.dex` that no longer fit in it are
+/// moved to the new dex files, the classes of `classes.dex` are never moved. Returns the names
+/// of the new dex files.
+pub fn layout_dex_files(apk: &mut Apk, original: &OriginalDexLayout) -> Result<Vec<String>> {
+    let mut classes: Vec<_> = apk.list_classes().into_iter().collect();
+    classes.sort();
+    let mut original_dex: Vec<Vec<(Class, References)>> =
+        original.dex_files.iter().map(|_| vec![]).collect();
+    let mut added = vec![];
+    for cls in classes {
+        let class = apk
+            .remove_class(&cls, None)?
+            .with_context(|| format!("Class {} not found", cls.__str__()))?;
+        let refs = References::of(&class)
+            .with_context(|| format!("Failed to count the references of {}", cls.__str__()))?;
+        match original.classes.get(&cls) {
+            Some(i) => original_dex[*i].push((class, refs)),
+            None => added.push((class, refs)),
+        }
+    }
+
+    let mut placed: Vec<(String, Class)> = vec![];
+    let mut moved = vec![];
+    for (i, classes) in original_dex.into_iter().enumerate() {
+        let name = &original.dex_files[i];
+        let mut dex_refs = References::default();
+        for (class, refs) in classes {
+            if dex_refs.fits(&refs) {
+                dex_refs.add(refs);
+                placed.push((name.clone(), class));
+            } else if i == 0 {
+                bail!(
+                    "The classes of the main dex {name} exceed the limit of {MAX_REFS} \
+                    references, {} cannot be kept in it",
+                    class.descriptor.__str__()
+                );
+            } else {
+                warn!(
+                    "{name} exceeds the limit of {MAX_REFS} references, move {} to a new dex \
+                    file",
+                    class.descriptor.__str__()
+                );
+                moved.push((class, refs));
+            }
+        }
+        debug!(
+            "{name}: {} methods, {} fields, {} types referenced",
+            dex_refs.methods.len(),
+            dex_refs.fields.len(),
+            dex_refs.types.len()
+        );
+    }
+
+    let mut new_dex_files: Vec<String> = vec![];
+    let mut dex_refs = References::default();
+    for (class, refs) in added.into_iter().chain(moved) {
+        if new_dex_files.is_empty() || !dex_refs.fits(&refs) {
+            if !References::default().fits(&refs) {
+                bail!(
+                    "{} alone exceeds the limit of {MAX_REFS} references",
+                    class.descriptor.__str__()
+                );
+            }
+            new_dex_files.push(dex_name(original.dex_files.len() + new_dex_files.len()));
+            dex_refs = References::default();
+        }
+        dex_refs.add(refs);
+        placed.push((new_dex_files.last().unwrap().clone(), class));
+    }
+    for (name, class) in placed {
+        apk.add_class(&name, class)?;
+    }
+    info!(
+        "Classes added by the patcher placed in {}",
+        if new_dex_files.is_empty() {
+            "no new dex file".into()
+        } else {
+            new_dex_files.join(", ")
+        }
+    );
+    Ok(new_dex_files)
+}
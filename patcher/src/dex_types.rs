@@ -1,7 +1,10 @@
 use androscalpel::{IdField, IdMethod, IdType};
 use anyhow::{bail, Result};
+use std::collections::HashSet;
 use std::sync::LazyLock;
 
+use crate::runtime_data::KotlinReflectionEntry;
+
 pub(crate) static MTH_INVOKE: LazyLock<IdMethod> = LazyLock::new(|| {
     IdMethod::from_smali(
     "Ljava/lang/reflect/Method;->invoke(Ljava/lang/Object;[Ljava/lang/Object;)Ljava/lang/Object;",
@@ -25,6 +28,18 @@ pub(crate) static MTH_GET_DEC_CLS: LazyLock<IdMethod> = LazyLock::new(|| {
 pub(crate) static STR_EQ: LazyLock<IdMethod> = LazyLock::new(|| {
     IdMethod::from_smali("Ljava/lang/String;->equals(Ljava/lang/Object;)Z").unwrap()
 });
+pub(crate) static STR_CONCAT: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Ljava/lang/String;->concat(Ljava/lang/String;)Ljava/lang/String;")
+        .unwrap()
+});
+pub(crate) static STR_HASH_CODE: LazyLock<IdMethod> =
+    LazyLock::new(|| IdMethod::from_smali("Ljava/lang/String;->hashCode()I").unwrap());
+pub(crate) static SYSTEM_LOAD: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Ljava/lang/System;->load(Ljava/lang/String;)V").unwrap()
+});
+pub(crate) static SYSTEM_LOAD_LIBRARY: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Ljava/lang/System;->loadLibrary(Ljava/lang/String;)V").unwrap()
+});
 pub(crate) static CLASS_NEW_INST: LazyLock<IdMethod> = LazyLock::new(|| {
     IdMethod::from_smali("Ljava/lang/Class;->newInstance()Ljava/lang/Object;").unwrap()
 });
@@ -99,13 +114,27 @@ pub(crate) static SCAL_TO_OBJ_FLOAT: LazyLock<IdMethod> = LazyLock::new(|| {
 pub(crate) static SCAL_TO_OBJ_DOUBLE: LazyLock<IdMethod> = LazyLock::new(|| {
     IdMethod::from_smali("Ljava/lang/Double;->valueOf(D)Ljava/lang/Double;").unwrap()
 });
+pub(crate) static METHOD_TYPE_OF: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali(
+        "Ljava/lang/invoke/MethodType;->methodType(Ljava/lang/Class;[Ljava/lang/Class;)Ljava/lang/invoke/MethodType;",
+    )
+    .unwrap()
+});
+pub(crate) static METHOD_TYPE_DESCRIPTOR: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali(
+        "Ljava/lang/invoke/MethodType;->toMethodDescriptorString()Ljava/lang/String;",
+    )
+    .unwrap()
+});
 pub(crate) static _GET_CLASS_LOADER: LazyLock<IdMethod> = LazyLock::new(|| {
     IdMethod::from_smali("Ljava/lang/Class;->getClassLoader()Ljava/lang/ClassLoader;").unwrap()
 });
+pub(crate) static BUFFER_REMAINING: LazyLock<IdMethod> =
+    LazyLock::new(|| IdMethod::from_smali("Ljava/nio/Buffer;->remaining()I").unwrap());
 pub(crate) static _GET_PARENT: LazyLock<IdMethod> = LazyLock::new(|| {
     IdMethod::from_smali("Ljava/lang/ClassLoader;->getParent()Ljava/lang/ClassLoader;").unwrap()
 });
-pub(crate) static _GET_CLASS: LazyLock<IdMethod> = LazyLock::new(|| {
+pub(crate) static GET_CLASS: LazyLock<IdMethod> = LazyLock::new(|| {
     IdMethod::from_smali("Ljava/lang/Object;->getClass()Ljava/lang/Class;").unwrap()
 });
 pub(crate) static _TO_STRING: LazyLock<IdMethod> = LazyLock::new(|| {
@@ -116,8 +145,109 @@ pub(crate) static _BOOT_CLASS_LOADER_TY: LazyLock<IdType> =
     LazyLock::new(|| IdType::from_smali("Ljava/lang/BootClassLoader;").unwrap());
 pub(crate) static OBJECT_TY: LazyLock<IdType> =
     LazyLock::new(|| IdType::from_smali("Ljava/lang/Object;").unwrap());
+pub(crate) static STRING_TY: LazyLock<IdType> =
+    LazyLock::new(|| IdType::from_smali("Ljava/lang/String;").unwrap());
+pub(crate) static BYTE_BUFFER_TY: LazyLock<IdType> =
+    LazyLock::new(|| IdType::from_smali("Ljava/nio/ByteBuffer;").unwrap());
 pub(crate) static DELEGATE_LAST_CLASS_LOADER: LazyLock<IdType> =
     LazyLock::new(|| IdType::from_smali("Ldalvik/system/DelegateLastClassLoader;").unwrap());
+pub(crate) static BASE_DEX_CLASS_LOADER: LazyLock<IdType> =
+    LazyLock::new(|| IdType::from_smali("Ldalvik/system/BaseDexClassLoader;").unwrap());
+/// The class loaders of the SDK that load code from dex or zip files or from memory.
+pub(crate) static DEX_CLASS_LOADERS: LazyLock<HashSet<IdType>> = LazyLock::new(|| {
+    [
+        "Ldalvik/system/DexClassLoader;",
+        "Ldalvik/system/PathClassLoader;",
+        "Ldalvik/system/InMemoryDexClassLoader;",
+        "Ldalvik/system/DelegateLastClassLoader;",
+    ]
+    .into_iter()
+    .map(|ty| IdType::from_smali(ty).unwrap())
+    .collect()
+});
+pub(crate) static CL_LOAD_CLASS: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Ljava/lang/ClassLoader;->loadClass(Ljava/lang/String;)Ljava/lang/Class;")
+        .unwrap()
+});
+pub(crate) static ERROR_TY: LazyLock<IdType> =
+    LazyLock::new(|| IdType::from_smali("Ljava/lang/Error;").unwrap());
+pub(crate) static ERROR_INIT: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Ljava/lang/Error;-><init>(Ljava/lang/String;)V").unwrap()
+});
+pub(crate) static LINKAGE_ERROR_TY: LazyLock<IdType> =
+    LazyLock::new(|| IdType::from_smali("Ljava/lang/LinkageError;").unwrap());
+pub(crate) static ITERATOR_NEXT: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Ljava/util/Iterator;->next()Ljava/lang/Object;").unwrap()
+});
+pub(crate) static ARRAY_NEW_INST: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali(
+        "Ljava/lang/reflect/Array;->newInstance(Ljava/lang/Class;I)Ljava/lang/Object;",
+    )
+    .unwrap()
+});
+pub(crate) static KCALLABLE_CALL: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Lkotlin/reflect/KCallable;->call([Ljava/lang/Object;)Ljava/lang/Object;")
+        .unwrap()
+});
+pub(crate) static KCALLABLE_CALL_BY: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Lkotlin/reflect/KCallable;->callBy(Ljava/util/Map;)Ljava/lang/Object;")
+        .unwrap()
+});
+pub(crate) static KCALLABLE_GET_PARAMS: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Lkotlin/reflect/KCallable;->getParameters()Ljava/util/List;").unwrap()
+});
+pub(crate) static KCLASS_CREATE_INSTANCE: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali(
+        "Lkotlin/reflect/full/KClasses;->createInstance(Lkotlin/reflect/KClass;)Ljava/lang/Object;",
+    )
+    .unwrap()
+});
+pub(crate) static KCLASS_GET_JAVA_CLASS: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali(
+        "Lkotlin/jvm/JvmClassMappingKt;->getJavaClass(Lkotlin/reflect/KClass;)Ljava/lang/Class;",
+    )
+    .unwrap()
+});
+pub(crate) static KFUNCTION_GET_JAVA_METHOD: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali(
+        "Lkotlin/reflect/jvm/ReflectJvmMapping;->getJavaMethod(Lkotlin/reflect/KFunction;)Ljava/lang/reflect/Method;",
+    )
+    .unwrap()
+});
+pub(crate) static KFUNCTION_GET_JAVA_CNSTR: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali(
+        "Lkotlin/reflect/jvm/ReflectJvmMapping;->getJavaConstructor(Lkotlin/reflect/KFunction;)Ljava/lang/reflect/Constructor;",
+    )
+    .unwrap()
+});
+pub(crate) static KFUNCTION_TY: LazyLock<IdType> =
+    LazyLock::new(|| IdType::from_smali("Lkotlin/reflect/KFunction;").unwrap());
+pub(crate) static LIST_SIZE: LazyLock<IdMethod> =
+    LazyLock::new(|| IdMethod::from_smali("Ljava/util/List;->size()I").unwrap());
+pub(crate) static LIST_GET: LazyLock<IdMethod> =
+    LazyLock::new(|| IdMethod::from_smali("Ljava/util/List;->get(I)Ljava/lang/Object;").unwrap());
+pub(crate) static MAP_GET: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Ljava/util/Map;->get(Ljava/lang/Object;)Ljava/lang/Object;").unwrap()
+});
+pub(crate) static MAP_CONTAINS_KEY: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Ljava/util/Map;->containsKey(Ljava/lang/Object;)Z").unwrap()
+});
+pub(crate) static ARRAY_LIST_TY: LazyLock<IdType> =
+    LazyLock::new(|| IdType::from_smali("Ljava/util/ArrayList;").unwrap());
+pub(crate) static ARRAY_LIST_INIT: LazyLock<IdMethod> =
+    LazyLock::new(|| IdMethod::from_smali("Ljava/util/ArrayList;-><init>()V").unwrap());
+pub(crate) static ARRAY_LIST_ADD: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Ljava/util/ArrayList;->add(Ljava/lang/Object;)Z").unwrap()
+});
+pub(crate) static ARRAY_LIST_TO_ARRAY: LazyLock<IdMethod> = LazyLock::new(|| {
+    IdMethod::from_smali("Ljava/util/ArrayList;->toArray()[Ljava/lang/Object;").unwrap()
+});
+/// The annotation attached to the methods generated or modified by the patcher.
+pub(crate) static PATCHED_ANNOTATION: LazyLock<IdType> =
+    LazyLock::new(|| IdType::from_smali("Ltheseus/Patched;").unwrap());
+/// The annotation attached to the native methods registered with `RegisterNatives()`.
+pub(crate) static NATIVE_BINDING_ANNOTATION: LazyLock<IdType> =
+    LazyLock::new(|| IdType::from_smali("Ltheseus/NativeBinding;").unwrap());
 
 pub(crate) static LOG_INFO: LazyLock<IdMethod> = LazyLock::new(|| {
     IdMethod::from_smali("Landroid/util/Log;->i(Ljava/lang/String;Ljava/lang/String;)I").unwrap()
@@ -146,6 +276,83 @@ pub(crate) static _APP_INFO_SOURCE_DIR: LazyLock<IdField> = LazyLock::new(|| {
         .unwrap()
 });
 
+/// The platform methods called by the code generated by the patcher. The calls to these methods
+/// are not edges of the original call graph, and should be ignored when comparing call graphs.
+pub static GLUE_METHODS: LazyLock<HashSet<IdMethod>> = LazyLock::new(|| {
+    [
+        &MTH_INVOKE,
+        &MTH_GET_NAME,
+        &MTH_GET_PARAMS_TY,
+        &MTH_GET_RET_TY,
+        &MTH_GET_DEC_CLS,
+        &STR_EQ,
+        &STR_CONCAT,
+        &STR_HASH_CODE,
+        &CLASS_NEW_INST,
+        &CNSTR_NEW_INST,
+        &CNSTR_GET_PARAMS_TY,
+        &CNSTR_GET_DEC_CLS,
+        &CLT_GET_DESCR_STRING,
+        &OBJ_TO_SCAL_BOOL,
+        &OBJ_TO_SCAL_BYTE,
+        &OBJ_TO_SCAL_SHORT,
+        &OBJ_TO_SCAL_CHAR,
+        &OBJ_TO_SCAL_INT,
+        &OBJ_TO_SCAL_LONG,
+        &OBJ_TO_SCAL_FLOAT,
+        &OBJ_TO_SCAL_DOUBLE,
+        &SCAL_TO_OBJ_BOOL,
+        &SCAL_TO_OBJ_BYTE,
+        &SCAL_TO_OBJ_SHORT,
+        &SCAL_TO_OBJ_CHAR,
+        &SCAL_TO_OBJ_INT,
+        &SCAL_TO_OBJ_LONG,
+        &SCAL_TO_OBJ_FLOAT,
+        &SCAL_TO_OBJ_DOUBLE,
+        &METHOD_TYPE_OF,
+        &METHOD_TYPE_DESCRIPTOR,
+        &_GET_CLASS_LOADER,
+        &BUFFER_REMAINING,
+        &_GET_PARENT,
+        &GET_CLASS,
+        &_TO_STRING,
+        &ERROR_INIT,
+        &LOG_INFO,
+        &KCALLABLE_GET_PARAMS,
+        &KCLASS_GET_JAVA_CLASS,
+        &KFUNCTION_GET_JAVA_METHOD,
+        &KFUNCTION_GET_JAVA_CNSTR,
+        &LIST_SIZE,
+        &LIST_GET,
+        &MAP_GET,
+        &MAP_CONTAINS_KEY,
+        &ARRAY_LIST_INIT,
+        &ARRAY_LIST_ADD,
+        &ARRAY_LIST_TO_ARRAY,
+    ]
+    .into_iter()
+    .map(|method| IdMethod::clone(method))
+    .collect()
+});
+
+/// Get the entry point of the Kotlin reflection API called by `method`, if any.
+///
+/// `call()` and `callBy()` are matched by name and prototype to also match the calls through
+/// the subinterfaces of `KCallable` (eg `KFunction`, `KFunction1`).
+pub fn kotlin_reflection_entry(method: &IdMethod) -> Option<KotlinReflectionEntry> {
+    if method == &*KCLASS_CREATE_INSTANCE {
+        Some(KotlinReflectionEntry::CreateInstance)
+    } else if !method.class_.__str__().starts_with("Lkotlin/reflect/") {
+        None
+    } else if method.name == KCALLABLE_CALL.name && method.proto == KCALLABLE_CALL.proto {
+        Some(KotlinReflectionEntry::Call)
+    } else if method.name == KCALLABLE_CALL_BY.name && method.proto == KCALLABLE_CALL_BY.proto {
+        Some(KotlinReflectionEntry::CallBy)
+    } else {
+        None
+    }
+}
+
 /// Get the method that convert a object to its scalar conterpart (eg `java.lang.Integer` to `int` with
 /// `Ljava/lang/Integer;->intValue()I`)
 ///
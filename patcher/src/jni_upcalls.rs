@@ -0,0 +1,218 @@
+//! Declaration of the Java methods called from native code.
+//!
+//! Native code calls Java methods through the JNI (`CallVoidMethod()`, `NewObject()`, ...), so
+//! these calls do not appear in the bytecode and static analysers miss the edges from the
+//! `native` methods to the methods they call. A `native` method has no code to patch, so for
+//! each `native` method with JNI upcalls in the runtime data, a companion method is added to
+//! its class: a private static method that calls each of the Java methods called by the native
+//! implementation. The companion method is never called, it only declares the calls: the
+//! call graphs of [`crate::callgraph`] link the `native` method to its companion method (see
+//! [`companion_of`]).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use androscalpel::{
+    Apk, Class, Code, DexString, IdMethod, IdMethodType, IdType, Instruction, Method,
+};
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+
+use crate::reflection_patcher::{nb_param_reg, patched_annotation};
+use crate::report::JniUpcallCompanion;
+use crate::runtime_data::RuntimeData;
+
+/// The prefix of the name of the companion methods.
+const COMPANION_PREFIX: &str = "theseus_jni_upcalls_";
+/// Register always holding `null` (or `0`).
+const REG_NULL: u8 = 0;
+/// Pair of registers always holding a wide `0`.
+const REG_WIDE: u8 = 1;
+/// First register of the arguments of the calls.
+const REG_FIRST_ARG: u8 = 3;
+
+/// The companion method declaring the JNI upcalls of `native_method`.
+pub fn companion_method(native_method: &IdMethod) -> IdMethod {
+    let mut hasher = DefaultHasher::new();
+    native_method.hash(&mut hasher);
+    IdMethod::new(
+        format!("{COMPANION_PREFIX}{:016x}", hasher.finish())
+            .as_str()
+            .into(),
+        IdMethodType::new(IdType::void(), vec![]),
+        native_method.class_.clone(),
+    )
+}
+
+/// Get the companion method of `method` if `method` is a native method of `class` with a
+/// companion method.
+pub fn companion_of(class: &Class, method: &Method) -> Option<IdMethod> {
+    if !method.is_native {
+        return None;
+    }
+    let companion = companion_method(&method.descriptor);
+    class
+        .direct_methods
+        .contains_key(&companion)
+        .then_some(companion)
+}
+
+/// Check if `method` is the companion method of a native method.
+pub fn is_companion_method(method: &IdMethod) -> bool {
+    String::try_from(&method.name).is_ok_and(|name| name.starts_with(COMPANION_PREFIX))
+}
+
+/// Add a companion method next to each `native` method of `apk` that called Java methods
+/// through the JNI, see the module documentation.
+pub fn add_jni_upcall_companions(
+    apk: &mut Apk,
+    runtime_data: &RuntimeData,
+) -> Result<Vec<JniUpcallCompanion>> {
+    // native method -> (target, is_static, is_interface)
+    let mut upcalls: BTreeMap<IdMethod, BTreeSet<(IdMethod, bool, bool)>> = BTreeMap::new();
+    for data in &runtime_data.jni_upcalls {
+        upcalls
+            .entry(data.get_static_native_method())
+            .or_default()
+            .insert((data.get_static_method(), data.is_static, data.is_interface));
+    }
+    let mut companions = vec![];
+    for (native_method, targets) in upcalls {
+        match add_companion(apk, &native_method, &targets, runtime_data) {
+            Ok(companion) => {
+                info!(
+                    "Declare {} JNI upcalls of {} in {}",
+                    targets.len(),
+                    native_method.__str__(),
+                    companion.__str__()
+                );
+                companions.push(JniUpcallCompanion {
+                    native_method,
+                    companion,
+                    targets: targets.into_iter().map(|(target, _, _)| target).collect(),
+                });
+            }
+            Err(err) => warn!(
+                "Failed to declare the JNI upcalls of {}: {err}",
+                native_method.__str__()
+            ),
+        }
+    }
+    Ok(companions)
+}
+
+/// Generate the companion method of `native_method` and add it to its class.
+fn add_companion(
+    apk: &mut Apk,
+    native_method: &IdMethod,
+    targets: &BTreeSet<(IdMethod, bool, bool)>,
+    runtime_data: &RuntimeData,
+) -> Result<IdMethod> {
+    let descriptor = companion_method(native_method);
+    let mut insns = vec![
+        Instruction::Const {
+            reg: REG_NULL,
+            lit: 0,
+        },
+        Instruction::ConstWide {
+            reg: REG_WIDE,
+            lit: 0,
+        },
+    ];
+    let mut registers_size = REG_FIRST_ARG as u16;
+    for (target, is_static, is_interface) in targets {
+        let is_constructor = target.name == DexString::from("<init>");
+        let mut args = vec![];
+        let mut reg = REG_FIRST_ARG as u16;
+        if is_constructor {
+            insns.push(Instruction::NewInstance {
+                reg: REG_FIRST_ARG,
+                lit: target.class_.clone(),
+            });
+            args.push(reg);
+            reg += 1;
+        } else if !is_static {
+            insns.push(Instruction::MoveObject {
+                from: REG_NULL as u16,
+                to: reg,
+            });
+            args.push(reg);
+            reg += 1;
+        }
+        for param in target.proto.get_parameters() {
+            if param.is_double() || param.is_long() {
+                insns.push(Instruction::MoveWide {
+                    from: REG_WIDE as u16,
+                    to: reg,
+                });
+                args.push(reg);
+                args.push(reg + 1);
+                reg += 2;
+            } else {
+                insns.push(Instruction::Move {
+                    from: REG_NULL as u16,
+                    to: reg,
+                });
+                args.push(reg);
+                reg += 1;
+            }
+        }
+        registers_size = registers_size.max(REG_FIRST_ARG as u16 + 1 + nb_param_reg(target));
+        let is_direct = is_constructor
+            || apk
+                .get_class(&target.class_)
+                .is_some_and(|class| class.direct_methods.contains_key(target));
+        insns.push(if *is_static {
+            Instruction::InvokeStatic {
+                method: target.clone(),
+                args,
+            }
+        } else if is_direct {
+            Instruction::InvokeDirect {
+                method: target.clone(),
+                args,
+            }
+        } else if *is_interface {
+            Instruction::InvokeInterface {
+                method: target.clone(),
+                args,
+            }
+        } else {
+            Instruction::InvokeVirtual {
+                method: target.clone(),
+                args,
+            }
+        });
+    }
+    insns.push(Instruction::ReturnVoid {});
+
+    let class = apk
+        .get_class_mut(&native_method.class_)
+        .with_context(|| format!("Class {} not found", native_method.class_.__str__()))?;
+    let method = class
+        .direct_methods
+        .get(native_method)
+        .or_else(|| class.virtual_methods.get(native_method))
+        .context("Method not found")?;
+    if !method.is_native {
+        bail!("{} is not a native method", native_method.__str__());
+    }
+    if class.direct_methods.contains_key(&descriptor) {
+        bail!("{} is already defined", descriptor.__str__());
+    }
+    let mut method = Method::new(descriptor.clone());
+    method.is_static = true;
+    method.is_final = true;
+    method.is_private = true;
+    method.code = Some(Code::new(registers_size, insns, None));
+    method.annotations.push(patched_annotation(
+        vec![],
+        targets
+            .iter()
+            .map(|(target, _, _)| target.__str__())
+            .collect(),
+        runtime_data.run_id.as_deref(),
+    ));
+    class.direct_methods.insert(descriptor.clone(), method);
+    Ok(descriptor)
+}
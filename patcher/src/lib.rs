@@ -1,10 +1,26 @@
-use androscalpel::{IdMethod, Instruction};
+use androscalpel::{DexString, IdMethod, Instruction};
 
+pub mod apk_signer;
+pub mod axml;
+pub mod callgraph;
+pub mod class_loader_patcher;
+pub mod class_loader_policy;
+pub mod class_mapping;
+pub mod code_format;
 pub mod code_loading_patcher;
+pub mod components;
+pub mod dex_layout;
 pub mod dex_types;
+pub mod jni_upcalls;
+pub mod native_bindings;
+pub mod native_libs;
+pub mod platform_api;
 pub mod reflection_patcher;
 pub mod register_manipulation;
+pub mod report;
 pub mod runtime_data;
+pub mod smali_dump;
+pub mod trace;
 use dex_types::*;
 
 // TODO:
@@ -32,6 +48,36 @@ pub fn labeling(_mth: &IdMethod, ins: &Instruction, addr: usize) -> Option<Strin
         {
             Some(format!("THESEUS_ADDR_{addr:08X}"))
         }
+        Instruction::InvokeStatic { method, .. } if method == &*SYSTEM_LOAD => {
+            Some(format!("THESEUS_ADDR_{addr:08X}"))
+        }
+        // Any override of ClassLoader.loadClass(String)
+        Instruction::InvokeVirtual { method, .. }
+            if method.name == CL_LOAD_CLASS.name && method.proto == CL_LOAD_CLASS.proto =>
+        {
+            Some(format!("THESEUS_ADDR_{addr:08X}"))
+        }
+        Instruction::InvokeStatic { method, .. } if method == &*ARRAY_NEW_INST => {
+            Some(format!("THESEUS_ADDR_{addr:08X}"))
+        }
+        // The iterators of java.util.ServiceLoader instantiate the service providers
+        Instruction::InvokeInterface { method, .. } if method == &*ITERATOR_NEXT => {
+            Some(format!("THESEUS_ADDR_{addr:08X}"))
+        }
+        // The entry points of the Kotlin reflection API, called through any KCallable subinterface
+        Instruction::InvokeVirtual { method, .. }
+        | Instruction::InvokeInterface { method, .. }
+        | Instruction::InvokeStatic { method, .. }
+            if kotlin_reflection_entry(method).is_some() =>
+        {
+            Some(format!("THESEUS_ADDR_{addr:08X}"))
+        }
+        Instruction::InvokeDirect { method, .. }
+            if DEX_CLASS_LOADERS.contains(&method.class_)
+                && method.name == DexString::from("<init>") =>
+        {
+            Some(format!("THESEUS_ADDR_{addr:08X}"))
+        }
         _ => None,
     }
 }
@@ -0,0 +1,195 @@
+//! Export of the native methods registered with the JNI function `RegisterNatives()`.
+//!
+//! Obfuscated applications register the implementations of their `native` methods from
+//! `JNI_OnLoad()` instead of exporting `Java_<class>_<method>` symbols, so the analysis tools
+//! cannot map a `native` method to its implementation. The bindings recorded at runtime are
+//! attached to the `native` methods of the patched application with a `Ltheseus/NativeBinding;`
+//! annotation (visible at build time only, so it does not change the behavior of the
+//! application), and can be exported in JSON for the native analysis tools.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use androscalpel::{
+    Apk, DexAnnotation, DexAnnotationItem, DexArray, DexValue, IdMethod, SmaliName,
+};
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::dex_types::NATIVE_BINDING_ANNOTATION;
+use crate::runtime_data::{NativeBindingData, RuntimeData};
+
+/// A native method bound with `RegisterNatives()`, as exported for the native analysis tools.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct NativeBinding {
+    /// The `native` method in the patched application.
+    pub method: IdMethod,
+    /// The `native` method when it was registered, if it was renamed in the patched
+    /// application.
+    pub original_method: Option<IdMethod>,
+    /// The class of the method as passed to `RegisterNatives()` (eg `com/example/Foo`).
+    pub class: String,
+    /// The name of the method, as in `JNINativeMethod.name`.
+    pub name: String,
+    /// The signature of the method, as in `JNINativeMethod.signature` (eg `(I)V`).
+    pub signature: String,
+    /// The name of the native library containing the implementation, if known.
+    pub library: Option<String>,
+    /// The name of the symbol of the implementation, if the library exports one.
+    pub symbol: Option<String>,
+    /// The offset of the implementation in `library`.
+    pub offset: Option<usize>,
+    /// If the `Ltheseus/NativeBinding;` annotation was added to the method.
+    pub annotated: bool,
+}
+
+impl NativeBinding {
+    fn new(data: &NativeBindingData, annotated: bool) -> Result<Self> {
+        let method = data.get_static_method();
+        let smali = method.try_to_smali()?;
+        let (class, name_and_signature) = smali
+            .split_once("->")
+            .with_context(|| format!("Invalid method {smali}"))?;
+        let (name, signature) = name_and_signature.split_at(
+            name_and_signature
+                .find('(')
+                .with_context(|| format!("Invalid method {smali}"))?,
+        );
+        Ok(Self {
+            class: class
+                .strip_prefix('L')
+                .and_then(|class| class.strip_suffix(';'))
+                .unwrap_or(class)
+                .to_string(),
+            name: name.to_string(),
+            signature: signature.to_string(),
+            original_method: data.renamed_method.as_ref().map(|_| data.method.clone()),
+            method,
+            library: data.library.clone(),
+            symbol: data.symbol.clone(),
+            offset: data.offset,
+            annotated,
+        })
+    }
+}
+
+/// Add the `Ltheseus/NativeBinding;` annotation to the native methods registered with
+/// `RegisterNatives()`, and list the bindings.
+///
+/// The annotation lists the implementations of the method in the arrays `library`, `symbol`
+/// and `offset` (one entry per implementation, empty strings for the unknown values), and the
+/// id of the run that collected the bindings in `runId`.
+pub fn annotate_native_bindings(
+    apk: &mut Apk,
+    runtime_data: &RuntimeData,
+) -> Result<Vec<NativeBinding>> {
+    let mut bindings_by_method: BTreeMap<IdMethod, Vec<&NativeBindingData>> = BTreeMap::new();
+    for data in &runtime_data.native_bindings {
+        bindings_by_method
+            .entry(data.get_static_method())
+            .or_default()
+            .push(data);
+    }
+    let mut bindings = vec![];
+    for (method, records) in bindings_by_method {
+        let annotated = match annotate_method(apk, &method, &records, runtime_data) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!(
+                    "Failed to annotate the native method {}: {err}",
+                    method.__str__()
+                );
+                false
+            }
+        };
+        for data in records {
+            bindings.push(NativeBinding::new(data, annotated)?);
+        }
+    }
+    Ok(bindings)
+}
+
+/// Add the `Ltheseus/NativeBinding;` annotation to `method`.
+fn annotate_method(
+    apk: &mut Apk,
+    method: &IdMethod,
+    records: &[&NativeBindingData],
+    runtime_data: &RuntimeData,
+) -> Result<()> {
+    let class = apk
+        .get_class_mut(&method.class_)
+        .with_context(|| format!("Class {} not found", method.class_.__str__()))?;
+    let method = class
+        .direct_methods
+        .get_mut(method)
+        .or_else(|| class.virtual_methods.get_mut(method))
+        .context("Method not found")?;
+    if !method.is_native {
+        warn!(
+            "{} is bound with RegisterNatives() but is not native",
+            method.descriptor.__str__()
+        );
+    }
+    let array = |values: Vec<String>| {
+        DexValue::Array(DexArray(
+            values
+                .into_iter()
+                .map(|value| DexValue::String(value.into()))
+                .collect(),
+        ))
+    };
+    let mut elements: HashMap<_, _> = [
+        (
+            "library".into(),
+            array(
+                records
+                    .iter()
+                    .map(|data| data.library.clone().unwrap_or_default())
+                    .collect(),
+            ),
+        ),
+        (
+            "symbol".into(),
+            array(
+                records
+                    .iter()
+                    .map(|data| data.symbol.clone().unwrap_or_default())
+                    .collect(),
+            ),
+        ),
+        (
+            "offset".into(),
+            array(
+                records
+                    .iter()
+                    .map(|data| {
+                        data.offset
+                            .map(|offset| format!("0x{offset:x}"))
+                            .unwrap_or_default()
+                    })
+                    .collect(),
+            ),
+        ),
+    ]
+    .into();
+    if let Some(run_id) = runtime_data.run_id.as_deref() {
+        elements.insert("runId".into(), DexValue::String(run_id.into()));
+    }
+    method.annotations.push(DexAnnotationItem {
+        visibility_build: true,
+        visibility_runtime: false,
+        visibility_system: false,
+        annotation: DexAnnotation {
+            type_: NATIVE_BINDING_ANNOTATION.clone(),
+            elements,
+        },
+    });
+    Ok(())
+}
+
+/// Write the bindings in JSON.
+pub fn write_native_bindings(bindings: &[NativeBinding], out: impl Write) -> Result<()> {
+    serde_json::to_writer_pretty(out, bindings)?;
+    Ok(())
+}
@@ -0,0 +1,393 @@
+//! Repackaging of the native libraries loaded at runtime with `System.load()`.
+//!
+//! Apps that download or decrypt a native library load it from an absolute path, so the library
+//! is not in the apk and the native part of the application is hidden from the analyst. The
+//! libraries recorded in the runtime data are added to the apk under `lib/<abi>/`, and the
+//! `System.load(path)` call sites that loaded them now call a generated method that calls
+//! `System.loadLibrary(name)` when `path` is one of the paths recorded at this site, and falls
+//! back to `System.load(path)` otherwise.
+//!
+//! The libraries are stored uncompressed and page-aligned by the builtin signer, so that they
+//! can be loaded from the apk when the application does not extract its native libraries
+//! (`android:extractNativeLibs="false"`). The external tools may compress them, in which case
+//! the application must extract its native libraries, see [`extracts_native_libs`].
+//!
+//! The package manager selects the ABI of the application from the `lib/<abi>/` directories of
+//! the apk, so the libraries are only added under the ABIs the apk already has (or under any
+//! ABI if the apk has no native library): adding a new ABI could make the package manager drop
+//! the libraries of the application.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use androscalpel::{Apk, Code, IdMethod, IdMethodType, IdType, Instruction, Method};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zip::ZipArchive;
+
+use crate::axml::BinaryXml;
+use crate::dex_types::*;
+use crate::reflection_patcher::patched_annotation;
+use crate::report::{NativeLoadRewrite, PatchedSite};
+use crate::runtime_data::{NativeLoadData, RuntimeData};
+
+/// A native library loaded at runtime, added to the apk.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct RepackagedLibrary {
+    /// The name of the library, as passed to `System.loadLibrary()`.
+    pub name: String,
+    pub abi: String,
+    /// The path of the library in the apk (`lib/<abi>/lib<name>.so`).
+    pub entry: String,
+    /// The paths the library was loaded from at runtime.
+    pub paths: Vec<String>,
+    /// If the apk already contained this library at `entry`.
+    pub already_in_apk: bool,
+}
+
+/// The result of [`repackage_native_libraries`].
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct RepackagedNativeCode {
+    /// The content of the libraries to add to the apk, by path in the apk.
+    pub files: HashMap<String, Vec<u8>>,
+    pub libraries: Vec<RepackagedLibrary>,
+    /// The `System.load()` call sites redirected to a generated method.
+    pub rewrites: Vec<NativeLoadRewrite>,
+}
+
+/// The entries of the original apk, read on demand to compare them with the libraries.
+struct ApkEntries {
+    archive: ZipArchive<File>,
+}
+
+impl ApkEntries {
+    fn open(apk: &Path) -> Result<Self> {
+        Ok(Self {
+            archive: ZipArchive::new(
+                File::open(apk).with_context(|| format!("Failed to open {}", apk.display()))?,
+            )?,
+        })
+    }
+
+    /// The ABIs of the native libraries of the apk (the `lib/<abi>/` directories).
+    fn abis(&self) -> BTreeSet<String> {
+        self.archive
+            .file_names()
+            .filter_map(|name| {
+                let (abi, file) = name.strip_prefix("lib/")?.split_once('/')?;
+                (!file.is_empty()).then(|| abi.to_string())
+            })
+            .collect()
+    }
+
+    fn read(&mut self, name: &str) -> Result<Option<Vec<u8>>> {
+        let Ok(mut file) = self.archive.by_name(name) else {
+            return Ok(None);
+        };
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+/// Get the name of a library from its path: `name` for `.../lib<name>.so`.
+fn library_name(path: &str) -> Option<String> {
+    let file_name = path.rsplit('/').next()?;
+    let name = file_name.strip_prefix("lib")?.strip_suffix(".so")?;
+    (!name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'))
+    .then(|| name.to_string())
+}
+
+/// Add the native libraries loaded with `System.load()` to the apk, and redirect the call
+/// sites that loaded them to `System.loadLibrary()`.
+///
+/// `apk_path` is the original apk, used to avoid overwriting its libraries. The generated
+/// methods are added to `tester_methods`, to be defined in `tester_methods_class`.
+pub fn repackage_native_libraries(
+    apk: &mut Apk,
+    apk_path: impl AsRef<Path>,
+    runtime_data: &RuntimeData,
+    tester_methods_class: IdType,
+    tester_methods: &mut HashMap<(IdMethod, String), Method>,
+) -> Result<RepackagedNativeCode> {
+    let mut entries = ApkEntries::open(apk_path.as_ref())?;
+    let mut result = RepackagedNativeCode::default();
+    // (abi, hash of the library) -> index in result.libraries
+    let mut libraries: HashMap<(String, Vec<u8>), usize> = HashMap::new();
+    // Loaded file -> index in result.libraries
+    let mut library_of_file: HashMap<PathBuf, usize> = HashMap::new();
+
+    let apk_abis = entries.abis();
+    let mut records: Vec<&NativeLoadData> = runtime_data.native_loads.iter().collect();
+    records.sort();
+    for data in &records {
+        let (Some(file), Some(abi)) = (&data.file, &data.abi) else {
+            warn!(
+                "The native library {} loaded by {} was not collected or has an unknown ABI, \
+                skip it",
+                data.path,
+                data.caller_method.__str__()
+            );
+            continue;
+        };
+        if !apk_abis.is_empty() && !apk_abis.contains(abi) {
+            warn!(
+                "The native library {} is built for {abi} but the apk only has libraries for \
+                {}: adding lib/{abi}/ would change the ABI selected at install, skip it",
+                data.path,
+                apk_abis.iter().cloned().collect::<Vec<_>>().join(", ")
+            );
+            continue;
+        }
+        if let Some(index) = library_of_file.get(file) {
+            let library = &mut result.libraries[*index];
+            if !library.paths.contains(&data.path) {
+                library.paths.push(data.path.clone());
+            }
+            continue;
+        }
+        let content = std::fs::read(file)
+            .with_context(|| format!("Failed to read the native library {}", file.display()))?;
+        let hash = Sha256::digest(&content).to_vec();
+        if let Some(index) = libraries.get(&(abi.clone(), hash.clone())) {
+            library_of_file.insert(file.clone(), *index);
+            let library = &mut result.libraries[*index];
+            if !library.paths.contains(&data.path) {
+                library.paths.push(data.path.clone());
+            }
+            continue;
+        }
+
+        let base_name = library_name(&data.path).unwrap_or_else(|| {
+            format!(
+                "theseus_{}",
+                hash[..8]
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>()
+            )
+        });
+        let mut name = base_name.clone();
+        let mut i = 1;
+        let already_in_apk = loop {
+            let entry = format!("lib/{abi}/lib{name}.so");
+            if let Some(other) = result.files.get(&entry) {
+                if *other == content {
+                    break false;
+                }
+            } else {
+                match entries.read(&entry)? {
+                    None => break false,
+                    Some(other) if other == content => break true,
+                    Some(_) => (),
+                }
+            }
+            debug!("{entry} is already used by another library");
+            name = format!("{base_name}_{i}");
+            i += 1;
+        };
+        let entry = format!("lib/{abi}/lib{name}.so");
+        info!(
+            "Repackage {} as {entry}{}",
+            data.path,
+            if already_in_apk {
+                " (already in the apk)"
+            } else {
+                ""
+            }
+        );
+        if !already_in_apk {
+            result.files.insert(entry.clone(), content);
+        }
+        libraries.insert((abi.clone(), hash), result.libraries.len());
+        library_of_file.insert(file.clone(), result.libraries.len());
+        result.libraries.push(RepackagedLibrary {
+            name,
+            abi: abi.clone(),
+            entry,
+            paths: vec![data.path.clone()],
+            already_in_apk,
+        });
+    }
+
+    // The libraries loaded at each call site: (caller, label) -> path -> library name
+    let mut sites: BTreeMap<(IdMethod, String), BTreeMap<String, String>> = BTreeMap::new();
+    for data in &records {
+        let Some(index) = data
+            .file
+            .as_ref()
+            .and_then(|file| library_of_file.get(file))
+        else {
+            continue;
+        };
+        sites
+            .entry((
+                data.get_static_caller(),
+                format!("THESEUS_ADDR_{:08X}", data.addr),
+            ))
+            .or_default()
+            .insert(data.path.clone(), result.libraries[*index].name.clone());
+    }
+    for ((caller, label), libraries) in sites {
+        let helper = gen_load_native_method(
+            tester_methods_class.clone(),
+            &caller,
+            &label,
+            &libraries,
+            runtime_data,
+        )?;
+        let helper_descriptor = helper.descriptor.clone();
+        if let Err(err) = redirect_load(apk, &caller, &label, &helper_descriptor) {
+            warn!(
+                "Failed to redirect the System.load() call at {label} in {}: {err}",
+                caller.__str__()
+            );
+            continue;
+        }
+        tester_methods.insert((SYSTEM_LOAD.clone(), helper_descriptor.__str__()), helper);
+        result.rewrites.push(NativeLoadRewrite {
+            caller,
+            label,
+            helper: helper_descriptor,
+            libraries: libraries.into_values().collect(),
+        });
+    }
+    Ok(result)
+}
+
+/// `android.R.attr.extractNativeLibs`
+const EXTRACT_NATIVE_LIBS_ATTR: u32 = 0x0101_04ea;
+
+/// Check if the application of the binary `manifest` extracts its native libraries when
+/// installed (`android:extractNativeLibs`, `true` if not set). If not, the libraries must be
+/// stored uncompressed and page-aligned in the apk to be loaded.
+pub fn extracts_native_libs(manifest: &[u8]) -> Result<bool> {
+    let xml = BinaryXml::parse(manifest)?;
+    let Some(root) = xml.root() else {
+        return Ok(true);
+    };
+    Ok(xml
+        .children(root)
+        .into_iter()
+        .find(|node| xml.element_name(*node) == Some("application"))
+        .and_then(|node| {
+            xml.attribute_bool(node, "extractNativeLibs", Some(EXTRACT_NATIVE_LIBS_ATTR))
+        })
+        .unwrap_or(true))
+}
+
+/// Replace the `System.load()` call labeled `label` in `caller` by a call to `helper`.
+fn redirect_load(apk: &mut Apk, caller: &IdMethod, label: &str, helper: &IdMethod) -> Result<()> {
+    let class = apk
+        .get_class_mut(&caller.class_)
+        .with_context(|| format!("Class {} not found", caller.class_.__str__()))?;
+    let method = class
+        .direct_methods
+        .get_mut(caller)
+        .or_else(|| class.virtual_methods.get_mut(caller))
+        .context("Method not found")?;
+    let code = method.code.as_mut().context("Code not found")?;
+    let mut current_addr_label: Option<String> = None;
+    for ins in code.insns.iter_mut() {
+        match ins {
+            Instruction::Label { name } => {
+                if name.starts_with("THESEUS_ADDR_") {
+                    current_addr_label = Some(name.clone());
+                }
+            }
+            Instruction::InvokeStatic { method, .. }
+                if method == &*SYSTEM_LOAD && current_addr_label.as_deref() == Some(label) =>
+            {
+                *method = helper.clone();
+                return Ok(());
+            }
+            ins => {
+                if !ins.is_pseudo_ins() {
+                    current_addr_label = None;
+                }
+            }
+        }
+    }
+    bail!("No System.load() call labeled {label}")
+}
+
+/// Generate the method called instead of `System.load(path)`: `System.loadLibrary()` is called
+/// with the name of the library repackaged for `path`, if any.
+fn gen_load_native_method(
+    tester_methods_class: IdType,
+    caller: &IdMethod,
+    label: &str,
+    libraries: &BTreeMap<String, String>,
+    runtime_data: &RuntimeData,
+) -> Result<Method> {
+    let mut hasher = DefaultHasher::new();
+    caller.hash(&mut hasher);
+    label.hash(&mut hasher);
+    let hash = hasher.finish();
+    let descriptor = IdMethod::new(
+        format!("load_native_{hash:016x}").as_str().into(),
+        IdMethodType::new(IdType::void(), vec![IdType::class("java/lang/String")]),
+        tester_methods_class,
+    );
+    let mut method = Method::new(descriptor);
+    const REG_TMP: u8 = 0;
+    const REG_IF_RES: u8 = 1;
+    const REG_PATH: u8 = 2;
+    let mut insns = vec![];
+    for (i, (path, name)) in libraries.iter().enumerate() {
+        let next_label = format!("label_not_{i}");
+        insns.append(&mut vec![
+            Instruction::ConstString {
+                reg: REG_TMP,
+                lit: path.as_str().into(),
+            },
+            Instruction::InvokeVirtual {
+                method: STR_EQ.clone(),
+                args: vec![REG_PATH as u16, REG_TMP as u16],
+            },
+            Instruction::MoveResult { to: REG_IF_RES },
+            Instruction::IfEqZ {
+                a: REG_IF_RES,
+                label: next_label.clone(),
+            },
+            Instruction::ConstString {
+                reg: REG_TMP,
+                lit: name.as_str().into(),
+            },
+            Instruction::InvokeStatic {
+                method: SYSTEM_LOAD_LIBRARY.clone(),
+                args: vec![REG_TMP as u16],
+            },
+            Instruction::ReturnVoid {},
+            Instruction::Label { name: next_label },
+        ]);
+    }
+    insns.append(&mut vec![
+        Instruction::InvokeStatic {
+            method: SYSTEM_LOAD.clone(),
+            args: vec![REG_PATH as u16],
+        },
+        Instruction::ReturnVoid {},
+    ]);
+    method.is_static = true;
+    method.is_final = true;
+    method.code = Some(Code::new(
+        3, //registers_size, 2 reg + 1 parameter reg
+        insns,
+        Some(vec![Some("path".into())]), // parameter_names
+    ));
+    method.annotations.push(patched_annotation(
+        vec![PatchedSite::site_id(caller, label)],
+        libraries.values().cloned().collect(),
+        runtime_data.run_id.as_deref(),
+    ));
+    Ok(method)
+}
@@ -0,0 +1,170 @@
+//! Classification of the platform methods called by reflection, to decide how to patch their
+//! call sites.
+//!
+//! Applications call hidden platform APIs by reflection because the direct calls do not
+//! link: since Android 9, the non-SDK members are restricted depending on the hidden API list
+//! they belong to and on the `targetSdkVersion` of the application. Replacing the reflective
+//! call by a direct call can make the patched application fail where the original worked, so
+//! the platform targets are classified with the platform list of androscalpel (the hidden API
+//! list of the platform members and the API level where the SDK members were added), and each
+//! class of target is patched, skipped, or called through a generated helper that falls back to
+//! the reflective call if the direct call does not link. The hidden API flags of a specific
+//! platform (`hiddenapi-flags.csv`, built with the platform) can be loaded to override the
+//! platform list.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+
+use androscalpel::platform_list;
+use androscalpel::{IdMethod, SmaliName};
+use anyhow::Result;
+use clap::ValueEnum;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// The hidden API list of a platform member.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HiddenApiList {
+    /// Part of the SDK.
+    Sdk,
+    /// Not part of the SDK, accessible to all applications.
+    Unsupported,
+    /// Not part of the SDK, accessible to the applications targeting at most this API level.
+    MaxTarget(u32),
+    /// Not part of the SDK, not accessible.
+    Blocked,
+}
+
+impl HiddenApiList {
+    /// Parse a flag of `hiddenapi-flags.csv`, `None` for the flags that are not a list (eg
+    /// `lo-prio`, `core-platform-api`).
+    fn from_flag(flag: &str) -> Option<Self> {
+        match flag {
+            "sdk" | "whitelist" => Some(Self::Sdk),
+            "unsupported" | "greylist" => Some(Self::Unsupported),
+            "max-target-o" | "greylist-max-o" => Some(Self::MaxTarget(27)),
+            "max-target-p" | "greylist-max-p" => Some(Self::MaxTarget(28)),
+            "max-target-q" | "greylist-max-q" => Some(Self::MaxTarget(29)),
+            "max-target-r" | "greylist-max-r" => Some(Self::MaxTarget(30)),
+            "max-target-s" => Some(Self::MaxTarget(32)),
+            "blocked" | "blacklist" => Some(Self::Blocked),
+            _ => None,
+        }
+    }
+}
+
+/// Whether an application can call a platform member directly.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiAccess {
+    /// The member is part of the SDK.
+    Sdk,
+    /// The member is not part of the SDK but is accessible to the application.
+    Restricted,
+    /// The member is not accessible to the application.
+    Blocked,
+    /// The member is part of the SDK, but was added after the `minSdkVersion` of the
+    /// application, so it may not exist on the devices running the application.
+    NewerSdk,
+    /// The member is neither in the platform list nor in the hidden API flags loaded.
+    Unknown,
+}
+
+/// What to do with a reflective call to a platform method.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlatformApiAction {
+    /// Call the method directly at the call site.
+    #[default]
+    Patch,
+    /// Do not patch the call to this method, keep the reflective call.
+    Skip,
+    /// Call the method from a generated method that falls back to the reflective call if the
+    /// direct call does not link.
+    Helper,
+}
+
+/// The hidden API flags overriding the platform list, and the actions to take for the restricted
+/// and blocked platform methods.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct PlatformApiPolicy {
+    /// The hidden API list of the platform members loaded from `hiddenapi-flags.csv`, by smali
+    /// representation. They take precedence over the platform list.
+    pub flags: HashMap<String, HiddenApiList>,
+    /// The `minSdkVersion` of the application, if known.
+    pub min_sdk: Option<u32>,
+    /// The `targetSdkVersion` of the application, if known.
+    pub target_sdk: Option<u32>,
+    /// The action for the members accessible to the application outside of the SDK, and for the
+    /// SDK members newer than `min_sdk`.
+    pub restricted: PlatformApiAction,
+    /// The action for the members not accessible to the application.
+    pub blocked: PlatformApiAction,
+}
+
+impl PlatformApiPolicy {
+    /// Load the hidden API flags of the platform from `hiddenapi-flags.csv`: one line per
+    /// member, the smali representation of the member followed by its flags.
+    pub fn load_flags(&mut self, input: impl Read) -> Result<()> {
+        for line in BufReader::new(input).lines() {
+            let line = line?;
+            let mut fields = line.split(',');
+            let Some(member) = fields.next().filter(|member| !member.is_empty()) else {
+                continue;
+            };
+            match fields.find_map(HiddenApiList::from_flag) {
+                Some(list) => {
+                    self.flags.insert(member.to_string(), list);
+                }
+                None => warn!("No hidden API list found for {member}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Classify `method`. Return `None` if `method` is not a platform method.
+    pub fn classify(&self, method: &IdMethod) -> Option<(Option<HiddenApiList>, ApiAccess)> {
+        if !method.class_.is_platform_class() {
+            return None;
+        }
+        let smali = method.try_to_smali().ok();
+        let list = smali.as_ref().and_then(|smali| {
+            self.flags.get(smali).copied().or_else(|| {
+                platform_list::hidden_api_flags(smali)?
+                    .iter()
+                    .find_map(|flag| HiddenApiList::from_flag(flag))
+            })
+        });
+        let access = match list {
+            None => ApiAccess::Unknown,
+            // If the min SDK is unknown, assume the member exists
+            Some(HiddenApiList::Sdk)
+                if smali
+                    .as_ref()
+                    .and_then(|smali| platform_list::api_level(smali))
+                    .zip(self.min_sdk)
+                    .is_some_and(|(level, sdk)| level > sdk) =>
+            {
+                ApiAccess::NewerSdk
+            }
+            Some(HiddenApiList::Sdk) => ApiAccess::Sdk,
+            Some(HiddenApiList::Unsupported) => ApiAccess::Restricted,
+            // If the target SDK is unknown, assume the member is accessible
+            Some(HiddenApiList::MaxTarget(max)) if self.target_sdk.is_none_or(|sdk| sdk <= max) => {
+                ApiAccess::Restricted
+            }
+            Some(HiddenApiList::MaxTarget(_)) | Some(HiddenApiList::Blocked) => ApiAccess::Blocked,
+        };
+        Some((list, access))
+    }
+
+    /// The action for the methods with the access `access`.
+    pub fn action(&self, access: ApiAccess) -> PlatformApiAction {
+        match access {
+            ApiAccess::Sdk | ApiAccess::Unknown => PlatformApiAction::Patch,
+            ApiAccess::Restricted | ApiAccess::NewerSdk => self.restricted,
+            ApiAccess::Blocked => self.blocked,
+        }
+    }
+}
@@ -1,34 +1,70 @@
 use androscalpel::SmaliName;
-use androscalpel::{Code, IdMethod, IdMethodType, IdType, Instruction, Method};
+use androscalpel::{
+    Code, DexAnnotation, DexAnnotationItem, DexArray, DexValue, IdMethod, IdMethodType, IdType,
+    Instruction, Method,
+};
 use anyhow::{bail, Context, Result};
 use log::{debug, warn};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::{DefaultHasher, Hash, Hasher};
 
+use crate::platform_api::{PlatformApiAction, PlatformApiPolicy};
+use crate::report::{PatchedSite, PlatformTarget};
+use crate::trace::{fallback_message, hit_message, TRACE_TAG};
 use crate::{dex_types::*, register_manipulation::*, runtime_data::*};
 
 const DEBUG: bool = false;
 
+/// Options changing the code generated by [`transform_method`].
+#[derive(Debug, Clone, Default)]
+pub struct PatchingOptions {
+    /// Minimum number of targets a `java.lang.reflect.Method.invoke()` call site must have to
+    /// be patched with a `sparse-switch` on a key computed once from the `Method` instead of a
+    /// chain of tests. If `None`, always use the chain of tests.
+    pub dispatch_threshold: Option<usize>,
+    /// Replace the original reflective call of the call sites with runtime data by a
+    /// `throw new Error()`, so that static analysers only see the direct calls. The generated
+    /// application is meant to be analysed, not run: the calls not seen at runtime now fail.
+    pub analysis_only: bool,
+    /// Log the patched call sites reached at runtime, and if a target matched (see
+    /// [`crate::trace`]).
+    pub trace: bool,
+    /// How to patch the `Method.invoke()` calls to platform methods, depending on their hidden
+    /// API list.
+    pub platform_api: PlatformApiPolicy,
+}
+
 // Interesting stuff: https://cs.android.com/android/platform/superproject/main/+/main:art/runtime/verifier/reg_type.h;drc=83db0626fad8c6e0508754fffcbbd58e539d14a5;l=94
 // https://cs.android.com/android/platform/superproject/main/+/main:art/runtime/verifier/method_verifier.cc;drc=83db0626fad8c6e0508754fffcbbd58e539d14a5;l=5328
 /// `meth`: the method that make reflectif calls. This is the method to patch.
 /// `ref_data`: the runtime data containing the reflectif calls informations.
+/// `runtime_data_index`: the index of `runtime_data`, used to find the calls made by `meth`.
+/// `options`: options changing the generated code.
 /// `tester_methods_class`: the class used to define the methods in `tester_methods`
 /// `tester_methods`: the methods used to test if a `java.lang.reflect.Method` or `java.lang.reflect.Constructor`
 ///     is a specific method. Methods are indexed by the IdMethod they detect, and have a name derived from the method
 ///     they detect.
+///
+/// Return the call sites patched.
 pub fn transform_method(
     meth: &mut Method,
     runtime_data: &RuntimeData,
+    runtime_data_index: &RuntimeDataIndex,
+    options: &PatchingOptions,
     tester_methods_class: IdType,
     tester_methods: &mut HashMap<(IdMethod, String), Method>,
-) -> Result<()> {
+) -> Result<Vec<PatchedSite>> {
     // checking meth.annotations might be usefull at some point
     //println!("{}", meth.descriptor.__str__());
-    let invoke_data = runtime_data.get_invoke_data_for(&meth.descriptor);
-    let class_new_inst_data = runtime_data.get_class_new_instance_data_for(&meth.descriptor);
-    let cnstr_new_inst_data = runtime_data.get_cnstr_new_instance_data_for(&meth.descriptor);
+    let no_data = MethodRuntimeData::default();
+    let method_data = runtime_data_index.get(&meth.descriptor).unwrap_or(&no_data);
+    let invoke_data = &method_data.invoke_data;
+    let class_new_inst_data = &method_data.class_new_inst_data;
+    let cnstr_new_inst_data = &method_data.cnstr_new_inst_data;
+    let kotlin_data = &method_data.kotlin_reflection;
+    let service_new_inst_data = &method_data.service_new_inst_data;
+    let array_new_inst_data = &method_data.array_new_inst_data;
 
     let code = meth
         .code
@@ -98,12 +134,26 @@ pub fn transform_method(
     let mut current_addr_label: Option<String> = None;
     let mut current_try_block_index = None;
     let mut old_try_block_end_label = None;
+    // The reflective call sites patched, and the methods they may now call directly
+    let mut patched_sites: Vec<PatchedSite> = vec![];
     while let Some(ins) = iter.next() {
         match ins {
             Instruction::InvokeVirtual { method, args }
-                if (method == &*MTH_INVOKE
-                    || method == &*CLASS_NEW_INST
-                    || method == &*CNSTR_NEW_INST)
+            | Instruction::InvokeInterface { method, args }
+            | Instruction::InvokeStatic { method, args }
+                if ((matches!(ins, Instruction::InvokeVirtual { .. })
+                    && (method == &*MTH_INVOKE
+                        || method == &*CLASS_NEW_INST
+                        || method == &*CNSTR_NEW_INST))
+                    || (matches!(ins, Instruction::InvokeStatic { .. })
+                        && method == &*ARRAY_NEW_INST)
+                    // Iterator.next() is called everywhere, only patch the sites with data
+                    || (matches!(ins, Instruction::InvokeInterface { .. })
+                        && method == &*ITERATOR_NEXT
+                        && current_addr_label
+                            .as_ref()
+                            .is_some_and(|label| service_new_inst_data.contains_key(label)))
+                    || kotlin_reflection_entry(method).is_some())
                     && current_addr_label.is_some() =>
             'invoke_patch: {
                 let addr_label = current_addr_label.as_ref().unwrap();
@@ -115,8 +165,14 @@ pub fn transform_method(
                 );
                 let end_label = if method == &*MTH_INVOKE {
                     format!("end_reflection_call_at_{}", addr_label.clone())
-                } else if method == &*CLASS_NEW_INST || method == &*CNSTR_NEW_INST {
+                } else if method == &*CLASS_NEW_INST
+                    || method == &*CNSTR_NEW_INST
+                    || method == &*ITERATOR_NEXT
+                    || method == &*ARRAY_NEW_INST
+                {
                     format!("end_reflection_instanciation_at_{}", addr_label.clone())
+                } else if kotlin_reflection_entry(method).is_some() {
+                    format!("end_kotlin_reflection_call_at_{}", addr_label.clone())
                 } else {
                     // This should not happen, cf the guard on the match
                     warn!(
@@ -142,6 +198,12 @@ pub fn transform_method(
                             && class_new_inst_data.contains_key(addr_label))
                         || (method == &*CNSTR_NEW_INST
                             && cnstr_new_inst_data.contains_key(addr_label))
+                        || (method == &*ITERATOR_NEXT
+                            && service_new_inst_data.contains_key(addr_label))
+                        || (method == &*ARRAY_NEW_INST
+                            && array_new_inst_data.contains_key(addr_label))
+                        || (kotlin_reflection_entry(method).is_some()
+                            && kotlin_data.contains_key(addr_label))
                     {
                         let regs_type = regs_type.get(addr_label).unwrap();
                         let mut used_reg = args.clone();
@@ -206,27 +268,123 @@ pub fn transform_method(
                         }
                     }
                 }
+                let site_id = PatchedSite::site_id(&meth.descriptor, addr_label);
+                let trace_site = options.trace.then_some(site_id.as_str());
+                // The platform methods called at the site, and the targets that are not skipped
+                let mut platform_targets: Vec<PlatformTarget> = vec![];
+                let site_invoke_data: Vec<ReflectionInvokeData> = invoke_data
+                    .get(addr_label)
+                    .into_iter()
+                    .flatten()
+                    .filter(|data| {
+                        let Some((list, access)) = options.platform_api.classify(&data.method)
+                        else {
+                            return true;
+                        };
+                        let action = options.platform_api.action(access);
+                        debug!(
+                            "Platform method {} ({access:?}) called at {}:{}: {action:?}",
+                            data.method.__str__(),
+                            meth.descriptor.__str__(),
+                            addr_label,
+                        );
+                        platform_targets.push(PlatformTarget {
+                            method: data.method.clone(),
+                            list,
+                            access,
+                            action,
+                            helper: None,
+                        });
+                        action != PlatformApiAction::Skip
+                    })
+                    .cloned()
+                    .collect();
+                let site_kotlin_data: Vec<KotlinReflectionData> =
+                    match kotlin_reflection_entry(method) {
+                        Some(entry) => kotlin_data
+                            .get(addr_label)
+                            .into_iter()
+                            .flatten()
+                            .filter(|data| is_patchable_kotlin_call(data, entry))
+                            .cloned()
+                            .collect(),
+                        None => vec![],
+                    };
+                // The blocks testing the object returned by a ServiceLoader iterator
+                let mut service_insns = vec![];
+                let use_helpers = platform_targets
+                    .iter()
+                    .any(|target| target.action == PlatformApiAction::Helper);
                 // TODO: recover from failure
-                if method == &*MTH_INVOKE {
-                    for ref_data in invoke_data.get(addr_label).unwrap_or(&vec![]) {
+                if method == &*MTH_INVOKE
+                    && !use_helpers
+                    && options
+                        .dispatch_threshold
+                        .is_some_and(|threshold| site_invoke_data.len() >= threshold)
+                {
+                    debug!(
+                        "Patching reflection call at {}:{} with a dispatch table",
+                        meth.descriptor.__str__(),
+                        addr_label,
+                    );
+                    for ins in get_invoke_dispatch_block(
+                        &site_invoke_data,
+                        args.as_slice(),
+                        &mut register_info,
+                        &end_label,
+                        move_ret.clone(),
+                        tester_methods_class.clone(),
+                        tester_methods,
+                        runtime_data,
+                        trace_site,
+                    )? {
+                        new_insns.push(ins);
+                    }
+                } else if method == &*MTH_INVOKE {
+                    for ref_data in &site_invoke_data {
                         debug!(
                             "Patching reflection call at {}:{} to {}",
                             meth.descriptor.__str__(),
                             addr_label,
                             ref_data.method.__str__()
                         );
-                        for ins in get_invoke_block(
-                            ref_data,
-                            args.as_slice(),
-                            &mut register_info,
+                        let platform_target = platform_targets
+                            .iter_mut()
+                            .find(|target| target.method == ref_data.method);
+                        let block = match platform_target {
+                            Some(target) if target.action == PlatformApiAction::Helper => {
+                                let (block, helper) = get_invoke_helper_block(
+                                    ref_data,
+                                    args.as_slice(),
+                                    &mut register_info,
+                                    &end_label,
+                                    move_ret.clone(),
+                                    &meth.descriptor,
+                                    tester_methods_class.clone(),
+                                    tester_methods,
+                                    runtime_data,
+                                )?;
+                                target.helper = Some(helper);
+                                block
+                            }
+                            _ => get_invoke_block(
+                                ref_data,
+                                args.as_slice(),
+                                &mut register_info,
+                                &end_label,
+                                move_ret.clone(),
+                                tester_methods_class.clone(),
+                                tester_methods,
+                                runtime_data,
+                            )?,
+                        };
+                        new_insns.append(&mut trace_hits(
+                            block,
                             &end_label,
-                            move_ret.clone(),
-                            tester_methods_class.clone(),
-                            tester_methods,
-                            runtime_data,
-                        )? {
-                            new_insns.push(ins);
-                        }
+                            register_info.array_val,
+                            trace_site,
+                            &ref_data.get_static_callee(),
+                        ));
                     }
                 } else if method == &*CLASS_NEW_INST {
                     for ref_data in class_new_inst_data.get(addr_label).unwrap_or(&vec![]) {
@@ -236,15 +394,20 @@ pub fn transform_method(
                             addr_label,
                             ref_data.constructor.__str__()
                         );
-                        for ins in get_class_new_inst_block(
+                        let block = get_class_new_inst_block(
                             ref_data,
                             args.as_slice(),
                             &mut register_info,
                             &end_label,
                             move_ret.clone(),
-                        )? {
-                            new_insns.push(ins);
-                        }
+                        )?;
+                        new_insns.append(&mut trace_hits(
+                            block,
+                            &end_label,
+                            register_info.array_val,
+                            trace_site,
+                            &ref_data.get_static_constructor(),
+                        ));
                     }
                 } else if method == &*CNSTR_NEW_INST {
                     for ref_data in cnstr_new_inst_data.get(addr_label).unwrap_or(&vec![]) {
@@ -254,7 +417,7 @@ pub fn transform_method(
                             addr_label,
                             ref_data.constructor.__str__()
                         );
-                        for ins in get_cnstr_new_inst_block(
+                        let block = get_cnstr_new_inst_block(
                             ref_data,
                             args.as_slice(),
                             &mut register_info,
@@ -263,19 +426,229 @@ pub fn transform_method(
                             tester_methods_class.clone(),
                             tester_methods,
                             runtime_data,
-                        )? {
-                            new_insns.push(ins);
-                        }
+                        )?;
+                        new_insns.append(&mut trace_hits(
+                            block,
+                            &end_label,
+                            register_info.array_val,
+                            trace_site,
+                            &ref_data.get_static_constructor(),
+                        ));
+                    }
+                } else if method == &*ITERATOR_NEXT {
+                    // The iterator instantiates the provider: the providers are tested after
+                    // the call
+                    for ref_data in service_new_inst_data.get(addr_label).unwrap_or(&vec![]) {
+                        debug!(
+                            "Patching service provider instantiation at {}:{} for {}",
+                            meth.descriptor.__str__(),
+                            addr_label,
+                            ref_data.constructor.__str__()
+                        );
+                        let block = get_service_new_inst_block(
+                            ref_data,
+                            &mut register_info,
+                            &end_label,
+                            move_ret.clone(),
+                        )?;
+                        service_insns.append(&mut trace_hits(
+                            block,
+                            &end_label,
+                            register_info.array_val,
+                            trace_site,
+                            &ref_data.get_static_constructor(),
+                        ));
+                    }
+                } else if method == &*ARRAY_NEW_INST {
+                    for ref_data in array_new_inst_data.get(addr_label).unwrap_or(&vec![]) {
+                        debug!(
+                            "Patching reflection array instantiation at {}:{} for {}",
+                            meth.descriptor.__str__(),
+                            addr_label,
+                            ref_data.component_type.__str__()
+                        );
+                        new_insns.append(&mut get_array_new_inst_block(
+                            ref_data,
+                            args.as_slice(),
+                            &mut register_info,
+                            &end_label,
+                            move_ret.clone(),
+                        )?);
+                    }
+                } else if let Some(entry) = kotlin_reflection_entry(method) {
+                    for ref_data in &site_kotlin_data {
+                        debug!(
+                            "Patching Kotlin reflection call at {}:{} to {}",
+                            meth.descriptor.__str__(),
+                            addr_label,
+                            ref_data.method.__str__()
+                        );
+                        let block = get_kotlin_reflection_block(
+                            ref_data,
+                            entry,
+                            args.as_slice(),
+                            &mut register_info,
+                            &end_label,
+                            move_ret.clone(),
+                            tester_methods_class.clone(),
+                            tester_methods,
+                            runtime_data,
+                        )?;
+                        new_insns.append(&mut trace_hits(
+                            block,
+                            &end_label,
+                            register_info.array_val,
+                            trace_site,
+                            &ref_data.get_static_callee(),
+                        ));
                     }
                 } else {
                     panic!("Should not happen!")
                 };
-                new_insns.push(ins.clone());
-                if let Some(move_ret) = move_ret {
-                    for ins in pseudo_insns.into_iter() {
-                        new_insns.push(ins);
+                let targets: Vec<IdMethod> = if method == &*MTH_INVOKE {
+                    site_invoke_data
+                        .iter()
+                        .map(|data| data.get_static_callee())
+                        .collect()
+                } else if method == &*CLASS_NEW_INST {
+                    class_new_inst_data
+                        .get(addr_label)
+                        .into_iter()
+                        .flatten()
+                        .map(|data| data.get_static_constructor())
+                        .collect()
+                } else if method == &*CNSTR_NEW_INST {
+                    cnstr_new_inst_data
+                        .get(addr_label)
+                        .into_iter()
+                        .flatten()
+                        .map(|data| data.get_static_constructor())
+                        .collect()
+                } else if method == &*ITERATOR_NEXT {
+                    service_new_inst_data
+                        .get(addr_label)
+                        .into_iter()
+                        .flatten()
+                        .map(|data| data.get_static_constructor())
+                        .collect()
+                } else if method == &*ARRAY_NEW_INST {
+                    vec![]
+                } else {
+                    site_kotlin_data
+                        .iter()
+                        .map(|data| data.get_static_callee())
+                        .collect()
+                };
+                let array_types: Vec<IdType> = if method == &*ARRAY_NEW_INST {
+                    array_new_inst_data
+                        .get(addr_label)
+                        .into_iter()
+                        .flatten()
+                        .map(|data| data.get_static_component_type())
+                        .collect()
+                } else {
+                    vec![]
+                };
+                let resolved = !targets.is_empty() || !array_types.is_empty();
+                // The skipped platform targets still go through the reflective call, and the
+                // ServiceLoader iterators must still be advanced
+                let remove_fallback = options.analysis_only
+                    && resolved
+                    && method != &*ITERATOR_NEXT
+                    && !platform_targets
+                        .iter()
+                        .any(|target| target.action == PlatformApiAction::Skip)
+                    && register_info.first_arg < u8::MAX as u16;
+                if resolved && method != &*ITERATOR_NEXT {
+                    if let Some(site) = trace_site {
+                        new_insns.append(&mut gen_trace_log(
+                            register_info.array_val,
+                            fallback_message(site),
+                        ));
+                    }
+                }
+                if resolved || !platform_targets.is_empty() {
+                    patched_sites.push(PatchedSite {
+                        id: site_id.clone(),
+                        caller: meth.descriptor.clone(),
+                        label: addr_label.clone(),
+                        reflective_method: method.clone(),
+                        targets,
+                        array_types,
+                        fallback_removed: remove_fallback,
+                        platform_targets,
+                    });
+                }
+                if remove_fallback {
+                    debug!(
+                        "Replace reflection call at {}:{} by an error",
+                        meth.descriptor.__str__(),
+                        addr_label,
+                    );
+                    if register_info.nb_arg_reg < 2 {
+                        register_info.nb_arg_reg = 2;
+                    }
+                    let reg_err = register_info.first_arg as u8;
+                    let reg_msg = reg_err + 1;
+                    new_insns.append(&mut vec![
+                        Instruction::NewInstance {
+                            reg: reg_err,
+                            lit: ERROR_TY.clone(),
+                        },
+                        Instruction::ConstString {
+                            reg: reg_msg,
+                            lit: format!(
+                                "Theseus analysis-only build: unexpected target for {} at {}:{}",
+                                method.__str__(),
+                                meth.descriptor.__str__(),
+                                addr_label
+                            )
+                            .into(),
+                        },
+                        Instruction::InvokeDirect {
+                            method: ERROR_INIT.clone(),
+                            args: vec![reg_err as u16, reg_msg as u16],
+                        },
+                        Instruction::Throw { reg: reg_err },
+                    ]);
+                    // Keep the labels
+                    if move_ret.is_some() {
+                        new_insns.extend(pseudo_insns);
+                    }
+                } else {
+                    if options.analysis_only && resolved && method != &*ITERATOR_NEXT {
+                        warn!(
+                            "Failed to remove the reflection call in {} at {}: no 8 bits register \
+                            available to throw an error, keep the original call",
+                            meth.descriptor.__str__(),
+                            addr_label,
+                        );
+                    }
+                    new_insns.push(ins.clone());
+                    if !service_insns.is_empty() {
+                        new_insns.extend(pseudo_insns);
+                        new_insns.push(Instruction::MoveResultObject {
+                            to: register_info.array_val,
+                        });
+                        new_insns.append(&mut service_insns);
+                        if let Some(Instruction::MoveResultObject { to }) = move_ret {
+                            new_insns.push(Instruction::MoveObject {
+                                from: register_info.array_val as u16,
+                                to: to as u16,
+                            });
+                        }
+                        if let Some(site) = trace_site {
+                            new_insns.append(&mut gen_trace_log(
+                                register_info.array_val,
+                                fallback_message(site),
+                            ));
+                        }
+                    } else if let Some(move_ret) = move_ret {
+                        for ins in pseudo_insns.into_iter() {
+                            new_insns.push(ins);
+                        }
+                        new_insns.push(move_ret);
                     }
-                    new_insns.push(move_ret);
                 }
                 let end_label = Instruction::Label { name: end_label };
                 new_insns.push(end_label.clone());
@@ -457,7 +830,128 @@ pub fn transform_method(
     code.insns.append(&mut new_insns);
     code.registers_size += register_info.get_nb_added_reg();
 
-    Ok(())
+    if !patched_sites.is_empty() {
+        let sites = patched_sites
+            .iter()
+            .map(|site| format!("{}:{}", site.label, site.reflective_method.__str__()))
+            .collect();
+        let targets = patched_sites
+            .iter()
+            .flat_map(|site| {
+                site.targets
+                    .iter()
+                    .map(|target| target.__str__())
+                    .chain(
+                        site.array_types
+                            .iter()
+                            .map(|ty| format!("{}[]", ty.__str__())),
+                    )
+                    .map(move |target| {
+                        format!(
+                            "{}:{} => {target}",
+                            site.label,
+                            site.reflective_method.__str__(),
+                        )
+                    })
+            })
+            .collect();
+        meth.annotations.push(patched_annotation(
+            sites,
+            targets,
+            runtime_data.run_id.as_deref(),
+        ));
+    }
+
+    Ok(patched_sites)
+}
+
+/// Generate the bytecode logging `msg` with the tag [`TRACE_TAG`], using the registers `reg`
+/// and `reg + 1`.
+fn gen_trace_log(reg: u8, msg: String) -> Vec<Instruction> {
+    vec![
+        Instruction::ConstString {
+            reg,
+            lit: TRACE_TAG.into(),
+        },
+        Instruction::ConstString {
+            reg: reg + 1,
+            lit: msg.into(),
+        },
+        Instruction::InvokeStatic {
+            method: LOG_INFO.clone(),
+            args: vec![reg as u16, reg as u16 + 1],
+        },
+    ]
+}
+
+/// Log the call to `target` before each jump to `end_label` in `block` if `trace_site` is set.
+/// `reg` and `reg + 1` must be free at those jumps.
+fn trace_hits(
+    block: Vec<Instruction>,
+    end_label: &str,
+    reg: u8,
+    trace_site: Option<&str>,
+    target: &IdMethod,
+) -> Vec<Instruction> {
+    let Some(site) = trace_site else {
+        return block;
+    };
+    let mut insns = vec![];
+    for ins in block {
+        if matches!(&ins, Instruction::Goto { label } if label == end_label) {
+            insns.append(&mut gen_trace_log(reg, hit_message(site, target)));
+        }
+        insns.push(ins);
+    }
+    insns
+}
+
+/// Generate the `Ltheseus/Patched;` annotation attached to the methods modified or generated by
+/// the patcher. The annotation is only visible at build time, so it does not change the behavior
+/// of the application, but remains in the dex for static analysers:
+///
+/// - `sites`: the reflective call sites patched, as `<label>:<reflective method>`
+/// - `targets`: the methods called directly, as `<site> => <method>` (or only `<method>` for
+///   the tester methods)
+/// - `runId`: the id of the run that collected the runtime data, if known
+pub(crate) fn patched_annotation(
+    sites: Vec<String>,
+    targets: Vec<String>,
+    run_id: Option<&str>,
+) -> DexAnnotationItem {
+    let mut elements: HashMap<_, _> = [
+        (
+            "sites".into(),
+            DexValue::Array(DexArray(
+                sites
+                    .into_iter()
+                    .map(|site| DexValue::String(site.into()))
+                    .collect(),
+            )),
+        ),
+        (
+            "targets".into(),
+            DexValue::Array(DexArray(
+                targets
+                    .into_iter()
+                    .map(|target| DexValue::String(target.into()))
+                    .collect(),
+            )),
+        ),
+    ]
+    .into();
+    if let Some(run_id) = run_id {
+        elements.insert("runId".into(), DexValue::String(run_id.into()));
+    }
+    DexAnnotationItem {
+        visibility_build: true,
+        visibility_runtime: false,
+        visibility_system: false,
+        annotation: DexAnnotation {
+            type_: PATCHED_ANNOTATION.clone(),
+            elements,
+        },
+    }
 }
 
 fn gen_tester_method(
@@ -465,7 +959,7 @@ fn gen_tester_method(
     method_to_test: IdMethod,
     is_constructor: bool,
     classloader: Option<String>,
-    _runtime_data: &RuntimeData,
+    runtime_data: &RuntimeData,
 ) -> Result<Method> {
     let mut hasher = DefaultHasher::new();
     if let Some(ref id) = classloader {
@@ -1166,6 +1660,11 @@ fn gen_tester_method(
         insns,
         Some(vec![Some("meth".into())]), // parameter_names
     ));
+    method.annotations.push(patched_annotation(
+        vec![],
+        vec![method_to_test.__str__()],
+        runtime_data.run_id.as_deref(),
+    ));
     Ok(method)
 }
 
@@ -1265,17 +1764,6 @@ fn get_invoke_block(
             invoke_arg.len()
         );
     };
-    let nb_args: usize = ref_data
-        .method
-        .proto
-        .get_parameters()
-        .iter()
-        .map(|ty| if ty.is_double() || ty.is_long() { 2 } else { 1 })
-        .sum();
-    if reg_inf.nb_arg_reg < nb_args as u16 + if ref_data.is_static { 0 } else { 1 } {
-        reg_inf.nb_arg_reg = nb_args as u16 + if ref_data.is_static { 0 } else { 1 };
-    }
-
     let abort_label = {
         // method descriptor in label are hard to debug
         let name = format!(
@@ -1304,7 +1792,52 @@ fn get_invoke_block(
         runtime_data,
     )?;
 
-    if !ref_data.is_static {
+    insns.append(&mut get_direct_invoke_insns(
+        &ref_data.get_static_callee(),
+        ref_data.is_static,
+        obj_inst,
+        arg_arr,
+        0,
+        reg_inf,
+        move_result,
+    )?);
+    insns.push(Instruction::Goto {
+        label: end_label.to_string(),
+    });
+    insns.push(Instruction::Label { name: abort_label });
+    // We need a few u8 regs here. For now, we assumes we work with less than 256 reg.
+    Ok(insns)
+}
+
+/// The number of registers used by the parameters of `method`, without `this`.
+pub(crate) fn nb_param_reg(method: &IdMethod) -> u16 {
+    method
+        .proto
+        .get_parameters()
+        .iter()
+        .map(|ty| if ty.is_double() || ty.is_long() { 2 } else { 1 })
+        .sum()
+}
+
+/// Generate the direct call to `callee`: the receiver is taken from `obj_inst` and the
+/// arguments from the array `arg_arr` starting at `first_index`, and the returned value is
+/// boxed and moved with `move_result` if set.
+fn get_direct_invoke_insns(
+    callee: &IdMethod,
+    is_static: bool,
+    obj_inst: u16,
+    arg_arr: u16,
+    first_index: i32,
+    reg_inf: &mut RegistersInfo,
+    move_result: Option<Instruction>,
+) -> Result<Vec<Instruction>> {
+    let nb_args = nb_param_reg(callee);
+    if reg_inf.nb_arg_reg < nb_args + if is_static { 0 } else { 1 } {
+        reg_inf.nb_arg_reg = nb_args + if is_static { 0 } else { 1 };
+    }
+
+    let mut insns = vec![];
+    if !is_static {
         // Move 'this' to fist arg
         // We do a small detour to `reg_inf.array_val` because we need a u8 reg to down cast the
         // Object reference to the right Class
@@ -1314,7 +1847,7 @@ fn get_invoke_block(
         });
         insns.push(Instruction::CheckCast {
             reg: reg_inf.array_val,
-            lit: ref_data.get_static_callee().class_,
+            lit: callee.class_.clone(),
         });
         insns.push(Instruction::MoveObject {
             from: reg_inf.array_val as u16,
@@ -1322,24 +1855,25 @@ fn get_invoke_block(
         });
     }
     insns.append(&mut get_args_from_obj_arr(
-        &ref_data.get_static_callee().proto.get_parameters(), // TODO: what if renambed args?
+        &callee.proto.get_parameters(), // TODO: what if renambed args?
         arg_arr,
-        reg_inf.first_arg + if ref_data.is_static { 0 } else { 1 },
+        first_index,
+        reg_inf.first_arg + if is_static { 0 } else { 1 },
         reg_inf,
     )?);
-    if ref_data.is_static {
+    if is_static {
         insns.push(Instruction::InvokeStatic {
-            method: ref_data.get_static_callee(),
-            args: (reg_inf.first_arg..reg_inf.first_arg + nb_args as u16).collect(),
+            method: callee.clone(),
+            args: (reg_inf.first_arg..reg_inf.first_arg + nb_args).collect(),
         });
     } else {
         insns.push(Instruction::InvokeVirtual {
-            method: ref_data.get_static_callee(),
-            args: (reg_inf.first_arg..reg_inf.first_arg + 1 + nb_args as u16).collect(),
+            method: callee.clone(),
+            args: (reg_inf.first_arg..reg_inf.first_arg + 1 + nb_args).collect(),
         });
     }
     if let Some(move_result) = move_result {
-        let ret_ty = ref_data.get_static_callee().proto.get_return_type();
+        let ret_ty = callee.proto.get_return_type();
         let res_reg = if let Instruction::MoveResultObject { to } = &move_result {
             *to
         } else {
@@ -1385,129 +1919,498 @@ fn get_invoke_block(
             });
         }
     }
-    insns.push(Instruction::Goto {
-        label: end_label.to_string(),
-    });
-    insns.push(Instruction::Label { name: abort_label });
-    // We need a few u8 regs here. For now, we assumes we work with less than 256 reg.
-    Ok(insns)
-}
-
-/// Generate bytecode that put the arguments of types `params` from an [java.lang.Object to
-/// types consecutive registers starting at `first_arg_reg`.
-/// `first_arg_reg` sould be `reg_inf.first_arg` or `reg_inf.first_arg+1` depending on if this
-/// is for a static or virtual call.
-fn get_args_from_obj_arr(
-    params: &[IdType],
-    array_reg: u16,
-    first_arg_reg: u16,
-    reg_inf: &mut RegistersInfo,
-) -> Result<Vec<Instruction>> {
-    let mut insns = vec![];
-    let mut restore_array = vec![];
-    let mut reg_count = 0;
-    let array_reg = if array_reg <= 0b1111 {
-        array_reg as u8
-    } else {
-        insns.push(Instruction::MoveObject {
-            from: array_reg,
-            to: reg_inf.array as u16,
-        });
-        restore_array.push(Instruction::MoveObject {
-            from: reg_inf.array as u16,
-            to: array_reg,
-        });
-        reg_inf.array
-    };
-    for (i, param) in params.iter().enumerate() {
-        insns.push(Instruction::Const {
-            reg: reg_inf.array_index,
-            lit: i as i32,
-        });
-        insns.push(Instruction::AGetObject {
-            dest: reg_inf.array_val,
-            arr: array_reg,
-            idx: reg_inf.array_index,
-        });
-        if param.is_class() || param.is_array() {
-            insns.push(Instruction::CheckCast {
-                reg: reg_inf.array_val,
-                lit: param.clone(),
-            });
-            insns.push(Instruction::MoveObject {
-                from: reg_inf.array_val as u16,
-                to: first_arg_reg + reg_count,
-            });
-            reg_count += 1;
-        } else if param.is_double() || param.is_long() {
-            insns.push(Instruction::CheckCast {
-                reg: reg_inf.array_val,
-                lit: get_obj_of_scalar(param)?,
-            });
-            insns.push(Instruction::InvokeVirtual {
-                method: get_obj_to_scalar_method(param)?,
-                args: vec![reg_inf.array_val as u16],
-            });
-            insns.push(Instruction::MoveResultWide {
-                to: reg_inf.array_val,
-            });
-            insns.push(Instruction::MoveWide {
-                from: reg_inf.array_val as u16,
-                to: first_arg_reg + reg_count,
-            });
-            reg_count += 2;
-        } else {
-            insns.push(Instruction::CheckCast {
-                reg: reg_inf.array_val,
-                lit: get_obj_of_scalar(param)?,
-            });
-            insns.push(Instruction::InvokeVirtual {
-                method: get_obj_to_scalar_method(param)?,
-                args: vec![reg_inf.array_val as u16],
-            });
-            insns.push(Instruction::MoveResult {
-                to: reg_inf.array_val,
-            });
-            insns.push(Instruction::Move {
-                from: reg_inf.array_val as u16,
-                to: first_arg_reg + reg_count,
-            });
-            reg_count += 1;
-        }
-    }
-    insns.append(&mut restore_array);
     Ok(insns)
 }
 
+/// Generate the block calling the platform method reflected in `ref_data` through a generated
+/// helper (see [`gen_platform_call_helper`]) when the reflected method matches. Return the
+/// instructions and the helper.
 #[allow(clippy::too_many_arguments)]
-fn get_cnstr_new_inst_block(
-    ref_data: &ReflectionCnstrNewInstData,
+fn get_invoke_helper_block(
+    ref_data: &ReflectionInvokeData,
     invoke_arg: &[u16],
     reg_inf: &mut RegistersInfo,
     end_label: &str,
     move_result: Option<Instruction>,
+    caller: &IdMethod,
     tester_methods_class: IdType,
     tester_methods: &mut HashMap<(IdMethod, String), Method>,
     runtime_data: &RuntimeData,
-) -> Result<Vec<Instruction>> {
-    let (cnst_reg, arg_arr) = if let &[a, b] = invoke_arg {
-        (a, b)
+) -> Result<(Vec<Instruction>, IdMethod)> {
+    let method_obj = if let &[a, _, _] = invoke_arg {
+        a
     } else {
         bail!(
-            "Method;->invoke arg should have exactrly 2 arguments, found {}",
+            "Method;->invoke arg should have exactly 3 arguments, found {}",
             invoke_arg.len()
         );
     };
-
-    let nb_args = ref_data.constructor.proto.get_parameters().len();
-    if reg_inf.nb_arg_reg < nb_args as u16 + 1 {
-        reg_inf.nb_arg_reg = nb_args as u16 + 1;
-    }
-
     let abort_label = {
-        // method descriptor in label are hard to debug
         let name = format!(
-            "end_static_instance_with_{}_from_classloader_{}_at_{:08X}",
+            "end_helper_call_to_{}_at_{:08X}",
+            ref_data.method.try_to_smali()?,
+            ref_data.addr
+        );
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        format!("end_helper_call_{:x}", hasher.finish())
+    };
+    let mut insns = test_method(
+        method_obj,
+        ref_data.method.clone(),
+        abort_label.clone(),
+        reg_inf,
+        tester_methods_class.clone(),
+        tester_methods,
+        None,
+        runtime_data,
+    )?;
+    let helper = gen_platform_call_helper(tester_methods_class, caller, ref_data, runtime_data)?;
+    let helper_descriptor = helper.descriptor.clone();
+    tester_methods
+        .entry((ref_data.method.clone(), helper_descriptor.__str__()))
+        .or_insert(helper);
+    insns.push(Instruction::InvokeStatic {
+        method: helper_descriptor.clone(),
+        args: invoke_arg.to_vec(),
+    });
+    if let Some(move_result) = move_result {
+        insns.push(move_result);
+    }
+    insns.push(Instruction::Goto {
+        label: end_label.to_string(),
+    });
+    insns.push(Instruction::Label { name: abort_label });
+    Ok((insns, helper_descriptor))
+}
+
+/// Generate the method calling the platform method reflected in `ref_data` for the call site
+/// of `caller` at `ref_data.addr`. The method takes the arguments of `Method.invoke()` and
+/// calls the platform method directly, or with `Method.invoke()` if the direct call throws a
+/// `LinkageError` (eg a `NoSuchMethodError` for a hidden API blocked when called directly).
+fn gen_platform_call_helper(
+    tester_methods_class: IdType,
+    caller: &IdMethod,
+    ref_data: &ReflectionInvokeData,
+    runtime_data: &RuntimeData,
+) -> Result<Method> {
+    let label = format!("THESEUS_ADDR_{:08X}", ref_data.addr);
+    let mut hasher = DefaultHasher::new();
+    caller.hash(&mut hasher);
+    label.hash(&mut hasher);
+    ref_data.method.hash(&mut hasher);
+    let hash = hasher.finish();
+    let descriptor = IdMethod::new(
+        format!("call_platform_{hash:016x}").as_str().into(),
+        IdMethodType::new(
+            MTH_INVOKE.proto.get_return_type(),
+            [MTH_INVOKE.class_.clone()]
+                .into_iter()
+                .chain(MTH_INVOKE.proto.get_parameters())
+                .collect(),
+        ),
+        tester_methods_class,
+    );
+    let mut method = Method::new(descriptor);
+    const REG_RES: u8 = 4;
+    let mut reg_inf = RegistersInfo {
+        array_val: 0,
+        array_index: 2,
+        array: 3,
+        first_arg: 5,
+        ..Default::default()
+    };
+    let nb_arg_reg = nb_param_reg(&ref_data.method) + if ref_data.is_static { 0 } else { 1 };
+    let reg_method = reg_inf.first_arg + nb_arg_reg;
+    let reg_obj = reg_method + 1;
+    let reg_args = reg_method + 2;
+    let is_void = ref_data
+        .get_static_callee()
+        .proto
+        .get_return_type()
+        .is_void();
+
+    let mut insns = vec![Instruction::Try {
+        end_label: "end_direct_call".into(),
+        handlers: vec![(LINKAGE_ERROR_TY.clone(), "reflective_call".into())],
+        default_handler: None,
+    }];
+    insns.append(&mut get_direct_invoke_insns(
+        &ref_data.get_static_callee(),
+        ref_data.is_static,
+        reg_obj,
+        reg_args,
+        0,
+        &mut reg_inf,
+        (!is_void).then_some(Instruction::MoveResultObject { to: REG_RES }),
+    )?);
+    insns.push(Instruction::Label {
+        name: "end_direct_call".into(),
+    });
+    if is_void {
+        insns.push(Instruction::Const {
+            reg: REG_RES,
+            lit: 0,
+        });
+    }
+    insns.append(&mut vec![
+        Instruction::ReturnObject { reg: REG_RES },
+        Instruction::Label {
+            name: "reflective_call".into(),
+        },
+        Instruction::InvokeVirtual {
+            method: MTH_INVOKE.clone(),
+            args: vec![reg_method, reg_obj, reg_args],
+        },
+        Instruction::MoveResultObject { to: REG_RES },
+        Instruction::ReturnObject { reg: REG_RES },
+    ]);
+    method.is_static = true;
+    method.is_final = true;
+    method.code = Some(Code::new(
+        reg_method + 3, //registers_size
+        insns,
+        Some(vec![
+            Some("method".into()),
+            Some("receiver".into()),
+            Some("args".into()),
+        ]), // parameter_names
+    ));
+    method.annotations.push(patched_annotation(
+        vec![PatchedSite::site_id(caller, &label)],
+        vec![ref_data.get_static_callee().__str__()],
+        runtime_data.run_id.as_deref(),
+    ));
+    Ok(method)
+}
+
+/// Compute the value `java.lang.String.hashCode()` returns for `string`.
+fn java_string_hash(string: &str) -> i32 {
+    string
+        .encode_utf16()
+        .fold(0i32, |hash, c| hash.wrapping_mul(31).wrapping_add(c as i32))
+}
+
+/// Compute the key used to dispatch a `java.lang.reflect.Method` to the block calling `method`:
+/// the hash of `"<declaring class descriptor>-><method name><method descriptor>"` (the smali
+/// representation of `method`), so that overloads get different keys. This is the value
+/// returned at runtime by the method generated by [`gen_dispatch_key_method`].
+fn dispatch_key(method: &IdMethod) -> Result<i32> {
+    Ok(java_string_hash(&method.try_to_smali()?))
+}
+
+/// Generate the method `dispatch_key(Ljava/lang/reflect/Method;)I` that compute the key of a
+/// `java.lang.reflect.Method` (see [`dispatch_key`]). The descriptor of the method is built with
+/// `java.lang.invoke.MethodType.toMethodDescriptorString()`.
+fn gen_dispatch_key_method(tester_methods_class: IdType, runtime_data: &RuntimeData) -> Method {
+    let descriptor = IdMethod::new(
+        "dispatch_key".into(),
+        IdMethodType::new(
+            IdType::int(),
+            vec![IdType::class("java/lang/reflect/Method")],
+        ),
+        tester_methods_class,
+    );
+    let mut method = Method::new(descriptor);
+    const REG_KEY: u8 = 0;
+    const REG_TMP: u8 = 1;
+    const REG_PARAMS: u8 = 2;
+    const REG_REF_METHOD: u8 = 3;
+    let insns = vec![
+        Instruction::InvokeVirtual {
+            method: MTH_GET_DEC_CLS.clone(),
+            args: vec![REG_REF_METHOD as u16],
+        },
+        Instruction::MoveResultObject { to: REG_KEY },
+        Instruction::InvokeVirtual {
+            method: CLT_GET_DESCR_STRING.clone(),
+            args: vec![REG_KEY as u16],
+        },
+        Instruction::MoveResultObject { to: REG_KEY },
+        Instruction::ConstString {
+            reg: REG_TMP,
+            lit: "->".into(),
+        },
+        Instruction::InvokeVirtual {
+            method: STR_CONCAT.clone(),
+            args: vec![REG_KEY as u16, REG_TMP as u16],
+        },
+        Instruction::MoveResultObject { to: REG_KEY },
+        Instruction::InvokeVirtual {
+            method: MTH_GET_NAME.clone(),
+            args: vec![REG_REF_METHOD as u16],
+        },
+        Instruction::MoveResultObject { to: REG_TMP },
+        Instruction::InvokeVirtual {
+            method: STR_CONCAT.clone(),
+            args: vec![REG_KEY as u16, REG_TMP as u16],
+        },
+        Instruction::MoveResultObject { to: REG_KEY },
+        Instruction::InvokeVirtual {
+            method: MTH_GET_RET_TY.clone(),
+            args: vec![REG_REF_METHOD as u16],
+        },
+        Instruction::MoveResultObject { to: REG_TMP },
+        Instruction::InvokeVirtual {
+            method: MTH_GET_PARAMS_TY.clone(),
+            args: vec![REG_REF_METHOD as u16],
+        },
+        Instruction::MoveResultObject { to: REG_PARAMS },
+        Instruction::InvokeStatic {
+            method: METHOD_TYPE_OF.clone(),
+            args: vec![REG_TMP as u16, REG_PARAMS as u16],
+        },
+        Instruction::MoveResultObject { to: REG_TMP },
+        Instruction::InvokeVirtual {
+            method: METHOD_TYPE_DESCRIPTOR.clone(),
+            args: vec![REG_TMP as u16],
+        },
+        Instruction::MoveResultObject { to: REG_TMP },
+        Instruction::InvokeVirtual {
+            method: STR_CONCAT.clone(),
+            args: vec![REG_KEY as u16, REG_TMP as u16],
+        },
+        Instruction::MoveResultObject { to: REG_KEY },
+        Instruction::InvokeVirtual {
+            method: STR_HASH_CODE.clone(),
+            args: vec![REG_KEY as u16],
+        },
+        Instruction::MoveResult { to: REG_KEY },
+        Instruction::Return { reg: REG_KEY },
+    ];
+    method.is_static = true;
+    method.is_final = true;
+    method.code = Some(Code::new(
+        4, //registers_size, 3 reg + 1 parameter reg
+        insns,
+        Some(vec![Some("meth".into())]), // parameter_names
+    ));
+    method.annotations.push(patched_annotation(
+        vec![],
+        vec![],
+        runtime_data.run_id.as_deref(),
+    ));
+    method
+}
+
+/// Generate the bytecode that patch a `java.lang.reflect.Method.invoke()` call site with many
+/// targets: the key of the `Method` is computed once, then a `sparse-switch` jumps to the
+/// block calling the targets with this key (usually only one). Like for the chain generated by
+/// [`get_invoke_block`], each target is still tested before being called, and when nothing
+/// matches, the execution continues after the block, on the original reflective call.
+#[allow(clippy::too_many_arguments)]
+fn get_invoke_dispatch_block(
+    refs_data: &[ReflectionInvokeData],
+    invoke_arg: &[u16],
+    reg_inf: &mut RegistersInfo,
+    end_label: &str,
+    move_result: Option<Instruction>,
+    tester_methods_class: IdType,
+    tester_methods: &mut HashMap<(IdMethod, String), Method>,
+    runtime_data: &RuntimeData,
+    trace_site: Option<&str>,
+) -> Result<Vec<Instruction>> {
+    let method_obj = if let &[a, _, _] = invoke_arg {
+        a
+    } else {
+        bail!(
+            "Method;->invoke arg should have exactly 3 arguments, found {}",
+            invoke_arg.len()
+        );
+    };
+    // BTreeMap to keep the generated code stable
+    let mut cases: BTreeMap<i32, Vec<&ReflectionInvokeData>> = BTreeMap::new();
+    for ref_data in refs_data {
+        cases
+            .entry(dispatch_key(&ref_data.method)?)
+            .or_default()
+            .push(ref_data);
+    }
+    let case_label = |key: i32| format!("{end_label}_dispatch_{key:08x}");
+    let default_label = format!("{end_label}_dispatch_default");
+
+    let key_method = tester_methods
+        .entry((
+            IdMethod::new(
+                "dispatch_key".into(),
+                IdMethodType::new(
+                    IdType::int(),
+                    vec![IdType::class("java/lang/reflect/Method")],
+                ),
+                tester_methods_class.clone(),
+            ),
+            "".into(),
+        ))
+        .or_insert_with(|| gen_dispatch_key_method(tester_methods_class.clone(), runtime_data))
+        .descriptor
+        .clone();
+    let mut insns = vec![
+        Instruction::InvokeStatic {
+            method: key_method,
+            args: vec![method_obj],
+        },
+        Instruction::MoveResult {
+            to: reg_inf.array_val,
+        },
+        Instruction::Switch {
+            reg: reg_inf.array_val,
+            branches: cases.keys().map(|key| (*key, case_label(*key))).collect(),
+        },
+        Instruction::Goto {
+            label: default_label.clone(),
+        },
+    ];
+    for (key, refs_data) in cases {
+        insns.push(Instruction::Label {
+            name: case_label(key),
+        });
+        for ref_data in refs_data {
+            let block = get_invoke_block(
+                ref_data,
+                invoke_arg,
+                reg_inf,
+                end_label,
+                move_result.clone(),
+                tester_methods_class.clone(),
+                tester_methods,
+                runtime_data,
+            )?;
+            insns.append(&mut trace_hits(
+                block,
+                end_label,
+                reg_inf.array_val,
+                trace_site,
+                &ref_data.get_static_callee(),
+            ));
+        }
+        insns.push(Instruction::Goto {
+            label: default_label.clone(),
+        });
+    }
+    insns.push(Instruction::Label {
+        name: default_label,
+    });
+    Ok(insns)
+}
+
+/// Generate bytecode that put the arguments of types `params` from an [java.lang.Object to
+/// types consecutive registers starting at `first_arg_reg`.
+/// `first_arg_reg` sould be `reg_inf.first_arg` or `reg_inf.first_arg+1` depending on if this
+/// is for a static or virtual call.
+/// `first_index` is the index of the first argument in the array (eg 1 when the array also
+/// contains the receiver, like for `KCallable.call()`).
+fn get_args_from_obj_arr(
+    params: &[IdType],
+    array_reg: u16,
+    first_index: i32,
+    first_arg_reg: u16,
+    reg_inf: &mut RegistersInfo,
+) -> Result<Vec<Instruction>> {
+    let mut insns = vec![];
+    let mut restore_array = vec![];
+    let mut reg_count = 0;
+    let array_reg = if array_reg <= 0b1111 {
+        array_reg as u8
+    } else {
+        insns.push(Instruction::MoveObject {
+            from: array_reg,
+            to: reg_inf.array as u16,
+        });
+        restore_array.push(Instruction::MoveObject {
+            from: reg_inf.array as u16,
+            to: array_reg,
+        });
+        reg_inf.array
+    };
+    for (i, param) in params.iter().enumerate() {
+        insns.push(Instruction::Const {
+            reg: reg_inf.array_index,
+            lit: first_index + i as i32,
+        });
+        insns.push(Instruction::AGetObject {
+            dest: reg_inf.array_val,
+            arr: array_reg,
+            idx: reg_inf.array_index,
+        });
+        if param.is_class() || param.is_array() {
+            insns.push(Instruction::CheckCast {
+                reg: reg_inf.array_val,
+                lit: param.clone(),
+            });
+            insns.push(Instruction::MoveObject {
+                from: reg_inf.array_val as u16,
+                to: first_arg_reg + reg_count,
+            });
+            reg_count += 1;
+        } else if param.is_double() || param.is_long() {
+            insns.push(Instruction::CheckCast {
+                reg: reg_inf.array_val,
+                lit: get_obj_of_scalar(param)?,
+            });
+            insns.push(Instruction::InvokeVirtual {
+                method: get_obj_to_scalar_method(param)?,
+                args: vec![reg_inf.array_val as u16],
+            });
+            insns.push(Instruction::MoveResultWide {
+                to: reg_inf.array_val,
+            });
+            insns.push(Instruction::MoveWide {
+                from: reg_inf.array_val as u16,
+                to: first_arg_reg + reg_count,
+            });
+            reg_count += 2;
+        } else {
+            insns.push(Instruction::CheckCast {
+                reg: reg_inf.array_val,
+                lit: get_obj_of_scalar(param)?,
+            });
+            insns.push(Instruction::InvokeVirtual {
+                method: get_obj_to_scalar_method(param)?,
+                args: vec![reg_inf.array_val as u16],
+            });
+            insns.push(Instruction::MoveResult {
+                to: reg_inf.array_val,
+            });
+            insns.push(Instruction::Move {
+                from: reg_inf.array_val as u16,
+                to: first_arg_reg + reg_count,
+            });
+            reg_count += 1;
+        }
+    }
+    insns.append(&mut restore_array);
+    Ok(insns)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn get_cnstr_new_inst_block(
+    ref_data: &ReflectionCnstrNewInstData,
+    invoke_arg: &[u16],
+    reg_inf: &mut RegistersInfo,
+    end_label: &str,
+    move_result: Option<Instruction>,
+    tester_methods_class: IdType,
+    tester_methods: &mut HashMap<(IdMethod, String), Method>,
+    runtime_data: &RuntimeData,
+) -> Result<Vec<Instruction>> {
+    let (cnst_reg, arg_arr) = if let &[a, b] = invoke_arg {
+        (a, b)
+    } else {
+        bail!(
+            "Method;->invoke arg should have exactrly 2 arguments, found {}",
+            invoke_arg.len()
+        );
+    };
+
+    let nb_args = ref_data.constructor.proto.get_parameters().len();
+    if reg_inf.nb_arg_reg < nb_args as u16 + 1 {
+        reg_inf.nb_arg_reg = nb_args as u16 + 1;
+    }
+
+    let abort_label = {
+        // method descriptor in label are hard to debug
+        let name = format!(
+            "end_static_instance_with_{}_from_classloader_{}_at_{:08X}",
             ref_data.constructor.try_to_smali()?,
             &ref_data.constructor_cl_id,
             ref_data.addr
@@ -1535,6 +2438,7 @@ fn get_cnstr_new_inst_block(
     insns.append(&mut get_args_from_obj_arr(
         &ref_data.constructor.proto.get_parameters(), // TODO: what if args are renammed?
         arg_arr,
+        0,
         reg_inf.first_arg + 1,
         reg_inf,
     )?);
@@ -1712,3 +2616,646 @@ fn get_class_new_inst_block(
         Instruction::Label { name: abort_label },
     ])
 }
+
+/// If the call recorded in `ref_data` can be patched at a call site of `entry`. Log the reason
+/// if it cannot.
+fn is_patchable_kotlin_call(ref_data: &KotlinReflectionData, entry: KotlinReflectionEntry) -> bool {
+    if ref_data.entry != entry {
+        warn!(
+            "Kotlin reflection data for {:?} found at a call site of {:?} in {}, ignored",
+            ref_data.entry,
+            entry,
+            ref_data.caller_method.__str__()
+        );
+        false
+    } else if ref_data.bound_receiver && !ref_data.is_static && !ref_data.is_constructor() {
+        warn!(
+            "Cannot patch the call to {} at {}:{:08X}: the receiver is bound to the callable",
+            ref_data.method.__str__(),
+            ref_data.caller_method.__str__(),
+            ref_data.addr
+        );
+        false
+    } else if entry == KotlinReflectionEntry::CreateInstance
+        && !(ref_data.is_constructor() && ref_data.method.proto.get_parameters().is_empty())
+    {
+        warn!(
+            "createInstance() can only call a constructor without parameters, found {}",
+            ref_data.method.__str__()
+        );
+        false
+    } else {
+        true
+    }
+}
+
+/// Generate the block calling the method recorded in `ref_data` at a call site of the Kotlin
+/// reflection `entry` when the callable (or class) matches.
+///
+/// For `call()` and `callBy()`, the callable is tested with `getJavaMethod()` (or
+/// `getJavaConstructor()`) and the tester methods, and the arguments are unpacked from the
+/// vararg array, the receiver of the unbound member functions being its first element. For
+/// `callBy()`, the array is built from the map by [`gen_kotlin_call_by_args`]. For
+/// `createInstance()`, the class is compared like for `Class.newInstance()`.
+#[allow(clippy::too_many_arguments)]
+fn get_kotlin_reflection_block(
+    ref_data: &KotlinReflectionData,
+    entry: KotlinReflectionEntry,
+    invoke_arg: &[u16],
+    reg_inf: &mut RegistersInfo,
+    end_label: &str,
+    move_result: Option<Instruction>,
+    tester_methods_class: IdType,
+    tester_methods: &mut HashMap<(IdMethod, String), Method>,
+    runtime_data: &RuntimeData,
+) -> Result<Vec<Instruction>> {
+    let abort_label = {
+        // method descriptor in label are hard to debug
+        let name = format!(
+            "end_kotlin_call_to_{}_from_classloader_{}_at_{:08X}",
+            ref_data.method.try_to_smali()?,
+            &ref_data.method_cl_id,
+            ref_data.addr
+        );
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        format!("end_static_call_{:x}", hasher.finish())
+    };
+    let callee = ref_data.get_static_callee();
+    let params = ref_data.method.proto.get_parameters();
+
+    if entry == KotlinReflectionEntry::CreateInstance {
+        let class_reg = if let &[a] = invoke_arg {
+            a
+        } else {
+            bail!(
+                "KClasses.createInstance arg should have exactly 1 argument, found {}",
+                invoke_arg.len()
+            );
+        };
+        let obj_reg = match move_result {
+            Some(Instruction::MoveResultObject { to }) => to,
+            _ => reg_inf.array_index,
+        };
+        return Ok(vec![
+            Instruction::InvokeStatic {
+                method: KCLASS_GET_JAVA_CLASS.clone(),
+                args: vec![class_reg],
+            },
+            Instruction::MoveResultObject {
+                to: reg_inf.array_index,
+            },
+            Instruction::InvokeVirtual {
+                method: CLT_GET_DESCR_STRING.clone(),
+                args: vec![reg_inf.array_index as u16],
+            },
+            Instruction::MoveResultObject {
+                to: reg_inf.array_index,
+            },
+            Instruction::ConstClass {
+                reg: reg_inf.array_val,
+                lit: ref_data.method.class_.clone(),
+            },
+            Instruction::InvokeVirtual {
+                method: CLT_GET_DESCR_STRING.clone(),
+                args: vec![reg_inf.array_val as u16],
+            },
+            Instruction::MoveResultObject {
+                to: reg_inf.array_val,
+            },
+            Instruction::InvokeVirtual {
+                method: STR_EQ.clone(),
+                args: vec![reg_inf.array_val as u16, reg_inf.array_index as u16],
+            },
+            Instruction::MoveResult {
+                to: reg_inf.array_val,
+            },
+            Instruction::IfEqZ {
+                a: reg_inf.array_val,
+                label: abort_label.clone(),
+            },
+            Instruction::NewInstance {
+                reg: obj_reg,
+                lit: callee.class_.clone(),
+            },
+            Instruction::InvokeDirect {
+                method: callee,
+                args: vec![obj_reg as u16],
+            },
+            Instruction::Goto {
+                label: end_label.to_string(),
+            },
+            Instruction::Label { name: abort_label },
+        ]);
+    }
+
+    let (callable_reg, arg_reg) = if let &[a, b] = invoke_arg {
+        (a, b)
+    } else {
+        bail!(
+            "KCallable.{{call,callBy}} arg should have exactly 2 arguments, found {}",
+            invoke_arg.len()
+        );
+    };
+    // The receiver of a member function is its first parameter in kotlin-reflect
+    let has_receiver = !ref_data.is_static && !ref_data.is_constructor();
+    let nb_kotlin_params = params.len() + if has_receiver { 1 } else { 0 };
+    let array = reg_inf.array;
+    let mut insns = vec![];
+    if entry == KotlinReflectionEntry::CallBy {
+        let name = format!("kotlin_call_by_args_{nb_kotlin_params}");
+        let helper = tester_methods
+            .entry((KCALLABLE_CALL_BY.clone(), name))
+            .or_insert_with(|| {
+                gen_kotlin_call_by_args(
+                    tester_methods_class.clone(),
+                    nb_kotlin_params,
+                    runtime_data,
+                )
+            })
+            .descriptor
+            .clone();
+        insns.append(&mut vec![
+            Instruction::InvokeStatic {
+                method: helper,
+                args: vec![callable_reg, arg_reg],
+            },
+            Instruction::MoveResultObject { to: array },
+            Instruction::IfEqZ {
+                a: array,
+                label: abort_label.clone(),
+            },
+        ]);
+    } else {
+        insns.append(&mut vec![
+            Instruction::MoveObject {
+                from: arg_reg,
+                to: array as u16,
+            },
+            Instruction::ArrayLength {
+                dest: reg_inf.array_index,
+                arr: array,
+            },
+            Instruction::Const {
+                reg: reg_inf.array_val,
+                lit: nb_kotlin_params as i32,
+            },
+            Instruction::IfNe {
+                a: reg_inf.array_index,
+                b: reg_inf.array_val,
+                label: abort_label.clone(),
+            },
+        ]);
+    }
+    // Get the java.lang.reflect.{Method,Constructor} of the callable, if it is a function
+    insns.append(&mut vec![
+        Instruction::MoveObject {
+            from: callable_reg,
+            to: reg_inf.array_val as u16,
+        },
+        Instruction::InstanceOf {
+            dest: reg_inf.array_index,
+            obj: reg_inf.array_val,
+            lit: KFUNCTION_TY.clone(),
+        },
+        Instruction::IfEqZ {
+            a: reg_inf.array_index,
+            label: abort_label.clone(),
+        },
+        Instruction::CheckCast {
+            reg: reg_inf.array_val,
+            lit: KFUNCTION_TY.clone(),
+        },
+        Instruction::InvokeStatic {
+            method: if ref_data.is_constructor() {
+                KFUNCTION_GET_JAVA_CNSTR.clone()
+            } else {
+                KFUNCTION_GET_JAVA_METHOD.clone()
+            },
+            args: vec![reg_inf.array_val as u16],
+        },
+        Instruction::MoveResultObject {
+            to: reg_inf.array_val,
+        },
+        Instruction::IfEqZ {
+            a: reg_inf.array_val,
+            label: abort_label.clone(),
+        },
+    ]);
+    let classloader = if ref_data.method.class_.is_platform_class() {
+        None
+    } else {
+        Some(ref_data.method_cl_id.clone())
+    };
+    if ref_data.is_constructor() {
+        insns.append(&mut test_cnstr(
+            reg_inf.array_val as u16,
+            ref_data.method.clone(),
+            abort_label.clone(),
+            reg_inf,
+            tester_methods_class,
+            tester_methods,
+            classloader,
+            runtime_data,
+        )?);
+        let nb_args = nb_param_reg(&callee);
+        if reg_inf.nb_arg_reg < nb_args + 1 {
+            reg_inf.nb_arg_reg = nb_args + 1;
+        }
+        insns.append(&mut get_args_from_obj_arr(
+            &params,
+            array as u16,
+            0,
+            reg_inf.first_arg + 1,
+            reg_inf,
+        )?);
+        insns.push(Instruction::NewInstance {
+            reg: reg_inf.array_val,
+            lit: callee.class_.clone(),
+        });
+        insns.push(Instruction::MoveObject {
+            from: reg_inf.array_val as u16,
+            to: reg_inf.first_arg,
+        });
+        insns.push(Instruction::InvokeDirect {
+            method: callee,
+            args: (reg_inf.first_arg..reg_inf.first_arg + nb_args + 1).collect(),
+        });
+        if let Some(Instruction::MoveResultObject { to }) = move_result {
+            insns.push(Instruction::MoveObject {
+                from: reg_inf.first_arg,
+                to: to as u16,
+            });
+        }
+    } else {
+        insns.append(&mut test_method(
+            reg_inf.array_val as u16,
+            ref_data.method.clone(),
+            abort_label.clone(),
+            reg_inf,
+            tester_methods_class,
+            tester_methods,
+            classloader,
+            runtime_data,
+        )?);
+        if has_receiver {
+            insns.push(Instruction::Const {
+                reg: reg_inf.array_index,
+                lit: 0,
+            });
+            insns.push(Instruction::AGetObject {
+                dest: reg_inf.array_val,
+                arr: array,
+                idx: reg_inf.array_index,
+            });
+        }
+        insns.append(&mut get_direct_invoke_insns(
+            &callee,
+            ref_data.is_static,
+            reg_inf.array_val as u16,
+            array as u16,
+            if has_receiver { 1 } else { 0 },
+            reg_inf,
+            move_result,
+        )?);
+    }
+    insns.push(Instruction::Goto {
+        label: end_label.to_string(),
+    });
+    insns.push(Instruction::Label { name: abort_label });
+    Ok(insns)
+}
+
+/// Generate the method `kotlin_call_by_args_<nb_params>(KCallable, Map)` that builds the
+/// argument array of `KCallable.call()` from the argument map of `KCallable.callBy()`, for the
+/// callables with `nb_params` parameters (including the receiver). The method returns `null`
+/// if the map does not contain all the parameters: the callable then uses the default value
+/// of the missing parameters, and the original `callBy()` must be called.
+fn gen_kotlin_call_by_args(
+    tester_methods_class: IdType,
+    nb_params: usize,
+    runtime_data: &RuntimeData,
+) -> Method {
+    let descriptor = IdMethod::new(
+        format!("kotlin_call_by_args_{nb_params}").as_str().into(),
+        IdMethodType::new(
+            ARRAY_LIST_TO_ARRAY.proto.get_return_type(),
+            vec![
+                IdType::class("kotlin/reflect/KCallable"),
+                IdType::class("java/util/Map"),
+            ],
+        ),
+        tester_methods_class,
+    );
+    let mut method = Method::new(descriptor);
+    const REG_PARAMS: u8 = 0;
+    const REG_LIST: u8 = 1;
+    const REG_VAL: u8 = 2;
+    const REG_IDX: u8 = 3;
+    const REG_TST: u8 = 4;
+    const REG_CALLABLE: u8 = 5;
+    const REG_MAP: u8 = 6;
+    let missing_label = "missing_parameter".to_string();
+    let mut insns = vec![
+        Instruction::InvokeInterface {
+            method: KCALLABLE_GET_PARAMS.clone(),
+            args: vec![REG_CALLABLE as u16],
+        },
+        Instruction::MoveResultObject { to: REG_PARAMS },
+        Instruction::InvokeInterface {
+            method: LIST_SIZE.clone(),
+            args: vec![REG_PARAMS as u16],
+        },
+        Instruction::MoveResult { to: REG_TST },
+        Instruction::Const {
+            reg: REG_IDX,
+            lit: nb_params as i32,
+        },
+        Instruction::IfNe {
+            a: REG_TST,
+            b: REG_IDX,
+            label: missing_label.clone(),
+        },
+        Instruction::NewInstance {
+            reg: REG_LIST,
+            lit: ARRAY_LIST_TY.clone(),
+        },
+        Instruction::InvokeDirect {
+            method: ARRAY_LIST_INIT.clone(),
+            args: vec![REG_LIST as u16],
+        },
+    ];
+    for i in 0..nb_params {
+        insns.append(&mut vec![
+            Instruction::Const {
+                reg: REG_IDX,
+                lit: i as i32,
+            },
+            Instruction::InvokeInterface {
+                method: LIST_GET.clone(),
+                args: vec![REG_PARAMS as u16, REG_IDX as u16],
+            },
+            Instruction::MoveResultObject { to: REG_VAL },
+            Instruction::InvokeInterface {
+                method: MAP_CONTAINS_KEY.clone(),
+                args: vec![REG_MAP as u16, REG_VAL as u16],
+            },
+            Instruction::MoveResult { to: REG_TST },
+            Instruction::IfEqZ {
+                a: REG_TST,
+                label: missing_label.clone(),
+            },
+            Instruction::InvokeInterface {
+                method: MAP_GET.clone(),
+                args: vec![REG_MAP as u16, REG_VAL as u16],
+            },
+            Instruction::MoveResultObject { to: REG_VAL },
+            Instruction::InvokeVirtual {
+                method: ARRAY_LIST_ADD.clone(),
+                args: vec![REG_LIST as u16, REG_VAL as u16],
+            },
+        ]);
+    }
+    insns.append(&mut vec![
+        Instruction::InvokeVirtual {
+            method: ARRAY_LIST_TO_ARRAY.clone(),
+            args: vec![REG_LIST as u16],
+        },
+        Instruction::MoveResultObject { to: REG_VAL },
+        Instruction::ReturnObject { reg: REG_VAL },
+        Instruction::Label {
+            name: missing_label,
+        },
+        Instruction::Const {
+            reg: REG_VAL,
+            lit: 0,
+        },
+        Instruction::ReturnObject { reg: REG_VAL },
+    ]);
+    method.is_static = true;
+    method.is_final = true;
+    method.code = Some(Code::new(
+        7, //registers_size, 5 reg + 2 parameter reg
+        insns,
+        Some(vec![Some("callable".into()), Some("args".into())]), // parameter_names
+    ));
+    method.annotations.push(patched_annotation(
+        vec![],
+        vec![],
+        runtime_data.run_id.as_deref(),
+    ));
+    method
+}
+
+/// Generate the block testing if the object returned by the iterator of a
+/// `java.util.ServiceLoader`, in `reg_inf.array_val`, is an instance of the provider of
+/// `ref_data`.
+///
+/// All the calls to `java.util.Iterator.next()` are labeled, so the block may run for any
+/// iterator: a `null` element goes to the next block without being tested.
+///
+/// The iterator already instantiated the provider when the block runs, so the call to the
+/// constructor cannot replace it: the `new-instance` is guarded by a second `null` check on the
+/// element, after the first one, so it is dead code at runtime. It is only emitted to make the
+/// instantiation visible to static analysis.
+fn get_service_new_inst_block(
+    ref_data: &ReflectionServiceNewInstData,
+    reg_inf: &mut RegistersInfo,
+    end_label: &str,
+    move_result: Option<Instruction>,
+) -> Result<Vec<Instruction>> {
+    if !ref_data.constructor.proto.get_parameters().is_empty() {
+        bail!(
+            "ServiceLoader can only instantiate providers with a zero args constructor, found {}",
+            ref_data.constructor.__str__()
+        );
+    }
+    let (abort_label, instantiated_label) = {
+        // method descriptor in label are hard to debug
+        let name = format!(
+            "end_service_instance_with_{}_from_classloader_{}_at_{:08X}",
+            ref_data.constructor.try_to_smali()?,
+            &ref_data.constructor_cl_id,
+            ref_data.addr
+        );
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        let hash = hasher.finish();
+        (
+            format!("end_static_call_{hash:x}"),
+            format!("service_instantiated_{hash:x}"),
+        )
+    };
+    let null_label = format!("{instantiated_label}_null");
+
+    let mut insns = vec![
+        Instruction::IfEqZ {
+            a: reg_inf.array_val,
+            label: abort_label.clone(),
+        },
+        Instruction::InvokeVirtual {
+            method: GET_CLASS.clone(),
+            args: vec![reg_inf.array_val as u16],
+        },
+        Instruction::MoveResultObject {
+            to: reg_inf.array_index,
+        },
+        Instruction::InvokeVirtual {
+            method: CLT_GET_DESCR_STRING.clone(),
+            args: vec![reg_inf.array_index as u16],
+        },
+        Instruction::MoveResultObject {
+            to: reg_inf.array_index,
+        },
+        Instruction::ConstClass {
+            reg: reg_inf.array,
+            lit: ref_data.constructor.class_.clone(),
+        },
+        Instruction::InvokeVirtual {
+            method: CLT_GET_DESCR_STRING.clone(),
+            args: vec![reg_inf.array as u16],
+        },
+        Instruction::MoveResultObject { to: reg_inf.array },
+        Instruction::InvokeVirtual {
+            method: STR_EQ.clone(),
+            args: vec![reg_inf.array as u16, reg_inf.array_index as u16],
+        },
+        Instruction::MoveResult {
+            to: reg_inf.array_index,
+        },
+        Instruction::IfEqZ {
+            a: reg_inf.array_index,
+            label: abort_label.clone(),
+        },
+        Instruction::CheckCast {
+            reg: reg_inf.array_val,
+            lit: ref_data.get_static_constructor().class_,
+        },
+        // Never taken, see above
+        Instruction::IfEqZ {
+            a: reg_inf.array_val,
+            label: null_label.clone(),
+        },
+        Instruction::Goto {
+            label: instantiated_label.clone(),
+        },
+        Instruction::Label { name: null_label },
+        Instruction::NewInstance {
+            reg: reg_inf.array_val,
+            lit: ref_data.get_static_constructor().class_,
+        },
+        Instruction::InvokeDirect {
+            method: ref_data.get_static_constructor(),
+            args: vec![reg_inf.array_val as u16],
+        },
+        Instruction::Label {
+            name: instantiated_label,
+        },
+    ];
+    if let Some(Instruction::MoveResultObject { to }) = move_result {
+        insns.push(Instruction::MoveObject {
+            from: reg_inf.array_val as u16,
+            to: to as u16,
+        });
+    }
+    insns.push(Instruction::Goto {
+        label: end_label.to_string(),
+    });
+    insns.push(Instruction::Label { name: abort_label });
+    Ok(insns)
+}
+
+/// Generate the block creating the array of `ref_data` with a `new-array` when the component
+/// type passed to `java.lang.reflect.Array.newInstance(Class, int)` matches.
+fn get_array_new_inst_block(
+    ref_data: &ReflectionArrayNewInstData,
+    invoke_arg: &[u16],
+    reg_inf: &mut RegistersInfo,
+    end_label: &str,
+    move_result: Option<Instruction>,
+) -> Result<Vec<Instruction>> {
+    let (class_reg, length_reg) = if let &[a, b] = invoke_arg {
+        (a, b)
+    } else {
+        bail!(
+            "Array.newInstance arg should have exactly 2 arguments, found {}",
+            invoke_arg.len()
+        );
+    };
+    let component_type = &ref_data.component_type;
+    let abort_label = {
+        let name = format!(
+            "end_static_array_of_{}_at_{:08X}",
+            component_type.try_to_smali()?,
+            ref_data.addr
+        );
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        format!("end_static_call_{:x}", hasher.finish())
+    };
+
+    let mut insns = vec![
+        Instruction::InvokeVirtual {
+            method: CLT_GET_DESCR_STRING.clone(),
+            args: vec![class_reg],
+        },
+        Instruction::MoveResultObject {
+            to: reg_inf.array_index,
+        },
+    ];
+    if component_type.is_class() || component_type.is_array() {
+        insns.append(&mut vec![
+            Instruction::ConstClass {
+                reg: reg_inf.array,
+                lit: component_type.clone(),
+            },
+            Instruction::InvokeVirtual {
+                method: CLT_GET_DESCR_STRING.clone(),
+                args: vec![reg_inf.array as u16],
+            },
+            Instruction::MoveResultObject { to: reg_inf.array },
+        ]);
+    } else {
+        // No const-class for the scalar types, compare the descriptor directly
+        insns.push(Instruction::ConstString {
+            reg: reg_inf.array,
+            lit: component_type.try_to_smali()?.into(),
+        });
+    }
+    insns.append(&mut vec![
+        Instruction::InvokeVirtual {
+            method: STR_EQ.clone(),
+            args: vec![reg_inf.array as u16, reg_inf.array_index as u16],
+        },
+        Instruction::MoveResult {
+            to: reg_inf.array_index,
+        },
+        Instruction::IfEqZ {
+            a: reg_inf.array_index,
+            label: abort_label.clone(),
+        },
+        Instruction::Move {
+            from: length_reg,
+            to: reg_inf.array_index as u16,
+        },
+        Instruction::NewArray {
+            reg: reg_inf.array_val,
+            size_reg: reg_inf.array_index,
+            lit: IdType::from_smali(&format!(
+                "[{}",
+                ref_data.get_static_component_type().try_to_smali()?
+            ))?,
+        },
+    ]);
+    if let Some(Instruction::MoveResultObject { to }) = move_result {
+        insns.push(Instruction::MoveObject {
+            from: reg_inf.array_val as u16,
+            to: to as u16,
+        });
+    }
+    insns.push(Instruction::Goto {
+        label: end_label.to_string(),
+    });
+    insns.push(Instruction::Label { name: abort_label });
+    Ok(insns)
+}
@@ -0,0 +1,208 @@
+//! Report of the modifications made by the patcher.
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use androscalpel::{IdMethod, IdType};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::components::RegisteredComponent;
+use crate::native_libs::RepackagedLibrary;
+use crate::platform_api::{ApiAccess, HiddenApiList, PlatformApiAction};
+
+/// The modifications made by the patcher, written with `--report`.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Deserialize, Serialize)]
+pub struct PatchReport {
+    /// The id of the run that collected the runtime data used to patch the application.
+    pub run_id: Option<String>,
+    /// The reflective call sites patched.
+    pub sites: Vec<PatchedSite>,
+    /// The class definitions dropped or renamed when inserting the code loaded at runtime.
+    #[serde(default)]
+    pub class_collisions: Vec<ClassCollision>,
+    /// The string constants rewritten to name the renamed classes.
+    #[serde(default)]
+    pub class_string_rewrites: Vec<ClassStringRewrite>,
+    /// The components of the code loaded at runtime added to the manifest.
+    #[serde(default)]
+    pub registered_components: Vec<RegisteredComponent>,
+    /// The class loader construction and `loadClass()` call sites rewritten.
+    #[serde(default)]
+    pub class_loader_rewrites: Vec<ClassLoaderRewrite>,
+    /// The native libraries loaded at runtime added to the apk.
+    #[serde(default)]
+    pub native_libraries: Vec<RepackagedLibrary>,
+    /// The `System.load()` call sites redirected to the repackaged libraries.
+    #[serde(default)]
+    pub native_load_rewrites: Vec<NativeLoadRewrite>,
+    /// The methods added next to the `native` methods to declare their JNI upcalls.
+    #[serde(default)]
+    pub jni_upcall_companions: Vec<JniUpcallCompanion>,
+    /// The dex files of the patched application.
+    #[serde(default)]
+    pub dex_files: Vec<DexFileReport>,
+}
+
+/// A reflective call site patched by [`crate::reflection_patcher::transform_method`].
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct PatchedSite {
+    /// The id of the site (see [`PatchedSite::site_id`]).
+    pub id: String,
+    /// The method containing the call site.
+    pub caller: IdMethod,
+    /// The label of the call site (`THESEUS_ADDR_XXXXXXXX`).
+    pub label: String,
+    /// The reflective method called at this site.
+    pub reflective_method: IdMethod,
+    /// The methods now called directly when the reflective call matches.
+    pub targets: Vec<IdMethod>,
+    /// The component types of the arrays now created directly when the reflective call
+    /// matches (`java.lang.reflect.Array.newInstance()`).
+    #[serde(default)]
+    pub array_types: Vec<IdType>,
+    /// If the original reflective call was removed (`--analysis-only`).
+    pub fallback_removed: bool,
+    /// The platform methods called at this site, and how their calls were patched.
+    #[serde(default)]
+    pub platform_targets: Vec<PlatformTarget>,
+}
+
+impl PatchedSite {
+    /// Compute the id of the call site at `label` in `caller`, `<caller>@<label>`.
+    pub fn site_id(caller: &IdMethod, label: &str) -> String {
+        format!("{}@{label}", caller.__str__())
+    }
+}
+
+/// A platform method called by reflection at a patched site.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct PlatformTarget {
+    pub method: IdMethod,
+    /// The hidden API list of the method, if found in the hidden API flags.
+    pub list: Option<HiddenApiList>,
+    pub access: ApiAccess,
+    pub action: PlatformApiAction,
+    /// The generated method calling `method`, if `action` is [`PlatformApiAction::Helper`].
+    pub helper: Option<IdMethod>,
+}
+
+/// What was done to a class definition that collided with another definition.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionAction {
+    /// The definition was removed from the patched application.
+    Dropped,
+    /// The definition was renamed to this type.
+    Renamed(IdType),
+}
+
+/// A class definition dropped or renamed by [`crate::code_loading_patcher::insert_code`].
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct ClassCollision {
+    /// The name of the class at runtime.
+    pub class: IdType,
+    /// The id of the class loader of the definition.
+    pub loader_id: String,
+    /// The class of the class loader of the definition.
+    pub loader_class: IdType,
+    /// The files loaded by the class loader, empty for the application.
+    pub source_files: Vec<PathBuf>,
+    pub action: CollisionAction,
+    /// Why the definition was dropped or renamed.
+    pub reason: String,
+}
+
+/// A `const-string` naming a class renamed by its class loader, rewritten to the new name.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+pub struct ClassStringRewrite {
+    /// The id of the class loader of the method.
+    pub loader_id: String,
+    /// The method containing the string, with its name in the patched application.
+    pub method: IdMethod,
+    pub original: String,
+    pub new: String,
+}
+
+/// The kinds of call sites rewritten by
+/// [`crate::class_loader_patcher::neutralize_class_loaders`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClassLoaderRewriteKind {
+    /// The class loader built is replaced by the class loader of the application when it loads
+    /// the recorded code.
+    Construction,
+    /// The call to `loadClass()` is redirected to a generated method.
+    LoadClass,
+}
+
+/// A class loader construction or `loadClass()` call site rewritten by
+/// [`crate::class_loader_patcher::neutralize_class_loaders`].
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct ClassLoaderRewrite {
+    /// The method containing the call site.
+    pub caller: IdMethod,
+    /// The label of the call site (`THESEUS_ADDR_XXXXXXXX`).
+    pub label: String,
+    pub kind: ClassLoaderRewriteKind,
+    /// The ids of the class loaders built or called at this site at runtime.
+    pub loader_ids: Vec<String>,
+    /// The generated method now called instead of `loadClass()`, or after the constructor to
+    /// select the class loader.
+    pub helper: Option<IdMethod>,
+    /// The classes the generated method returns with a `const-class`.
+    pub classes: Vec<IdType>,
+}
+
+/// A `System.load()` call site redirected by
+/// [`crate::native_libs::repackage_native_libraries`].
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct NativeLoadRewrite {
+    /// The method containing the call site.
+    pub caller: IdMethod,
+    /// The label of the call site (`THESEUS_ADDR_XXXXXXXX`).
+    pub label: String,
+    /// The generated method now called instead of `System.load()`.
+    pub helper: IdMethod,
+    /// The names of the libraries the generated method may load with `System.loadLibrary()`.
+    pub libraries: Vec<String>,
+}
+
+/// A method generated by [`crate::jni_upcalls::add_jni_upcall_companions`] to declare the
+/// Java methods called from the native implementation of a `native` method.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct JniUpcallCompanion {
+    /// The `native` method.
+    pub native_method: IdMethod,
+    /// The generated method, defined in the class of `native_method`.
+    pub companion: IdMethod,
+    /// The Java methods called from native code.
+    pub targets: Vec<IdMethod>,
+}
+
+impl PatchReport {
+    pub fn write(&self, out: impl Write) -> Result<()> {
+        serde_json::to_writer_pretty(out, self)?;
+        Ok(())
+    }
+
+    pub fn load(input: impl Read) -> Result<Self> {
+        Ok(serde_json::from_reader(input)?)
+    }
+}
+
+/// The number of classes and references of a dex file of the patched application, read from
+/// its header.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct DexFileReport {
+    pub name: String,
+    /// If the dex file was not in the original application, `None` if the dex files of the
+    /// original application could not be listed.
+    pub generated: Option<bool>,
+    pub classes: u32,
+    pub string_ids: u32,
+    pub type_ids: u32,
+    pub proto_ids: u32,
+    pub field_ids: u32,
+    pub method_ids: u32,
+}
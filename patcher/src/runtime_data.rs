@@ -1,4 +1,4 @@
-use androscalpel::{IdMethod, IdType};
+use androscalpel::{DexString, IdMethod, IdType};
 use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 
@@ -10,12 +10,38 @@ pub struct RuntimeData {
     pub class_new_inst_data: Vec<ReflectionClassNewInstData>,
     pub cnstr_new_inst_data: Vec<ReflectionCnstrNewInstData>,
     pub dyn_code_load: Vec<DynamicCodeLoadingData>,
+    /// The classes loaded with `ClassLoader.loadClass()` from the class loaders of
+    /// [`Self::dyn_code_load`].
+    #[serde(default)]
+    pub class_loading: Vec<ClassLoadingData>,
+    /// The native libraries loaded with `System.load()`.
+    #[serde(default)]
+    pub native_loads: Vec<NativeLoadData>,
+    /// The calls made through the Kotlin reflection API (`kotlin.reflect`).
+    #[serde(default)]
+    pub kotlin_reflection: Vec<KotlinReflectionData>,
+    /// The service providers instantiated by the iterators of `java.util.ServiceLoader`.
+    #[serde(default)]
+    pub service_new_inst_data: Vec<ReflectionServiceNewInstData>,
+    /// The arrays created with `java.lang.reflect.Array.newInstance()`.
+    #[serde(default)]
+    pub array_new_inst_data: Vec<ReflectionArrayNewInstData>,
+    /// The Java methods called from native code through the JNI (`Call*Method()`,
+    /// `NewObject()`).
+    #[serde(default)]
+    pub jni_upcalls: Vec<JniUpcallData>,
+    /// The implementations of the native methods registered with the JNI function
+    /// `RegisterNatives()`.
+    #[serde(default)]
+    pub native_bindings: Vec<NativeBindingData>,
     /// The id of the class loader of the apk (the main classloader)
     pub apk_cl_id: Option<String>,
     /// Additionnal classloader data.
     pub classloaders: HashMap<String, ClassLoaderData>,
     /// Additionnal application data.
     pub app_info: Option<AppInfo>,
+    /// Unique id of the run that collected the data.
+    pub run_id: Option<String>,
 }
 
 impl RuntimeData {
@@ -26,6 +52,20 @@ impl RuntimeData {
         self.class_new_inst_data.dedup();
         self.cnstr_new_inst_data.sort();
         self.cnstr_new_inst_data.dedup();
+        self.class_loading.sort();
+        self.class_loading.dedup();
+        self.native_loads.sort();
+        self.native_loads.dedup();
+        self.kotlin_reflection.sort();
+        self.kotlin_reflection.dedup();
+        self.service_new_inst_data.sort();
+        self.service_new_inst_data.dedup();
+        self.array_new_inst_data.sort();
+        self.array_new_inst_data.dedup();
+        self.jni_upcalls.sort();
+        self.jni_upcalls.dedup();
+        self.native_bindings.sort();
+        self.native_bindings.dedup();
         // TODO; dedup dyn_code_load?
     }
     /// List all the methods that made reflection calls.
@@ -41,11 +81,32 @@ impl RuntimeData {
                         self.cnstr_new_inst_data
                             .iter()
                             .map(|data| data.caller_method.clone()),
+                    )
+                    .chain(
+                        self.kotlin_reflection
+                            .iter()
+                            .map(|data| data.caller_method.clone()),
+                    )
+                    .chain(
+                        self.service_new_inst_data
+                            .iter()
+                            .map(|data| data.caller_method.clone()),
+                    )
+                    .chain(
+                        self.array_new_inst_data
+                            .iter()
+                            .map(|data| data.caller_method.clone()),
                     ),
             )
             .collect()
     }
 
+    /// Build the index of the reflection data by caller method. This is what the patching code
+    /// should use, the `get_*_for()` methods scan all the data at each call.
+    pub fn index(&self) -> RuntimeDataIndex {
+        RuntimeDataIndex::new(self)
+    }
+
     /// List all data collected from called to `java.lang.reflect.Method.invoke()` made by
     /// `method`.
     pub fn get_invoke_data_for(
@@ -102,6 +163,98 @@ impl RuntimeData {
     }
 }
 
+/// The reflection data collected for the calls made by one method, indexed by the label of the
+/// call site (`THESEUS_ADDR_XXXXXXXX`, see [`crate::labeling`]).
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct MethodRuntimeData {
+    pub invoke_data: HashMap<String, Vec<ReflectionInvokeData>>,
+    pub class_new_inst_data: HashMap<String, Vec<ReflectionClassNewInstData>>,
+    pub cnstr_new_inst_data: HashMap<String, Vec<ReflectionCnstrNewInstData>>,
+    pub kotlin_reflection: HashMap<String, Vec<KotlinReflectionData>>,
+    pub service_new_inst_data: HashMap<String, Vec<ReflectionServiceNewInstData>>,
+    pub array_new_inst_data: HashMap<String, Vec<ReflectionArrayNewInstData>>,
+}
+
+/// Index of the reflection data of a [`RuntimeData`]: caller method -> label -> records.
+///
+/// Built once with [`RuntimeData::index`], so that looking up the data of a method does not
+/// require scanning all the records.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct RuntimeDataIndex {
+    methods: HashMap<IdMethod, MethodRuntimeData>,
+}
+
+impl RuntimeDataIndex {
+    pub fn new(data: &RuntimeData) -> Self {
+        let mut methods: HashMap<IdMethod, MethodRuntimeData> = HashMap::new();
+        for val in &data.invoke_data {
+            methods
+                .entry(val.caller_method.clone())
+                .or_default()
+                .invoke_data
+                .entry(format!("THESEUS_ADDR_{:08X}", val.addr))
+                .or_default()
+                .push(val.clone());
+        }
+        for val in &data.class_new_inst_data {
+            methods
+                .entry(val.caller_method.clone())
+                .or_default()
+                .class_new_inst_data
+                .entry(format!("THESEUS_ADDR_{:08X}", val.addr))
+                .or_default()
+                .push(val.clone());
+        }
+        for val in &data.cnstr_new_inst_data {
+            methods
+                .entry(val.caller_method.clone())
+                .or_default()
+                .cnstr_new_inst_data
+                .entry(format!("THESEUS_ADDR_{:08X}", val.addr))
+                .or_default()
+                .push(val.clone());
+        }
+        for val in &data.kotlin_reflection {
+            methods
+                .entry(val.caller_method.clone())
+                .or_default()
+                .kotlin_reflection
+                .entry(format!("THESEUS_ADDR_{:08X}", val.addr))
+                .or_default()
+                .push(val.clone());
+        }
+        for val in &data.service_new_inst_data {
+            methods
+                .entry(val.caller_method.clone())
+                .or_default()
+                .service_new_inst_data
+                .entry(format!("THESEUS_ADDR_{:08X}", val.addr))
+                .or_default()
+                .push(val.clone());
+        }
+        for val in &data.array_new_inst_data {
+            methods
+                .entry(val.caller_method.clone())
+                .or_default()
+                .array_new_inst_data
+                .entry(format!("THESEUS_ADDR_{:08X}", val.addr))
+                .or_default()
+                .push(val.clone());
+        }
+        Self { methods }
+    }
+
+    /// Get the data collected for the reflection calls made by `method`.
+    pub fn get(&self, method: &IdMethod) -> Option<&MethodRuntimeData> {
+        self.methods.get(method)
+    }
+
+    /// List all the methods that made reflection calls.
+    pub fn methods(&self) -> impl Iterator<Item = &IdMethod> {
+        self.methods.keys()
+    }
+}
+
 /// Structure storing the runtime information of a reflection call using
 /// `java.lang.reflect.Method.invoke()`.
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
@@ -132,6 +285,67 @@ impl ReflectionInvokeData {
     }
 }
 
+/// The entry points of the Kotlin reflection API.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum KotlinReflectionEntry {
+    /// `kotlin.reflect.KCallable.call(vararg args)`
+    Call,
+    /// `kotlin.reflect.KCallable.callBy(args: Map<KParameter, Any?>)`
+    CallBy,
+    /// `kotlin.reflect.full.createInstance()` (`KClasses.createInstance(KClass)`)
+    CreateInstance,
+}
+
+/// Structure storing the runtime information of a call made through the Kotlin reflection API.
+///
+/// kotlin-reflect calls the method with `java.lang.reflect.Method.invoke()` (or
+/// `Constructor.newInstance()`) from its internals: this records the call to the entry point
+/// made by the application instead.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+pub struct KotlinReflectionData {
+    /// The entry point called by the application.
+    pub entry: KotlinReflectionEntry,
+    /// The method (or constructor) called by kotlin-reflect (at runtime)
+    pub method: IdMethod,
+    /// The id of the classloader defining the method
+    pub method_cl_id: String,
+    /// The name of the method to call statically.
+    pub renamed_method: Option<IdMethod>,
+    /// The method calling the entry point (at runtime)
+    pub caller_method: IdMethod,
+    /// The id of the classloader defining the caller method
+    pub caller_cl_id: String,
+    /// The name of the method that call the entry point (statically)
+    pub renamed_caller_method: Option<IdMethod>,
+    /// Address where the entry point was called in `caller_method`.
+    pub addr: usize,
+    /// If the method is static (static method don't take 'this' as argument)
+    pub is_static: bool,
+    /// If the callable is bound to a receiver (eg `obj::method`), the receiver is then not
+    /// passed in the arguments.
+    pub bound_receiver: bool,
+}
+
+impl KotlinReflectionData {
+    pub fn get_static_callee(&self) -> IdMethod {
+        self.renamed_method
+            .clone()
+            .unwrap_or_else(|| self.method.clone())
+    }
+
+    pub fn get_static_caller(&self) -> IdMethod {
+        self.renamed_caller_method
+            .clone()
+            .unwrap_or_else(|| self.caller_method.clone())
+    }
+
+    /// If the method called is a constructor.
+    pub fn is_constructor(&self) -> bool {
+        self.method.name == DexString::from("<init>")
+    }
+}
+
 /// Structure storing the runtime information of a reflection instanciation using
 /// `java.lang.Class.newInstance()`.
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
@@ -208,6 +422,148 @@ impl ReflectionCnstrNewInstData {
     }
 }
 
+/// Structure storing the runtime information of a service provider instantiated by the iterator
+/// of a `java.util.ServiceLoader`.
+///
+/// The `ServiceLoader` instantiates the provider with reflection from its internals when the
+/// application calls `java.util.Iterator.next()`: this records the call to `next()`.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+pub struct ReflectionServiceNewInstData {
+    /// The constructor of the provider called by the `ServiceLoader`
+    pub constructor: IdMethod,
+    /// The id of the classloader defining the constructor
+    pub constructor_cl_id: String,
+    /// The name of the constructor to call statically.
+    pub renamed_constructor: Option<IdMethod>,
+    /// The method calling `java.util.Iterator.next()`
+    pub caller_method: IdMethod,
+    /// The id of the classloader defining the caller method
+    pub caller_cl_id: String,
+    /// The name of the method that call the method (statically)
+    pub renamed_caller_method: Option<IdMethod>,
+    /// Address where the call to `java.util.Iterator.next()` was made in `caller_method`.
+    pub addr: usize,
+}
+
+impl ReflectionServiceNewInstData {
+    pub fn get_static_constructor(&self) -> IdMethod {
+        self.renamed_constructor
+            .clone()
+            .unwrap_or_else(|| self.constructor.clone())
+    }
+
+    pub fn get_static_caller(&self) -> IdMethod {
+        self.renamed_caller_method
+            .clone()
+            .unwrap_or_else(|| self.caller_method.clone())
+    }
+}
+
+/// Structure storing the runtime information of an array created with
+/// `java.lang.reflect.Array.newInstance(Class, int)`.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+pub struct ReflectionArrayNewInstData {
+    /// The type of the components of the array
+    pub component_type: IdType,
+    /// The id of the classloader defining the component type, `None` for the scalar types and
+    /// the classes of the boot classloader.
+    pub component_cl_id: Option<String>,
+    /// The name of the component type to use statically.
+    pub renamed_component_type: Option<IdType>,
+    /// The method calling `java.lang.reflect.Array.newInstance()`
+    pub caller_method: IdMethod,
+    /// The id of the classloader defining the caller method
+    pub caller_cl_id: String,
+    /// The name of the method that call the method (statically)
+    pub renamed_caller_method: Option<IdMethod>,
+    /// Address where the call to `java.lang.reflect.Array.newInstance()` was made in
+    /// `caller_method`.
+    pub addr: usize,
+}
+
+impl ReflectionArrayNewInstData {
+    pub fn get_static_component_type(&self) -> IdType {
+        self.renamed_component_type
+            .clone()
+            .unwrap_or_else(|| self.component_type.clone())
+    }
+}
+
+/// Structure storing the runtime information of a Java method called from native code through
+/// the JNI.
+///
+/// The call is made from the native implementation of a Java `native` method, so there is no
+/// call site in the bytecode: the record is attached to the `native` method.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+pub struct JniUpcallData {
+    /// The JNI function used to call the method (eg `CallVoidMethodV`, `NewObjectA`)
+    pub function: String,
+    /// The Java method called from native code
+    pub method: IdMethod,
+    /// The id of the classloader defining the method, `None` for the classes of the boot
+    /// classloader.
+    pub method_cl_id: Option<String>,
+    /// The name of the method to call statically.
+    pub renamed_method: Option<IdMethod>,
+    /// If the method is static
+    pub is_static: bool,
+    /// If the method is declared by an interface
+    pub is_interface: bool,
+    /// The `native` method whose implementation called the JNI function
+    pub native_method: IdMethod,
+    /// The id of the classloader defining the native method
+    pub native_method_cl_id: String,
+    /// The name of the native method in the patched application
+    pub renamed_native_method: Option<IdMethod>,
+    /// The name of the native library calling the JNI function, if known
+    pub library: Option<String>,
+    /// The offset in `library` of the return address of the call to the JNI function
+    pub offset: Option<usize>,
+}
+
+impl JniUpcallData {
+    pub fn get_static_method(&self) -> IdMethod {
+        self.renamed_method
+            .clone()
+            .unwrap_or_else(|| self.method.clone())
+    }
+
+    pub fn get_static_native_method(&self) -> IdMethod {
+        self.renamed_native_method
+            .clone()
+            .unwrap_or_else(|| self.native_method.clone())
+    }
+}
+
+/// Structure storing the implementation of a native method registered with the JNI function
+/// `RegisterNatives()`.
+///
+/// The implementation of these methods cannot be found from the name of the symbols
+/// (`Java_<class>_<method>`), the function is only known at runtime.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+pub struct NativeBindingData {
+    /// The `native` method bound
+    pub method: IdMethod,
+    /// The id of the classloader defining the method
+    pub method_cl_id: String,
+    /// The name of the method in the patched application
+    pub renamed_method: Option<IdMethod>,
+    /// The name of the native library containing the implementation, if known
+    pub library: Option<String>,
+    /// The name of the symbol of the implementation, if the library exports one
+    pub symbol: Option<String>,
+    /// The offset of the implementation in `library`
+    pub offset: Option<usize>,
+}
+
+impl NativeBindingData {
+    pub fn get_static_method(&self) -> IdMethod {
+        self.renamed_method
+            .clone()
+            .unwrap_or_else(|| self.method.clone())
+    }
+}
+
 /// Structure storing the runtime information of a dynamic code loading.
 #[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
 pub struct DynamicCodeLoadingData {
@@ -217,8 +573,109 @@ pub struct DynamicCodeLoadingData {
     pub classloader: String,
     /// An identifier for the parent classloader, valid for one specific run of the applications.
     pub classloader_parent: Option<String>,
+    /// The identifiers of the shared library classloaders consulted before the code of the
+    /// classloader (`BaseDexClassLoader.sharedLibraryLoaders`). `sharedLibraryLoadersAfter` is
+    /// not set when the code is loaded, see [`ClassLoaderData::shared_libraries_after`].
+    #[serde(default)]
+    pub classloader_shared_libraries: Vec<String>,
     /// The path to the files storing the .dex/.apk/other bytecode loaded.
     pub files: Vec<PathBuf>,
+    /// The paths of the loaded files on the device, as given to the classloader. Empty for the
+    /// code loaded from memory.
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// Where the application constructed the classloader, if it was found in the stack.
+    #[serde(default)]
+    pub construction_site: Option<ClassLoaderConstructionSite>,
+}
+
+/// Structure storing the call to the constructor of a classloader that loaded code.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+pub struct ClassLoaderConstructionSite {
+    /// The constructor of the classloader called.
+    pub constructor: IdMethod,
+    /// The method calling the constructor.
+    pub caller_method: IdMethod,
+    /// The id of the classloader defining the caller method
+    pub caller_cl_id: String,
+    /// The name of the method that call the method (statically)
+    pub renamed_caller_method: Option<IdMethod>,
+    /// Address where the constructor was called in `caller_method`.
+    pub addr: usize,
+}
+
+impl ClassLoaderConstructionSite {
+    pub fn get_static_caller(&self) -> IdMethod {
+        self.renamed_caller_method
+            .clone()
+            .unwrap_or_else(|| self.caller_method.clone())
+    }
+}
+
+/// Structure storing the runtime information of a class loaded with
+/// `java.lang.ClassLoader.loadClass()`.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+pub struct ClassLoadingData {
+    /// The class loaded.
+    pub class: IdType,
+    /// The id of the classloader `loadClass()` was called on.
+    pub classloader: String,
+    /// The id of the classloader defining the class.
+    pub class_cl_id: String,
+    /// The name of the class in the patched application.
+    pub renamed_class: Option<IdType>,
+    /// The method calling `java.lang.ClassLoader.loadClass()`
+    pub caller_method: IdMethod,
+    /// The id of the classloader defining the caller method
+    pub caller_cl_id: String,
+    /// The name of the method that call the method (statically)
+    pub renamed_caller_method: Option<IdMethod>,
+    /// Address where the call to `java.lang.ClassLoader.loadClass()` was made in
+    /// `caller_method`.
+    pub addr: usize,
+}
+
+impl ClassLoadingData {
+    pub fn get_static_class(&self) -> IdType {
+        self.renamed_class
+            .clone()
+            .unwrap_or_else(|| self.class.clone())
+    }
+
+    pub fn get_static_caller(&self) -> IdMethod {
+        self.renamed_caller_method
+            .clone()
+            .unwrap_or_else(|| self.caller_method.clone())
+    }
+}
+
+/// Structure storing the runtime information of a native library loaded with
+/// `java.lang.System.load()`.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize, PartialOrd, Ord)]
+pub struct NativeLoadData {
+    /// The path passed to `java.lang.System.load()`.
+    pub path: String,
+    /// The path to the file storing a copy of the library, if it could be read.
+    pub file: Option<PathBuf>,
+    /// The ABI of the library (eg `arm64-v8a`), read from its ELF header.
+    pub abi: Option<String>,
+    /// The method calling `java.lang.System.load()`
+    pub caller_method: IdMethod,
+    /// The id of the classloader defining the caller method. The library is bound to this
+    /// classloader.
+    pub caller_cl_id: String,
+    /// The name of the method that call the method (statically)
+    pub renamed_caller_method: Option<IdMethod>,
+    /// Address where the call to `java.lang.System.load()` was made in `caller_method`.
+    pub addr: usize,
+}
+
+impl NativeLoadData {
+    pub fn get_static_caller(&self) -> IdMethod {
+        self.renamed_caller_method
+            .clone()
+            .unwrap_or_else(|| self.caller_method.clone())
+    }
 }
 
 /// Structure storing the runtime information of a classloader.
@@ -234,6 +691,14 @@ pub struct ClassLoaderData {
     pub string_representation: String,
     /// The class of the class loader.
     pub cname: IdType,
+    /// The Ids of the shared library classloaders searched before the classes of the
+    /// classloader (`BaseDexClassLoader.sharedLibraryLoaders`).
+    #[serde(default)]
+    pub shared_libraries: Vec<String>,
+    /// The Ids of the shared library classloaders searched after the classes of the
+    /// classloader (`BaseDexClassLoader.sharedLibraryLoadersAfter`).
+    #[serde(default)]
+    pub shared_libraries_after: Vec<String>,
 }
 
 /// Structure storing application information
@@ -0,0 +1,98 @@
+//! Export of the methods modified by the patcher as unified diffs of their smali-like listing,
+//! for review.
+
+use std::fs::{create_dir_all, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use androscalpel::{Class, Instruction, Method};
+use anyhow::{Context, Result};
+use similar::TextDiff;
+
+/// Number of unchanged lines shown around the changes.
+const CONTEXT_RADIUS: usize = 8;
+
+/// Generate the listing of `method`. The labels (including the `THESEUS_ADDR_XXXXXXXX` labels
+/// added by [`crate::labeling`]) are not indented so they can be used as anchors.
+pub fn method_listing(method: &Method) -> String {
+    let mut listing = format!(".method {}\n", method.descriptor.__str__());
+    if let Some(code) = method.code.as_ref() {
+        listing.push_str(&format!("    .registers {}\n", code.registers_size));
+        for ins in &code.insns {
+            match ins {
+                Instruction::Label { name } => listing.push_str(&format!(":{name}\n")),
+                ins => listing.push_str(&format!("    {}\n", ins.__str__())),
+            }
+        }
+    }
+    listing.push_str(".end method\n");
+    listing
+}
+
+/// Compute the path of the file for `name` in `dir`.
+fn dump_path(dir: &Path, name: &str, extension: &str) -> PathBuf {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '$' | '-' | '_' => c,
+            '/' => '.',
+            _ => '_',
+        })
+        .collect();
+    dir.join(format!("{name}.{extension}"))
+}
+
+fn write_diff(path: &Path, old_name: &str, old: &str, new_name: &str, new: &str) -> Result<()> {
+    let diff = TextDiff::from_lines(old, new);
+    let mut file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    write!(
+        file,
+        "{}",
+        diff.unified_diff()
+            .context_radius(CONTEXT_RADIUS)
+            .header(old_name, new_name)
+    )?;
+    Ok(())
+}
+
+/// Write the diff between `original` and `patched` in `dir`.
+pub fn dump_method_diff(dir: &Path, original: &Method, patched: &Method) -> Result<()> {
+    create_dir_all(dir)?;
+    let name = patched.descriptor.__str__();
+    write_diff(
+        &dump_path(dir, &name, "diff"),
+        &format!("original/{name}"),
+        &method_listing(original),
+        &format!("patched/{name}"),
+        &method_listing(patched),
+    )
+}
+
+/// Write the listing of the methods of a class generated by the patcher in `dir`, as a diff
+/// creating the class.
+pub fn dump_generated_class(dir: &Path, class: &Class) -> Result<()> {
+    create_dir_all(dir)?;
+    let name = class.descriptor.__str__();
+    let mut methods: Vec<_> = class
+        .direct_methods
+        .values()
+        .chain(class.virtual_methods.values())
+        .collect();
+    methods.sort_by(|a, b| a.descriptor.cmp(&b.descriptor));
+    let listing = format!(
+        ".class {name}\n\n{}",
+        methods
+            .into_iter()
+            .map(method_listing)
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    write_diff(
+        &dump_path(dir, &name, "diff"),
+        "/dev/null",
+        "",
+        &format!("patched/{name}"),
+        &listing,
+    )
+}
@@ -0,0 +1,131 @@
+//! Statistics about the patched call sites reached at runtime.
+//!
+//! When patching with `--trace`, each patched call site logs a line with the tag [`TRACE_TAG`]
+//! when a target matches (`site=<site id> target=<method>`) or when the execution falls back
+//! to the original reflective call (`site=<site id> fallback`). This module parses a logcat
+//! capture and joins it with the [`PatchReport`] of the patched application.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, Read};
+
+use androscalpel::IdMethod;
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::report::PatchReport;
+
+/// The tag used by the generated code to log the patched call sites reached.
+pub const TRACE_TAG: &str = "THESEUS_TRACE";
+
+/// Generate the message logged when `target` is called directly at `site`.
+pub fn hit_message(site: &str, target: &IdMethod) -> String {
+    format!("site={site} target={}", target.__str__())
+}
+
+/// Generate the message logged when the execution falls back to the reflective call at `site`.
+pub fn fallback_message(site: &str) -> String {
+    format!("site={site} fallback")
+}
+
+/// A line logged by a patched call site.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TraceEvent {
+    /// `target` was called directly.
+    Hit { site: String, target: String },
+    /// No target matched, the original reflective call was used.
+    Fallback { site: String },
+}
+
+/// Parse the trace lines of a logcat capture. The other lines are ignored, so any format
+/// printing the tag before the message works (`threadtime`, `brief`, ...).
+pub fn parse_logcat(input: impl Read) -> Result<Vec<TraceEvent>> {
+    let mut events = vec![];
+    for line in BufReader::new(input).lines() {
+        let line = line?;
+        let Some((_, msg)) = line.split_once(TRACE_TAG) else {
+            continue;
+        };
+        let Some((_, msg)) = msg.split_once("site=") else {
+            continue;
+        };
+        let mut tokens = msg.split_whitespace();
+        let Some(site) = tokens.next() else {
+            continue;
+        };
+        match tokens.next() {
+            Some("fallback") => events.push(TraceEvent::Fallback { site: site.into() }),
+            Some(target) if target.starts_with("target=") => events.push(TraceEvent::Hit {
+                site: site.into(),
+                target: target["target=".len()..].into(),
+            }),
+            _ => warn!("Invalid trace line: {line}"),
+        }
+    }
+    Ok(events)
+}
+
+/// The number of times a patched call site was reached.
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub struct SiteStatistics {
+    /// The id of the site.
+    pub site: String,
+    /// If the site is listed in the patch report.
+    pub in_report: bool,
+    /// The targets patched in, from the patch report.
+    pub targets: Vec<IdMethod>,
+    /// The number of direct calls to each target.
+    pub hits: BTreeMap<String, usize>,
+    /// The number of times the original reflective call was used.
+    pub fallbacks: usize,
+    /// The ratio of the calls handled by a direct call, `None` if the site was never reached.
+    pub hit_rate: Option<f64>,
+}
+
+/// Compute the statistics of each site of `report`, and of the sites found in `events` but
+/// not in `report` (usually a report from another patching run).
+pub fn site_statistics(report: &PatchReport, events: &[TraceEvent]) -> Vec<SiteStatistics> {
+    let mut stats: BTreeMap<String, SiteStatistics> = BTreeMap::new();
+    let new_stats = |site: &str, in_report, targets| SiteStatistics {
+        site: site.into(),
+        in_report,
+        targets,
+        hits: BTreeMap::new(),
+        fallbacks: 0,
+        hit_rate: None,
+    };
+    for site in &report.sites {
+        stats.insert(
+            site.id.clone(),
+            new_stats(&site.id, true, site.targets.clone()),
+        );
+    }
+    let mut unknown_sites: BTreeSet<&str> = BTreeSet::new();
+    for event in events {
+        let site = match event {
+            TraceEvent::Hit { site, .. } | TraceEvent::Fallback { site } => site,
+        };
+        let stat = stats.entry(site.clone()).or_insert_with(|| {
+            unknown_sites.insert(site.as_str());
+            new_stats(site.as_str(), false, vec![])
+        });
+        match event {
+            TraceEvent::Hit { target, .. } => *stat.hits.entry(target.clone()).or_default() += 1,
+            TraceEvent::Fallback { .. } => stat.fallbacks += 1,
+        }
+    }
+    for site in unknown_sites {
+        warn!("Site {site} found in the trace but not in the patch report");
+    }
+    stats
+        .into_values()
+        .map(|mut stat| {
+            let nb_hits: usize = stat.hits.values().sum();
+            let total = nb_hits + stat.fallbacks;
+            if total != 0 {
+                stat.hit_rate = Some(nb_hits as f64 / total as f64);
+            }
+            stat
+        })
+        .collect()
+}
@@ -1,6 +1,7 @@
 package com.example.theseus.dynandref;
 
 import android.app.Activity;
+import android.os.Build;
 import android.util.Log;
 import java.lang.ClassLoader;
 import dalvik.system.PathClassLoader;
@@ -22,6 +23,8 @@ public class Main {
     static ClassLoader inMemoryDexClassLoader = null;
     static ClassLoader pathClassLoaderParent = null;
     static ClassLoader pathClassLoader = null;
+    static ClassLoader sharedLibraryPathClassLoaderParent = null;
+    static ClassLoader sharedLibraryPathClassLoader = null;
 
     public static String getdexfile(Activity ac, String name) throws Exception {
         File dexfile = new File(ac.getCacheDir(), name);
@@ -98,6 +101,26 @@ public class Main {
                 }
                 cl = pathClassLoaderParent;
             }
+        } else if (clname.equals("SharedLibraryPathClassLoader")) {
+            // The constructor taking shared libraries is only public since API 29
+            if (Build.VERSION.SDK_INT < Build.VERSION_CODES.Q) {
+                Utils.popup(ac, "Skipped", "SharedLibraryPathClassLoader needs API 29");
+                return;
+            }
+            // The shared library is consulted after the parent but before the dex of the class loader
+            if (parent == null) {
+                if (sharedLibraryPathClassLoader == null) {
+                    ClassLoader[] sharedLibraries = {new PathClassLoader(getdexfile(ac, "c.dex"), parent)};
+                    sharedLibraryPathClassLoader = new PathClassLoader(getdexfile(ac, name), null, parent, sharedLibraries);
+                }
+                cl = sharedLibraryPathClassLoader;
+            } else {
+                if (sharedLibraryPathClassLoaderParent == null) {
+                    ClassLoader[] sharedLibraries = {new PathClassLoader(getdexfile(ac, "d.dex"), parent)};
+                    sharedLibraryPathClassLoaderParent = new PathClassLoader(getdexfile(ac, name), null, parent, sharedLibraries);
+                }
+                cl = sharedLibraryPathClassLoaderParent;
+            }
         }
 
         if (hasCollision) {
@@ -54,6 +54,17 @@ public class MainActivity extends Activity {
             out = new FileOutputStream(outFile);
             Utils.copy(in, out);
             outFile.renameTo(new File(getCacheDir(), "b.dex"));
+            // Loaded by the shared library class loaders
+            in = assetManager.open("a.dex");
+            outFile = new File(getCacheDir(), "c.dex_");
+            out = new FileOutputStream(outFile);
+            Utils.copy(in, out);
+            outFile.renameTo(new File(getCacheDir(), "c.dex"));
+            in = assetManager.open("a.dex");
+            outFile = new File(getCacheDir(), "d.dex_");
+            out = new FileOutputStream(outFile);
+            Utils.copy(in, out);
+            outFile.renameTo(new File(getCacheDir(), "d.dex"));
         } catch (IOException e) {}
         try {
             in.close();
@@ -0,0 +1,3 @@
+package com.example.theseus.dynandref;
+
+public class NoCollisionWithoutParentSharedLibraryPathClassLoaderActivity extends MethodActivity {}
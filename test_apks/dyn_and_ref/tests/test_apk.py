@@ -68,6 +68,17 @@ def clear_log(adb: str | Path):
     subprocess.Popen([adb, "logcat", "-c"])
 
 
+def get_sdk_version(adb: str | Path) -> int:
+    return int(
+        subprocess.run(
+            [adb, "shell", "getprop", "ro.build.version.sdk"],
+            encoding="utf-8",
+            stdout=subprocess.PIPE,
+            check=True,
+        ).stdout.strip()
+    )
+
+
 def get_log_proc(adb: str | Path, tag: str) -> subprocess.Popen:
     return subprocess.Popen(
         [adb, "logcat", "-s", tag, "--format=raw"],
@@ -106,6 +117,7 @@ CLASS_LOADERS = [
     "DexClassLoader",
     "InMemoryDexClassLoader",
     "PathClassLoader",
+    "SharedLibraryPathClassLoader",
 ]
 METHOD_TYPES = [
     "Virtual",
@@ -124,7 +136,11 @@ def main():
     adb = get_adb()
     clear_log(adb)
     logs = get_log_proc(adb, "THESEUS")
+    sdk_version = get_sdk_version(adb)
     for clname in CLASS_LOADERS:
+        # PathClassLoader(String, String, ClassLoader, ClassLoader[]) is public since API 29
+        if clname == "SharedLibraryPathClassLoader" and sdk_version < 29:
+            continue
         for hasCollision in COLLISION_OPT:
             for hasParent in PARENT_OPT:
                 for methodType in METHOD_TYPES:
@@ -17,8 +17,8 @@ def patch_apk(
     runtime_data: Path,
     apk: Path,
     apkout: Path,
-    zipalign: Path,
-    apksigner: Path,
+    zipalign: None | Path,
+    apksigner: None | Path,
     keystore: Path,
     keypass: None | str = None,
 ):
@@ -26,6 +26,13 @@ def patch_apk(
     if keypass is not None:
         optional_args.append("--keypassword")
         optional_args.append(keypass)
+    # Without zipalign and apksigner, the patcher uses its builtin signer
+    if zipalign is not None:
+        optional_args.append("-z")
+        optional_args.append(str(zipalign.absolute()))
+    if apksigner is not None:
+        optional_args.append("-a")
+        optional_args.append(str(apksigner.absolute()))
     subprocess.run(
         [
             str(PATCHER_BIN_PATH.absolute()),
@@ -37,10 +44,6 @@ def patch_apk(
             str(apkout.absolute()),
             "-k",
             str(keystore.absolute()),
-            "-z",
-            str(zipalign.absolute()),
-            "-a",
-            str(apksigner.absolute()),
             "--code-loading-patch-strategy",
             "model-class-loaders",
             *optional_args,
@@ -143,20 +146,14 @@ def main():
     else:
         runner_f = None
 
-    if zipalign is None:
+    if zipalign is None or apksigner is None:
         print(
-            "Could not find zipalign, please install an android build-tools package. "
-            "If one is already installed, please use `--zipalign` to provide the path "
-            "to the zipalign executable."
+            "Could not find zipalign or apksigner, the apk will be aligned and signed by "
+            "the patcher itself. To use the android build-tools instead, use `--zipalign` "
+            "and `--apksigner` to provide the path to the executables."
         )
-        exit(1)
-    if apksigner is None:
-        print(
-            "Could not find apksigner, please install an android build-tools package. "
-            "If one is already installed, please use `--apksigner` to provide the path "
-            "to the apksigner executable."
-        )
-        exit(1)
+        zipalign = None
+        apksigner = None
     if keytool is None and not args.keystore.exists():
         print(
             f"Could not find keytool and {str(args.keystore)} does not exist. Either "